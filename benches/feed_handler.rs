@@ -0,0 +1,231 @@
+use bitmex_md::bitmex_message::{parse, BitmexMessage};
+use bitmex_md::instrument::SymbolRegistry;
+use bitmex_md::l2_decoder::L2Decoder;
+use bitmex_md::order_book::OrderBook;
+use bitmex_md::parser::Parser;
+use criterion::{criterion_group, criterion_main, BenchmarkId, Criterion, Throughput};
+use llws::FrameAssembler;
+use std::hint::black_box;
+
+#[allow(dead_code)]
+#[path = "../src/fixtures.rs"]
+mod fixtures;
+
+use fixtures::{INFO, SNAPSHOT, SUBSCRIBE, TRADE};
+
+const TRADE_SNAPSHOT: &[u8] = b"{\"table\":\"trade\",\"action\":\"partial\",\"keys\":[],\"types\":{\"timestamp\":\"timestamp\",\"symbol\":\"symbol\",\"side\":\"symbol\",\"size\":\"long\",\"price\":\"float\",\"tickDirection\":\"symbol\",\"trdMatchID\":\"guid\",\"grossValue\":\"long\",\"homeNotional\":\"float\",\"foreignNotional\":\"float\"},\"foreignKeys\":{\"symbol\":\"instrument\",\"side\":\"side\"},\"attributes\":{\"timestamp\":\"sorted\",\"symbol\":\"grouped\"},\"filter\":{\"symbol\":\"XBTUSD\"},\"data\":[{\"timestamp\":\"2020-07-19T19:42:57.047Z\",\"symbol\":\"XBTUSD\",\"side\":\"Sell\",\"size\":446,\"price\":9155.5,\"tickDirection\":\"MinusTick\",\"trdMatchID\":\"3a90d7b2-8b2b-556f-0dc5-bfde052e240b\",\"grossValue\":4871212,\"homeNotional\":0.04871212,\"foreignNotional\":446}]}";
+const INSERT: &[u8] = b"{\"table\":\"orderBookL2\",\"action\":\"insert\",\"data\":[{\"symbol\":\"XBTUSD\",\"id\":8799071200,\"side\":\"Buy\",\"size\":1,\"price\":9288}]}";
+const DELETE: &[u8] = b"{\"table\":\"orderBookL2\",\"action\":\"delete\",\"data\":[{\"symbol\":\"XBTUSD\",\"id\":8799071200,\"side\":\"Buy\"}]}";
+const UPDATE: &[u8] = b"{\"table\":\"orderBookL2\",\"action\":\"update\",\"data\":[{\"symbol\":\"XBTUSD\",\"id\":8799070850,\"side\":\"Sell\",\"size\":182112},{\"symbol\":\"XBTUSD\",\"id\":8799070950,\"side\":\"Buy\",\"size\":19575}]}";
+
+// ids of the levels of the snapshot, by side
+const ASK_IDS: [i64; 9] = [
+    8799070500, 8799070550, 8799070600, 8799070650, 8799070700, 8799070750, 8799070800, 8799070850,
+    8799070900,
+];
+const BID_IDS: [i64; 5] = [8799070950, 8799071000, 8799071050, 8799071100, 8799071150];
+
+// update of the snapshot levels with the given number of entries, as sent on a busy book
+fn update_batch(len: usize) -> String {
+    let entries: Vec<String> = (0..len)
+        .map(|i| {
+            let (id, side) = if i % 2 == 0 {
+                (ASK_IDS[(i / 2) % ASK_IDS.len()], "Sell")
+            } else {
+                (BID_IDS[(i / 2) % BID_IDS.len()], "Buy")
+            };
+            format!(
+                "{{\"symbol\":\"XBTUSD\",\"id\":{},\"side\":\"{}\",\"size\":{}}}",
+                id,
+                side,
+                1000 + i
+            )
+        })
+        .collect();
+    format!(
+        "{{\"table\":\"orderBookL2\",\"action\":\"update\",\"data\":[{}]}}",
+        entries.join(",")
+    )
+}
+
+fn parsed(text: &[u8]) -> BitmexMessage {
+    parse(text).ok().unwrap()
+}
+
+// unmasked text frame, as sent by the server
+fn frame(out: &mut Vec<u8>, payload: &[u8]) {
+    out.push(0x81);
+    let len = payload.len();
+    if len < 126 {
+        out.push(len as u8);
+    } else if len <= u16::MAX as usize {
+        out.push(126);
+        out.extend_from_slice(&(len as u16).to_be_bytes());
+    } else {
+        out.push(127);
+        out.extend_from_slice(&(len as u64).to_be_bytes());
+    }
+    out.extend_from_slice(payload);
+}
+
+fn throughput(messages: usize, bytes: usize) -> Throughput {
+    Throughput::ElementsAndBytes {
+        elements: messages as u64,
+        bytes: bytes as u64,
+    }
+}
+
+fn bench_parse(c: &mut Criterion) {
+    let mut group = c.benchmark_group("parse");
+    let update_100 = update_batch(100);
+    let update_1000 = update_batch(1000);
+    let messages = [
+        ("info", INFO),
+        ("subscribe", SUBSCRIBE),
+        ("snapshot", SNAPSHOT),
+        ("insert", INSERT),
+        ("update", UPDATE),
+        ("update_100", update_100.as_bytes()),
+        ("update_1000", update_1000.as_bytes()),
+        ("delete", DELETE),
+        ("trade_snapshot", TRADE_SNAPSHOT),
+        ("trade", TRADE),
+    ];
+    for (name, text) in messages.iter() {
+        group.throughput(throughput(1, text.len()));
+        group.bench_with_input(BenchmarkId::from_parameter(name), text, |b, text| {
+            b.iter(|| parse(black_box(text)).ok())
+        });
+    }
+    group.finish();
+
+    let mut group = c.benchmark_group("l2_decoder");
+    let messages = [
+        ("insert", INSERT),
+        ("update", UPDATE),
+        ("update_100", update_100.as_bytes()),
+        ("update_1000", update_1000.as_bytes()),
+        ("delete", DELETE),
+    ];
+    let mut decoder = L2Decoder::new();
+    let mut symbols = SymbolRegistry::new();
+    for (name, text) in messages.iter() {
+        group.throughput(throughput(1, text.len()));
+        group.bench_with_input(BenchmarkId::from_parameter(name), text, |b, text| {
+            b.iter(|| decoder.decode(black_box(text), &mut symbols).ok())
+        });
+    }
+    group.finish();
+
+    let mut group = c.benchmark_group("parser");
+    let messages = [
+        ("insert", INSERT),
+        ("update", UPDATE),
+        ("update_100", update_100.as_bytes()),
+        ("update_1000", update_1000.as_bytes()),
+        ("delete", DELETE),
+        ("trade", TRADE),
+    ];
+    let mut parser = Parser::new();
+    for (name, text) in messages.iter() {
+        group.throughput(throughput(1, text.len()));
+        group.bench_with_input(BenchmarkId::from_parameter(name), text, |b, text| {
+            b.iter(|| parser.parse(black_box(text), &mut symbols).is_ok())
+        });
+    }
+    group.finish();
+}
+
+fn bench_order_book(c: &mut Criterion) {
+    let mut group = c.benchmark_group("order_book");
+    let mut symbols = SymbolRegistry::new();
+    let snapshot = parsed(SNAPSHOT);
+    let insert = parsed(INSERT);
+    let delete = parsed(DELETE);
+
+    group.throughput(throughput(1, SNAPSHOT.len()));
+    group.bench_function("snapshot", |b| {
+        let mut book = OrderBook::new(&mut symbols, "XBTUSD");
+        b.iter(|| {
+            book.apply(black_box(&snapshot), |event| {
+                black_box(event);
+            })
+        })
+    });
+
+    // the level inserted is deleted again to keep the book unchanged
+    group.throughput(throughput(2, INSERT.len() + DELETE.len()));
+    group.bench_function("insert_delete", |b| {
+        let mut book = OrderBook::new(&mut symbols, "XBTUSD");
+        book.apply(&snapshot, |_| {});
+        b.iter(|| {
+            book.apply(black_box(&insert), |event| {
+                black_box(event);
+            });
+            book.apply(black_box(&delete), |event| {
+                black_box(event);
+            });
+        })
+    });
+
+    for len in [2, 100, 1000].iter() {
+        let text = update_batch(*len);
+        let update = parsed(text.as_bytes());
+        group.throughput(throughput(1, text.len()));
+        group.bench_with_input(BenchmarkId::new("update", len), &update, |b, update| {
+            let mut book = OrderBook::new(&mut symbols, "XBTUSD");
+            book.apply(&snapshot, |_| {});
+            b.iter(|| {
+                book.apply(black_box(update), |event| {
+                    black_box(event);
+                })
+            })
+        });
+    }
+    group.finish();
+}
+
+// frames read from the socket to book events: a snapshot followed by update, insert, delete and
+// trade messages, fed to the FrameAssembler in reads of the size used by the examples
+fn bench_frame_to_event(c: &mut Criterion) {
+    let mut group = c.benchmark_group("frame_to_event");
+    let mut symbols = SymbolRegistry::new();
+    let update_100 = update_batch(100);
+    let mut stream = vec![];
+    let mut messages = 0;
+    frame(&mut stream, SNAPSHOT);
+    messages += 1;
+    for _ in 0..100 {
+        for text in [UPDATE, INSERT, DELETE, TRADE, update_100.as_bytes()].iter() {
+            frame(&mut stream, text);
+            messages += 1;
+        }
+    }
+
+    for read_size in [8192, 65536].iter() {
+        group.throughput(throughput(messages, stream.len()));
+        group.bench_with_input(
+            BenchmarkId::new("read_size", read_size),
+            read_size,
+            |b, read_size| {
+                let mut assembler = FrameAssembler::new();
+                let mut book = OrderBook::new(&mut symbols, "XBTUSD");
+                b.iter(|| {
+                    let mut events = 0u64;
+                    for read in stream.chunks(*read_size) {
+                        assembler.read(read, |_, payload| {
+                            if let Ok(message) = parse(payload) {
+                                book.apply(&message, |_| events += 1);
+                            }
+                        });
+                    }
+                    events
+                })
+            },
+        );
+    }
+    group.finish();
+}
+
+criterion_group!(benches, bench_parse, bench_order_book, bench_frame_to_event);
+criterion_main!(benches);
@@ -1,9 +1,15 @@
 use bitmex_md::bitmex_md_handler::BitmexMdHandler;
+#[cfg(feature = "latency")]
+use bitmex_md::latency::{Clock, MessageType, Stage, StageTimes};
+#[cfg(feature = "latency")]
+use bitmex_md::normalized::MarketDataSource;
 use llws::{generate_mask, FrameAssembler, FrameHeader, FrameWriter, OpCode};
 use native_tls::TlsConnector;
 use std::io::{Read, Write};
 use std::net::TcpStream;
 use std::time::Instant;
+#[cfg(feature = "latency")]
+use std::time::{SystemTime, UNIX_EPOCH};
 use url::Url;
 
 fn main() {
@@ -13,6 +19,12 @@ fn main() {
     // add symbol filter to consume data
     handler.add_symbol("XBTUSD");
 
+    // parse the payloads with the handler to measure its latency
+    #[cfg(feature = "latency")]
+    let clock = Clock::new();
+    #[cfg(feature = "latency")]
+    handler.set_latency_clock(clock);
+
     // connect
     // TODO: connection function which does all of this
     //  the client function is useful because it does the handshake with any arbitrary stream that
@@ -62,7 +74,10 @@ fn main() {
 
     // write the subscribe message
     match socket.write(&out_buffer[0..message_len]) {
-        Ok(n) => println!("bytes written: {}", n),
+        Ok(n) => {
+            println!("bytes written: {}", n);
+            handler.on_request_sent(&subscription_request);
+        }
         Err(ref e) => println!("error sending subscription request message: {:?}", e),
     }
 
@@ -75,6 +90,8 @@ fn main() {
     println!("start read loop");
     loop {
         // let read = socket.read(&mut buffer);
+        #[cfg(feature = "latency")]
+        let mut times = StageTimes::new(clock.now());
         let read = socket.read(&mut buffer[..]);
         match read {
             Ok(0) => {}
@@ -82,13 +99,26 @@ fn main() {
                 // frame_assembler.read(&buffer[0..n], frame_printer);
 
                 let start = Instant::now();
+                #[cfg(not(feature = "latency"))]
                 frame_assembler.read(&buffer[0..n], frame_noop);
+                #[cfg(feature = "latency")]
+                times.end(Stage::SocketRead, clock.now());
+                #[cfg(feature = "latency")]
+                frame_assembler.read(&buffer[0..n], |_, payload| {
+                    times.end(Stage::FrameAssembly, clock.now());
+                    handler.set_stage_times(times);
+                    let _ = handler.on_payload(unix_nanos(), payload, &mut |_| {});
+                    // the next payload of the read is assembled from here
+                    times = StageTimes::new(clock.now());
+                });
                 let parse_end = Instant::now();
                 let parse_elapsed = parse_end.duration_since(start);
                 println!(
                     "elapsed duration {} nanos to read message",
                     parse_elapsed.as_nanos()
                 );
+                #[cfg(feature = "latency")]
+                print_latency(&mut handler);
             }
             Err(ref e) if e.kind() == std::io::ErrorKind::WouldBlock => break,
             Err(_) => break,
@@ -101,4 +131,52 @@ fn frame_printer(op_code: u8, payload: &[u8]) {
     println!("{:?}", String::from_utf8_lossy(payload));
 }
 
+#[cfg(not(feature = "latency"))]
 fn frame_noop(_op_code: u8, _payload: &[u8]) {}
+
+// receive time of the payloads, for the exchange latency of the trades
+#[cfg(feature = "latency")]
+fn unix_nanos() -> i64 {
+    SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .unwrap()
+        .as_nanos() as i64
+}
+
+// print the latency percentiles every 1000 messages
+#[cfg(feature = "latency")]
+fn print_latency(handler: &mut BitmexMdHandler) {
+    let stats = match handler.latency_stats_mut() {
+        Some(stats) => stats,
+        None => return,
+    };
+    let messages: u64 = MessageType::ALL
+        .iter()
+        .map(|message_type| stats.stage(Stage::Parse, *message_type).len())
+        .sum();
+    if messages < 1000 {
+        return;
+    }
+    for stage in Stage::ALL.iter() {
+        for message_type in MessageType::ALL.iter() {
+            let summary = stats.stage_summary(*stage, *message_type);
+            if summary.count > 0 {
+                println!(
+                    "{} {}: count {} p50 {} p99 {} max {} nanos",
+                    stage.name(),
+                    message_type.name(),
+                    summary.count,
+                    summary.p50,
+                    summary.p99,
+                    summary.max
+                );
+            }
+        }
+    }
+    let exchange = stats.exchange_summary();
+    println!(
+        "exchange to receive: count {} p50 {} p99 {} nanos",
+        exchange.count, exchange.p50, exchange.p99
+    );
+    stats.reset();
+}
@@ -0,0 +1,80 @@
+#![no_main]
+use bitmex_md::bitmex_message::parse;
+use bitmex_md::instrument::SymbolRegistry;
+use bitmex_md::normalized::Side;
+use bitmex_md::order_book::{BookEvent, OrderBook};
+use bitmex_md::parser::Parser;
+use libfuzzer_sys::fuzz_target;
+use llws::FrameAssembler;
+use std::cmp::Ordering;
+
+// Feeds the input to the FrameAssembler in chunks (the first byte is the chunk size), parses the
+// text frames and applies them to a book, checking the invariants of the book after each message.
+// The frames are also given to the Parser and applied to a second book which must give the same
+// events.
+fuzz_target!(|data: &[u8]| {
+    let (chunk_size, data) = match data.split_first() {
+        Some((chunk_size, data)) => (*chunk_size as usize + 1, data),
+        None => return,
+    };
+    let mut assembler = FrameAssembler::new();
+    // the books are built from the registry the parser interns the symbols of the rows into
+    let mut symbols = SymbolRegistry::new();
+    let mut book = OrderBook::new(&mut symbols, "XBTUSD");
+    let mut parser = Parser::new();
+    let mut parser_book = OrderBook::new(&mut symbols, "XBTUSD");
+    for chunk in data.chunks(chunk_size) {
+        assembler.read(chunk, |op_code, payload| {
+            if op_code != 1 {
+                return;
+            }
+            let mut events = vec![];
+            if let Ok(message) = parse(payload) {
+                let mut violation = false;
+                book.apply(&message, |event| {
+                    if let BookEvent::Violation(_) = event {
+                        violation = true;
+                    }
+                    events.push(event);
+                });
+                check_book(&book);
+                assert!(!violation || !book.is_valid());
+            }
+            let mut parser_events = vec![];
+            if let Ok(message) = parser.parse(payload, &mut symbols) {
+                parser_book.apply_ref(&message, |event| parser_events.push(event));
+            }
+            assert_eq!(parser_events, events);
+            assert_eq!(parser_book.is_valid(), book.is_valid());
+            assert_eq!(parser_book.top_of_book(), book.top_of_book());
+        });
+    }
+});
+
+// sides are sorted by price and every level of a side is in the book
+fn check_book(book: &OrderBook) {
+    let mut previous: Option<f64> = None;
+    for level in book.bids() {
+        assert_eq!(level.side, Side::Buy);
+        assert!(level.size != 0);
+        assert_eq!(book.level(level.id), Some(level));
+        assert!(previous.map_or(true, |price| level.price.total_cmp(&price) == Ordering::Less));
+        previous = Some(level.price);
+    }
+    let mut previous: Option<f64> = None;
+    for level in book.asks() {
+        assert_eq!(level.side, Side::Sell);
+        assert!(level.size != 0);
+        assert_eq!(book.level(level.id), Some(level));
+        assert!(previous.map_or(true, |price| level.price.total_cmp(&price) == Ordering::Greater));
+        previous = Some(level.price);
+    }
+    if book.is_valid() {
+        if let (Some(bid), Some(ask)) = (book.best_bid(), book.best_ask()) {
+            assert!(bid.price < ask.price);
+        }
+    }
+    let top = book.top_of_book();
+    assert_eq!(top.bid.as_ref(), book.best_bid());
+    assert_eq!(top.ask.as_ref(), book.best_ask());
+}
@@ -0,0 +1,10 @@
+#![no_main]
+use bitmex_md::bitmex_message::{parse, parse_multiplexed, parse_table};
+use libfuzzer_sys::fuzz_target;
+
+// parsers must return an error on any malformed input, never panic
+fuzz_target!(|data: &[u8]| {
+    let _ = parse(data);
+    let _ = parse_table(data);
+    let _ = parse_multiplexed(data);
+});
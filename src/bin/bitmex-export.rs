@@ -0,0 +1,146 @@
+use bitmex_md::bitmex_message::{BitmexMessage, TradeEntry};
+use bitmex_md::export::{BookSnapshotCsvWriter, TradeCsvWriter, TradeJsonlWriter};
+use bitmex_md::instrument::SymbolRegistry;
+use bitmex_md::order_book::OrderBook;
+use bitmex_md::replayer::Replayer;
+use std::fs::File;
+use std::io::{self, BufWriter, Write};
+use std::path::PathBuf;
+use std::process;
+
+const USAGE: &str =
+    "usage: bitmex-export <trades-csv|trades-jsonl|book-csv> <output|-> <recording>... \
+[--symbol SYMBOL] [--depth N] [--interval-ms N]";
+
+const FORMATS: [&str; 3] = ["trades-csv", "trades-jsonl", "book-csv"];
+
+struct Args {
+    format: String,
+    output: String,
+    recordings: Vec<PathBuf>,
+    symbol: String,
+    depth: usize,
+    interval_ms: u64,
+}
+
+fn parse_args() -> Result<Args, String> {
+    let mut args = std::env::args().skip(1);
+    let format = args.next().ok_or("missing format")?;
+    // checked before the output is created, so that a typo does not truncate an existing file
+    if !FORMATS.contains(&format.as_str()) {
+        return Err(format!("unknown format {}", format));
+    }
+    let output = args.next().ok_or("missing output")?;
+    let mut parsed = Args {
+        format,
+        output,
+        recordings: vec![],
+        symbol: String::from("XBTUSD"),
+        depth: 10,
+        interval_ms: 1000,
+    };
+    while let Some(arg) = args.next() {
+        match arg.as_str() {
+            "--symbol" => parsed.symbol = args.next().ok_or("missing symbol")?,
+            "--depth" => {
+                parsed.depth = args
+                    .next()
+                    .and_then(|v| v.parse().ok())
+                    .ok_or("invalid depth")?
+            }
+            "--interval-ms" => {
+                parsed.interval_ms = args
+                    .next()
+                    .and_then(|v| v.parse().ok())
+                    .ok_or("invalid interval")?
+            }
+            _ => parsed.recordings.push(PathBuf::from(arg)),
+        }
+    }
+    if parsed.recordings.is_empty() {
+        return Err(String::from("missing recording"));
+    }
+    Ok(parsed)
+}
+
+fn export(args: &Args, output: Box<dyn Write>) -> io::Result<()> {
+    let replayer = Replayer::new(&args.recordings);
+    match args.format.as_str() {
+        "trades-csv" => {
+            let mut writer = TradeCsvWriter::new(output);
+            export_trades(&replayer, |entry| writer.write(entry))?;
+            writer.flush()
+        }
+        "trades-jsonl" => {
+            let mut writer = TradeJsonlWriter::new(output);
+            export_trades(&replayer, |entry| writer.write(entry))?;
+            writer.flush()
+        }
+        "book-csv" => {
+            // the replayed messages are parsed with parse, the book matches their rows on the
+            // symbol and the registry only assigns the id of the book
+            let mut symbols = SymbolRegistry::new();
+            let mut book = OrderBook::new(&mut symbols, &args.symbol);
+            let mut writer = BookSnapshotCsvWriter::new(output, args.depth);
+            writer.set_interval(args.interval_ms * 1_000_000);
+            let mut result = Ok(false);
+            replayer.run(|receive_time, message| {
+                if let Ok(message) = message {
+                    book.apply(&message, |_| {});
+                    if book.is_valid() && result.is_ok() {
+                        result = writer.write(receive_time, &book);
+                    }
+                }
+            })?;
+            result?;
+            writer.flush()
+        }
+        _ => Err(io::Error::new(
+            io::ErrorKind::InvalidInput,
+            format!("unknown format {}", args.format),
+        )),
+    }
+}
+
+// give the trades of the replayed messages to the writer, until the first write error
+fn export_trades<F>(replayer: &Replayer, mut write: F) -> io::Result<()>
+where
+    F: FnMut(&TradeEntry) -> io::Result<()>,
+{
+    let mut result = Ok(());
+    replayer.run(|_, message| {
+        if let Ok(BitmexMessage::Trade(trade)) = message {
+            for entry in trade.data.iter() {
+                if result.is_ok() {
+                    result = write(entry);
+                }
+            }
+        }
+    })?;
+    result
+}
+
+fn main() {
+    let args = match parse_args() {
+        Ok(args) => args,
+        Err(e) => {
+            eprintln!("{}\n{}", e, USAGE);
+            process::exit(2);
+        }
+    };
+    let output: Box<dyn Write> = if args.output == "-" {
+        Box::new(BufWriter::new(io::stdout()))
+    } else {
+        match File::create(&args.output) {
+            Ok(file) => Box::new(BufWriter::new(file)),
+            Err(e) => {
+                eprintln!("unable to create {}: {}", args.output, e);
+                process::exit(1);
+            }
+        }
+    };
+    if let Err(e) = export(&args, output) {
+        eprintln!("export failed: {}", e);
+        process::exit(1);
+    }
+}
@@ -1,30 +1,342 @@
-use crate::bitmex_message::MarketDataSubscriptionRequest;
+use crate::bitmex_message::{
+    make_envelope, parse, parse_envelope, BitmexMessage, MarketDataSubscriptionRequest,
+    MultiplexType, StreamError, StreamMessage,
+};
+use crate::instrument::SymbolRegistry;
+#[cfg(feature = "latency")]
+use crate::latency::{Clock, LatencyStats, MessageType, Stage, StageTimes};
+use crate::metrics::{FeedMetrics, SubscriptionState};
+use crate::normalized::{normalize_bitmex, MarketDataEvent, MarketDataSource, SourceError};
+use crate::order_book::OrderBook;
+use crate::recorder::Recorder;
+use crate::ws_server::TEXT_OP_CODE;
 use llws::handshake::HandshakeError;
+use std::collections::HashMap;
 use std::io::{Read, Write};
+use std::sync::Arc;
+
+// path of the realtime endpoint
+pub const REALTIME_PATH: &str = "/realtime";
+// path of the multiplexed realtime endpoint
+pub const REALTIME_MULTIPLEX_PATH: &str = "/realtimemd";
+
+// Subscription state of a multiplexed stream
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum StreamState {
+    // stream added, open request not sent yet
+    Pending,
+    // open request sent, waiting for the welcome message
+    Opening,
+    // welcome message received on the stream
+    Open,
+    // subscription acknowledged on the stream
+    Subscribed,
+    // error reply received on the stream, e.g. the subscription was rejected
+    Failed,
+    // close request sent
+    Closed,
+}
+
+// Logical stream of the multiplexed endpoint with its own symbol subscriptions
+pub struct MarketDataStream {
+    topic: String,
+    symbols: Vec<String>,
+    state: StreamState,
+}
+
+impl MarketDataStream {
+    pub fn topic(&self) -> &str {
+        &self.topic
+    }
+
+    pub fn symbols(&self) -> &[String] {
+        &self.symbols
+    }
+
+    pub fn state(&self) -> StreamState {
+        self.state
+    }
+}
 
 pub struct BitmexMdHandler {
     symbols: Vec<String>,
+    // ids of the instruments of the normalized events, shared with the Parser and the books (see
+    // SymbolRegistry)
+    instruments: SymbolRegistry,
+    streams: HashMap<String, MarketDataStream>,
+    // books kept from the orderBookL2 messages given to on_payload
+    books: Vec<OrderBook>,
+    metrics: Option<Arc<FeedMetrics>>,
+    recorder: Option<Recorder>,
+    #[cfg(feature = "latency")]
+    latency: Option<(Clock, LatencyStats)>,
+    // times of the stages ended by the caller for the next payload (see set_stage_times)
+    #[cfg(feature = "latency")]
+    stage_times: Option<StageTimes>,
 }
 
 impl BitmexMdHandler {
     pub fn new() -> Self {
-        BitmexMdHandler { symbols: vec![] }
+        BitmexMdHandler {
+            symbols: vec![],
+            instruments: SymbolRegistry::new(),
+            streams: HashMap::new(),
+            books: vec![],
+            metrics: None,
+            recorder: None,
+            #[cfg(feature = "latency")]
+            latency: None,
+            #[cfg(feature = "latency")]
+            stage_times: None,
+        }
     }
 
     pub fn add_symbol(&mut self, symbol: &str) {
+        self.instruments.intern(symbol);
         self.symbols.push(String::from(symbol))
     }
 
+    pub fn instruments(&self) -> &SymbolRegistry {
+        &self.instruments
+    }
+
+    // registry to give to Parser::parse and OrderBook::new, so that the rows, the books and the
+    // normalized events of the handler share the instrument ids
+    pub fn instruments_mut(&mut self) -> &mut SymbolRegistry {
+        &mut self.instruments
+    }
+
+    // keep the order book of a symbol, its orderBookL2 topic is added to the subscription request
+    pub fn add_book(&mut self, symbol: &str) {
+        let book = OrderBook::new(&mut self.instruments, symbol);
+        self.books.push(book);
+    }
+
+    pub fn book(&self, symbol: &str) -> Option<&OrderBook> {
+        self.books.iter().find(|book| book.symbol() == symbol)
+    }
+
+    // e.g. to enable the auto resubscribe or set the id to price mapping of a book
+    pub fn book_mut(&mut self, symbol: &str) -> Option<&mut OrderBook> {
+        self.books.iter_mut().find(|book| book.symbol() == symbol)
+    }
+
+    // metrics fed with the connections, subscriptions, payloads and book events of the handler
+    pub fn set_metrics(&mut self, metrics: Arc<FeedMetrics>) {
+        self.metrics = Some(metrics);
+    }
+
+    pub fn metrics(&self) -> Option<&Arc<FeedMetrics>> {
+        self.metrics.as_ref()
+    }
+
+    // record the payloads given to on_payload with their receive time, before they are parsed
+    pub fn set_recorder(&mut self, recorder: Recorder) {
+        self.recorder = Some(recorder);
+    }
+
+    // stop recording, the recorder is given back to be closed
+    pub fn take_recorder(&mut self) -> Option<Recorder> {
+        self.recorder.take()
+    }
+
+    // record the parse, book apply and dispatch latency of the payloads given to on_payload, timed
+    // with the given clock, and the exchange latency of the trades
+    #[cfg(feature = "latency")]
+    pub fn set_latency_clock(&mut self, clock: Clock) {
+        self.latency = Some((clock, LatencyStats::new()));
+    }
+
+    // times of the next payload given to on_payload, with the stages before it (socket read and
+    // frame assembly) ended by the caller with the clock of the handler. The parse stage of a
+    // payload without them starts when on_payload is called.
+    #[cfg(feature = "latency")]
+    pub fn set_stage_times(&mut self, times: StageTimes) {
+        self.stage_times = Some(times);
+    }
+
+    #[cfg(feature = "latency")]
+    pub fn latency_stats(&self) -> Option<&LatencyStats> {
+        self.latency.as_ref().map(|(_, stats)| stats)
+    }
+
+    // e.g. to reset the histograms after printing them
+    #[cfg(feature = "latency")]
+    pub fn latency_stats_mut(&mut self) -> Option<&mut LatencyStats> {
+        self.latency.as_mut().map(|(_, stats)| stats)
+    }
+
+    // request to subscribe to the topics of the symbols, the topics are marked as requested once
+    // the request is sent (see on_request_sent)
     pub fn get_subscription_request(&self) -> String {
-        let mut md_request = MarketDataSubscriptionRequest {
-            op: String::from("subscribe"),
-            args: vec![],
+        let mut topics = subscription_topics(&self.symbols);
+        for book in self.books.iter() {
+            topics.push(String::from("orderBookL2:") + book.symbol());
+        }
+        subscription_request(topics)
+    }
+
+    // requests to unsubscribe and subscribe again to a topic (e.g. orderBookL2:XBTUSD), this is
+    // how a fresh partial is requested after a book integrity violation
+    pub fn get_resubscribe_requests(&self, topic: &str) -> Vec<String> {
+        let mut requests = vec![];
+        for op in ["unsubscribe", "subscribe"].iter() {
+            let md_request = MarketDataSubscriptionRequest {
+                op: String::from(*op),
+                args: vec![String::from(topic)],
+            };
+            requests.push(serde_json::to_string(&md_request).unwrap());
+        }
+        requests
+    }
+
+    // add a stream to be opened on the multiplexed endpoint
+    pub fn add_stream(&mut self, stream_id: &str, topic: &str) {
+        self.streams.insert(
+            String::from(stream_id),
+            MarketDataStream {
+                topic: String::from(topic),
+                symbols: vec![],
+                state: StreamState::Pending,
+            },
+        );
+    }
+
+    // add symbol filter to a stream, returns false if the stream does not exist
+    pub fn add_stream_symbol(&mut self, stream_id: &str, symbol: &str) -> bool {
+        match self.streams.get_mut(stream_id) {
+            Some(stream) => {
+                stream.symbols.push(String::from(symbol));
+                true
+            }
+            None => false,
+        }
+    }
+
+    pub fn get_stream(&self, stream_id: &str) -> Option<&MarketDataStream> {
+        self.streams.get(stream_id)
+    }
+
+    // envelopes to open all pending streams, the streams are marked as opening once the requests
+    // are sent (see on_request_sent)
+    pub fn get_open_stream_requests(&self) -> Vec<String> {
+        let mut requests = vec![];
+        for (stream_id, stream) in self.streams.iter() {
+            if stream.state == StreamState::Pending {
+                requests.push(make_envelope(
+                    MultiplexType::Subscribe,
+                    stream_id,
+                    &stream.topic,
+                    None,
+                ));
+            }
+        }
+        requests
+    }
+
+    // envelope with the subscription request for the symbols of a stream
+    pub fn get_stream_subscription_request(&self, stream_id: &str) -> Option<String> {
+        let stream = self.streams.get(stream_id)?;
+        Some(make_envelope(
+            MultiplexType::Message,
+            stream_id,
+            &stream.topic,
+            Some(&subscription_request(subscription_topics(&stream.symbols))),
+        ))
+    }
+
+    // envelope to close a stream, the stream is marked as closed once the request is sent (see
+    // on_request_sent)
+    pub fn get_close_stream_request(&self, stream_id: &str) -> Option<String> {
+        let stream = self.streams.get(stream_id)?;
+        Some(make_envelope(
+            MultiplexType::Unsubscribe,
+            stream_id,
+            &stream.topic,
+            None,
+        ))
+    }
+
+    // update the state of the stream a message was received on, the subscriptions acknowledged
+    // on the stream are marked as subscribed in the metrics
+    pub fn on_stream_message(&mut self, stream_message: &StreamMessage) {
+        if let (Some(metrics), BitmexMessage::Subscribe(subscribe)) =
+            (&self.metrics, &stream_message.message)
+        {
+            if subscribe.success() {
+                metrics.set_subscription_state(subscribe.topic(), SubscriptionState::Subscribed);
+            }
+        }
+        if let Some(stream) = self.streams.get_mut(&stream_message.stream_id) {
+            match &stream_message.message {
+                BitmexMessage::Info(_) if stream.state == StreamState::Opening => {
+                    stream.state = StreamState::Open
+                }
+                BitmexMessage::Subscribe(subscribe) if subscribe.success() => {
+                    stream.state = StreamState::Subscribed
+                }
+                _ => {}
+            }
+        }
+    }
+
+    // mark the stream an error reply was received on as failed, BitMEX rejects a request with
+    // {"status":400,"error":...} instead of an unsuccessful subscribe message
+    pub fn on_stream_error(&mut self, stream_error: &StreamError) {
+        if let Some(stream) = self.streams.get_mut(&stream_error.stream_id) {
+            stream.state = StreamState::Failed;
+        }
+    }
+
+    // update the state of the handler once a request was written to the connection: the topics
+    // of a subscribe request are requested, the topics of an unsubscribe request or of a closed
+    // stream are forgotten, an opened stream waits for its welcome message
+    pub fn on_request_sent(&mut self, request: &str) {
+        let envelope = match parse_envelope(request.as_bytes()) {
+            Ok(envelope) => envelope,
+            Err(_) => return self.on_subscription_request_sent(request),
         };
-        for symbol in self.symbols.iter() {
-            // md_request.args.push(String::from("orderBookL2:") + symbol);
-            md_request.args.push(String::from("trade:") + symbol);
+        match (envelope.msg_type, envelope.payload) {
+            (MultiplexType::Message, Some(payload)) => {
+                self.on_subscription_request_sent(payload.get())
+            }
+            (MultiplexType::Subscribe, _) => {
+                if let Some(stream) = self.streams.get_mut(&envelope.stream_id) {
+                    if stream.state == StreamState::Pending {
+                        stream.state = StreamState::Opening;
+                    }
+                }
+            }
+            (MultiplexType::Unsubscribe, _) => {
+                if let Some(stream) = self.streams.get_mut(&envelope.stream_id) {
+                    stream.state = StreamState::Closed;
+                    if let Some(metrics) = &self.metrics {
+                        for topic in subscription_topics(&stream.symbols).iter() {
+                            metrics.remove_subscription(topic);
+                        }
+                    }
+                }
+            }
+            _ => {}
+        }
+    }
+
+    fn on_subscription_request_sent(&self, request: &str) {
+        let metrics = match &self.metrics {
+            Some(metrics) => metrics,
+            None => return,
+        };
+        if let Ok(request) = serde_json::from_str::<MarketDataSubscriptionRequest>(request) {
+            for topic in request.args.iter() {
+                match request.op.as_str() {
+                    "subscribe" => {
+                        metrics.set_subscription_state(topic, SubscriptionState::Requested)
+                    }
+                    "unsubscribe" => metrics.remove_subscription(topic),
+                    _ => {}
+                }
+            }
         }
-        serde_json::to_string(&md_request).unwrap()
     }
 
     // initiate client handshake over the given stream
@@ -37,6 +349,214 @@ impl BitmexMdHandler {
     where
         Stream: Read + Write,
     {
-        llws::handshake::do_handshake(host, path, stream)
+        let stream = llws::handshake::do_handshake(host, path, stream)?;
+        if let Some(metrics) = &self.metrics {
+            metrics.on_connect();
+        }
+        Ok(stream)
+    }
+}
+
+impl MarketDataSource for BitmexMdHandler {
+    fn venue(&self) -> &str {
+        "bitmex"
+    }
+
+    fn add_symbol(&mut self, symbol: &str) {
+        BitmexMdHandler::add_symbol(self, symbol)
+    }
+
+    fn instruments(&self) -> &SymbolRegistry {
+        BitmexMdHandler::instruments(self)
+    }
+
+    fn subscription_requests(&self) -> Vec<String> {
+        vec![self.get_subscription_request()]
+    }
+
+    fn on_payload(
+        &mut self,
+        receive_time: i64,
+        payload: &[u8],
+        on_event: &mut dyn FnMut(MarketDataEvent),
+    ) -> Result<(), SourceError> {
+        if let Some(recorder) = &mut self.recorder {
+            recorder
+                .record_at(receive_time as u64, TEXT_OP_CODE, payload)
+                .map_err(|_| SourceError::RecordingFailed)?;
+        }
+        #[cfg(feature = "latency")]
+        let stage_times = self.stage_times.take();
+        #[cfg(feature = "latency")]
+        let mut times = self
+            .latency
+            .as_ref()
+            .map(|(clock, _)| stage_times.unwrap_or_else(|| StageTimes::new(clock.now())));
+        let message = match parse(payload) {
+            Ok(message) => message,
+            Err(error) => {
+                if let Some(metrics) = &self.metrics {
+                    metrics.on_parse_error(&error);
+                }
+                #[cfg(feature = "latency")]
+                if let (Some((clock, stats)), Some(times)) = (&mut self.latency, &mut times) {
+                    times.end(Stage::Parse, clock.now());
+                    stats.record(MessageType::Other, times);
+                }
+                return Err(SourceError::InvalidMessage);
+            }
+        };
+        #[cfg(feature = "latency")]
+        if let (Some((clock, _)), Some(times)) = (&self.latency, &mut times) {
+            times.end(Stage::Parse, clock.now());
+        }
+        if let Some(metrics) = &self.metrics {
+            metrics.on_message(&message);
+        }
+        let metrics = &self.metrics;
+        for book in self.books.iter_mut() {
+            book.apply(&message, |event| {
+                if let Some(metrics) = metrics {
+                    metrics.on_book_event(&event);
+                }
+            });
+        }
+        #[cfg(feature = "latency")]
+        if let (Some((clock, _)), Some(times)) = (&self.latency, &mut times) {
+            if !self.books.is_empty() {
+                times.end(Stage::BookApply, clock.now());
+            }
+        }
+        let result = normalize_bitmex(receive_time, &message, &mut self.instruments, on_event);
+        #[cfg(feature = "latency")]
+        if let (Some((clock, stats)), Some(times)) = (&mut self.latency, &mut times) {
+            times.end(Stage::Dispatch, clock.now());
+            stats.record(MessageType::from_message(&message), times);
+            if let BitmexMessage::Trade(trade) = &message {
+                stats.record_trades(&trade.data, receive_time);
+            }
+        }
+        result
+    }
+}
+
+fn subscription_topics(symbols: &[String]) -> Vec<String> {
+    let mut topics = vec![];
+    for symbol in symbols.iter() {
+        // topics.push(String::from("orderBookL2:") + symbol);
+        topics.push(String::from("trade:") + symbol);
+    }
+    topics
+}
+
+fn subscription_request(topics: Vec<String>) -> String {
+    let md_request = MarketDataSubscriptionRequest {
+        op: String::from("subscribe"),
+        args: topics,
+    };
+    serde_json::to_string(&md_request).unwrap()
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::bitmex_md_handler::{BitmexMdHandler, StreamState};
+    use crate::bitmex_message::{parse_multiplexed, parse_multiplexed_error};
+    use crate::fixtures::{INFO, TOP_SNAPSHOT};
+    use crate::normalized::MarketDataSource;
+    use crate::recorder::{Recorder, RecorderConfig, RecordingReader};
+    use std::fs;
+
+    #[test]
+    fn stream_states() {
+        let mut handler = BitmexMdHandler::new();
+        handler.add_stream("stream-1", "md");
+        handler.add_stream_symbol("stream-1", "XBTUSD");
+        let open = handler.get_open_stream_requests();
+        assert_eq!(open, ["[1,\"stream-1\",\"md\"]"]);
+        assert_eq!(
+            handler.get_stream("stream-1").unwrap().state(),
+            StreamState::Pending
+        );
+        handler.on_request_sent(&open[0]);
+        assert_eq!(
+            handler.get_stream("stream-1").unwrap().state(),
+            StreamState::Opening
+        );
+        assert!(handler.get_open_stream_requests().is_empty());
+
+        let info = b"[0,\"stream-1\",\"md\",{\"info\":\"Welcome to the BitMEX Realtime API.\",\"version\":\"2020-06-30T21:03:12.000Z\",\"timestamp\":\"2020-07-08T11:00:02.855Z\",\"docs\":\"https://www.bitmex.com/app/wsAPI\",\"limit\":{\"remaining\":39}}]";
+        handler.on_stream_message(&parse_multiplexed(info).unwrap());
+        assert_eq!(
+            handler.get_stream("stream-1").unwrap().state(),
+            StreamState::Open
+        );
+
+        // a rejected subscription is an error reply, not a subscribe message
+        let rejected = b"[0,\"stream-1\",\"md\",{\"status\":400,\"error\":\"Unknown or expired table: trad\",\"meta\":{},\"request\":{\"op\":\"subscribe\",\"args\":[\"trad:XBTUSD\"]}}]";
+        assert!(parse_multiplexed(rejected).is_err());
+        let stream_error = parse_multiplexed_error(rejected).unwrap();
+        assert_eq!(stream_error.error.status, 400);
+        handler.on_stream_error(&stream_error);
+        assert_eq!(
+            handler.get_stream("stream-1").unwrap().state(),
+            StreamState::Failed
+        );
+        let subscribed = b"[0,\"stream-1\",\"md\",{\"success\":true,\"subscribe\":\"trade:XBTUSD\",\"request\":{\"op\":\"subscribe\",\"args\":[\"trade:XBTUSD\"]}}]";
+        handler.on_stream_message(&parse_multiplexed(subscribed).unwrap());
+        assert_eq!(
+            handler.get_stream("stream-1").unwrap().state(),
+            StreamState::Subscribed
+        );
+
+        // the stream is closed once the close request is sent
+        let close = handler.get_close_stream_request("stream-1").unwrap();
+        assert_eq!(close, "[2,\"stream-1\",\"md\"]");
+        assert_eq!(
+            handler.get_stream("stream-1").unwrap().state(),
+            StreamState::Subscribed
+        );
+        handler.on_request_sent(&close);
+        assert_eq!(
+            handler.get_stream("stream-1").unwrap().state(),
+            StreamState::Closed
+        );
+        assert!(handler.get_close_stream_request("stream-2").is_none());
+    }
+
+    #[test]
+    fn record_payloads() {
+        let directory = std::env::temp_dir().join("bitmex-md-handler-recorder-test");
+        let _ = fs::remove_dir_all(&directory);
+        let mut handler = BitmexMdHandler::new();
+        handler.set_recorder(
+            Recorder::new(RecorderConfig::new(
+                &directory,
+                "wss://www.bitmex.com/realtime",
+            ))
+            .unwrap(),
+        );
+        let mut events = 0;
+        handler.on_payload(1, INFO, &mut |_| events += 1).unwrap();
+        handler
+            .on_payload(2, TOP_SNAPSHOT, &mut |_| events += 1)
+            .unwrap();
+        // payloads that fail to parse are recorded too
+        assert!(handler.on_payload(3, b"{", &mut |_| {}).is_err());
+
+        let recorder = handler.take_recorder().unwrap();
+        let path = recorder.files()[0].clone();
+        recorder.close().unwrap();
+        let frames: Vec<_> = RecordingReader::open(&path)
+            .unwrap()
+            .map(|f| f.unwrap())
+            .collect();
+        assert_eq!(frames.len(), 3);
+        assert_eq!(frames[1].receive_time, 2);
+        assert_eq!(frames[1].op_code, 1);
+        assert_eq!(frames[1].payload, TOP_SNAPSHOT);
+        assert_eq!(frames[2].payload, b"{");
+        assert!(events > 0);
+
+        let _ = fs::remove_dir_all(&directory);
     }
 }
@@ -1,48 +1,52 @@
+use serde::de::DeserializeOwned;
 use serde::{Deserialize, Serialize};
+use serde_json::value::RawValue;
+#[cfg(feature = "simd")]
+use std::cell::RefCell;
 
 #[derive(Serialize, Deserialize, Debug)]
 pub struct UpdateEntry {
-    symbol: String,
-    id: i64,
-    side: String,
-    size: i64,
+    pub symbol: String,
+    pub id: i64,
+    pub side: String,
+    pub size: i64,
 }
 
 #[derive(Serialize, Deserialize, Debug)]
 pub struct UpdateMessage {
-    table: String,
-    action: String,
-    data: Vec<UpdateEntry>,
+    pub table: String,
+    pub action: String,
+    pub data: Vec<UpdateEntry>,
 }
 
 #[derive(Serialize, Deserialize, Debug)]
 pub struct DeleteEntry {
-    symbol: String,
-    id: i64,
-    side: String,
+    pub symbol: String,
+    pub id: i64,
+    pub side: String,
 }
 
 #[derive(Serialize, Deserialize, Debug)]
 pub struct DeleteMessage {
-    table: String,
-    action: String,
-    data: Vec<DeleteEntry>,
+    pub table: String,
+    pub action: String,
+    pub data: Vec<DeleteEntry>,
 }
 
 #[derive(Serialize, Deserialize, Debug)]
 pub struct InsertEntry {
-    symbol: String,
-    id: i64,
-    side: String,
-    size: i64,
-    price: f64,
+    pub symbol: String,
+    pub id: i64,
+    pub side: String,
+    pub size: i64,
+    pub price: f64,
 }
 
 #[derive(Serialize, Deserialize, Debug)]
 pub struct InsertMessage {
-    table: String,
-    action: String,
-    data: Vec<InsertEntry>,
+    pub table: String,
+    pub action: String,
+    pub data: Vec<InsertEntry>,
 }
 
 #[derive(Serialize, Deserialize, Debug)]
@@ -68,20 +72,20 @@ pub struct Attributes {
 
 #[derive(Serialize, Deserialize, Debug)]
 pub struct Filter {
-    symbol: String,
+    pub symbol: String,
 }
 
 #[derive(Serialize, Deserialize, Debug)]
 pub struct SnapshotMessage {
-    table: String,
-    action: String,
-    keys: Vec<String>,
-    types: Types,
+    pub table: String,
+    pub action: String,
+    pub keys: Vec<String>,
+    pub types: Types,
     #[serde(rename = "foreignKeys")]
-    foreign_keys: ForeignKeys,
-    attributes: Attributes,
-    filter: Filter,
-    data: Vec<InsertEntry>,
+    pub foreign_keys: ForeignKeys,
+    pub attributes: Attributes,
+    pub filter: Filter,
+    pub data: Vec<InsertEntry>,
 }
 
 #[derive(Serialize, Deserialize, Debug)]
@@ -98,6 +102,13 @@ pub struct InfoMessage {
     limit: Limit,
 }
 
+impl InfoMessage {
+    // requests left in the rate limit window
+    pub fn limit_remaining(&self) -> i32 {
+        self.limit.remaining
+    }
+}
+
 #[derive(Serialize, Deserialize, Debug)]
 pub struct Request {
     op: String,
@@ -111,24 +122,42 @@ pub struct SubscribeMessage {
     request: Request,
 }
 
+impl SubscribeMessage {
+    pub fn success(&self) -> bool {
+        self.success
+    }
+
+    // topic subscribed to, e.g. orderBookL2:XBTUSD
+    pub fn topic(&self) -> &str {
+        &self.subscribe
+    }
+}
+
+// Reply to a rejected request, e.g. {"status":400,"error":"Unknown table: foo","request":{...}}
+#[derive(Serialize, Deserialize, Debug)]
+pub struct ErrorMessage {
+    pub status: u16,
+    pub error: String,
+}
+
 #[derive(Serialize, Deserialize, Debug)]
 pub struct TradeSnapshotMessage {
-    table: String,
-    action: String,
-    keys: Vec<String>,
-    types: TradeTypes,
+    pub table: String,
+    pub action: String,
+    pub keys: Vec<String>,
+    pub types: TradeTypes,
     #[serde(rename = "foreignKeys")]
-    foreign_keys: ForeignKeys,
-    attributes: TradeAttributes,
-    filter: Filter,
-    data: Vec<TradeEntry>,
+    pub foreign_keys: ForeignKeys,
+    pub attributes: TradeAttributes,
+    pub filter: Filter,
+    pub data: Vec<TradeEntry>,
 }
 
 #[derive(Serialize, Deserialize, Debug)]
 pub struct TradeMessage {
-    table: String,
-    action: String,
-    data: Vec<TradeEntry>,
+    pub table: String,
+    pub action: String,
+    pub data: Vec<TradeEntry>,
 }
 
 #[derive(Serialize, Deserialize, Debug)]
@@ -158,21 +187,157 @@ pub struct TradeAttributes {
 
 #[derive(Serialize, Deserialize, Debug)]
 pub struct TradeEntry {
-    timestamp: String,
-    symbol: String,
-    side: String,
-    size: f64,
-    price: f64,
+    pub timestamp: String,
+    pub symbol: String,
+    pub side: String,
+    pub size: f64,
+    pub price: f64,
     #[serde(rename = "tickDirection")]
-    tick_direction: String,
+    pub tick_direction: String,
     #[serde(rename = "trdMatchID")]
-    trd_match_id: String,
+    pub trd_match_id: String,
     #[serde(rename = "grossValue")]
-    gross_value: f64,
+    pub gross_value: f64,
     #[serde(rename = "homeNotional")]
-    home_notional: f64,
+    pub home_notional: f64,
     #[serde(rename = "foreignNotional")]
-    foreign_notional: f64,
+    pub foreign_notional: f64,
+}
+
+impl TradeEntry {
+    // trade timestamp in nanos since unix epoch
+    pub fn timestamp_nanos(&self) -> Option<i64> {
+        parse_timestamp(&self.timestamp)
+    }
+}
+
+// parse a BitMEX timestamp (e.g. 2020-07-19T19:43:21.401Z) to nanos since unix epoch
+pub fn parse_timestamp(timestamp: &str) -> Option<i64> {
+    let bytes = timestamp.as_bytes();
+    if bytes.len() < 20
+        || bytes[4] != b'-'
+        || bytes[7] != b'-'
+        || bytes[10] != b'T'
+        || bytes[13] != b':'
+        || bytes[16] != b':'
+        || *bytes.last()? != b'Z'
+    {
+        return None;
+    }
+    let number = |from: usize, to: usize| -> Option<i64> {
+        let digits = bytes.get(from..to)?;
+        if !digits.iter().all(u8::is_ascii_digit) {
+            return None;
+        }
+        timestamp.get(from..to)?.parse().ok()
+    };
+    let (year, month, day) = (number(0, 4)?, number(5, 7)?, number(8, 10)?);
+    let (hour, minute, second) = (number(11, 13)?, number(14, 16)?, number(17, 19)?);
+    if !(1..=12).contains(&month)
+        || !(1..=days_in_month(year, month)).contains(&day)
+        || hour > 23
+        || minute > 59
+        || second > 59
+    {
+        return None;
+    }
+    let days = days_from_civil(year, month, day);
+    let seconds = days * 86400 + hour * 3600 + minute * 60 + second;
+
+    // optional fraction of a second, up to nanos
+    let mut nanos = 0;
+    let fraction = &bytes[19..bytes.len() - 1];
+    if !fraction.is_empty() {
+        if fraction[0] != b'.' || fraction.len() == 1 || fraction.len() > 10 {
+            return None;
+        }
+        for digit in fraction[1..].iter() {
+            if !digit.is_ascii_digit() {
+                return None;
+            }
+            nanos = nanos * 10 + (digit - b'0') as i64;
+        }
+        nanos *= 10_i64.pow(10 - fraction.len() as u32);
+    }
+    // i64 nanos only cover the years 1677 to 2262
+    seconds.checked_mul(1_000_000_000)?.checked_add(nanos)
+}
+
+fn days_in_month(year: i64, month: i64) -> i64 {
+    match month {
+        2 if year % 4 == 0 && (year % 100 != 0 || year % 400 == 0) => 29,
+        2 => 28,
+        4 | 6 | 9 | 11 => 30,
+        _ => 31,
+    }
+}
+
+// parse a uuid (e.g. ec06df7b-0dc0-8181-f693-c9f39fb57e56) to its 16 bytes
+pub fn parse_uuid(uuid: &str) -> Option<[u8; 16]> {
+    let mut bytes = [0u8; 16];
+    let mut count = 0;
+    let mut high: Option<u8> = None;
+    for c in uuid.chars() {
+        if c == '-' {
+            continue;
+        }
+        let digit = c.to_digit(16)? as u8;
+        match high.take() {
+            Some(h) => {
+                *bytes.get_mut(count)? = (h << 4) | digit;
+                count += 1;
+            }
+            None => high = Some(digit),
+        }
+    }
+    if count == 16 && high.is_none() {
+        Some(bytes)
+    } else {
+        None
+    }
+}
+
+// days since unix epoch of a gregorian calendar date
+fn days_from_civil(year: i64, month: i64, day: i64) -> i64 {
+    let year = if month <= 2 { year - 1 } else { year };
+    let era = if year >= 0 { year } else { year - 399 } / 400;
+    let year_of_era = year - era * 400;
+    let day_of_year = (153 * ((month + 9) % 12) + 2) / 5 + day - 1;
+    let day_of_era = year_of_era * 365 + year_of_era / 4 - year_of_era / 100 + day_of_year;
+    era * 146097 + day_of_era - 719468
+}
+
+// format nanos since unix epoch as a BitMEX timestamp with millis (e.g. 2020-07-19T19:43:21.401Z)
+pub fn format_timestamp(nanos: i64) -> String {
+    let millis = nanos.div_euclid(1_000_000);
+    let seconds = millis.div_euclid(1000);
+    let (year, month, day) = civil_from_days(seconds.div_euclid(86400));
+    let second_of_day = seconds.rem_euclid(86400);
+    format!(
+        "{:04}-{:02}-{:02}T{:02}:{:02}:{:02}.{:03}Z",
+        year,
+        month,
+        day,
+        second_of_day / 3600,
+        second_of_day % 3600 / 60,
+        second_of_day % 60,
+        millis.rem_euclid(1000)
+    )
+}
+
+// gregorian calendar date of a number of days since unix epoch
+fn civil_from_days(days: i64) -> (i64, i64, i64) {
+    let days = days + 719468;
+    let era = if days >= 0 { days } else { days - 146096 } / 146097;
+    let day_of_era = days - era * 146097;
+    let year_of_era =
+        (day_of_era - day_of_era / 1460 + day_of_era / 36524 - day_of_era / 146096) / 365;
+    let day_of_year = day_of_era - (365 * year_of_era + year_of_era / 4 - year_of_era / 100);
+    let mp = (5 * day_of_year + 2) / 153;
+    let day = day_of_year - (153 * mp + 2) / 5 + 1;
+    let month = if mp < 10 { mp + 3 } else { mp - 9 };
+    let year = year_of_era + era * 400 + if month <= 2 { 1 } else { 0 };
+    (year, month, day)
 }
 
 // MD Subscription Request for Bitmex
@@ -182,6 +347,7 @@ pub struct MarketDataSubscriptionRequest {
     pub args: Vec<String>,
 }
 
+#[derive(Debug)]
 pub enum BitmexMessage {
     Update(UpdateMessage),
     Delete(DeleteMessage),
@@ -193,6 +359,21 @@ pub enum BitmexMessage {
     Subscribe(SubscribeMessage),
 }
 
+// Generic table message. Rows are kept as raw json objects so that any table can be handled
+// without a dedicated type, the keys are only present on the partial
+#[derive(Serialize, Deserialize, Debug)]
+pub struct TableMessage {
+    pub table: String,
+    pub action: String,
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub keys: Vec<String>,
+    // fields selecting the rows of a partial (e.g. {"symbol":"XBTUSD"}), empty if the partial
+    // holds all the rows of the table
+    #[serde(default, skip_serializing_if = "serde_json::Map::is_empty")]
+    pub filter: serde_json::Map<String, serde_json::Value>,
+    pub data: Vec<serde_json::Map<String, serde_json::Value>>,
+}
+
 #[derive(Debug)]
 pub enum ParseError {
     Invalid,
@@ -200,38 +381,96 @@ pub enum ParseError {
     InvalidTable,
 }
 
+// three bytes at a fixed offset of the message, empty if the message is too short
+fn peek(message: &[u8], offset: usize) -> &[u8] {
+    message.get(offset..offset + 3).unwrap_or(&[])
+}
+
+// JSON decoder used by parse for the message types
+trait JsonBackend {
+    fn from_json<T: DeserializeOwned>(message: &[u8]) -> Result<T, ParseError>;
+}
+
+#[cfg(any(test, not(feature = "simd")))]
+struct SerdeJson;
+
+#[cfg(any(test, not(feature = "simd")))]
+impl JsonBackend for SerdeJson {
+    fn from_json<T: DeserializeOwned>(message: &[u8]) -> Result<T, ParseError> {
+        serde_json::from_slice(message).map_err(|_| ParseError::Invalid)
+    }
+}
+
+#[cfg(feature = "simd")]
+thread_local! {
+    static SIMD_BUFFERS: RefCell<(Vec<u8>, simd_json::Buffers)> =
+        RefCell::new((Vec::new(), simd_json::Buffers::default()));
+}
+
+// simd-json decodes in place, the message is copied to a buffer kept per thread. The simd
+// implementation is selected at runtime from the cpu features, with a portable fallback.
+// simd-json ends a number at a NUL byte instead of failing, valid JSON never has a raw NUL so
+// these messages are rejected up front like serde_json does.
+#[cfg(feature = "simd")]
+struct SimdJson;
+
+#[cfg(feature = "simd")]
+impl JsonBackend for SimdJson {
+    fn from_json<T: DeserializeOwned>(message: &[u8]) -> Result<T, ParseError> {
+        if message.contains(&0) {
+            return Err(ParseError::Invalid);
+        }
+        SIMD_BUFFERS.with(|buffers| {
+            let (input, buffers) = &mut *buffers.borrow_mut();
+            input.clear();
+            input.extend_from_slice(message);
+            simd_json::serde::from_slice_with_buffers(input, buffers)
+                .map_err(|_| ParseError::Invalid)
+        })
+    }
+}
+
+#[cfg(feature = "simd")]
+type DefaultBackend = SimdJson;
+#[cfg(not(feature = "simd"))]
+type DefaultBackend = SerdeJson;
+
+// parse a realtime message, the JSON is decoded with simd-json if the simd feature is enabled
 pub fn parse(message: &[u8]) -> Result<BitmexMessage, ParseError> {
+    parse_with::<DefaultBackend>(message)
+}
+
+fn parse_with<B: JsonBackend>(message: &[u8]) -> Result<BitmexMessage, ParseError> {
     // peek at the type found at the beginning of the message
-    let peek_type = &message[2..5];
+    let peek_type = peek(message, 2);
     match peek_type {
         // info: info message type received when connection is established
         b"inf" => {
-            let info_msg: InfoMessage = serde_json::from_slice(&message).unwrap();
+            let info_msg: InfoMessage = B::from_json(message)?;
             Ok(BitmexMessage::Info(info_msg))
         }
         // success: success message received when a subscription request is successful
         b"suc" => {
-            let subscribe_msg: SubscribeMessage = serde_json::from_slice(&message).unwrap();
+            let subscribe_msg: SubscribeMessage = B::from_json(message)?;
             Ok(BitmexMessage::Subscribe(subscribe_msg))
         }
         // table: table message received for a channel (e.g. orderBookL2, trade)
         b"tab" => {
             // peek at the table type
-            let peek_table = &message[10..13];
+            let peek_table = peek(message, 10);
             match peek_table {
                 // trade table
                 b"tra" => {
-                    let peek_action = &message[27..30];
+                    let peek_action = peek(message, 27);
                     match peek_action {
                         // partial: trade snapshot (schema + last trade)
                         b"par" => {
-                            let trade_snapshot: TradeSnapshotMessage =
-                                serde_json::from_slice(&message).unwrap();
+                            let trade_snapshot: TradeSnapshotMessage = B::from_json(message)?;
                             Ok(BitmexMessage::TradeSnapshot(trade_snapshot))
                         }
                         // insert: trade
                         b"ins" => {
-                            let trade: TradeMessage = serde_json::from_slice(&message).unwrap();
+                            let trade: TradeMessage = B::from_json(message)?;
                             Ok(BitmexMessage::Trade(trade))
                         }
                         _ => {
@@ -243,27 +482,26 @@ pub fn parse(message: &[u8]) -> Result<BitmexMessage, ParseError> {
                 // order book l2 table
                 b"ord" => {
                     // peek at the action type to determine the message type to parse
-                    let peek_action = &message[33..36];
+                    let peek_action = peek(message, 33);
                     match peek_action {
                         // partial: order book snapshot message
                         b"par" => {
-                            let snapshot: SnapshotMessage =
-                                serde_json::from_slice(&message).unwrap();
+                            let snapshot: SnapshotMessage = B::from_json(message)?;
                             Ok(BitmexMessage::Snapshot(snapshot))
                         }
                         // update
                         b"upd" => {
-                            let update: UpdateMessage = serde_json::from_slice(&message).unwrap();
+                            let update: UpdateMessage = B::from_json(message)?;
                             Ok(BitmexMessage::Update(update))
                         }
                         // insert
                         b"ins" => {
-                            let insert: InsertMessage = serde_json::from_slice(&message).unwrap();
+                            let insert: InsertMessage = B::from_json(message)?;
                             Ok(BitmexMessage::Insert(insert))
                         }
                         // delete
                         b"del" => {
-                            let delete: DeleteMessage = serde_json::from_slice(&message).unwrap();
+                            let delete: DeleteMessage = B::from_json(message)?;
                             Ok(BitmexMessage::Delete(delete))
                         }
                         _ => {
@@ -282,9 +520,132 @@ pub fn parse(message: &[u8]) -> Result<BitmexMessage, ParseError> {
     }
 }
 
+// parse a table message (partial, insert, update or delete) for any table into its generic form
+pub fn parse_table(message: &[u8]) -> Result<TableMessage, ParseError> {
+    let table_msg: TableMessage =
+        serde_json::from_slice(message).map_err(|_| ParseError::Invalid)?;
+    match table_msg.action.as_str() {
+        "partial" | "insert" | "update" | "delete" => Ok(table_msg),
+        _ => Err(ParseError::InvalidAction),
+    }
+}
+
+// Multiplexed stream envelope type, first element of a /realtimemd envelope
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum MultiplexType {
+    Message = 0,
+    Subscribe = 1,
+    Unsubscribe = 2,
+}
+
+// Envelope of the multiplexed (/realtimemd) endpoint: [type, id, topic, payload]. The payload is
+// left as raw json so it can be parsed as a regular realtime message.
+#[derive(Debug)]
+pub struct MultiplexEnvelope<'a> {
+    pub msg_type: MultiplexType,
+    pub stream_id: String,
+    pub topic: String,
+    pub payload: Option<&'a RawValue>,
+}
+
+// Message received on a multiplexed stream
+pub struct StreamMessage {
+    pub stream_id: String,
+    pub topic: String,
+    pub message: BitmexMessage,
+}
+
+pub fn parse_envelope(message: &[u8]) -> Result<MultiplexEnvelope<'_>, ParseError> {
+    let elements: Vec<&RawValue> =
+        serde_json::from_slice(message).map_err(|_| ParseError::Invalid)?;
+    if elements.len() < 3 || elements.len() > 4 {
+        return Err(ParseError::Invalid);
+    }
+    let msg_type = match elements[0].get() {
+        "0" => MultiplexType::Message,
+        "1" => MultiplexType::Subscribe,
+        "2" => MultiplexType::Unsubscribe,
+        _ => return Err(ParseError::Invalid),
+    };
+    let stream_id: String =
+        serde_json::from_str(elements[1].get()).map_err(|_| ParseError::Invalid)?;
+    let topic: String = serde_json::from_str(elements[2].get()).map_err(|_| ParseError::Invalid)?;
+    Ok(MultiplexEnvelope {
+        msg_type,
+        stream_id,
+        topic,
+        payload: elements.get(3).copied(),
+    })
+}
+
+// parse a message received on the multiplexed endpoint, the payload of the envelope is parsed
+// with the same parser used for the regular realtime endpoint
+pub fn parse_multiplexed(message: &[u8]) -> Result<StreamMessage, ParseError> {
+    let envelope = parse_envelope(message)?;
+    match (envelope.msg_type, envelope.payload) {
+        (MultiplexType::Message, Some(payload)) => {
+            let message = parse(payload.get().as_bytes())?;
+            Ok(StreamMessage {
+                stream_id: envelope.stream_id,
+                topic: envelope.topic,
+                message,
+            })
+        }
+        _ => Err(ParseError::Invalid),
+    }
+}
+
+// Error reply received on a multiplexed stream
+pub struct StreamError {
+    pub stream_id: String,
+    pub topic: String,
+    pub error: ErrorMessage,
+}
+
+// error replies are not realtime messages, parse gives an error for them
+pub fn parse_error(message: &[u8]) -> Result<ErrorMessage, ParseError> {
+    serde_json::from_slice(message).map_err(|_| ParseError::Invalid)
+}
+
+// parse an error reply received on the multiplexed endpoint, e.g. the rejection of the
+// subscription request of a stream
+pub fn parse_multiplexed_error(message: &[u8]) -> Result<StreamError, ParseError> {
+    let envelope = parse_envelope(message)?;
+    match (envelope.msg_type, envelope.payload) {
+        (MultiplexType::Message, Some(payload)) => Ok(StreamError {
+            stream_id: envelope.stream_id,
+            topic: envelope.topic,
+            error: parse_error(payload.get().as_bytes())?,
+        }),
+        _ => Err(ParseError::Invalid),
+    }
+}
+
+// build an envelope for the multiplexed endpoint, the payload must be valid json
+pub fn make_envelope(
+    msg_type: MultiplexType,
+    stream_id: &str,
+    topic: &str,
+    payload: Option<&str>,
+) -> String {
+    let stream_id = serde_json::to_string(stream_id).unwrap();
+    let topic = serde_json::to_string(topic).unwrap();
+    match payload {
+        Some(payload) => format!("[{},{},{},{}]", msg_type as u8, stream_id, topic, payload),
+        None => format!("[{},{},{}]", msg_type as u8, stream_id, topic),
+    }
+}
+
 #[cfg(test)]
 mod tests {
-    use crate::bitmex_message::{parse, BitmexMessage};
+    use crate::bitmex_message::{
+        format_timestamp, make_envelope, parse, parse_envelope, parse_multiplexed, parse_table,
+        parse_timestamp, BitmexMessage, MultiplexType,
+    };
+    #[cfg(feature = "simd")]
+    use crate::bitmex_message::{parse_with, SerdeJson, SimdJson};
+    #[cfg(feature = "simd")]
+    use proptest::prelude::*;
 
     #[test]
     fn parse_info_message() {
@@ -456,4 +817,227 @@ mod tests {
             _ => panic!("message parser error"),
         }
     }
+
+    #[test]
+    fn parse_generic_table_message() {
+        let text = b"{\"table\":\"orderBookL2\",\"action\":\"update\",\"data\":[{\"symbol\":\"XBTUSD\",\"id\":8799065200,\"side\":\"Sell\",\"size\":182112}]}";
+        let table_message = parse_table(text.as_ref()).unwrap();
+        assert_eq!(table_message.table, "orderBookL2");
+        assert_eq!(table_message.action, "update");
+        assert!(table_message.keys.is_empty());
+        assert_eq!(table_message.data.len(), 1);
+        assert_eq!(table_message.data[0]["size"], 182112);
+    }
+
+    #[test]
+    fn parse_multiplexed_message() {
+        let text = b"[0,\"stream-1\",\"md\",{\"table\":\"orderBookL2\",\"action\":\"delete\",\"data\":[{\"symbol\":\"XBTUSD\",\"id\":8799594200,\"side\":\"Buy\"}]}]";
+        let stream_message = parse_multiplexed(text.as_ref()).unwrap();
+        assert_eq!(stream_message.stream_id, "stream-1");
+        assert_eq!(stream_message.topic, "md");
+        match stream_message.message {
+            BitmexMessage::Delete(delete_message) => {
+                assert_eq!(delete_message.data.len(), 1);
+            }
+            _ => panic!("wrong message type"),
+        }
+    }
+
+    #[test]
+    fn make_and_parse_envelope() {
+        let open = make_envelope(MultiplexType::Subscribe, "stream-1", "md", None);
+        assert_eq!(open, "[1,\"stream-1\",\"md\"]");
+        let envelope = parse_envelope(open.as_bytes()).unwrap();
+        assert_eq!(envelope.msg_type, MultiplexType::Subscribe);
+        assert_eq!(envelope.stream_id, "stream-1");
+        assert_eq!(envelope.topic, "md");
+        assert!(envelope.payload.is_none());
+
+        let request = make_envelope(
+            MultiplexType::Message,
+            "stream-1",
+            "md",
+            Some("{\"op\":\"subscribe\",\"args\":[\"trade:XBTUSD\"]}"),
+        );
+        let envelope = parse_envelope(request.as_bytes()).unwrap();
+        assert_eq!(envelope.msg_type, MultiplexType::Message);
+        assert_eq!(
+            envelope.payload.unwrap().get(),
+            "{\"op\":\"subscribe\",\"args\":[\"trade:XBTUSD\"]}"
+        );
+    }
+
+    #[test]
+    fn parse_trade_timestamp() {
+        assert_eq!(
+            parse_timestamp("2020-07-19T19:43:21.401Z"),
+            Some(1595187801401000000)
+        );
+        assert_eq!(parse_timestamp("1970-01-01T00:00:00Z"), Some(0));
+        assert_eq!(
+            parse_timestamp("2020-02-29T00:00:00.000000001Z"),
+            Some(1582934400000000001)
+        );
+        assert_eq!(parse_timestamp("2020-07-19 19:43:21.401Z"), None);
+        assert_eq!(parse_timestamp("9999-12-31T23:59:59.999Z"), None);
+        // fields out of range are rejected rather than rolled over
+        for invalid in [
+            "2020-00-19T19:43:21.401Z",
+            "2020-13-19T19:43:21.401Z",
+            "2020-07-00T19:43:21.401Z",
+            "2020-07-32T19:43:21.401Z",
+            "2020-04-31T19:43:21.401Z",
+            "2019-02-29T19:43:21.401Z",
+            "2100-02-29T19:43:21.401Z",
+            "2020-07-19T24:43:21.401Z",
+            "2020-07-19T19:60:21.401Z",
+            "2020-07-19T19:43:60.401Z",
+            "2020-+7-19T19:43:21.401Z",
+        ]
+        .iter()
+        {
+            assert_eq!(parse_timestamp(invalid), None, "{}", invalid);
+        }
+        assert!(parse_timestamp("2000-02-29T00:00:00Z").is_some());
+
+        assert_eq!(
+            format_timestamp(1595187801401000000),
+            "2020-07-19T19:43:21.401Z"
+        );
+        assert_eq!(
+            format_timestamp(1582934400000000001),
+            "2020-02-29T00:00:00.000Z"
+        );
+        assert_eq!(format_timestamp(0), "1970-01-01T00:00:00.000Z");
+    }
+
+    #[test]
+    fn parse_truncated_and_corrupted_messages() {
+        let text = b"{\"table\":\"orderBookL2\",\"action\":\"insert\",\"data\":[{\"symbol\":\"XBTUSD\",\"id\":8798141850,\"side\":\"Sell\",\"size\":1,\"price\":18581.5}]}";
+        // truncated messages are errors, not panics
+        for len in 0..text.len() {
+            assert!(parse(&text[..len]).is_err());
+        }
+        let mut corrupted = text.to_vec();
+        for i in 0..corrupted.len() {
+            let byte = corrupted[i];
+            corrupted[i] = b'"';
+            let _ = parse(&corrupted);
+            corrupted[i] = byte;
+        }
+        assert!(parse(b"").is_err());
+        assert!(parse(b"{\"tab").is_err());
+    }
+
+    // both backends must give the same message, or both an error
+    #[cfg(feature = "simd")]
+    fn assert_same_parse(message: &[u8]) {
+        assert_eq!(
+            format!("{:?}", parse_with::<SerdeJson>(message)),
+            format!("{:?}", parse_with::<SimdJson>(message)),
+            "{}",
+            String::from_utf8_lossy(message)
+        );
+    }
+
+    #[cfg(feature = "simd")]
+    const FIXTURES: [&[u8]; 5] = [
+        b"{\"info\":\"Welcome to the BitMEX Realtime API.\",\"version\":\"2020-06-30T21:03:12.000Z\",\"timestamp\":\"2020-07-08T11:00:02.855Z\",\"docs\":\"https://www.bitmex.com/app/wsAPI\",\"limit\":{\"remaining\":39}}",
+        b"{\"success\":true,\"subscribe\":\"orderBookL2:XBTUSD\",\"request\":{\"op\":\"subscribe\",\"args\":[\"orderBookL2:XBTUSD\"]}}",
+        b"{\"table\":\"orderBookL2\",\"action\":\"partial\",\"keys\":[\"symbol\",\"id\",\"side\"],\"types\":{\"symbol\":\"symbol\",\"id\":\"long\",\"side\":\"symbol\",\"size\":\"long\",\"price\":\"float\"},\"foreignKeys\":{\"symbol\":\"instrument\",\"side\":\"side\"},\"attributes\":{\"symbol\":\"parted\",\"id\":\"sorted\"},\"filter\":{\"symbol\":\"XBTUSD\"},\"data\":[{\"symbol\":\"XBTUSD\",\"id\":8799070900,\"side\":\"Sell\",\"size\":1186665,\"price\":9291},{\"symbol\":\"XBTUSD\",\"id\":8799070950,\"side\":\"Buy\",\"size\":1023444,\"price\":9290.5}]}",
+        b"{\"table\":\"trade\",\"action\":\"partial\",\"keys\":[],\"types\":{\"timestamp\":\"timestamp\",\"symbol\":\"symbol\",\"side\":\"symbol\",\"size\":\"long\",\"price\":\"float\",\"tickDirection\":\"symbol\",\"trdMatchID\":\"guid\",\"grossValue\":\"long\",\"homeNotional\":\"float\",\"foreignNotional\":\"float\"},\"foreignKeys\":{\"symbol\":\"instrument\",\"side\":\"side\"},\"attributes\":{\"timestamp\":\"sorted\",\"symbol\":\"grouped\"},\"filter\":{\"symbol\":\"XBTUSD\"},\"data\":[{\"timestamp\":\"2020-07-19T19:42:57.047Z\",\"symbol\":\"XBTUSD\",\"side\":\"Sell\",\"size\":446,\"price\":9155.5,\"tickDirection\":\"MinusTick\",\"trdMatchID\":\"3a90d7b2-8b2b-556f-0dc5-bfde052e240b\",\"grossValue\":4871212,\"homeNotional\":0.04871212,\"foreignNotional\":446}]}",
+        b"{\"table\":\"orderBookL2\",\"action\":\"delete\",\"data\":[{\"symbol\":\"XBTUSD\",\"id\":8799594200,\"side\":\"Buy\"}]}",
+    ];
+
+    #[cfg(feature = "simd")]
+    fn l2_entry_strategy() -> impl Strategy<Value = String> {
+        (
+            "[A-Z0-9]{1,8}|XBT\\\\u0055SD",
+            any::<i64>(),
+            prop_oneof![Just("Buy"), Just("Sell")],
+            any::<i64>(),
+            0u32..4_000_000,
+        )
+            .prop_map(|(symbol, id, side, size, ticks)| {
+                format!(
+                    "{{\"symbol\":\"{}\",\"id\":{},\"side\":\"{}\",\"size\":{},\"price\":{}}}",
+                    symbol,
+                    id,
+                    side,
+                    size,
+                    ticks as f64 * 0.5
+                )
+            })
+    }
+
+    #[cfg(feature = "simd")]
+    fn trade_entry_strategy() -> impl Strategy<Value = String> {
+        (0u32..100_000_000, 1u64..1 << 40, 0u64..100_000_000_000)
+            .prop_map(|(ticks, size, notional)| {
+                format!(
+                    "{{\"timestamp\":\"2020-07-19T19:43:21.401Z\",\"symbol\":\"XBTUSD\",\"side\":\"Sell\",\"size\":{},\"price\":{},\"tickDirection\":\"ZeroMinusTick\",\"trdMatchID\":\"ec06df7b-0dc0-8181-f693-c9f39fb57e56\",\"grossValue\":{},\"homeNotional\":{},\"foreignNotional\":{}}}",
+                    size,
+                    ticks as f64 * 0.01,
+                    notional,
+                    notional as f64 / 1e8,
+                    size
+                )
+            })
+    }
+
+    #[cfg(feature = "simd")]
+    #[test]
+    fn simd_backend_matches_serde_json() {
+        for fixture in FIXTURES.iter() {
+            assert_same_parse(fixture);
+            assert!(parse_with::<SimdJson>(fixture).is_ok());
+            for len in 0..fixture.len() {
+                assert_same_parse(&fixture[..len]);
+            }
+        }
+        // NUL byte in a number
+        let mut message = FIXTURES[4].to_vec();
+        message[74] = 0;
+        assert_same_parse(&message);
+        assert!(parse_with::<SimdJson>(&message).is_err());
+    }
+
+    #[cfg(feature = "simd")]
+    proptest! {
+        #[test]
+        fn simd_backend_matches_serde_json_on_l2_messages(
+            action in prop_oneof![Just("insert"), Just("update"), Just("delete")],
+            entries in proptest::collection::vec(l2_entry_strategy(), 0..20),
+        ) {
+            let message = format!(
+                "{{\"table\":\"orderBookL2\",\"action\":\"{}\",\"data\":[{}]}}",
+                action,
+                entries.join(",")
+            );
+            assert_same_parse(message.as_bytes());
+        }
+
+        #[test]
+        fn simd_backend_matches_serde_json_on_trades(
+            entries in proptest::collection::vec(trade_entry_strategy(), 1..10),
+        ) {
+            let message = format!(
+                "{{\"table\":\"trade\",\"action\":\"insert\",\"data\":[{}]}}",
+                entries.join(",")
+            );
+            assert_same_parse(message.as_bytes());
+        }
+
+        #[test]
+        fn simd_backend_matches_serde_json_on_corrupted_messages(
+            fixture in 0..FIXTURES.len(),
+            position in any::<prop::sample::Index>(),
+            byte in any::<u8>(),
+        ) {
+            let mut message = FIXTURES[fixture].to_vec();
+            let i = position.index(message.len());
+            message[i] = byte;
+            assert_same_parse(&message);
+        }
+    }
 }
@@ -0,0 +1,187 @@
+use crate::normalized::Side;
+use crate::order_book::{Level, OrderBook};
+
+// tolerance used when assigning a price to a bucket, keeps prices on a bucket boundary from
+// falling into the next bucket because of floating point error
+const BUCKET_EPSILON: f64 = 1e-9;
+
+// Aggregated depth of the levels in a price bucket. Cumulative values include all buckets from
+// the top of the book up to and including this one.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct DepthBucket {
+    pub price: f64,
+    pub size: i64,
+    // size x price
+    pub notional: f64,
+    pub cumulative_size: i64,
+    pub cumulative_notional: f64,
+    // number of book levels in the bucket
+    pub levels: usize,
+}
+
+// Price bucketed depth view of an order book. Bids are grouped down to the bucket price and asks
+// up to the bucket price, so a bucket never shows a better price than the levels it contains.
+pub struct DepthView {
+    bucket_width: f64,
+    max_buckets: usize,
+}
+
+impl DepthView {
+    // bucket width in price units, a width of zero keeps one bucket per level
+    pub fn new(bucket_width: f64) -> Self {
+        DepthView {
+            bucket_width,
+            max_buckets: usize::MAX,
+        }
+    }
+
+    // bucket width as a number of ticks
+    pub fn with_ticks(ticks: u32, tick_size: f64) -> Self {
+        DepthView::new(f64::from(ticks) * tick_size)
+    }
+
+    pub fn set_max_buckets(&mut self, max_buckets: usize) {
+        self.max_buckets = max_buckets;
+    }
+
+    pub fn bucket_width(&self) -> f64 {
+        self.bucket_width
+    }
+
+    pub fn bids(&self, book: &OrderBook) -> Vec<DepthBucket> {
+        self.aggregate(book.bids(), Side::Buy)
+    }
+
+    pub fn asks(&self, book: &OrderBook) -> Vec<DepthBucket> {
+        self.aggregate(book.asks(), Side::Sell)
+    }
+
+    pub fn side(&self, book: &OrderBook, side: Side) -> Vec<DepthBucket> {
+        match side {
+            Side::Buy => self.bids(book),
+            Side::Sell => self.asks(book),
+        }
+    }
+
+    fn bucket_price(&self, price: f64, side: Side) -> f64 {
+        if self.bucket_width <= 0.0 {
+            return price;
+        }
+        let buckets = price / self.bucket_width;
+        match side {
+            Side::Buy => (buckets + BUCKET_EPSILON).floor() * self.bucket_width,
+            Side::Sell => (buckets - BUCKET_EPSILON).ceil() * self.bucket_width,
+        }
+    }
+
+    // levels are expected from best to worst price
+    fn aggregate<'a, I>(&self, levels: I, side: Side) -> Vec<DepthBucket>
+    where
+        I: Iterator<Item = &'a Level>,
+    {
+        let mut buckets: Vec<DepthBucket> = vec![];
+        let mut cumulative_size = 0;
+        let mut cumulative_notional = 0.0;
+        for level in levels {
+            let price = self.bucket_price(level.price, side);
+            let notional = level.size as f64 * level.price;
+            cumulative_size += level.size;
+            cumulative_notional += notional;
+            match buckets.last_mut() {
+                Some(bucket) if bucket.price == price => {
+                    bucket.size += level.size;
+                    bucket.notional += notional;
+                    bucket.cumulative_size = cumulative_size;
+                    bucket.cumulative_notional = cumulative_notional;
+                    bucket.levels += 1;
+                }
+                _ => {
+                    if buckets.len() == self.max_buckets {
+                        break;
+                    }
+                    buckets.push(DepthBucket {
+                        price,
+                        size: level.size,
+                        notional,
+                        cumulative_size,
+                        cumulative_notional,
+                        levels: 1,
+                    });
+                }
+            }
+        }
+        buckets
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::bitmex_message::parse;
+    use crate::book_view::DepthView;
+    use crate::fixtures::SNAPSHOT;
+    use crate::instrument::SymbolRegistry;
+    use crate::order_book::OrderBook;
+
+    fn book() -> OrderBook {
+        let mut symbols = SymbolRegistry::new();
+        let mut book = OrderBook::new(&mut symbols, "XBTUSD");
+        book.apply(&parse(SNAPSHOT).ok().unwrap(), |_| {});
+        book
+    }
+
+    #[test]
+    fn bid_buckets() {
+        let book = book();
+        let buckets = DepthView::new(1.0).bids(&book);
+        assert_eq!(buckets.len(), 3);
+
+        assert_eq!(buckets[0].price, 9290.0);
+        assert_eq!(buckets[0].size, 1023444 + 23490);
+        assert_eq!(buckets[0].levels, 2);
+        assert_eq!(buckets[0].notional, 1023444.0 * 9290.5 + 23490.0 * 9290.0);
+
+        assert_eq!(buckets[1].price, 9289.0);
+        assert_eq!(buckets[1].size, 155749 + 10723);
+        assert_eq!(buckets[1].cumulative_size, 1023444 + 23490 + 155749 + 10723);
+
+        assert_eq!(buckets[2].price, 9288.0);
+        assert_eq!(buckets[2].cumulative_size, 1215519);
+    }
+
+    #[test]
+    fn ask_buckets_in_ticks() {
+        let book = book();
+        // 4 ticks of 0.5 is a bucket width of 2
+        let mut view = DepthView::with_ticks(4, 0.5);
+        view.set_max_buckets(2);
+        let buckets = view.asks(&book);
+        assert_eq!(buckets.len(), 2);
+
+        assert_eq!(buckets[0].price, 9292.0);
+        assert_eq!(buckets[0].size, 1186665 + 832 + 82020);
+        assert_eq!(buckets[0].levels, 3);
+
+        assert_eq!(buckets[1].price, 9294.0);
+        assert_eq!(buckets[1].size, 56948 + 19978 + 67377 + 162802);
+        assert_eq!(
+            buckets[1].cumulative_notional,
+            1186665.0 * 9291.0
+                + 832.0 * 9291.5
+                + 82020.0 * 9292.0
+                + 56948.0 * 9292.5
+                + 19978.0 * 9293.0
+                + 67377.0 * 9293.5
+                + 162802.0 * 9294.0
+        );
+    }
+
+    #[test]
+    fn level_depth_curve() {
+        let book = book();
+        let buckets = DepthView::new(0.0).asks(&book);
+        assert_eq!(buckets.len(), 9);
+        assert_eq!(buckets[0].price, 9291.0);
+        assert_eq!(buckets[8].price, 9295.0);
+        assert_eq!(buckets[8].cumulative_size, 2023307);
+    }
+}
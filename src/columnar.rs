@@ -0,0 +1,566 @@
+use crate::bitmex_message::{BitmexMessage, TradeEntry};
+use crate::normalized::Side;
+use crate::order_book::{Level, OrderBook};
+use arrow::array::{
+    ArrayBuilder, ArrayRef, Float64Builder, Int64Builder, StringBuilder, UInt32Builder,
+};
+use arrow::datatypes::{DataType, Field, Schema, SchemaRef};
+use arrow::error::ArrowError;
+use arrow::record_batch::RecordBatch;
+use parquet::arrow::ArrowWriter;
+use parquet::errors::ParquetError;
+use parquet::file::properties::WriterProperties;
+use std::io::Write;
+use std::sync::Arc;
+
+pub const DEFAULT_BATCH_SIZE: usize = 8192;
+pub const DEFAULT_ROW_GROUP_SIZE: usize = 1024 * 1024;
+
+pub struct ColumnarConfig {
+    // number of rows buffered before a record batch is built
+    pub batch_size: usize,
+    // max number of rows in a parquet row group
+    pub row_group_size: usize,
+}
+
+impl Default for ColumnarConfig {
+    fn default() -> Self {
+        ColumnarConfig {
+            batch_size: DEFAULT_BATCH_SIZE,
+            row_group_size: DEFAULT_ROW_GROUP_SIZE,
+        }
+    }
+}
+
+// Builds arrow record batches from rows appended to it
+pub trait BatchBuilder {
+    fn schema(&self) -> SchemaRef;
+
+    // number of rows appended since the last batch
+    fn len(&self) -> usize;
+
+    fn is_empty(&self) -> bool {
+        self.len() == 0
+    }
+
+    // build a record batch of the rows appended since the last batch
+    fn finish(&mut self) -> Result<RecordBatch, ArrowError>;
+}
+
+fn side_name(side: Side) -> &'static str {
+    match side {
+        Side::Buy => "Buy",
+        Side::Sell => "Sell",
+    }
+}
+
+// Trades with all the TradeEntry fields, the timestamp is in nanos since unix epoch
+pub struct TradeBatchBuilder {
+    schema: SchemaRef,
+    timestamp: Int64Builder,
+    symbol: StringBuilder,
+    side: StringBuilder,
+    size: Float64Builder,
+    price: Float64Builder,
+    tick_direction: StringBuilder,
+    trd_match_id: StringBuilder,
+    gross_value: Float64Builder,
+    home_notional: Float64Builder,
+    foreign_notional: Float64Builder,
+}
+
+impl TradeBatchBuilder {
+    pub fn new() -> Self {
+        let schema = Schema::new(vec![
+            Field::new("timestamp", DataType::Int64, true),
+            Field::new("symbol", DataType::Utf8, false),
+            Field::new("side", DataType::Utf8, false),
+            Field::new("size", DataType::Float64, false),
+            Field::new("price", DataType::Float64, false),
+            Field::new("tick_direction", DataType::Utf8, false),
+            Field::new("trd_match_id", DataType::Utf8, false),
+            Field::new("gross_value", DataType::Float64, false),
+            Field::new("home_notional", DataType::Float64, false),
+            Field::new("foreign_notional", DataType::Float64, false),
+        ]);
+        TradeBatchBuilder {
+            schema: Arc::new(schema),
+            timestamp: Int64Builder::new(),
+            symbol: StringBuilder::new(),
+            side: StringBuilder::new(),
+            size: Float64Builder::new(),
+            price: Float64Builder::new(),
+            tick_direction: StringBuilder::new(),
+            trd_match_id: StringBuilder::new(),
+            gross_value: Float64Builder::new(),
+            home_notional: Float64Builder::new(),
+            foreign_notional: Float64Builder::new(),
+        }
+    }
+
+    pub fn append(&mut self, trade: &TradeEntry) {
+        self.timestamp.append_option(trade.timestamp_nanos());
+        self.symbol.append_value(&trade.symbol);
+        self.side.append_value(&trade.side);
+        self.size.append_value(trade.size);
+        self.price.append_value(trade.price);
+        self.tick_direction.append_value(&trade.tick_direction);
+        self.trd_match_id.append_value(&trade.trd_match_id);
+        self.gross_value.append_value(trade.gross_value);
+        self.home_notional.append_value(trade.home_notional);
+        self.foreign_notional.append_value(trade.foreign_notional);
+    }
+}
+
+impl Default for TradeBatchBuilder {
+    fn default() -> Self {
+        TradeBatchBuilder::new()
+    }
+}
+
+impl BatchBuilder for TradeBatchBuilder {
+    fn schema(&self) -> SchemaRef {
+        self.schema.clone()
+    }
+
+    fn len(&self) -> usize {
+        self.timestamp.len()
+    }
+
+    fn finish(&mut self) -> Result<RecordBatch, ArrowError> {
+        let columns: Vec<ArrayRef> = vec![
+            Arc::new(self.timestamp.finish()),
+            Arc::new(self.symbol.finish()),
+            Arc::new(self.side.finish()),
+            Arc::new(self.size.finish()),
+            Arc::new(self.price.finish()),
+            Arc::new(self.tick_direction.finish()),
+            Arc::new(self.trd_match_id.finish()),
+            Arc::new(self.gross_value.finish()),
+            Arc::new(self.home_notional.finish()),
+            Arc::new(self.foreign_notional.finish()),
+        ];
+        RecordBatch::try_new(self.schema.clone(), columns)
+    }
+}
+
+// orderBookL2 rows of partial, insert, update and delete messages, one row per entry. The
+// timestamp is the receive time given with the message. Size is null for deletes and price is
+// null for updates and deletes.
+pub struct L2DeltaBatchBuilder {
+    schema: SchemaRef,
+    timestamp: Int64Builder,
+    action: StringBuilder,
+    symbol: StringBuilder,
+    id: Int64Builder,
+    side: StringBuilder,
+    size: Int64Builder,
+    price: Float64Builder,
+}
+
+impl L2DeltaBatchBuilder {
+    pub fn new() -> Self {
+        let schema = Schema::new(vec![
+            Field::new("timestamp", DataType::Int64, false),
+            Field::new("action", DataType::Utf8, false),
+            Field::new("symbol", DataType::Utf8, false),
+            Field::new("id", DataType::Int64, false),
+            Field::new("side", DataType::Utf8, false),
+            Field::new("size", DataType::Int64, true),
+            Field::new("price", DataType::Float64, true),
+        ]);
+        L2DeltaBatchBuilder {
+            schema: Arc::new(schema),
+            timestamp: Int64Builder::new(),
+            action: StringBuilder::new(),
+            symbol: StringBuilder::new(),
+            id: Int64Builder::new(),
+            side: StringBuilder::new(),
+            size: Int64Builder::new(),
+            price: Float64Builder::new(),
+        }
+    }
+
+    // append the entries of an orderBookL2 message, other messages are ignored. Returns the
+    // number of rows appended.
+    pub fn append_message(&mut self, timestamp: i64, message: &BitmexMessage) -> usize {
+        match message {
+            BitmexMessage::Snapshot(snapshot) => {
+                for entry in snapshot.data.iter() {
+                    self.append_row(
+                        timestamp,
+                        "partial",
+                        &entry.symbol,
+                        entry.id,
+                        &entry.side,
+                        Some(entry.size),
+                        Some(entry.price),
+                    );
+                }
+                snapshot.data.len()
+            }
+            BitmexMessage::Insert(insert) => {
+                for entry in insert.data.iter() {
+                    self.append_row(
+                        timestamp,
+                        "insert",
+                        &entry.symbol,
+                        entry.id,
+                        &entry.side,
+                        Some(entry.size),
+                        Some(entry.price),
+                    );
+                }
+                insert.data.len()
+            }
+            BitmexMessage::Update(update) => {
+                for entry in update.data.iter() {
+                    self.append_row(
+                        timestamp,
+                        "update",
+                        &entry.symbol,
+                        entry.id,
+                        &entry.side,
+                        Some(entry.size),
+                        None,
+                    );
+                }
+                update.data.len()
+            }
+            BitmexMessage::Delete(delete) => {
+                for entry in delete.data.iter() {
+                    self.append_row(
+                        timestamp,
+                        "delete",
+                        &entry.symbol,
+                        entry.id,
+                        &entry.side,
+                        None,
+                        None,
+                    );
+                }
+                delete.data.len()
+            }
+            _ => 0,
+        }
+    }
+
+    #[allow(clippy::too_many_arguments)]
+    fn append_row(
+        &mut self,
+        timestamp: i64,
+        action: &str,
+        symbol: &str,
+        id: i64,
+        side: &str,
+        size: Option<i64>,
+        price: Option<f64>,
+    ) {
+        self.timestamp.append_value(timestamp);
+        self.action.append_value(action);
+        self.symbol.append_value(symbol);
+        self.id.append_value(id);
+        self.side.append_value(side);
+        self.size.append_option(size);
+        self.price.append_option(price);
+    }
+}
+
+impl Default for L2DeltaBatchBuilder {
+    fn default() -> Self {
+        L2DeltaBatchBuilder::new()
+    }
+}
+
+impl BatchBuilder for L2DeltaBatchBuilder {
+    fn schema(&self) -> SchemaRef {
+        self.schema.clone()
+    }
+
+    fn len(&self) -> usize {
+        self.timestamp.len()
+    }
+
+    fn finish(&mut self) -> Result<RecordBatch, ArrowError> {
+        let columns: Vec<ArrayRef> = vec![
+            Arc::new(self.timestamp.finish()),
+            Arc::new(self.action.finish()),
+            Arc::new(self.symbol.finish()),
+            Arc::new(self.id.finish()),
+            Arc::new(self.side.finish()),
+            Arc::new(self.size.finish()),
+            Arc::new(self.price.finish()),
+        ];
+        RecordBatch::try_new(self.schema.clone(), columns)
+    }
+}
+
+// Top levels of the book, one row per level. Level 1 is the best price of the side.
+pub struct BookSnapshotBatchBuilder {
+    schema: SchemaRef,
+    depth: usize,
+    timestamp: Int64Builder,
+    symbol: StringBuilder,
+    side: StringBuilder,
+    level: UInt32Builder,
+    price: Float64Builder,
+    size: Int64Builder,
+}
+
+impl BookSnapshotBatchBuilder {
+    // depth is the number of levels per side in a snapshot
+    pub fn new(depth: usize) -> Self {
+        let schema = Schema::new(vec![
+            Field::new("timestamp", DataType::Int64, false),
+            Field::new("symbol", DataType::Utf8, false),
+            Field::new("side", DataType::Utf8, false),
+            Field::new("level", DataType::UInt32, false),
+            Field::new("price", DataType::Float64, false),
+            Field::new("size", DataType::Int64, false),
+        ]);
+        BookSnapshotBatchBuilder {
+            schema: Arc::new(schema),
+            depth,
+            timestamp: Int64Builder::new(),
+            symbol: StringBuilder::new(),
+            side: StringBuilder::new(),
+            level: UInt32Builder::new(),
+            price: Float64Builder::new(),
+            size: Int64Builder::new(),
+        }
+    }
+
+    pub fn append(&mut self, timestamp: i64, book: &OrderBook) {
+        let bids: Vec<Level> = book.bids().take(self.depth).copied().collect();
+        let asks: Vec<Level> = book.asks().take(self.depth).copied().collect();
+        for levels in [bids, asks].iter() {
+            for (i, level) in levels.iter().enumerate() {
+                self.timestamp.append_value(timestamp);
+                self.symbol.append_value(book.symbol());
+                self.side.append_value(side_name(level.side));
+                self.level.append_value(i as u32 + 1);
+                self.price.append_value(level.price);
+                self.size.append_value(level.size);
+            }
+        }
+    }
+}
+
+impl BatchBuilder for BookSnapshotBatchBuilder {
+    fn schema(&self) -> SchemaRef {
+        self.schema.clone()
+    }
+
+    fn len(&self) -> usize {
+        self.timestamp.len()
+    }
+
+    fn finish(&mut self) -> Result<RecordBatch, ArrowError> {
+        let columns: Vec<ArrayRef> = vec![
+            Arc::new(self.timestamp.finish()),
+            Arc::new(self.symbol.finish()),
+            Arc::new(self.side.finish()),
+            Arc::new(self.level.finish()),
+            Arc::new(self.price.finish()),
+            Arc::new(self.size.finish()),
+        ];
+        RecordBatch::try_new(self.schema.clone(), columns)
+    }
+}
+
+// Parquet file writer, rows are appended to the batch builder and written as a record batch
+// every batch_size rows
+pub struct ParquetWriter<W: Write + Send, B: BatchBuilder> {
+    writer: ArrowWriter<W>,
+    builder: B,
+    batch_size: usize,
+}
+
+pub type TradeParquetWriter<W> = ParquetWriter<W, TradeBatchBuilder>;
+pub type L2DeltaParquetWriter<W> = ParquetWriter<W, L2DeltaBatchBuilder>;
+pub type BookSnapshotParquetWriter<W> = ParquetWriter<W, BookSnapshotBatchBuilder>;
+
+impl<W: Write + Send, B: BatchBuilder> ParquetWriter<W, B> {
+    pub fn new(writer: W, builder: B, config: &ColumnarConfig) -> Result<Self, ParquetError> {
+        let properties = WriterProperties::builder()
+            .set_max_row_group_size(config.row_group_size)
+            .build();
+        let writer = ArrowWriter::try_new(writer, builder.schema(), Some(properties))?;
+        Ok(ParquetWriter {
+            writer,
+            builder,
+            batch_size: config.batch_size.max(1),
+        })
+    }
+
+    // write the buffered rows as a record batch
+    pub fn flush(&mut self) -> Result<(), ParquetError> {
+        if !self.builder.is_empty() {
+            let batch = self.builder.finish()?;
+            self.writer.write(&batch)?;
+        }
+        Ok(())
+    }
+
+    // flush the buffered rows and write the parquet footer
+    pub fn close(mut self) -> Result<(), ParquetError> {
+        self.flush()?;
+        self.writer.close()?;
+        Ok(())
+    }
+
+    fn flush_if_full(&mut self) -> Result<(), ParquetError> {
+        if self.builder.len() >= self.batch_size {
+            self.flush()?;
+        }
+        Ok(())
+    }
+}
+
+impl<W: Write + Send> ParquetWriter<W, TradeBatchBuilder> {
+    pub fn write(&mut self, trade: &TradeEntry) -> Result<(), ParquetError> {
+        self.builder.append(trade);
+        self.flush_if_full()
+    }
+}
+
+impl<W: Write + Send> ParquetWriter<W, L2DeltaBatchBuilder> {
+    pub fn write(&mut self, timestamp: i64, message: &BitmexMessage) -> Result<(), ParquetError> {
+        self.builder.append_message(timestamp, message);
+        self.flush_if_full()
+    }
+}
+
+impl<W: Write + Send> ParquetWriter<W, BookSnapshotBatchBuilder> {
+    pub fn write(&mut self, timestamp: i64, book: &OrderBook) -> Result<(), ParquetError> {
+        self.builder.append(timestamp, book);
+        self.flush_if_full()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::bitmex_message::{parse, BitmexMessage};
+    use crate::columnar::{
+        BatchBuilder, BookSnapshotBatchBuilder, ColumnarConfig, L2DeltaBatchBuilder, ParquetWriter,
+        TradeBatchBuilder,
+    };
+    use crate::fixtures::{DELETE, SNAPSHOT, TRADE, UPDATE};
+    use crate::instrument::SymbolRegistry;
+    use crate::order_book::OrderBook;
+    use arrow::array::{Array, Float64Array, Int64Array, StringArray, UInt32Array};
+    use parquet::arrow::arrow_reader::ParquetRecordBatchReaderBuilder;
+    use std::fs::{self, File};
+
+    fn trade_entry() -> crate::bitmex_message::TradeEntry {
+        match parse(TRADE) {
+            Ok(BitmexMessage::Trade(mut trade)) => trade.data.remove(0),
+            _ => panic!("wrong message type"),
+        }
+    }
+
+    #[test]
+    fn trade_batch() {
+        let mut builder = TradeBatchBuilder::new();
+        builder.append(&trade_entry());
+        let batch = builder.finish().unwrap();
+        assert_eq!(batch.num_rows(), 1);
+        assert_eq!(batch.num_columns(), 10);
+        let timestamp = batch
+            .column(0)
+            .as_any()
+            .downcast_ref::<Int64Array>()
+            .unwrap();
+        assert_eq!(timestamp.value(0), 1595187801401000000);
+        let trd_match_id = batch
+            .column(6)
+            .as_any()
+            .downcast_ref::<StringArray>()
+            .unwrap();
+        assert_eq!(
+            trd_match_id.value(0),
+            "ec06df7b-0dc0-8181-f693-c9f39fb57e56"
+        );
+        assert!(builder.is_empty());
+    }
+
+    #[test]
+    fn l2_delta_batch() {
+        let mut builder = L2DeltaBatchBuilder::new();
+        let update = parse(UPDATE).ok().unwrap();
+        let delete = parse(DELETE).ok().unwrap();
+        assert_eq!(builder.append_message(1, &update), 2);
+        assert_eq!(builder.append_message(2, &delete), 1);
+        let batch = builder.finish().unwrap();
+        assert_eq!(batch.num_rows(), 3);
+        let action = batch
+            .column(1)
+            .as_any()
+            .downcast_ref::<StringArray>()
+            .unwrap();
+        assert_eq!(action.value(2), "delete");
+        let size = batch
+            .column(5)
+            .as_any()
+            .downcast_ref::<Int64Array>()
+            .unwrap();
+        assert_eq!(size.value(1), 19575);
+        assert!(size.is_null(2));
+        let price = batch
+            .column(6)
+            .as_any()
+            .downcast_ref::<Float64Array>()
+            .unwrap();
+        assert_eq!(price.null_count(), 3);
+    }
+
+    #[test]
+    fn book_snapshot_batch() {
+        let mut symbols = SymbolRegistry::new();
+        let mut book = OrderBook::new(&mut symbols, "XBTUSD");
+        book.apply(&parse(SNAPSHOT).ok().unwrap(), |_| {});
+        let mut builder = BookSnapshotBatchBuilder::new(1);
+        builder.append(10, &book);
+        let batch = builder.finish().unwrap();
+        assert_eq!(batch.num_rows(), 2);
+        let level = batch
+            .column(3)
+            .as_any()
+            .downcast_ref::<UInt32Array>()
+            .unwrap();
+        assert_eq!(level.value(1), 1);
+        let price = batch
+            .column(4)
+            .as_any()
+            .downcast_ref::<Float64Array>()
+            .unwrap();
+        assert_eq!(price.value(0), 9290.5);
+        assert_eq!(price.value(1), 9291.0);
+    }
+
+    #[test]
+    fn write_trades_parquet() {
+        let path = std::env::temp_dir().join("bitmex-md-trades-test.parquet");
+        let config = ColumnarConfig {
+            batch_size: 2,
+            row_group_size: 2,
+        };
+        let mut writer = ParquetWriter::new(
+            File::create(&path).unwrap(),
+            TradeBatchBuilder::new(),
+            &config,
+        )
+        .unwrap();
+        let trade = trade_entry();
+        for _ in 0..5 {
+            writer.write(&trade).unwrap();
+        }
+        writer.close().unwrap();
+
+        let reader = ParquetRecordBatchReaderBuilder::try_new(File::open(&path).unwrap()).unwrap();
+        assert_eq!(reader.metadata().num_row_groups(), 3);
+        let rows: usize = reader.build().unwrap().map(|b| b.unwrap().num_rows()).sum();
+        assert_eq!(rows, 5);
+        let _ = fs::remove_file(&path);
+    }
+}
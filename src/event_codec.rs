@@ -0,0 +1,885 @@
+use crate::bitmex_message::{parse_uuid, BitmexMessage, TradeEntry};
+use crate::normalized::{DeltaAction, Side};
+use crate::order_book::TopOfBook;
+use std::convert::TryInto;
+
+// Binary encoding of normalized market data events. A buffer is a sequence of fixed layout
+// records, all integers and floats are little endian:
+//   header: version u8, kind u8, record length u16 (header included)
+//   trade:  timestamp i64, symbol [u8; 16], side u8, padding [u8; 7], price f64, size f64,
+//           trade id [u8; 16]
+//   quote:  timestamp i64, symbol [u8; 16], bid price f64, bid size i64, ask price f64,
+//           ask size i64
+//   l2 delta: timestamp i64, symbol [u8; 16], action u8, side u8, flags u8, padding [u8; 5],
+//           id i64, size i64, price f64
+// Symbols are utf8 padded with zeros. Timestamps are nanos since unix epoch, the trade timestamp
+// is the exchange timestamp and the quote and l2 delta timestamps are the receive time.
+pub const ENCODING_VERSION: u8 = 1;
+
+pub const HEADER_LEN: usize = 4;
+pub const SYMBOL_LEN: usize = 16;
+pub const TRADE_RECORD_LEN: usize = HEADER_LEN + 64;
+pub const QUOTE_RECORD_LEN: usize = HEADER_LEN + 56;
+pub const L2_DELTA_RECORD_LEN: usize = HEADER_LEN + 56;
+
+const TRADE_KIND: u8 = 1;
+const QUOTE_KIND: u8 = 2;
+const L2_DELTA_KIND: u8 = 3;
+
+// l2 delta flags
+const HAS_SIZE: u8 = 1;
+const HAS_PRICE: u8 = 2;
+
+#[derive(Debug, PartialEq)]
+pub enum EncodeError {
+    SymbolTooLong,
+    InvalidSide,
+    InvalidTimestamp,
+    InvalidTradeId,
+}
+
+#[derive(Debug, PartialEq)]
+pub enum DecodeError {
+    Truncated,
+    UnsupportedVersion,
+    UnknownKind,
+    InvalidLength,
+    InvalidSymbol,
+    InvalidSide,
+    InvalidAction,
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub struct TradeRecord {
+    pub timestamp: i64,
+    pub symbol: String,
+    pub side: Side,
+    pub price: f64,
+    pub size: f64,
+    // BitMEX trdMatchID uuid as bytes
+    pub trade_id: [u8; 16],
+}
+
+// Best bid and ask, a missing side has a zero price and size
+#[derive(Debug, Clone, PartialEq)]
+pub struct QuoteRecord {
+    pub timestamp: i64,
+    pub symbol: String,
+    pub bid_price: f64,
+    pub bid_size: i64,
+    pub ask_price: f64,
+    pub ask_size: i64,
+}
+
+// orderBookL2 entry, updates have no price and deletes have neither size nor price
+#[derive(Debug, Clone, PartialEq)]
+pub struct L2DeltaRecord {
+    pub timestamp: i64,
+    pub symbol: String,
+    pub action: DeltaAction,
+    pub side: Side,
+    pub id: i64,
+    pub size: Option<i64>,
+    pub price: Option<f64>,
+}
+
+fn side_code(side: Side) -> u8 {
+    match side {
+        Side::Buy => 1,
+        Side::Sell => 2,
+    }
+}
+
+fn side_from_code(code: u8) -> Option<Side> {
+    match code {
+        1 => Some(Side::Buy),
+        2 => Some(Side::Sell),
+        _ => None,
+    }
+}
+
+fn action_code(action: DeltaAction) -> u8 {
+    match action {
+        DeltaAction::Partial => 1,
+        DeltaAction::Insert => 2,
+        DeltaAction::Update => 3,
+        DeltaAction::Delete => 4,
+    }
+}
+
+fn action_from_code(code: u8) -> Option<DeltaAction> {
+    match code {
+        1 => Some(DeltaAction::Partial),
+        2 => Some(DeltaAction::Insert),
+        3 => Some(DeltaAction::Update),
+        4 => Some(DeltaAction::Delete),
+        _ => None,
+    }
+}
+
+// Appends encoded records to a reusable buffer
+pub struct Encoder {
+    buf: Vec<u8>,
+}
+
+impl Encoder {
+    pub fn new() -> Self {
+        Encoder { buf: vec![] }
+    }
+
+    pub fn with_capacity(capacity: usize) -> Self {
+        Encoder {
+            buf: Vec::with_capacity(capacity),
+        }
+    }
+
+    pub fn as_bytes(&self) -> &[u8] {
+        &self.buf
+    }
+
+    pub fn len(&self) -> usize {
+        self.buf.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.buf.is_empty()
+    }
+
+    // remove the encoded records, the buffer capacity is kept
+    pub fn clear(&mut self) {
+        self.buf.clear();
+    }
+
+    pub fn encode_trade(&mut self, trade: &TradeRecord) -> Result<(), EncodeError> {
+        self.begin(TRADE_KIND, TRADE_RECORD_LEN, trade.timestamp, &trade.symbol)?;
+        self.buf.push(side_code(trade.side));
+        self.buf.extend_from_slice(&[0u8; 7]);
+        self.buf.extend_from_slice(&trade.price.to_le_bytes());
+        self.buf.extend_from_slice(&trade.size.to_le_bytes());
+        self.buf.extend_from_slice(&trade.trade_id);
+        Ok(())
+    }
+
+    pub fn encode_quote(&mut self, quote: &QuoteRecord) -> Result<(), EncodeError> {
+        self.begin(QUOTE_KIND, QUOTE_RECORD_LEN, quote.timestamp, &quote.symbol)?;
+        self.buf.extend_from_slice(&quote.bid_price.to_le_bytes());
+        self.buf.extend_from_slice(&quote.bid_size.to_le_bytes());
+        self.buf.extend_from_slice(&quote.ask_price.to_le_bytes());
+        self.buf.extend_from_slice(&quote.ask_size.to_le_bytes());
+        Ok(())
+    }
+
+    pub fn encode_l2_delta(&mut self, delta: &L2DeltaRecord) -> Result<(), EncodeError> {
+        self.begin(
+            L2_DELTA_KIND,
+            L2_DELTA_RECORD_LEN,
+            delta.timestamp,
+            &delta.symbol,
+        )?;
+        let mut flags = 0;
+        if delta.size.is_some() {
+            flags |= HAS_SIZE;
+        }
+        if delta.price.is_some() {
+            flags |= HAS_PRICE;
+        }
+        self.buf.push(action_code(delta.action));
+        self.buf.push(side_code(delta.side));
+        self.buf.push(flags);
+        self.buf.extend_from_slice(&[0u8; 5]);
+        self.buf.extend_from_slice(&delta.id.to_le_bytes());
+        self.buf
+            .extend_from_slice(&delta.size.unwrap_or(0).to_le_bytes());
+        self.buf
+            .extend_from_slice(&delta.price.unwrap_or(0.0).to_le_bytes());
+        Ok(())
+    }
+
+    // encode the top of book of a symbol as a quote
+    pub fn encode_top_of_book(
+        &mut self,
+        timestamp: i64,
+        symbol: &str,
+        top: &TopOfBook,
+    ) -> Result<(), EncodeError> {
+        self.encode_quote(&QuoteRecord {
+            timestamp,
+            symbol: String::from(symbol),
+            bid_price: top.bid.map(|l| l.price).unwrap_or(0.0),
+            bid_size: top.bid.map(|l| l.size).unwrap_or(0),
+            ask_price: top.ask.map(|l| l.price).unwrap_or(0.0),
+            ask_size: top.ask.map(|l| l.size).unwrap_or(0),
+        })
+    }
+
+    // encode the trades and orderBookL2 entries of a message, other messages are ignored. The
+    // timestamp is the receive time used for the l2 deltas. Returns the number of records
+    // encoded, nothing is encoded if an entry can not be encoded.
+    pub fn encode_message(
+        &mut self,
+        timestamp: i64,
+        message: &BitmexMessage,
+    ) -> Result<usize, EncodeError> {
+        let start = self.buf.len();
+        let result = self.encode_entries(timestamp, message);
+        if result.is_err() {
+            self.buf.truncate(start);
+        }
+        result
+    }
+
+    fn encode_entries(
+        &mut self,
+        timestamp: i64,
+        message: &BitmexMessage,
+    ) -> Result<usize, EncodeError> {
+        match message {
+            BitmexMessage::Trade(trade) => self.encode_trade_entries(&trade.data),
+            BitmexMessage::TradeSnapshot(trade) => self.encode_trade_entries(&trade.data),
+            BitmexMessage::Snapshot(snapshot) => {
+                for entry in snapshot.data.iter() {
+                    self.encode_l2_entry(
+                        timestamp,
+                        DeltaAction::Partial,
+                        &entry.symbol,
+                        &entry.side,
+                        entry.id,
+                        Some(entry.size),
+                        Some(entry.price),
+                    )?;
+                }
+                Ok(snapshot.data.len())
+            }
+            BitmexMessage::Insert(insert) => {
+                for entry in insert.data.iter() {
+                    self.encode_l2_entry(
+                        timestamp,
+                        DeltaAction::Insert,
+                        &entry.symbol,
+                        &entry.side,
+                        entry.id,
+                        Some(entry.size),
+                        Some(entry.price),
+                    )?;
+                }
+                Ok(insert.data.len())
+            }
+            BitmexMessage::Update(update) => {
+                for entry in update.data.iter() {
+                    self.encode_l2_entry(
+                        timestamp,
+                        DeltaAction::Update,
+                        &entry.symbol,
+                        &entry.side,
+                        entry.id,
+                        Some(entry.size),
+                        None,
+                    )?;
+                }
+                Ok(update.data.len())
+            }
+            BitmexMessage::Delete(delete) => {
+                for entry in delete.data.iter() {
+                    self.encode_l2_entry(
+                        timestamp,
+                        DeltaAction::Delete,
+                        &entry.symbol,
+                        &entry.side,
+                        entry.id,
+                        None,
+                        None,
+                    )?;
+                }
+                Ok(delete.data.len())
+            }
+            _ => Ok(0),
+        }
+    }
+
+    fn encode_trade_entries(&mut self, entries: &[TradeEntry]) -> Result<usize, EncodeError> {
+        for entry in entries.iter() {
+            self.encode_trade(&TradeRecord {
+                timestamp: entry
+                    .timestamp_nanos()
+                    .ok_or(EncodeError::InvalidTimestamp)?,
+                symbol: entry.symbol.clone(),
+                side: Side::from_bitmex(&entry.side).ok_or(EncodeError::InvalidSide)?,
+                price: entry.price,
+                size: entry.size,
+                trade_id: parse_uuid(&entry.trd_match_id).ok_or(EncodeError::InvalidTradeId)?,
+            })?;
+        }
+        Ok(entries.len())
+    }
+
+    #[allow(clippy::too_many_arguments)]
+    fn encode_l2_entry(
+        &mut self,
+        timestamp: i64,
+        action: DeltaAction,
+        symbol: &str,
+        side: &str,
+        id: i64,
+        size: Option<i64>,
+        price: Option<f64>,
+    ) -> Result<(), EncodeError> {
+        self.encode_l2_delta(&L2DeltaRecord {
+            timestamp,
+            symbol: String::from(symbol),
+            action,
+            side: Side::from_bitmex(side).ok_or(EncodeError::InvalidSide)?,
+            id,
+            size,
+            price,
+        })
+    }
+
+    // write the record header, timestamp and symbol
+    fn begin(
+        &mut self,
+        kind: u8,
+        len: usize,
+        timestamp: i64,
+        symbol: &str,
+    ) -> Result<(), EncodeError> {
+        if symbol.len() > SYMBOL_LEN {
+            return Err(EncodeError::SymbolTooLong);
+        }
+        self.buf.reserve(len);
+        self.buf.push(ENCODING_VERSION);
+        self.buf.push(kind);
+        self.buf.extend_from_slice(&(len as u16).to_le_bytes());
+        self.buf.extend_from_slice(&timestamp.to_le_bytes());
+        self.buf.extend_from_slice(symbol.as_bytes());
+        self.buf
+            .extend_from_slice(&[0u8; SYMBOL_LEN][symbol.len()..]);
+        Ok(())
+    }
+}
+
+impl Default for Encoder {
+    fn default() -> Self {
+        Encoder::new()
+    }
+}
+
+fn read_i64(buf: &[u8], offset: usize) -> i64 {
+    i64::from_le_bytes(buf[offset..offset + 8].try_into().unwrap())
+}
+
+fn read_f64(buf: &[u8], offset: usize) -> f64 {
+    f64::from_le_bytes(buf[offset..offset + 8].try_into().unwrap())
+}
+
+// Views over the bytes of a record, the fields are read on access. The offsets are relative to
+// the start of the record body.
+#[derive(Debug, Clone, Copy)]
+pub struct TradeView<'a> {
+    body: &'a [u8],
+    symbol: &'a str,
+}
+
+impl<'a> TradeView<'a> {
+    pub fn timestamp(&self) -> i64 {
+        read_i64(self.body, 0)
+    }
+
+    pub fn symbol(&self) -> &'a str {
+        self.symbol
+    }
+
+    pub fn side(&self) -> Side {
+        // checked when the record is decoded
+        side_from_code(self.body[24]).unwrap()
+    }
+
+    pub fn price(&self) -> f64 {
+        read_f64(self.body, 32)
+    }
+
+    pub fn size(&self) -> f64 {
+        read_f64(self.body, 40)
+    }
+
+    pub fn trade_id(&self) -> &'a [u8] {
+        &self.body[48..64]
+    }
+
+    pub fn to_record(&self) -> TradeRecord {
+        let mut trade_id = [0u8; 16];
+        trade_id.copy_from_slice(self.trade_id());
+        TradeRecord {
+            timestamp: self.timestamp(),
+            symbol: String::from(self.symbol),
+            side: self.side(),
+            price: self.price(),
+            size: self.size(),
+            trade_id,
+        }
+    }
+}
+
+#[derive(Debug, Clone, Copy)]
+pub struct QuoteView<'a> {
+    body: &'a [u8],
+    symbol: &'a str,
+}
+
+impl<'a> QuoteView<'a> {
+    pub fn timestamp(&self) -> i64 {
+        read_i64(self.body, 0)
+    }
+
+    pub fn symbol(&self) -> &'a str {
+        self.symbol
+    }
+
+    pub fn bid_price(&self) -> f64 {
+        read_f64(self.body, 24)
+    }
+
+    pub fn bid_size(&self) -> i64 {
+        read_i64(self.body, 32)
+    }
+
+    pub fn ask_price(&self) -> f64 {
+        read_f64(self.body, 40)
+    }
+
+    pub fn ask_size(&self) -> i64 {
+        read_i64(self.body, 48)
+    }
+
+    pub fn to_record(&self) -> QuoteRecord {
+        QuoteRecord {
+            timestamp: self.timestamp(),
+            symbol: String::from(self.symbol),
+            bid_price: self.bid_price(),
+            bid_size: self.bid_size(),
+            ask_price: self.ask_price(),
+            ask_size: self.ask_size(),
+        }
+    }
+}
+
+#[derive(Debug, Clone, Copy)]
+pub struct L2DeltaView<'a> {
+    body: &'a [u8],
+    symbol: &'a str,
+}
+
+impl<'a> L2DeltaView<'a> {
+    pub fn timestamp(&self) -> i64 {
+        read_i64(self.body, 0)
+    }
+
+    pub fn symbol(&self) -> &'a str {
+        self.symbol
+    }
+
+    pub fn action(&self) -> DeltaAction {
+        // checked when the record is decoded
+        action_from_code(self.body[24]).unwrap()
+    }
+
+    pub fn side(&self) -> Side {
+        side_from_code(self.body[25]).unwrap()
+    }
+
+    pub fn id(&self) -> i64 {
+        read_i64(self.body, 32)
+    }
+
+    pub fn size(&self) -> Option<i64> {
+        if self.body[26] & HAS_SIZE != 0 {
+            Some(read_i64(self.body, 40))
+        } else {
+            None
+        }
+    }
+
+    pub fn price(&self) -> Option<f64> {
+        if self.body[26] & HAS_PRICE != 0 {
+            Some(read_f64(self.body, 48))
+        } else {
+            None
+        }
+    }
+
+    pub fn to_record(&self) -> L2DeltaRecord {
+        L2DeltaRecord {
+            timestamp: self.timestamp(),
+            symbol: String::from(self.symbol),
+            action: self.action(),
+            side: self.side(),
+            id: self.id(),
+            size: self.size(),
+            price: self.price(),
+        }
+    }
+}
+
+#[derive(Debug, Clone, Copy)]
+pub enum Record<'a> {
+    Trade(TradeView<'a>),
+    Quote(QuoteView<'a>),
+    L2Delta(L2DeltaView<'a>),
+}
+
+// decode the record at the start of the buffer, returns the record and its length
+pub fn decode(buf: &[u8]) -> Result<(Record<'_>, usize), DecodeError> {
+    if buf.len() < HEADER_LEN {
+        return Err(DecodeError::Truncated);
+    }
+    if buf[0] != ENCODING_VERSION {
+        return Err(DecodeError::UnsupportedVersion);
+    }
+    let kind = buf[1];
+    let len = u16::from_le_bytes([buf[2], buf[3]]) as usize;
+    let expected_len = match kind {
+        TRADE_KIND => TRADE_RECORD_LEN,
+        QUOTE_KIND => QUOTE_RECORD_LEN,
+        L2_DELTA_KIND => L2_DELTA_RECORD_LEN,
+        _ => return Err(DecodeError::UnknownKind),
+    };
+    if len != expected_len {
+        return Err(DecodeError::InvalidLength);
+    }
+    if buf.len() < len {
+        return Err(DecodeError::Truncated);
+    }
+    let body = &buf[HEADER_LEN..len];
+    let symbol = &body[8..8 + SYMBOL_LEN];
+    let symbol_len = symbol.iter().position(|b| *b == 0).unwrap_or(SYMBOL_LEN);
+    let symbol =
+        std::str::from_utf8(&symbol[..symbol_len]).map_err(|_| DecodeError::InvalidSymbol)?;
+
+    let record = match kind {
+        TRADE_KIND => {
+            side_from_code(body[24]).ok_or(DecodeError::InvalidSide)?;
+            Record::Trade(TradeView { body, symbol })
+        }
+        QUOTE_KIND => Record::Quote(QuoteView { body, symbol }),
+        _ => {
+            action_from_code(body[24]).ok_or(DecodeError::InvalidAction)?;
+            side_from_code(body[25]).ok_or(DecodeError::InvalidSide)?;
+            Record::L2Delta(L2DeltaView { body, symbol })
+        }
+    };
+    Ok((record, len))
+}
+
+// Iterates over the records of a buffer without copying, stops at the first error
+pub struct Decoder<'a> {
+    buf: &'a [u8],
+    failed: bool,
+}
+
+impl<'a> Decoder<'a> {
+    pub fn new(buf: &'a [u8]) -> Self {
+        Decoder { buf, failed: false }
+    }
+
+    // bytes not decoded yet
+    pub fn remaining(&self) -> &'a [u8] {
+        self.buf
+    }
+}
+
+impl<'a> Iterator for Decoder<'a> {
+    type Item = Result<Record<'a>, DecodeError>;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        if self.buf.is_empty() || self.failed {
+            return None;
+        }
+        match decode(self.buf) {
+            Ok((record, len)) => {
+                self.buf = &self.buf[len..];
+                Some(Ok(record))
+            }
+            Err(e) => {
+                self.failed = true;
+                Some(Err(e))
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::bitmex_message::{parse, parse_uuid};
+    use crate::event_codec::{
+        decode, DecodeError, Decoder, EncodeError, Encoder, L2DeltaRecord, QuoteRecord, Record,
+        TradeRecord, L2_DELTA_RECORD_LEN, TRADE_RECORD_LEN,
+    };
+    use crate::fixtures::TRADE;
+    use crate::normalized::{DeltaAction, Side};
+    use proptest::prelude::*;
+
+    const UPDATE: &[u8] = b"{\"table\":\"orderBookL2\",\"action\":\"update\",\"data\":[{\"symbol\":\"XBTUSD\",\"id\":8799065200,\"side\":\"Sell\",\"size\":182112},{\"symbol\":\"XBTUSD\",\"id\":8799065250,\"side\":\"Buy\",\"size\":19575}]}";
+
+    fn symbol_strategy() -> impl Strategy<Value = String> {
+        "[A-Z0-9.]{0,16}"
+    }
+
+    fn side_strategy() -> impl Strategy<Value = Side> {
+        prop_oneof![Just(Side::Buy), Just(Side::Sell)]
+    }
+
+    fn action_strategy() -> impl Strategy<Value = DeltaAction> {
+        prop_oneof![
+            Just(DeltaAction::Partial),
+            Just(DeltaAction::Insert),
+            Just(DeltaAction::Update),
+            Just(DeltaAction::Delete),
+        ]
+    }
+
+    fn trade_strategy() -> impl Strategy<Value = TradeRecord> {
+        (
+            any::<i64>(),
+            symbol_strategy(),
+            side_strategy(),
+            any::<f64>(),
+            any::<f64>(),
+            any::<[u8; 16]>(),
+        )
+            .prop_map(
+                |(timestamp, symbol, side, price, size, trade_id)| TradeRecord {
+                    timestamp,
+                    symbol,
+                    side,
+                    price,
+                    size,
+                    trade_id,
+                },
+            )
+    }
+
+    fn quote_strategy() -> impl Strategy<Value = QuoteRecord> {
+        (
+            any::<i64>(),
+            symbol_strategy(),
+            any::<f64>(),
+            any::<i64>(),
+            any::<f64>(),
+            any::<i64>(),
+        )
+            .prop_map(
+                |(timestamp, symbol, bid_price, bid_size, ask_price, ask_size)| QuoteRecord {
+                    timestamp,
+                    symbol,
+                    bid_price,
+                    bid_size,
+                    ask_price,
+                    ask_size,
+                },
+            )
+    }
+
+    fn l2_delta_strategy() -> impl Strategy<Value = L2DeltaRecord> {
+        (
+            any::<i64>(),
+            symbol_strategy(),
+            action_strategy(),
+            side_strategy(),
+            any::<i64>(),
+            any::<Option<i64>>(),
+            any::<Option<f64>>(),
+        )
+            .prop_map(
+                |(timestamp, symbol, action, side, id, size, price)| L2DeltaRecord {
+                    timestamp,
+                    symbol,
+                    action,
+                    side,
+                    id,
+                    size,
+                    price,
+                },
+            )
+    }
+
+    // compare floats by bits so that NaN round trips are checked too
+    fn same_bits(a: f64, b: f64) -> bool {
+        a.to_bits() == b.to_bits()
+    }
+
+    proptest! {
+        #[test]
+        fn trade_round_trip(trade in trade_strategy()) {
+            let mut encoder = Encoder::new();
+            encoder.encode_trade(&trade).unwrap();
+            prop_assert_eq!(encoder.len(), TRADE_RECORD_LEN);
+            let (record, len) = decode(encoder.as_bytes()).unwrap();
+            prop_assert_eq!(len, TRADE_RECORD_LEN);
+            let decoded = match record {
+                Record::Trade(view) => view.to_record(),
+                _ => panic!("wrong record type"),
+            };
+            prop_assert_eq!(decoded.timestamp, trade.timestamp);
+            prop_assert_eq!(&decoded.symbol, &trade.symbol);
+            prop_assert_eq!(decoded.side, trade.side);
+            prop_assert!(same_bits(decoded.price, trade.price));
+            prop_assert!(same_bits(decoded.size, trade.size));
+            prop_assert_eq!(decoded.trade_id, trade.trade_id);
+        }
+
+        #[test]
+        fn quote_round_trip(quote in quote_strategy()) {
+            let mut encoder = Encoder::new();
+            encoder.encode_quote(&quote).unwrap();
+            let decoded = match decode(encoder.as_bytes()).unwrap().0 {
+                Record::Quote(view) => view.to_record(),
+                _ => panic!("wrong record type"),
+            };
+            prop_assert_eq!(decoded.timestamp, quote.timestamp);
+            prop_assert_eq!(&decoded.symbol, &quote.symbol);
+            prop_assert!(same_bits(decoded.bid_price, quote.bid_price));
+            prop_assert_eq!(decoded.bid_size, quote.bid_size);
+            prop_assert!(same_bits(decoded.ask_price, quote.ask_price));
+            prop_assert_eq!(decoded.ask_size, quote.ask_size);
+        }
+
+        #[test]
+        fn l2_delta_round_trip(delta in l2_delta_strategy()) {
+            let mut encoder = Encoder::new();
+            encoder.encode_l2_delta(&delta).unwrap();
+            let decoded = match decode(encoder.as_bytes()).unwrap().0 {
+                Record::L2Delta(view) => view.to_record(),
+                _ => panic!("wrong record type"),
+            };
+            prop_assert_eq!(decoded.timestamp, delta.timestamp);
+            prop_assert_eq!(&decoded.symbol, &delta.symbol);
+            prop_assert_eq!(decoded.action, delta.action);
+            prop_assert_eq!(decoded.side, delta.side);
+            prop_assert_eq!(decoded.id, delta.id);
+            prop_assert_eq!(decoded.size, delta.size);
+            prop_assert_eq!(decoded.price.map(f64::to_bits), delta.price.map(f64::to_bits));
+        }
+
+        #[test]
+        fn sequence_round_trip(
+            trades in prop::collection::vec(trade_strategy(), 0..8),
+            deltas in prop::collection::vec(l2_delta_strategy(), 0..8),
+        ) {
+            let mut encoder = Encoder::new();
+            for (trade, delta) in trades.iter().zip(deltas.iter()) {
+                encoder.encode_trade(trade).unwrap();
+                encoder.encode_l2_delta(delta).unwrap();
+            }
+            let records: Vec<Record> = Decoder::new(encoder.as_bytes())
+                .map(|r| r.unwrap())
+                .collect();
+            prop_assert_eq!(records.len(), 2 * trades.len().min(deltas.len()));
+            for (i, record) in records.iter().enumerate() {
+                match record {
+                    Record::Trade(view) => prop_assert_eq!(view.timestamp(), trades[i / 2].timestamp),
+                    Record::L2Delta(view) => prop_assert_eq!(view.id(), deltas[i / 2].id),
+                    Record::Quote(_) => panic!("unexpected quote"),
+                }
+            }
+        }
+
+        #[test]
+        fn decode_arbitrary_bytes(bytes in prop::collection::vec(any::<u8>(), 0..256)) {
+            // must not panic
+            for record in Decoder::new(&bytes) {
+                if let Ok(Record::L2Delta(view)) = record {
+                    view.to_record();
+                }
+            }
+        }
+    }
+
+    #[test]
+    fn encode_bitmex_messages() {
+        let mut encoder = Encoder::new();
+        let trade = parse(TRADE).ok().unwrap();
+        assert_eq!(encoder.encode_message(0, &trade), Ok(1));
+        let update = parse(UPDATE).ok().unwrap();
+        assert_eq!(encoder.encode_message(42, &update), Ok(2));
+        assert_eq!(encoder.len(), TRADE_RECORD_LEN + 2 * L2_DELTA_RECORD_LEN);
+
+        let records: Vec<Record> = Decoder::new(encoder.as_bytes())
+            .map(|r| r.unwrap())
+            .collect();
+        match records[0] {
+            Record::Trade(view) => {
+                assert_eq!(view.timestamp(), 1595187801401000000);
+                assert_eq!(view.symbol(), "XBTUSD");
+                assert_eq!(view.side(), Side::Sell);
+                assert_eq!(view.price(), 9155.5);
+                assert_eq!(
+                    view.trade_id(),
+                    &parse_uuid("ec06df7b-0dc0-8181-f693-c9f39fb57e56").unwrap()[..]
+                );
+            }
+            _ => panic!("wrong record type"),
+        }
+        match records[2] {
+            Record::L2Delta(view) => {
+                assert_eq!(view.timestamp(), 42);
+                assert_eq!(view.action(), DeltaAction::Update);
+                assert_eq!(view.side(), Side::Buy);
+                assert_eq!(view.id(), 8799065250);
+                assert_eq!(view.size(), Some(19575));
+                assert_eq!(view.price(), None);
+            }
+            _ => panic!("wrong record type"),
+        }
+    }
+
+    #[test]
+    fn encode_errors() {
+        let mut encoder = Encoder::new();
+        let quote = QuoteRecord {
+            timestamp: 0,
+            symbol: String::from("SYMBOL-LONGER-THAN-16"),
+            bid_price: 0.0,
+            bid_size: 0,
+            ask_price: 0.0,
+            ask_size: 0,
+        };
+        assert_eq!(
+            encoder.encode_quote(&quote),
+            Err(EncodeError::SymbolTooLong)
+        );
+
+        // nothing of the message is kept when an entry fails
+        let update = parse(b"{\"table\":\"orderBookL2\",\"action\":\"update\",\"data\":[{\"symbol\":\"XBTUSD\",\"id\":1,\"side\":\"Sell\",\"size\":1},{\"symbol\":\"XBTUSD\",\"id\":2,\"side\":\"None\",\"size\":1}]}").ok().unwrap();
+        assert_eq!(
+            encoder.encode_message(0, &update),
+            Err(EncodeError::InvalidSide)
+        );
+        assert!(encoder.is_empty());
+    }
+
+    #[test]
+    fn decode_errors() {
+        let mut encoder = Encoder::new();
+        encoder
+            .encode_trade(&TradeRecord {
+                timestamp: 1,
+                symbol: String::from("XBTUSD"),
+                side: Side::Buy,
+                price: 1.0,
+                size: 1.0,
+                trade_id: [0u8; 16],
+            })
+            .unwrap();
+        let bytes = encoder.as_bytes();
+        assert_eq!(decode(&bytes[..10]).err(), Some(DecodeError::Truncated));
+
+        let mut bytes = bytes.to_vec();
+        bytes[0] = 2;
+        assert_eq!(decode(&bytes).err(), Some(DecodeError::UnsupportedVersion));
+        bytes[0] = 1;
+        bytes[1] = 9;
+        assert_eq!(decode(&bytes).err(), Some(DecodeError::UnknownKind));
+        bytes[1] = 1;
+        bytes[4 + 24] = 0;
+        assert_eq!(decode(&bytes).err(), Some(DecodeError::InvalidSide));
+    }
+}
@@ -0,0 +1,227 @@
+use crate::bitmex_message::TradeEntry;
+use crate::order_book::{Level, OrderBook};
+use std::io::{self, Write};
+
+pub const TRADE_CSV_HEADER: &str = "timestamp,symbol,side,size,price,tick_direction,trd_match_id,\
+gross_value,home_notional,foreign_notional";
+
+// write a csv field, quoted if it contains a separator, quote or line break
+fn write_csv_field<W: Write>(writer: &mut W, value: &str) -> io::Result<()> {
+    if value.contains([',', '"', '\n', '\r']) {
+        write!(writer, "\"{}\"", value.replace('"', "\"\""))
+    } else {
+        writer.write_all(value.as_bytes())
+    }
+}
+
+// Writes trades as csv rows, the header is written before the first row
+pub struct TradeCsvWriter<W: Write> {
+    writer: W,
+    header_written: bool,
+}
+
+impl<W: Write> TradeCsvWriter<W> {
+    pub fn new(writer: W) -> Self {
+        TradeCsvWriter {
+            writer,
+            header_written: false,
+        }
+    }
+
+    pub fn write(&mut self, trade: &TradeEntry) -> io::Result<()> {
+        if !self.header_written {
+            writeln!(self.writer, "{}", TRADE_CSV_HEADER)?;
+            self.header_written = true;
+        }
+        let w = &mut self.writer;
+        write_csv_field(w, &trade.timestamp)?;
+        w.write_all(b",")?;
+        write_csv_field(w, &trade.symbol)?;
+        w.write_all(b",")?;
+        write_csv_field(w, &trade.side)?;
+        write!(w, ",{},{},", trade.size, trade.price)?;
+        write_csv_field(w, &trade.tick_direction)?;
+        w.write_all(b",")?;
+        write_csv_field(w, &trade.trd_match_id)?;
+        writeln!(
+            w,
+            ",{},{},{}",
+            trade.gross_value, trade.home_notional, trade.foreign_notional
+        )
+    }
+
+    pub fn flush(&mut self) -> io::Result<()> {
+        self.writer.flush()
+    }
+
+    pub fn into_inner(self) -> W {
+        self.writer
+    }
+}
+
+// Writes trades as json lines, using the BitMEX field names
+pub struct TradeJsonlWriter<W: Write> {
+    writer: W,
+}
+
+impl<W: Write> TradeJsonlWriter<W> {
+    pub fn new(writer: W) -> Self {
+        TradeJsonlWriter { writer }
+    }
+
+    pub fn write(&mut self, trade: &TradeEntry) -> io::Result<()> {
+        serde_json::to_writer(&mut self.writer, trade)?;
+        self.writer.write_all(b"\n")
+    }
+
+    pub fn flush(&mut self) -> io::Result<()> {
+        self.writer.flush()
+    }
+
+    pub fn into_inner(self) -> W {
+        self.writer
+    }
+}
+
+// Writes the top levels of the book as wide csv rows:
+//   timestamp,symbol,bid_price_1,bid_size_1,..,bid_price_n,bid_size_n,ask_price_1,ask_size_1,..
+// Missing levels are left empty. With an interval, rows are only written when at least interval
+// has elapsed since the last row.
+pub struct BookSnapshotCsvWriter<W: Write> {
+    writer: W,
+    depth: usize,
+    interval: u64,
+    last_timestamp: Option<u64>,
+    header_written: bool,
+}
+
+impl<W: Write> BookSnapshotCsvWriter<W> {
+    pub fn new(writer: W, depth: usize) -> Self {
+        BookSnapshotCsvWriter {
+            writer,
+            depth,
+            interval: 0,
+            last_timestamp: None,
+            header_written: false,
+        }
+    }
+
+    // min time between two rows, in the unit of the timestamps given to write
+    pub fn set_interval(&mut self, interval: u64) {
+        self.interval = interval;
+    }
+
+    // write a row if the interval has elapsed, returns true if a row was written
+    pub fn write(&mut self, timestamp: u64, book: &OrderBook) -> io::Result<bool> {
+        if let Some(last_timestamp) = self.last_timestamp {
+            if timestamp < last_timestamp + self.interval.max(1) {
+                return Ok(false);
+            }
+        }
+        if !self.header_written {
+            self.write_header()?;
+        }
+        write!(self.writer, "{},", timestamp)?;
+        write_csv_field(&mut self.writer, book.symbol())?;
+        self.write_levels(book.bids())?;
+        self.write_levels(book.asks())?;
+        self.writer.write_all(b"\n")?;
+        self.last_timestamp = Some(timestamp);
+        Ok(true)
+    }
+
+    pub fn flush(&mut self) -> io::Result<()> {
+        self.writer.flush()
+    }
+
+    pub fn into_inner(self) -> W {
+        self.writer
+    }
+
+    fn write_header(&mut self) -> io::Result<()> {
+        self.writer.write_all(b"timestamp,symbol")?;
+        for side in ["bid", "ask"].iter() {
+            for i in 1..=self.depth {
+                write!(self.writer, ",{}_price_{},{}_size_{}", side, i, side, i)?;
+            }
+        }
+        self.writer.write_all(b"\n")?;
+        self.header_written = true;
+        Ok(())
+    }
+
+    fn write_levels<'a, I>(&mut self, levels: I) -> io::Result<()>
+    where
+        I: Iterator<Item = &'a Level>,
+    {
+        let mut written = 0;
+        for level in levels.take(self.depth) {
+            write!(self.writer, ",{},{}", level.price, level.size)?;
+            written += 1;
+        }
+        for _ in written..self.depth {
+            self.writer.write_all(b",,")?;
+        }
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::bitmex_message::{parse, BitmexMessage};
+    use crate::export::{BookSnapshotCsvWriter, TradeCsvWriter, TradeJsonlWriter};
+    use crate::fixtures::{SNAPSHOT, TRADE};
+    use crate::instrument::SymbolRegistry;
+    use crate::order_book::OrderBook;
+
+    #[test]
+    fn trades_to_csv_and_jsonl() {
+        let trade = match parse(TRADE) {
+            Ok(BitmexMessage::Trade(trade)) => trade,
+            _ => panic!("wrong message type"),
+        };
+
+        let mut csv = TradeCsvWriter::new(vec![]);
+        csv.write(&trade.data[0]).unwrap();
+        let csv = String::from_utf8(csv.into_inner()).unwrap();
+        assert_eq!(
+            csv,
+            "timestamp,symbol,side,size,price,tick_direction,trd_match_id,gross_value,home_notional,foreign_notional\n\
+            2020-07-19T19:43:21.401Z,XBTUSD,Sell,16000,9155.5,ZeroMinusTick,ec06df7b-0dc0-8181-f693-c9f39fb57e56,174752000,1.74752,16000\n"
+        );
+
+        let mut jsonl = TradeJsonlWriter::new(vec![]);
+        jsonl.write(&trade.data[0]).unwrap();
+        jsonl.write(&trade.data[0]).unwrap();
+        let jsonl = String::from_utf8(jsonl.into_inner()).unwrap();
+        let lines: Vec<&str> = jsonl.lines().collect();
+        assert_eq!(lines.len(), 2);
+        let row: serde_json::Value = serde_json::from_str(lines[0]).unwrap();
+        assert_eq!(row["trdMatchID"], "ec06df7b-0dc0-8181-f693-c9f39fb57e56");
+        assert_eq!(row["price"], 9155.5);
+    }
+
+    #[test]
+    fn book_snapshot_rows() {
+        let mut symbols = SymbolRegistry::new();
+        let mut book = OrderBook::new(&mut symbols, "XBTUSD");
+        book.apply(&parse(SNAPSHOT).ok().unwrap(), |_| {});
+
+        let mut writer = BookSnapshotCsvWriter::new(vec![], 2);
+        writer.set_interval(1000);
+        assert!(writer.write(5000, &book).unwrap());
+        assert!(!writer.write(5500, &book).unwrap());
+        assert!(writer.write(6000, &book).unwrap());
+        let csv = String::from_utf8(writer.into_inner()).unwrap();
+        let lines: Vec<&str> = csv.lines().collect();
+        assert_eq!(lines.len(), 3);
+        assert_eq!(
+            lines[0],
+            "timestamp,symbol,bid_price_1,bid_size_1,bid_price_2,bid_size_2,ask_price_1,ask_size_1,ask_price_2,ask_size_2"
+        );
+        assert_eq!(
+            lines[1],
+            "5000,XBTUSD,9290.5,1023444,9290,23490,9291,1186665,9291.5,832"
+        );
+    }
+}
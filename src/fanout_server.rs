@@ -0,0 +1,677 @@
+use crate::bitmex_message::{parse_table, MarketDataSubscriptionRequest, ParseError, TableMessage};
+use crate::table_store::{Row, TableStore};
+use crate::ws_server::{
+    invalid_data, read_client_frame, upgrade_response, welcome_message, write_frame, CLOSE_OP_CODE,
+    CONTINUATION_OP_CODE, MAX_MESSAGE_LEN, PING_OP_CODE, PONG_OP_CODE, TEXT_OP_CODE,
+};
+use serde::Serialize;
+use serde_json::Value;
+use std::collections::HashMap;
+use std::io::{self, Read, Write};
+use std::net::{SocketAddr, TcpListener, TcpStream, ToSocketAddrs};
+
+pub const DEFAULT_MAX_BUFFERED: usize = 16 << 20;
+
+// Schema of a table as sent upstream in its partial
+struct TableSchema {
+    types: Value,
+    foreign_keys: Value,
+    attributes: Value,
+}
+
+#[derive(Serialize)]
+struct PartialMessage<'a> {
+    table: &'a str,
+    action: &'a str,
+    keys: &'a [String],
+    types: &'a Value,
+    #[serde(rename = "foreignKeys")]
+    foreign_keys: &'a Value,
+    attributes: &'a Value,
+    filter: Value,
+    data: Vec<&'a Row>,
+}
+
+fn row_symbol(row: &Row) -> Option<&str> {
+    row.get("symbol").and_then(Value::as_str)
+}
+
+// partial of a table for a symbol (all symbols if none) from the rows of the store
+fn partial_message(
+    store: &TableStore,
+    schemas: &HashMap<String, TableSchema>,
+    table_name: &str,
+    symbol: Option<&str>,
+) -> Option<String> {
+    let table = store.get(table_name)?;
+    let schema = schemas.get(table_name)?;
+    let mut data: Vec<&Row> = table
+        .rows()
+        .iter()
+        .filter(|row| symbol.is_none() || row_symbol(row) == symbol)
+        .collect();
+    // keyed rows are sent in id order like the BitMEX partials
+    if table.is_keyed() {
+        data.sort_by_key(|row| row.get("id").and_then(Value::as_i64));
+    }
+    let filter = match symbol {
+        Some(symbol) => serde_json::json!({ "symbol": symbol }),
+        None => serde_json::json!({}),
+    };
+    serde_json::to_string(&PartialMessage {
+        table: table_name,
+        action: "partial",
+        keys: table.keys(),
+        types: &schema.types,
+        foreign_keys: &schema.foreign_keys,
+        attributes: &schema.attributes,
+        filter,
+        data,
+    })
+    .ok()
+}
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+enum ClientState {
+    // waiting for the http upgrade request
+    Handshake,
+    Open,
+    Closed,
+}
+
+// Subscription of a client to a table, for one symbol or all. The client only gets the deltas
+// once it got the partial.
+struct Subscription {
+    topic: String,
+    table: String,
+    symbol: Option<String>,
+    live: bool,
+}
+
+struct Client {
+    stream: TcpStream,
+    state: ClientState,
+    in_buf: Vec<u8>,
+    out_buf: Vec<u8>,
+    // payload of a fragmented message
+    message: Vec<u8>,
+    subscriptions: Vec<Subscription>,
+}
+
+impl Client {
+    fn send_text(&mut self, text: &[u8]) {
+        write_frame(&mut self.out_buf, TEXT_OP_CODE, text);
+    }
+
+    // write the buffered output, as much as the socket takes
+    fn flush(&mut self, max_buffered: usize) {
+        while !self.out_buf.is_empty() {
+            match self.stream.write(&self.out_buf) {
+                Ok(0) => {
+                    self.state = ClientState::Closed;
+                    return;
+                }
+                Ok(n) => {
+                    self.out_buf.drain(..n);
+                }
+                Err(ref e) if e.kind() == io::ErrorKind::WouldBlock => break,
+                Err(ref e) if e.kind() == io::ErrorKind::Interrupted => {}
+                Err(_) => {
+                    self.state = ClientState::Closed;
+                    return;
+                }
+            }
+        }
+        // a client not keeping up with the feed is dropped
+        if self.out_buf.len() > max_buffered {
+            self.state = ClientState::Closed;
+        }
+    }
+}
+
+// WebSocket server re-broadcasting an upstream BitMEX feed to local clients. Clients use the
+// BitMEX realtime protocol: they get the welcome message on connect, subscribe with
+// {"op":"subscribe","args":["orderBookL2:XBTUSD"]} and get the partial built from the state kept
+// from the upstream messages followed by the live messages. The server is single threaded and
+// non blocking, poll is to be called in the loop reading the upstream connection.
+pub struct FanoutServer {
+    listener: TcpListener,
+    clients: Vec<Client>,
+    store: TableStore,
+    schemas: HashMap<String, TableSchema>,
+    max_buffered: usize,
+}
+
+impl FanoutServer {
+    pub fn bind<A: ToSocketAddrs>(address: A) -> io::Result<Self> {
+        let listener = TcpListener::bind(address)?;
+        listener.set_nonblocking(true)?;
+        Ok(FanoutServer {
+            listener,
+            clients: vec![],
+            store: TableStore::new(),
+            schemas: HashMap::new(),
+            max_buffered: DEFAULT_MAX_BUFFERED,
+        })
+    }
+
+    pub fn local_addr(&self) -> io::Result<SocketAddr> {
+        self.listener.local_addr()
+    }
+
+    // max bytes buffered for a client before it is disconnected
+    pub fn set_max_buffered(&mut self, max_buffered: usize) {
+        self.max_buffered = max_buffered;
+    }
+
+    // number of connected clients, handshake included
+    pub fn client_count(&self) -> usize {
+        self.clients.len()
+    }
+
+    // accept new clients, handle the client requests and write the buffered output
+    pub fn poll(&mut self) -> io::Result<()> {
+        loop {
+            match self.listener.accept() {
+                Ok((stream, _)) => {
+                    stream.set_nonblocking(true)?;
+                    stream.set_nodelay(true)?;
+                    self.clients.push(Client {
+                        stream,
+                        state: ClientState::Handshake,
+                        in_buf: vec![],
+                        out_buf: vec![],
+                        message: vec![],
+                        subscriptions: vec![],
+                    });
+                }
+                Err(ref e) if e.kind() == io::ErrorKind::WouldBlock => break,
+                Err(e) => return Err(e),
+            }
+        }
+
+        let mut buf = [0u8; 4096];
+        for i in 0..self.clients.len() {
+            loop {
+                match self.clients[i].stream.read(&mut buf) {
+                    Ok(0) => {
+                        self.clients[i].state = ClientState::Closed;
+                        break;
+                    }
+                    Ok(n) => self.clients[i].in_buf.extend_from_slice(&buf[..n]),
+                    Err(ref e) if e.kind() == io::ErrorKind::WouldBlock => break,
+                    Err(ref e) if e.kind() == io::ErrorKind::Interrupted => {}
+                    Err(_) => {
+                        self.clients[i].state = ClientState::Closed;
+                        break;
+                    }
+                }
+            }
+            if self.process_input(i).is_err() {
+                self.clients[i].state = ClientState::Closed;
+            }
+        }
+        self.flush_clients();
+        Ok(())
+    }
+
+    // apply an upstream BitMEX payload to the state and forward it to the subscribed clients.
+    // Payloads which are not table messages are ignored.
+    pub fn on_upstream(&mut self, payload: &[u8]) -> Result<(), ParseError> {
+        let message = parse_table(payload)?;
+        if message.action == "partial" {
+            let value: Value = serde_json::from_slice(payload).map_err(|_| ParseError::Invalid)?;
+            self.schemas.insert(
+                message.table.clone(),
+                TableSchema {
+                    types: value["types"].clone(),
+                    foreign_keys: value["foreignKeys"].clone(),
+                    attributes: value["attributes"].clone(),
+                },
+            );
+        }
+        if self.store.apply(&message).is_err() {
+            // the state is out of sync until the next partial, nothing is forwarded
+            return Ok(());
+        }
+
+        // symbol of a per topic partial (e.g. orderBookL2:XBTUSD), None for a partial of all rows
+        let partial_symbol = message.filter.get("symbol").and_then(Value::as_str);
+        let mut symbols: Vec<&str> = message.data.iter().filter_map(row_symbol).collect();
+        symbols.sort_unstable();
+        symbols.dedup();
+
+        let store = &self.store;
+        let schemas = &self.schemas;
+        // payloads filtered by symbol, made once for all clients
+        let mut filtered: HashMap<&str, Vec<u8>> = HashMap::new();
+        for client in self.clients.iter_mut() {
+            if client.state != ClientState::Open {
+                continue;
+            }
+            for i in 0..client.subscriptions.len() {
+                let subscription = &client.subscriptions[i];
+                if subscription.table != message.table {
+                    continue;
+                }
+                if message.action == "partial" {
+                    // the subscriptions of the symbol of the partial (all of them for a partial
+                    // without filter) and the ones to the whole table get the partial, without
+                    // rows for the symbols the upstream partial has none of
+                    let symbol = subscription.symbol.as_deref();
+                    if partial_symbol.is_some() && symbol.is_some() && symbol != partial_symbol {
+                        continue;
+                    }
+                    if let Some(partial) = partial_message(store, schemas, &message.table, symbol) {
+                        client.send_text(partial.as_bytes());
+                        client.subscriptions[i].live = true;
+                    }
+                    continue;
+                }
+                if !subscription.live {
+                    continue;
+                }
+                let symbol = match subscription.symbol.as_deref() {
+                    Some(symbol) => match symbols.iter().find(|s| **s == symbol) {
+                        Some(symbol) => Some(*symbol),
+                        None => continue,
+                    },
+                    None => None,
+                };
+                if symbol.is_none() || symbols.len() == 1 {
+                    client.send_text(payload);
+                } else if let Some(symbol) = symbol {
+                    let text = filtered
+                        .entry(symbol)
+                        .or_insert_with(|| filter_message(&message, symbol));
+                    write_frame(&mut client.out_buf, TEXT_OP_CODE, text);
+                }
+            }
+        }
+        self.flush_clients();
+        Ok(())
+    }
+
+    fn flush_clients(&mut self) {
+        let max_buffered = self.max_buffered;
+        for client in self.clients.iter_mut() {
+            client.flush(max_buffered);
+        }
+        self.clients.retain(|c| c.state != ClientState::Closed);
+    }
+
+    fn process_input(&mut self, i: usize) -> io::Result<()> {
+        if self.clients[i].state == ClientState::Handshake {
+            self.handshake(i)?;
+        }
+        while self.clients[i].state == ClientState::Open {
+            let (frame, len) = match read_client_frame(&self.clients[i].in_buf)? {
+                Some(frame) => frame,
+                None => break,
+            };
+            self.clients[i].in_buf.drain(..len);
+            match frame.op_code {
+                TEXT_OP_CODE | CONTINUATION_OP_CODE => {
+                    let client = &mut self.clients[i];
+                    client.message.extend_from_slice(&frame.payload);
+                    if client.message.len() > MAX_MESSAGE_LEN {
+                        return Err(invalid_data("client message too long"));
+                    }
+                    if frame.is_final {
+                        let message = std::mem::take(&mut client.message);
+                        self.on_request(i, &message);
+                    }
+                }
+                PING_OP_CODE => {
+                    write_frame(&mut self.clients[i].out_buf, PONG_OP_CODE, &frame.payload)
+                }
+                CLOSE_OP_CODE => {
+                    let client = &mut self.clients[i];
+                    write_frame(&mut client.out_buf, CLOSE_OP_CODE, &frame.payload);
+                    client.flush(usize::MAX);
+                    client.state = ClientState::Closed;
+                }
+                _ => {}
+            }
+        }
+        Ok(())
+    }
+
+    // answer the http upgrade request and send the welcome message
+    fn handshake(&mut self, i: usize) -> io::Result<()> {
+        let client = &mut self.clients[i];
+        let (response, len) = match upgrade_response(&client.in_buf)? {
+            Some(upgrade) => upgrade,
+            None => return Ok(()),
+        };
+        client.in_buf.drain(..len);
+        client.out_buf.extend_from_slice(response.as_bytes());
+        client.state = ClientState::Open;
+        client.send_text(welcome_message().as_bytes());
+        Ok(())
+    }
+
+    fn on_request(&mut self, i: usize, message: &[u8]) {
+        if message == b"ping" {
+            self.clients[i].send_text(b"pong");
+            return;
+        }
+        let request: MarketDataSubscriptionRequest = match serde_json::from_slice(message) {
+            Ok(request) => request,
+            Err(_) => {
+                self.clients[i]
+                    .send_text(b"{\"status\":400,\"error\":\"Unable to parse request.\"}");
+                return;
+            }
+        };
+        let request_json = serde_json::to_string(&request).unwrap_or_default();
+        for topic in request.args.iter() {
+            match request.op.as_str() {
+                "subscribe" => self.subscribe(i, topic, &request_json),
+                "unsubscribe" => {
+                    let client = &mut self.clients[i];
+                    client.subscriptions.retain(|s| &s.topic != topic);
+                    let reply = format!(
+                        "{{\"success\":true,\"unsubscribe\":{},\"request\":{}}}",
+                        Value::from(topic.as_str()),
+                        request_json
+                    );
+                    client.send_text(reply.as_bytes());
+                }
+                _ => {
+                    let reply = format!(
+                        "{{\"status\":400,\"error\":\"Unknown or unsupported operation.\",\"request\":{}}}",
+                        request_json
+                    );
+                    self.clients[i].send_text(reply.as_bytes());
+                }
+            }
+        }
+    }
+
+    fn subscribe(&mut self, i: usize, topic: &str, request_json: &str) {
+        let (table, symbol) = match topic.split_once(':') {
+            Some((table, symbol)) => (table, Some(symbol)),
+            None => (topic, None),
+        };
+        let client = &mut self.clients[i];
+        if client.subscriptions.iter().any(|s| s.topic == topic) {
+            let error = format!("You are already subscribed to this topic: {}", topic);
+            let reply = format!(
+                "{{\"status\":400,\"error\":{},\"request\":{}}}",
+                Value::from(error),
+                request_json
+            );
+            client.send_text(reply.as_bytes());
+            return;
+        }
+        let reply = format!(
+            "{{\"success\":true,\"subscribe\":{},\"request\":{}}}",
+            Value::from(topic),
+            request_json
+        );
+        client.send_text(reply.as_bytes());
+
+        // without the upstream partial yet the partial is sent once it is received
+        let partial = partial_message(&self.store, &self.schemas, table, symbol);
+        let live = partial.is_some();
+        if let Some(partial) = partial {
+            client.send_text(partial.as_bytes());
+        }
+        client.subscriptions.push(Subscription {
+            topic: String::from(topic),
+            table: String::from(table),
+            symbol: symbol.map(String::from),
+            live,
+        });
+    }
+}
+
+// table message with only the rows of a symbol
+fn filter_message(message: &TableMessage, symbol: &str) -> Vec<u8> {
+    let filtered = TableMessage {
+        table: message.table.clone(),
+        action: message.action.clone(),
+        keys: vec![],
+        filter: serde_json::Map::new(),
+        data: message
+            .data
+            .iter()
+            .filter(|row| row_symbol(row) == Some(symbol))
+            .cloned()
+            .collect(),
+    };
+    serde_json::to_vec(&filtered).unwrap_or_default()
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::bitmex_message::{parse, BitmexMessage};
+    use crate::fanout_server::FanoutServer;
+    use crate::fixtures::{TOP_SNAPSHOT, TOP_UPDATE};
+    use serde_json::Value;
+    use std::io::{ErrorKind, Read, Write};
+    use std::net::TcpStream;
+    use std::time::{Duration, Instant};
+
+    const SNAPSHOT: &[u8] = b"{\"table\":\"orderBookL2\",\"action\":\"partial\",\"keys\":[\"symbol\",\"id\",\"side\"],\"types\":{\"symbol\":\"symbol\",\"id\":\"long\",\"side\":\"symbol\",\"size\":\"long\",\"price\":\"float\"},\"foreignKeys\":{\"symbol\":\"instrument\",\"side\":\"side\"},\"attributes\":{\"symbol\":\"parted\",\"id\":\"sorted\"},\"filter\":{},\"data\":[{\"symbol\":\"XBTUSD\",\"id\":8799070850,\"side\":\"Sell\",\"size\":832,\"price\":9291.5},{\"symbol\":\"XBTUSD\",\"id\":8799070950,\"side\":\"Buy\",\"size\":1023444,\"price\":9290.5},{\"symbol\":\"ETHUSD\",\"id\":29699998100,\"side\":\"Sell\",\"size\":10,\"price\":240}]}";
+    const ETHUSD_SNAPSHOT: &[u8] = b"{\"table\":\"orderBookL2\",\"action\":\"partial\",\"keys\":[\"symbol\",\"id\",\"side\"],\"types\":{\"symbol\":\"symbol\",\"id\":\"long\",\"side\":\"symbol\",\"size\":\"long\",\"price\":\"float\"},\"foreignKeys\":{\"symbol\":\"instrument\",\"side\":\"side\"},\"attributes\":{\"symbol\":\"parted\",\"id\":\"sorted\"},\"filter\":{\"symbol\":\"ETHUSD\"},\"data\":[{\"symbol\":\"ETHUSD\",\"id\":29699998100,\"side\":\"Sell\",\"size\":10,\"price\":240}]}";
+    const MIXED_UPDATE: &[u8] = b"{\"table\":\"orderBookL2\",\"action\":\"update\",\"data\":[{\"symbol\":\"ETHUSD\",\"id\":29699998100,\"side\":\"Sell\",\"size\":11},{\"symbol\":\"XBTUSD\",\"id\":8799070950,\"side\":\"Buy\",\"size\":5}]}";
+
+    // masked text frame, as sent by a client
+    fn client_frame(text: &[u8]) -> Vec<u8> {
+        let mask = [1u8, 2, 3, 4];
+        let mut frame = vec![0x81, 0x80 | text.len() as u8];
+        frame.extend_from_slice(&mask);
+        frame.extend(text.iter().enumerate().map(|(i, b)| b ^ mask[i % 4]));
+        frame
+    }
+
+    // read a text frame from the server, polling it while waiting
+    fn read_text(server: &mut FanoutServer, client: &mut TcpStream, buf: &mut Vec<u8>) -> String {
+        let start = Instant::now();
+        loop {
+            if buf.len() >= 2 {
+                let (len, offset) = match buf[1] {
+                    126 => (u16::from_be_bytes([buf[2], buf[3]]) as usize, 4),
+                    len => (len as usize, 2),
+                };
+                if buf.len() >= offset + len {
+                    let text = String::from_utf8(buf[offset..offset + len].to_vec()).unwrap();
+                    buf.drain(..offset + len);
+                    return text;
+                }
+            }
+            assert!(
+                start.elapsed() < Duration::from_secs(5),
+                "no frame received"
+            );
+            server.poll().unwrap();
+            let mut chunk = [0u8; 4096];
+            match client.read(&mut chunk) {
+                Ok(n) => buf.extend_from_slice(&chunk[..n]),
+                Err(ref e) if e.kind() == ErrorKind::WouldBlock => {}
+                Err(e) => panic!("{:?}", e),
+            }
+        }
+    }
+
+    fn connect(server: &mut FanoutServer) -> (TcpStream, Vec<u8>) {
+        let mut client = TcpStream::connect(server.local_addr().unwrap()).unwrap();
+        client.set_nonblocking(true).unwrap();
+        client
+            .write_all(b"GET /realtime HTTP/1.1\r\nHost: localhost\r\nUpgrade: websocket\r\nConnection: Upgrade\r\nSec-WebSocket-Key: dGhlIHNhbXBsZSBub25jZQ==\r\nSec-WebSocket-Version: 13\r\n\r\n")
+            .unwrap();
+        let mut buf = vec![];
+        let start = Instant::now();
+        while !buf.windows(4).any(|w| w == b"\r\n\r\n") {
+            assert!(start.elapsed() < Duration::from_secs(5), "no handshake");
+            server.poll().unwrap();
+            let mut chunk = [0u8; 4096];
+            if let Ok(n) = client.read(&mut chunk) {
+                buf.extend_from_slice(&chunk[..n]);
+            }
+        }
+        let end = buf.windows(4).position(|w| w == b"\r\n\r\n").unwrap() + 4;
+        let response = String::from_utf8(buf.drain(..end).collect()).unwrap();
+        assert!(response.starts_with("HTTP/1.1 101"));
+        assert!(response.contains("Sec-WebSocket-Accept: s3pPLMBiTxaQ9kYGzzhZRbK+xOo="));
+        (client, buf)
+    }
+
+    #[test]
+    fn partial_then_live_deltas() {
+        let mut server = FanoutServer::bind("127.0.0.1:0").unwrap();
+        let (mut client, mut buf) = connect(&mut server);
+        let welcome = read_text(&mut server, &mut client, &mut buf);
+        assert!(matches!(
+            parse(welcome.as_bytes()),
+            Ok(BitmexMessage::Info(_))
+        ));
+
+        // subscribed before the upstream partial
+        client
+            .write_all(&client_frame(
+                b"{\"op\":\"subscribe\",\"args\":[\"orderBookL2:XBTUSD\"]}",
+            ))
+            .unwrap();
+        let success = read_text(&mut server, &mut client, &mut buf);
+        assert!(matches!(
+            parse(success.as_bytes()),
+            Ok(BitmexMessage::Subscribe(_))
+        ));
+        // dropped, no partial sent yet
+        server.on_upstream(TOP_UPDATE).unwrap();
+
+        server.on_upstream(SNAPSHOT).unwrap();
+        server.on_upstream(TOP_UPDATE).unwrap();
+        let partial = read_text(&mut server, &mut client, &mut buf);
+        match parse(partial.as_bytes()) {
+            Ok(BitmexMessage::Snapshot(snapshot)) => {
+                assert_eq!(snapshot.filter.symbol, "XBTUSD");
+                assert_eq!(snapshot.data.len(), 2);
+                assert_eq!(snapshot.data[0].id, 8799070850);
+            }
+            _ => panic!("expected a partial: {}", partial),
+        }
+        assert_eq!(
+            read_text(&mut server, &mut client, &mut buf).as_bytes(),
+            TOP_UPDATE
+        );
+
+        // only the XBTUSD rows are forwarded
+        server.on_upstream(MIXED_UPDATE).unwrap();
+        match parse(read_text(&mut server, &mut client, &mut buf).as_bytes()) {
+            Ok(BitmexMessage::Update(update)) => {
+                assert_eq!(update.data.len(), 1);
+                assert_eq!(update.data[0].symbol, "XBTUSD");
+                assert_eq!(update.data[0].size, 5);
+            }
+            _ => panic!("expected an update"),
+        }
+
+        // a new client gets the partial of the current state on subscribe
+        let (mut second, mut second_buf) = connect(&mut server);
+        read_text(&mut server, &mut second, &mut second_buf);
+        second
+            .write_all(&client_frame(
+                b"{\"op\":\"subscribe\",\"args\":[\"orderBookL2:XBTUSD\"]}",
+            ))
+            .unwrap();
+        read_text(&mut server, &mut second, &mut second_buf);
+        match parse(read_text(&mut server, &mut second, &mut second_buf).as_bytes()) {
+            Ok(BitmexMessage::Snapshot(snapshot)) => {
+                assert_eq!(snapshot.data[0].size, 900);
+                assert_eq!(snapshot.data[1].size, 5);
+            }
+            _ => panic!("expected a partial"),
+        }
+        assert_eq!(server.client_count(), 2);
+
+        client.write_all(&client_frame(b"ping")).unwrap();
+        assert_eq!(read_text(&mut server, &mut client, &mut buf), "pong");
+    }
+
+    #[test]
+    fn partial_without_rows_and_repeated_subscribe() {
+        let mut server = FanoutServer::bind("127.0.0.1:0").unwrap();
+        let (mut client, mut buf) = connect(&mut server);
+        read_text(&mut server, &mut client, &mut buf);
+        let subscribe = b"{\"op\":\"subscribe\",\"args\":[\"orderBookL2:X\\\"Y\"]}";
+        client.write_all(&client_frame(subscribe)).unwrap();
+        let success: Value =
+            serde_json::from_str(&read_text(&mut server, &mut client, &mut buf)).unwrap();
+        assert_eq!(success["subscribe"], "orderBookL2:X\"Y");
+
+        // the upstream partial has no rows of the symbol, the client gets an empty partial
+        server.on_upstream(SNAPSHOT).unwrap();
+        match parse(read_text(&mut server, &mut client, &mut buf).as_bytes()) {
+            Ok(BitmexMessage::Snapshot(snapshot)) => {
+                assert_eq!(snapshot.filter.symbol, "X\"Y");
+                assert!(snapshot.data.is_empty());
+            }
+            _ => panic!("expected a partial"),
+        }
+
+        client.write_all(&client_frame(subscribe)).unwrap();
+        let error: Value =
+            serde_json::from_str(&read_text(&mut server, &mut client, &mut buf)).unwrap();
+        assert_eq!(error["status"], 400);
+        assert_eq!(
+            error["error"],
+            "You are already subscribed to this topic: orderBookL2:X\"Y"
+        );
+    }
+
+    #[test]
+    fn partials_of_different_symbols() {
+        let mut server = FanoutServer::bind("127.0.0.1:0").unwrap();
+        let (mut xbtusd, mut xbtusd_buf) = connect(&mut server);
+        let (mut ethusd, mut ethusd_buf) = connect(&mut server);
+        for (client, buf, topic) in [
+            (&mut xbtusd, &mut xbtusd_buf, "orderBookL2:XBTUSD"),
+            (&mut ethusd, &mut ethusd_buf, "orderBookL2:ETHUSD"),
+        ] {
+            read_text(&mut server, client, buf);
+            let request = format!("{{\"op\":\"subscribe\",\"args\":[\"{}\"]}}", topic);
+            client.write_all(&client_frame(request.as_bytes())).unwrap();
+            read_text(&mut server, client, buf);
+        }
+
+        // each client only gets the partial of its symbol
+        server.on_upstream(TOP_SNAPSHOT).unwrap();
+        server.on_upstream(ETHUSD_SNAPSHOT).unwrap();
+        server.on_upstream(TOP_UPDATE).unwrap();
+        server.on_upstream(MIXED_UPDATE).unwrap();
+        match parse(read_text(&mut server, &mut xbtusd, &mut xbtusd_buf).as_bytes()) {
+            Ok(BitmexMessage::Snapshot(snapshot)) => {
+                assert_eq!(snapshot.filter.symbol, "XBTUSD");
+                assert_eq!(snapshot.data.len(), 2);
+            }
+            _ => panic!("expected a partial"),
+        }
+        assert_eq!(
+            read_text(&mut server, &mut xbtusd, &mut xbtusd_buf).as_bytes(),
+            TOP_UPDATE
+        );
+        match parse(read_text(&mut server, &mut ethusd, &mut ethusd_buf).as_bytes()) {
+            Ok(BitmexMessage::Snapshot(snapshot)) => {
+                assert_eq!(snapshot.filter.symbol, "ETHUSD");
+                assert_eq!(snapshot.data.len(), 1);
+                assert_eq!(snapshot.data[0].id, 29699998100);
+            }
+            _ => panic!("expected a partial"),
+        }
+        match parse(read_text(&mut server, &mut ethusd, &mut ethusd_buf).as_bytes()) {
+            Ok(BitmexMessage::Update(update)) => assert_eq!(update.data[0].symbol, "ETHUSD"),
+            _ => panic!("expected an update"),
+        }
+        match parse(read_text(&mut server, &mut xbtusd, &mut xbtusd_buf).as_bytes()) {
+            Ok(BitmexMessage::Update(update)) => {
+                assert_eq!(update.data.len(), 1);
+                assert_eq!(update.data[0].symbol, "XBTUSD");
+            }
+            _ => panic!("expected an update"),
+        }
+    }
+}
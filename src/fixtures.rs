@@ -0,0 +1,28 @@
+// Realtime messages shared by the tests and the benchmarks (benches/feed_handler.rs), the book
+// fixtures are levels of a recorded XBTUSD orderBookL2 session.
+
+// welcome message sent when the connection is established
+pub const INFO: &[u8] = b"{\"info\":\"Welcome to the BitMEX Realtime API.\",\"version\":\"2020-06-30T21:03:12.000Z\",\"timestamp\":\"2020-07-08T11:00:02.855Z\",\"docs\":\"https://www.bitmex.com/app/wsAPI\",\"limit\":{\"remaining\":39}}";
+
+// acknowledgement of a subscription
+pub const SUBSCRIBE: &[u8] = b"{\"success\":true,\"subscribe\":\"orderBookL2:XBTUSD\",\"request\":{\"op\":\"subscribe\",\"args\":[\"orderBookL2:XBTUSD\"]}}";
+
+// XBTUSD book of 9 asks from 9291 to 9295 and 5 bids from 9290.5 to 9288.5
+pub const SNAPSHOT: &[u8] = b"{\"table\":\"orderBookL2\",\"action\":\"partial\",\"keys\":[\"symbol\",\"id\",\"side\"],\"types\":{\"symbol\":\"symbol\",\"id\":\"long\",\"side\":\"symbol\",\"size\":\"long\",\"price\":\"float\"},\"foreignKeys\":{\"symbol\":\"instrument\",\"side\":\"side\"},\"attributes\":{\"symbol\":\"parted\",\"id\":\"sorted\"},\"filter\":{\"symbol\":\"XBTUSD\"},\"data\":[{\"symbol\":\"XBTUSD\",\"id\":8799070500,\"side\":\"Sell\",\"size\":384243,\"price\":9295},{\"symbol\":\"XBTUSD\",\"id\":8799070550,\"side\":\"Sell\",\"size\":62442,\"price\":9294.5},{\"symbol\":\"XBTUSD\",\"id\":8799070600,\"side\":\"Sell\",\"size\":162802,\"price\":9294},{\"symbol\":\"XBTUSD\",\"id\":8799070650,\"side\":\"Sell\",\"size\":67377,\"price\":9293.5},{\"symbol\":\"XBTUSD\",\"id\":8799070700,\"side\":\"Sell\",\"size\":19978,\"price\":9293},{\"symbol\":\"XBTUSD\",\"id\":8799070750,\"side\":\"Sell\",\"size\":56948,\"price\":9292.5},{\"symbol\":\"XBTUSD\",\"id\":8799070800,\"side\":\"Sell\",\"size\":82020,\"price\":9292},{\"symbol\":\"XBTUSD\",\"id\":8799070850,\"side\":\"Sell\",\"size\":832,\"price\":9291.5},{\"symbol\":\"XBTUSD\",\"id\":8799070900,\"side\":\"Sell\",\"size\":1186665,\"price\":9291},{\"symbol\":\"XBTUSD\",\"id\":8799070950,\"side\":\"Buy\",\"size\":1023444,\"price\":9290.5},{\"symbol\":\"XBTUSD\",\"id\":8799071000,\"side\":\"Buy\",\"size\":23490,\"price\":9290},{\"symbol\":\"XBTUSD\",\"id\":8799071050,\"side\":\"Buy\",\"size\":155749,\"price\":9289.5},{\"symbol\":\"XBTUSD\",\"id\":8799071100,\"side\":\"Buy\",\"size\":10723,\"price\":9289},{\"symbol\":\"XBTUSD\",\"id\":8799071150,\"side\":\"Buy\",\"size\":2113,\"price\":9288.5}]}";
+
+// XBTUSD book of one ask at 9291.5 and one bid at 9290.5
+pub const TOP_SNAPSHOT: &[u8] = b"{\"table\":\"orderBookL2\",\"action\":\"partial\",\"keys\":[\"symbol\",\"id\",\"side\"],\"types\":{\"symbol\":\"symbol\",\"id\":\"long\",\"side\":\"symbol\",\"size\":\"long\",\"price\":\"float\"},\"foreignKeys\":{\"symbol\":\"instrument\",\"side\":\"side\"},\"attributes\":{\"symbol\":\"parted\",\"id\":\"sorted\"},\"filter\":{\"symbol\":\"XBTUSD\"},\"data\":[{\"symbol\":\"XBTUSD\",\"id\":8799070850,\"side\":\"Sell\",\"size\":832,\"price\":9291.5},{\"symbol\":\"XBTUSD\",\"id\":8799070950,\"side\":\"Buy\",\"size\":1023444,\"price\":9290.5}]}";
+
+// resize of the ask of TOP_SNAPSHOT
+pub const TOP_UPDATE: &[u8] = b"{\"table\":\"orderBookL2\",\"action\":\"update\",\"data\":[{\"symbol\":\"XBTUSD\",\"id\":8799070850,\"side\":\"Sell\",\"size\":900}]}";
+
+// new best ask at 9291 over TOP_SNAPSHOT, then resized
+pub const ASK_INSERT: &[u8] = b"{\"table\":\"orderBookL2\",\"action\":\"insert\",\"data\":[{\"symbol\":\"XBTUSD\",\"id\":8799070900,\"side\":\"Sell\",\"size\":10,\"price\":9291}]}";
+pub const ASK_UPDATE: &[u8] = b"{\"table\":\"orderBookL2\",\"action\":\"update\",\"data\":[{\"symbol\":\"XBTUSD\",\"id\":8799070900,\"side\":\"Sell\",\"size\":20}]}";
+
+// deltas of levels which are not in the snapshots
+pub const INSERT: &[u8] = b"{\"table\":\"orderBookL2\",\"action\":\"insert\",\"data\":[{\"symbol\":\"XBTUSD\",\"id\":8798141850,\"side\":\"Sell\",\"size\":1,\"price\":18581.5}]}";
+pub const UPDATE: &[u8] = b"{\"table\":\"orderBookL2\",\"action\":\"update\",\"data\":[{\"symbol\":\"XBTUSD\",\"id\":8799065200,\"side\":\"Sell\",\"size\":182112},{\"symbol\":\"XBTUSD\",\"id\":8799065250,\"side\":\"Sell\",\"size\":19575}]}";
+pub const DELETE: &[u8] = b"{\"table\":\"orderBookL2\",\"action\":\"delete\",\"data\":[{\"symbol\":\"XBTUSD\",\"id\":8799594200,\"side\":\"Buy\"}]}";
+
+pub const TRADE: &[u8] = b"{\"table\":\"trade\",\"action\":\"insert\",\"data\":[{\"timestamp\":\"2020-07-19T19:43:21.401Z\",\"symbol\":\"XBTUSD\",\"side\":\"Sell\",\"size\":16000,\"price\":9155.5,\"tickDirection\":\"ZeroMinusTick\",\"trdMatchID\":\"ec06df7b-0dc0-8181-f693-c9f39fb57e56\",\"grossValue\":174752000,\"homeNotional\":1.74752,\"foreignNotional\":16000}]}";
@@ -0,0 +1,268 @@
+use crate::bitmex_message::Filter;
+use std::collections::HashMap;
+
+// orderBookL2 level ids are offset by the instrument index times this multiplier
+const ID_INDEX_MULTIPLIER: i64 = 100_000_000;
+
+// Mapping between orderBookL2 level ids and prices for one instrument. BitMEX encodes the price
+// of a level in its id as id = (100000000 * index) - (price / tick_size), where the index is the
+// position of the instrument in the instrument list and the tick size is the legacy tick size
+// used for the id encoding.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct PriceIdMap {
+    index: i64,
+    tick_size: f64,
+}
+
+impl PriceIdMap {
+    pub fn new(index: i64, tick_size: f64) -> Self {
+        PriceIdMap { index, tick_size }
+    }
+
+    pub fn price(&self, id: i64) -> f64 {
+        let ticks = ID_INDEX_MULTIPLIER * self.index - id;
+        if self.tick_size >= 1.0 {
+            return ticks as f64 * self.tick_size;
+        }
+        // fractional tick sizes are not exact in binary, dividing by the exact number of ticks per
+        // unit gives the closest f64 to the price, as parsed from the messages
+        ticks as f64 / (1.0 / self.tick_size).round()
+    }
+
+    pub fn id(&self, price: f64) -> i64 {
+        let ticks = (price / self.tick_size).round() as i64;
+        ID_INDEX_MULTIPLIER * self.index - ticks
+    }
+}
+
+// Per instrument id to price mappings, keyed by symbol
+pub struct PriceIdMaps {
+    maps: HashMap<String, PriceIdMap>,
+}
+
+impl PriceIdMaps {
+    pub fn new() -> Self {
+        PriceIdMaps {
+            maps: HashMap::new(),
+        }
+    }
+
+    pub fn add(&mut self, symbol: &str, index: i64, tick_size: f64) {
+        self.maps
+            .insert(String::from(symbol), PriceIdMap::new(index, tick_size));
+    }
+
+    pub fn get(&self, symbol: &str) -> Option<&PriceIdMap> {
+        self.maps.get(symbol)
+    }
+
+    pub fn price(&self, symbol: &str, id: i64) -> Option<f64> {
+        self.get(symbol).map(|map| map.price(id))
+    }
+
+    pub fn id(&self, symbol: &str, price: f64) -> Option<i64> {
+        self.get(symbol).map(|map| map.id(price))
+    }
+}
+
+impl Default for PriceIdMaps {
+    fn default() -> Self {
+        PriceIdMaps::new()
+    }
+}
+
+// Small integer id of an instrument, assigned by a SymbolRegistry in the order the symbols are
+// first seen. Ids are only meaningful within the registry that assigned them.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord, Hash)]
+pub struct InstrumentId(u32);
+
+impl InstrumentId {
+    // position of the instrument in its registry, usable as an index into per instrument vectors
+    pub fn index(&self) -> usize {
+        self.0 as usize
+    }
+}
+
+// Interned BitMEX symbols. Symbols are registered when first seen (e.g. from the filter of a
+// partial or the entries of a delta), later lookups of a known symbol do not allocate.
+//
+// One registry is shared by everything exchanging ids: the BitmexMdHandler owns the registry of
+// the normalized events, the Parser interns the symbols of its rows into the registry given to
+// parse (BitmexMdHandler::instruments_mut) and the books are built with OrderBook::new from that
+// same registry, since apply_ref matches the rows by id. The RecoveryState of a
+// MulticastPublisher keeps a registry of its own, its ids never leave it (the packets and the
+// snapshots carry the symbols).
+#[derive(Debug, Clone, Default)]
+pub struct SymbolRegistry {
+    ids: HashMap<String, InstrumentId>,
+    symbols: Vec<String>,
+}
+
+impl SymbolRegistry {
+    pub fn new() -> Self {
+        SymbolRegistry {
+            ids: HashMap::new(),
+            symbols: vec![],
+        }
+    }
+
+    // id of the symbol, registering it if it was not seen before
+    pub fn intern(&mut self, symbol: &str) -> InstrumentId {
+        if let Some(id) = self.ids.get(symbol) {
+            return *id;
+        }
+        let id = InstrumentId(self.symbols.len() as u32);
+        self.symbols.push(String::from(symbol));
+        self.ids.insert(String::from(symbol), id);
+        id
+    }
+
+    // register the symbol of the filter of a partial
+    pub fn add_filter(&mut self, filter: &Filter) -> InstrumentId {
+        self.intern(&filter.symbol)
+    }
+
+    pub fn id(&self, symbol: &str) -> Option<InstrumentId> {
+        self.ids.get(symbol).copied()
+    }
+
+    pub fn symbol(&self, id: InstrumentId) -> Option<&str> {
+        self.symbols.get(id.index()).map(|symbol| symbol.as_str())
+    }
+
+    pub fn len(&self) -> usize {
+        self.symbols.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.symbols.is_empty()
+    }
+
+    // registered symbols by id
+    pub fn iter(&self) -> impl Iterator<Item = (InstrumentId, &str)> {
+        self.symbols
+            .iter()
+            .enumerate()
+            .map(|(index, symbol)| (InstrumentId(index as u32), symbol.as_str()))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::bitmex_md_handler::BitmexMdHandler;
+    use crate::bitmex_message::Filter;
+    use crate::fixtures::TRADE;
+    use crate::instrument::{PriceIdMap, PriceIdMaps, SymbolRegistry};
+    use crate::normalized::{MarketDataEvent, MarketDataSource};
+    use crate::parser::{BitmexMessageRef, Parser};
+
+    #[test]
+    fn xbtusd_id_to_price() {
+        let map = PriceIdMap::new(88, 0.01);
+        // ids and prices from the orderBookL2 snapshot fixture
+        let levels = [
+            (8799070500, 9295.0),
+            (8799070550, 9294.5),
+            (8799070600, 9294.0),
+            (8799070650, 9293.5),
+            (8799070700, 9293.0),
+            (8799070750, 9292.5),
+            (8799070800, 9292.0),
+            (8799070850, 9291.5),
+            (8799070900, 9291.0),
+            (8799070950, 9290.5),
+            (8799071000, 9290.0),
+            (8799071050, 9289.5),
+            (8799071100, 9289.0),
+            (8799071150, 9288.5),
+        ];
+        for (id, price) in levels.iter() {
+            assert_eq!(map.price(*id), *price);
+            assert_eq!(map.id(*price), *id);
+        }
+    }
+
+    #[test]
+    fn lookup_by_symbol() {
+        let mut maps = PriceIdMaps::new();
+        maps.add("XBTUSD", 88, 0.01);
+        assert_eq!(maps.price("XBTUSD", 8798141850), Some(18581.5));
+        assert_eq!(maps.id("XBTUSD", 18581.5), Some(8798141850));
+        assert_eq!(maps.price("ETHUSD", 8798141850), None);
+    }
+
+    #[test]
+    fn prices_off_the_half_dollar_grid() {
+        let map = PriceIdMap::new(88, 0.01);
+        for (id, price) in [
+            (8799070499, 9295.01),
+            (8799070495, 9295.05),
+            (8799070504, 9294.96),
+            (8799999999, 0.01),
+        ]
+        .iter()
+        {
+            assert_eq!(map.price(*id), *price);
+            assert_eq!(map.id(*price), *id);
+        }
+        // every cent of a price range maps back to the price as written
+        for cents in 900_000..1_000_000 {
+            let price: f64 = format!("{}.{:02}", cents / 100, cents % 100)
+                .parse()
+                .unwrap();
+            assert_eq!(map.price(map.id(price)), price);
+        }
+        let map = PriceIdMap::new(1, 5.0);
+        assert_eq!(map.price(map.id(1235.0)), 1235.0);
+    }
+
+    #[test]
+    fn intern_symbols() {
+        let mut symbols = SymbolRegistry::new();
+        assert!(symbols.is_empty());
+        let xbtusd = symbols.add_filter(&Filter {
+            symbol: String::from("XBTUSD"),
+        });
+        let ethusd = symbols.intern("ETHUSD");
+        assert_ne!(xbtusd, ethusd);
+        assert_eq!(symbols.intern("XBTUSD"), xbtusd);
+        assert_eq!(symbols.len(), 2);
+        assert_eq!((xbtusd.index(), ethusd.index()), (0, 1));
+        assert_eq!(symbols.id("ETHUSD"), Some(ethusd));
+        assert_eq!(symbols.id("XRPUSD"), None);
+        assert_eq!(symbols.symbol(xbtusd), Some("XBTUSD"));
+        let registered: Vec<_> = symbols.iter().collect();
+        assert_eq!(registered, [(xbtusd, "XBTUSD"), (ethusd, "ETHUSD")]);
+        assert_eq!(SymbolRegistry::new().symbol(xbtusd), None);
+    }
+
+    #[test]
+    fn handler_and_parser_ids() {
+        let mut handler = BitmexMdHandler::new();
+        handler.add_symbol("ETHUSD");
+        handler.add_symbol("XBTUSD");
+        let mut parser = Parser::new();
+
+        let mut trades = vec![];
+        handler
+            .on_payload(0, TRADE, &mut |event| {
+                if let MarketDataEvent::Trade(trade) = event {
+                    trades.push(trade.instrument);
+                }
+            })
+            .unwrap();
+        match parser.parse(TRADE, handler.instruments_mut()).unwrap() {
+            BitmexMessageRef::Trade(rows) => assert_eq!(trades, vec![rows[0].instrument]),
+            _ => panic!("wrong message type"),
+        }
+        assert_eq!(trades[0], handler.instruments().id("XBTUSD").unwrap());
+
+        // symbols first seen by the parser are known to the handler
+        let delete = b"{\"table\":\"orderBookL2\",\"action\":\"delete\",\"data\":[{\"symbol\":\"XRPUSD\",\"id\":1,\"side\":\"Buy\"}]}";
+        let instrument = match parser.parse(delete, handler.instruments_mut()).unwrap() {
+            BitmexMessageRef::Delete(entries) => entries[0].instrument,
+            _ => panic!("wrong message type"),
+        };
+        assert_eq!(handler.instruments().symbol(instrument), Some("XRPUSD"));
+    }
+}
@@ -0,0 +1,236 @@
+// Minimal JSON scanner shared by the specialized decoders. Values are read in place from the
+// message, strings are only handled without escapes and in ascii, any other valid JSON the
+// decoders do not expect is reported as unsupported so that the message can be parsed with
+// serde_json instead.
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub(crate) enum ScanError {
+    // valid json that the scanner does not handle (e.g. escaped strings, nested values)
+    Unsupported,
+    // invalid json or a value of the wrong type
+    Invalid,
+}
+
+pub(crate) struct Scanner<'a> {
+    buf: &'a [u8],
+    pos: usize,
+}
+
+impl<'a> Scanner<'a> {
+    // scan the message from the given position, e.g. after a fixed prefix already checked
+    pub(crate) fn new(buf: &'a [u8], pos: usize) -> Self {
+        Scanner { buf, pos }
+    }
+
+    fn peek(&self) -> Option<u8> {
+        self.buf.get(self.pos).copied()
+    }
+
+    fn next(&mut self) -> Result<u8, ScanError> {
+        let byte = self.peek().ok_or(ScanError::Invalid)?;
+        self.pos += 1;
+        Ok(byte)
+    }
+
+    fn expect(&mut self, byte: u8) -> Result<(), ScanError> {
+        if self.next()? != byte {
+            return Err(ScanError::Invalid);
+        }
+        Ok(())
+    }
+
+    fn skip_whitespace(&mut self) {
+        while let Some(b' ' | b'\n' | b'\r' | b'\t') = self.peek() {
+            self.pos += 1;
+        }
+    }
+
+    // scan an array, on_element must consume one element
+    pub(crate) fn array<F>(&mut self, mut on_element: F) -> Result<(), ScanError>
+    where
+        F: FnMut(&mut Self) -> Result<(), ScanError>,
+    {
+        self.skip_whitespace();
+        self.expect(b'[')?;
+        self.skip_whitespace();
+        if self.peek() == Some(b']') {
+            self.pos += 1;
+            return Ok(());
+        }
+        loop {
+            self.skip_whitespace();
+            on_element(self)?;
+            self.skip_whitespace();
+            match self.next()? {
+                b',' => {}
+                b']' => return Ok(()),
+                _ => return Err(ScanError::Invalid),
+            }
+        }
+    }
+
+    // scan an object, on_field gets the key and must consume the value
+    pub(crate) fn object<F>(&mut self, mut on_field: F) -> Result<(), ScanError>
+    where
+        F: FnMut(&mut Self, &'a [u8]) -> Result<(), ScanError>,
+    {
+        self.skip_whitespace();
+        self.expect(b'{')?;
+        self.skip_whitespace();
+        if self.peek() == Some(b'}') {
+            self.pos += 1;
+            return Ok(());
+        }
+        loop {
+            self.skip_whitespace();
+            let key = self.string()?;
+            self.skip_whitespace();
+            self.expect(b':')?;
+            self.skip_whitespace();
+            on_field(self, key)?;
+            self.skip_whitespace();
+            match self.next()? {
+                b',' => {}
+                b'}' => return Ok(()),
+                _ => return Err(ScanError::Invalid),
+            }
+        }
+    }
+
+    // end of the top level object whose last field was just scanned
+    pub(crate) fn end_object(&mut self) -> Result<(), ScanError> {
+        self.skip_whitespace();
+        match self.next()? {
+            b'}' => {}
+            // more fields
+            b',' => return Err(ScanError::Unsupported),
+            _ => return Err(ScanError::Invalid),
+        }
+        self.skip_whitespace();
+        if self.pos != self.buf.len() {
+            return Err(ScanError::Invalid);
+        }
+        Ok(())
+    }
+
+    // ascii string without escapes
+    pub(crate) fn string(&mut self) -> Result<&'a [u8], ScanError> {
+        self.expect(b'"')?;
+        let start = self.pos;
+        loop {
+            match self.next()? {
+                b'"' => return Ok(&self.buf[start..self.pos - 1]),
+                b'\\' => return Err(ScanError::Unsupported),
+                byte if byte < 0x20 => return Err(ScanError::Invalid),
+                byte if byte >= 0x80 => return Err(ScanError::Unsupported),
+                _ => {}
+            }
+        }
+    }
+
+    // string() as str
+    pub(crate) fn str(&mut self) -> Result<&'a str, ScanError> {
+        std::str::from_utf8(self.string()?).map_err(|_| ScanError::Invalid)
+    }
+
+    // json number, returns the text of the number and true if it has no fraction or exponent
+    fn number(&mut self) -> Result<(&'a str, bool), ScanError> {
+        let start = self.pos;
+        let mut integral = true;
+        if self.peek() == Some(b'-') {
+            self.pos += 1;
+        }
+        match self.next()? {
+            b'0' => {}
+            b'1'..=b'9' => self.digits(),
+            _ => return Err(ScanError::Invalid),
+        }
+        if self.peek() == Some(b'.') {
+            self.pos += 1;
+            integral = false;
+            self.required_digits()?;
+        }
+        if let Some(b'e' | b'E') = self.peek() {
+            self.pos += 1;
+            integral = false;
+            if let Some(b'+' | b'-') = self.peek() {
+                self.pos += 1;
+            }
+            self.required_digits()?;
+        }
+        // only ascii bytes were read
+        let text =
+            std::str::from_utf8(&self.buf[start..self.pos]).map_err(|_| ScanError::Invalid)?;
+        Ok((text, integral))
+    }
+
+    fn digits(&mut self) {
+        while let Some(b'0'..=b'9') = self.peek() {
+            self.pos += 1;
+        }
+    }
+
+    fn required_digits(&mut self) -> Result<(), ScanError> {
+        let start = self.pos;
+        self.digits();
+        if self.pos == start {
+            return Err(ScanError::Invalid);
+        }
+        Ok(())
+    }
+
+    pub(crate) fn integer(&mut self) -> Result<i64, ScanError> {
+        match self.number()? {
+            (text, true) => text.parse().map_err(|_| ScanError::Invalid),
+            _ => Err(ScanError::Invalid),
+        }
+    }
+
+    // float with correct rounding, the same value as serde_json with float_roundtrip
+    pub(crate) fn float(&mut self) -> Result<f64, ScanError> {
+        let (text, _) = self.number()?;
+        match text.parse::<f64>() {
+            Ok(value) if value.is_finite() => Ok(value),
+            _ => Err(ScanError::Invalid),
+        }
+    }
+
+    // skip the value of an unknown field, only scalars are handled
+    pub(crate) fn skip_value(&mut self) -> Result<(), ScanError> {
+        match self.peek() {
+            Some(b'"') => self.string().map(|_| ()),
+            Some(b'-' | b'0'..=b'9') => self.number().map(|_| ()),
+            Some(b'{' | b'[') => Err(ScanError::Unsupported),
+            _ => {
+                for literal in [&b"true"[..], b"false", b"null"].iter() {
+                    if self.buf[self.pos..].starts_with(literal) {
+                        self.pos += literal.len();
+                        return Ok(());
+                    }
+                }
+                Err(ScanError::Invalid)
+            }
+        }
+    }
+}
+
+// Fields found in an object, as bits of a mask. Checks for duplicates and missing fields.
+#[derive(Default)]
+pub(crate) struct FieldSet(u32);
+
+impl FieldSet {
+    pub(crate) fn add(&mut self, field: u32) -> Result<(), ScanError> {
+        if self.0 & field != 0 {
+            return Err(ScanError::Invalid);
+        }
+        self.0 |= field;
+        Ok(())
+    }
+
+    pub(crate) fn require(&self, fields: u32) -> Result<(), ScanError> {
+        if self.0 & fields != fields {
+            return Err(ScanError::Invalid);
+        }
+        Ok(())
+    }
+}
@@ -0,0 +1,411 @@
+use crate::instrument::{InstrumentId, SymbolRegistry};
+use crate::json_scanner::{FieldSet, ScanError, Scanner};
+use crate::normalized::{DeltaAction, Side};
+
+// Decoder specialized for the orderBookL2 insert, update and delete messages. The rows are
+// scanned in place into a buffer of entries reused from one message to the next, there is no
+// allocation once the buffer has grown to the largest batch. Only the layout sent by BitMEX is
+// handled (fields in any order, ascii symbols without escapes, unknown scalar fields skipped),
+// anything else is an error and the message should be given to bitmex_message::parse instead.
+
+const INSERT_PREFIX: &[u8] = b"{\"table\":\"orderBookL2\",\"action\":\"insert\",\"data\":";
+const UPDATE_PREFIX: &[u8] = b"{\"table\":\"orderBookL2\",\"action\":\"update\",\"data\":";
+const DELETE_PREFIX: &[u8] = b"{\"table\":\"orderBookL2\",\"action\":\"delete\",\"data\":";
+
+// fields of a row
+const SYMBOL_FIELD: u32 = 1;
+const ID_FIELD: u32 = 2;
+const SIDE_FIELD: u32 = 4;
+const SIZE_FIELD: u32 = 8;
+const PRICE_FIELD: u32 = 16;
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum L2DecodeError {
+    // message does not start like an orderBookL2 insert, update or delete
+    NotL2Delta,
+    // valid json that the scanner does not handle (e.g. escaped strings, nested values)
+    Unsupported,
+    // invalid json or a row that does not match the schema of the action
+    Invalid,
+}
+
+impl From<ScanError> for L2DecodeError {
+    fn from(error: ScanError) -> Self {
+        match error {
+            ScanError::Unsupported => L2DecodeError::Unsupported,
+            ScanError::Invalid => L2DecodeError::Invalid,
+        }
+    }
+}
+
+// Row of an orderBookL2 delta with the instrument id of its symbol. The size is set on inserts and
+// updates and the price on inserts, they are also kept if sent on other actions.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct L2Entry {
+    pub instrument: InstrumentId,
+    pub id: i64,
+    pub side: Side,
+    pub size: Option<i64>,
+    pub price: Option<f64>,
+}
+
+pub struct L2Decoder {
+    action: DeltaAction,
+    entries: Vec<L2Entry>,
+}
+
+impl Default for L2Decoder {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl L2Decoder {
+    pub fn new() -> Self {
+        L2Decoder {
+            action: DeltaAction::Update,
+            entries: vec![],
+        }
+    }
+
+    // action of the last message decoded
+    pub fn action(&self) -> DeltaAction {
+        self.action
+    }
+
+    // rows of the last message decoded, empty after an error
+    pub fn entries(&self) -> &[L2Entry] {
+        &self.entries
+    }
+
+    // decode a message, the symbols of the rows are interned in the registry
+    pub fn decode(
+        &mut self,
+        message: &[u8],
+        symbols: &mut SymbolRegistry,
+    ) -> Result<DeltaAction, L2DecodeError> {
+        self.entries.clear();
+        let result = self.decode_entries(message, symbols);
+        if result.is_err() {
+            self.entries.clear();
+        }
+        result
+    }
+
+    fn decode_entries(
+        &mut self,
+        message: &[u8],
+        symbols: &mut SymbolRegistry,
+    ) -> Result<DeltaAction, L2DecodeError> {
+        let (action, required) = if message.starts_with(UPDATE_PREFIX) {
+            (
+                DeltaAction::Update,
+                SYMBOL_FIELD | ID_FIELD | SIDE_FIELD | SIZE_FIELD,
+            )
+        } else if message.starts_with(INSERT_PREFIX) {
+            let required = SYMBOL_FIELD | ID_FIELD | SIDE_FIELD | SIZE_FIELD | PRICE_FIELD;
+            (DeltaAction::Insert, required)
+        } else if message.starts_with(DELETE_PREFIX) {
+            (DeltaAction::Delete, SYMBOL_FIELD | ID_FIELD | SIDE_FIELD)
+        } else {
+            return Err(L2DecodeError::NotL2Delta);
+        };
+        self.action = action;
+
+        // all the prefixes have the same length
+        let mut scanner = Scanner::new(message, UPDATE_PREFIX.len());
+        let entries = &mut self.entries;
+        scanner.array(|scanner| {
+            entries.push(row(scanner, required, symbols)?);
+            Ok(())
+        })?;
+        scanner.end_object()?;
+        Ok(action)
+    }
+}
+
+fn row(
+    scanner: &mut Scanner,
+    required: u32,
+    symbols: &mut SymbolRegistry,
+) -> Result<L2Entry, ScanError> {
+    // the instrument is only known once the symbol field is read
+    let mut instrument: Option<InstrumentId> = None;
+    let mut id = 0;
+    let mut side = Side::Buy;
+    let mut size = None;
+    let mut price = None;
+    let mut fields = FieldSet::default();
+    scanner.object(|scanner, key| {
+        match key {
+            b"symbol" => {
+                fields.add(SYMBOL_FIELD)?;
+                instrument = Some(symbols.intern(scanner.str()?));
+            }
+            b"id" => {
+                fields.add(ID_FIELD)?;
+                id = scanner.integer()?;
+            }
+            b"side" => {
+                fields.add(SIDE_FIELD)?;
+                side = match scanner.string()? {
+                    b"Buy" => Side::Buy,
+                    b"Sell" => Side::Sell,
+                    _ => return Err(ScanError::Unsupported),
+                };
+            }
+            b"size" => {
+                fields.add(SIZE_FIELD)?;
+                size = Some(scanner.integer()?);
+            }
+            b"price" => {
+                fields.add(PRICE_FIELD)?;
+                price = Some(scanner.float()?);
+            }
+            _ => scanner.skip_value()?,
+        }
+        Ok(())
+    })?;
+    fields.require(required)?;
+    Ok(L2Entry {
+        instrument: instrument.ok_or(ScanError::Invalid)?,
+        id,
+        side,
+        size,
+        price,
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::bitmex_message::{parse, BitmexMessage};
+    use crate::fixtures::{DELETE, INSERT, UPDATE};
+    use crate::instrument::SymbolRegistry;
+    use crate::l2_decoder::{L2DecodeError, L2Decoder, L2Entry};
+    use crate::normalized::{DeltaAction, Side};
+    use proptest::prelude::*;
+
+    // rows of the message decoded with parse, none if parse fails or it is not a delta
+    fn parsed_entries(
+        message: &[u8],
+        symbols: &mut SymbolRegistry,
+    ) -> Option<(DeltaAction, Vec<L2Entry>)> {
+        let mut entry = |symbol: &str, id, side: &str, size, price| {
+            Some(L2Entry {
+                instrument: symbols.intern(symbol),
+                id,
+                side: Side::from_bitmex(side)?,
+                size,
+                price,
+            })
+        };
+        match parse(message).ok()? {
+            BitmexMessage::Insert(insert) => Some((
+                DeltaAction::Insert,
+                insert
+                    .data
+                    .iter()
+                    .map(|e| entry(&e.symbol, e.id, &e.side, Some(e.size), Some(e.price)))
+                    .collect::<Option<_>>()?,
+            )),
+            BitmexMessage::Update(update) => Some((
+                DeltaAction::Update,
+                update
+                    .data
+                    .iter()
+                    .map(|e| entry(&e.symbol, e.id, &e.side, Some(e.size), None))
+                    .collect::<Option<_>>()?,
+            )),
+            BitmexMessage::Delete(delete) => Some((
+                DeltaAction::Delete,
+                delete
+                    .data
+                    .iter()
+                    .map(|e| entry(&e.symbol, e.id, &e.side, None, None))
+                    .collect::<Option<_>>()?,
+            )),
+            _ => None,
+        }
+    }
+
+    // whenever the decoder accepts a message, parse must give the same rows. Fields that the
+    // parse types do not have are ignored.
+    fn check_against_parse(
+        decoder: &mut L2Decoder,
+        symbols: &mut SymbolRegistry,
+        message: &[u8],
+    ) -> bool {
+        let action = match decoder.decode(message, symbols) {
+            Ok(action) => action,
+            Err(_) => return false,
+        };
+        let (parsed_action, parsed) = parsed_entries(message, symbols).expect("parse failed");
+        assert_eq!(action, parsed_action);
+        let decoded: Vec<L2Entry> = decoder
+            .entries()
+            .iter()
+            .map(|entry| match action {
+                DeltaAction::Update => L2Entry {
+                    price: None,
+                    ..*entry
+                },
+                DeltaAction::Delete => L2Entry {
+                    size: None,
+                    price: None,
+                    ..*entry
+                },
+                _ => *entry,
+            })
+            .collect();
+        assert_eq!(decoded, parsed);
+        true
+    }
+
+    #[test]
+    fn decode_deltas() {
+        let mut decoder = L2Decoder::new();
+        let mut symbols = SymbolRegistry::new();
+        assert_eq!(
+            decoder.decode(INSERT, &mut symbols),
+            Ok(DeltaAction::Insert)
+        );
+        let entry = decoder.entries()[0];
+        assert_eq!(symbols.symbol(entry.instrument), Some("XBTUSD"));
+        assert_eq!(entry.id, 8798141850);
+        assert_eq!(entry.side, Side::Sell);
+        assert_eq!(entry.size, Some(1));
+        assert_eq!(entry.price, Some(18581.5));
+
+        assert_eq!(
+            decoder.decode(UPDATE, &mut symbols),
+            Ok(DeltaAction::Update)
+        );
+        assert_eq!(decoder.entries().len(), 2);
+        assert_eq!(decoder.entries()[1].size, Some(19575));
+        assert_eq!(decoder.entries()[1].price, None);
+
+        assert_eq!(
+            decoder.decode(DELETE, &mut symbols),
+            Ok(DeltaAction::Delete)
+        );
+        assert_eq!(decoder.entries()[0].side, Side::Buy);
+        assert_eq!(decoder.entries()[0].size, None);
+
+        for message in [INSERT, UPDATE, DELETE].iter() {
+            assert!(check_against_parse(&mut decoder, &mut symbols, message));
+        }
+    }
+
+    #[test]
+    fn unsupported_and_invalid_messages() {
+        let mut decoder = L2Decoder::new();
+        let mut symbols = SymbolRegistry::new();
+        assert_eq!(
+            decoder.decode(
+                b"{\"table\":\"trade\",\"action\":\"insert\",\"data\":[]}",
+                &mut symbols
+            ),
+            Err(L2DecodeError::NotL2Delta)
+        );
+        assert_eq!(
+            decoder.decode(
+                b"{\"table\":\"orderBookL2\",\"action\":\"partial\",\"keys\":[]}",
+                &mut symbols
+            ),
+            Err(L2DecodeError::NotL2Delta)
+        );
+        assert_eq!(
+            decoder.decode(b"{\"table\":\"orderBookL2\",\"action\":\"delete\",\"data\":[{\"symbol\":\"XBT\\u0055SD\",\"id\":1,\"side\":\"Buy\"}]}", &mut symbols),
+            Err(L2DecodeError::Unsupported)
+        );
+        assert_eq!(
+            decoder.decode(b"{\"table\":\"orderBookL2\",\"action\":\"delete\",\"data\":[{\"symbol\":\"XBTUSD\",\"id\":01,\"side\":\"Buy\"}]}", &mut symbols),
+            Err(L2DecodeError::Invalid)
+        );
+        assert_eq!(
+            decoder.decode(b"{\"table\":\"orderBookL2\",\"action\":\"delete\",\"data\":[{\"symbol\":\"XBTUSD\",\"id\":1,\"id\":2,\"side\":\"Buy\"}]}", &mut symbols),
+            Err(L2DecodeError::Invalid)
+        );
+        // update without a size
+        assert_eq!(
+            decoder.decode(b"{\"table\":\"orderBookL2\",\"action\":\"update\",\"data\":[{\"symbol\":\"XBTUSD\",\"id\":1,\"side\":\"Buy\"}]}", &mut symbols),
+            Err(L2DecodeError::Invalid)
+        );
+        assert!(decoder.entries().is_empty());
+        for len in 0..UPDATE.len() {
+            assert!(decoder.decode(&UPDATE[..len], &mut symbols).is_err());
+        }
+
+        // unknown scalar fields are skipped
+        let message = b"{\"table\":\"orderBookL2\",\"action\":\"update\",\"data\":[ {\"symbol\":\"XBTUSD\", \"id\":1,\"side\":\"Buy\",\"size\":5,\"price\":9290.5,\"timestamp\":\"2021-01-01T00:00:00.000Z\",\"x\":null} ] }";
+        assert!(check_against_parse(&mut decoder, &mut symbols, message));
+        assert_eq!(decoder.entries()[0].price, Some(9290.5));
+    }
+
+    #[test]
+    fn entries_buffer_is_reused() {
+        let mut decoder = L2Decoder::new();
+        let mut symbols = SymbolRegistry::new();
+        decoder.decode(UPDATE, &mut symbols).unwrap();
+        let capacity = decoder.entries.capacity();
+        let buffer = decoder.entries.as_ptr();
+        for _ in 0..100 {
+            decoder.decode(UPDATE, &mut symbols).unwrap();
+            decoder.decode(INSERT, &mut symbols).unwrap();
+        }
+        assert_eq!(decoder.entries.capacity(), capacity);
+        assert_eq!(decoder.entries.as_ptr(), buffer);
+    }
+
+    fn row_strategy() -> impl Strategy<Value = String> {
+        (
+            "[A-Z0-9]{1,16}",
+            any::<i64>(),
+            prop_oneof![Just("Buy"), Just("Sell")],
+            any::<i64>(),
+            (0u32..10_000_000, 0u32..100),
+            any::<bool>(),
+        )
+            .prop_map(|(symbol, id, side, size, (units, cents), timestamp)| {
+                let mut row = format!(
+                    "{{\"symbol\":\"{}\",\"id\":{},\"side\":\"{}\",\"size\":{},\"price\":{}.{:02}",
+                    symbol, id, side, size, units, cents
+                );
+                if timestamp {
+                    row.push_str(",\"timestamp\":\"2021-01-01T00:00:00.000Z\"");
+                }
+                row.push('}');
+                row
+            })
+    }
+
+    proptest! {
+        #[test]
+        fn decoder_matches_parse(
+            action in prop_oneof![Just("insert"), Just("update"), Just("delete")],
+            rows in proptest::collection::vec(row_strategy(), 0..50),
+        ) {
+            let message = format!(
+                "{{\"table\":\"orderBookL2\",\"action\":\"{}\",\"data\":[{}]}}",
+                action,
+                rows.join(",")
+            );
+            let mut decoder = L2Decoder::new();
+            let mut symbols = SymbolRegistry::new();
+            prop_assert!(check_against_parse(&mut decoder, &mut symbols, message.as_bytes()));
+            prop_assert_eq!(decoder.entries().len(), rows.len());
+        }
+
+        #[test]
+        fn decoder_matches_parse_on_corrupted_messages(
+            message in prop_oneof![Just(INSERT), Just(UPDATE), Just(DELETE)],
+            position in any::<prop::sample::Index>(),
+            byte in any::<u8>(),
+        ) {
+            let mut message = message.to_vec();
+            let i = position.index(message.len());
+            message[i] = byte;
+            check_against_parse(&mut L2Decoder::new(), &mut SymbolRegistry::new(), &message);
+        }
+    }
+}
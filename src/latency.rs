@@ -0,0 +1,509 @@
+use crate::bitmex_message::{BitmexMessage, TradeEntry};
+use crate::parser::BitmexMessageRef;
+use hdrhistogram::Histogram;
+use std::time::Duration;
+
+// Latency of the stages of the receive pipeline, per message type, and of the trades from the
+// exchange timestamp to the local receive time. The caller takes timestamps with a Clock at the
+// end of each stage of a message and records them once the message is handled:
+//
+//   let mut times = StageTimes::new(clock.now());
+//   let n = socket.read(&mut buffer)?;
+//   times.end(Stage::SocketRead, clock.now());
+//   assembler.read(&buffer[..n], |_, payload| {
+//       times.end(Stage::FrameAssembly, clock.now());
+//       let message = parser.parse(payload);
+//       times.end(Stage::Parse, clock.now());
+//       ...
+//       stats.record(MessageType::from_ref(&message), &times);
+//       // the next message of the read starts where this one ended
+//       times = StageTimes::new(clock.now());
+//   });
+
+// stage durations above this are recorded as this value
+const MAX_LATENCY_NANOS: u64 = 60_000_000_000;
+// precision of the histograms, 2 significant digits keeps them small enough to have one per stage
+// and message type
+const SIGNIFICANT_DIGITS: u8 = 2;
+
+pub const STAGE_COUNT: usize = 5;
+pub const MESSAGE_TYPE_COUNT: usize = 7;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Stage {
+    // read of the socket, the read the message was completed by
+    SocketRead,
+    // reassembly of the websocket frame
+    FrameAssembly,
+    Parse,
+    // apply of the message to the books
+    BookApply,
+    // delivery of the events to the listeners
+    Dispatch,
+}
+
+impl Stage {
+    pub const ALL: [Stage; STAGE_COUNT] = [
+        Stage::SocketRead,
+        Stage::FrameAssembly,
+        Stage::Parse,
+        Stage::BookApply,
+        Stage::Dispatch,
+    ];
+
+    pub fn name(&self) -> &'static str {
+        match self {
+            Stage::SocketRead => "socket_read",
+            Stage::FrameAssembly => "frame_assembly",
+            Stage::Parse => "parse",
+            Stage::BookApply => "book_apply",
+            Stage::Dispatch => "dispatch",
+        }
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum MessageType {
+    Partial,
+    Insert,
+    Update,
+    Delete,
+    TradePartial,
+    Trade,
+    // info, subscribe and messages that could not be parsed
+    Other,
+}
+
+impl MessageType {
+    pub const ALL: [MessageType; MESSAGE_TYPE_COUNT] = [
+        MessageType::Partial,
+        MessageType::Insert,
+        MessageType::Update,
+        MessageType::Delete,
+        MessageType::TradePartial,
+        MessageType::Trade,
+        MessageType::Other,
+    ];
+
+    pub fn from_message(message: &BitmexMessage) -> Self {
+        match message {
+            BitmexMessage::Snapshot(_) => MessageType::Partial,
+            BitmexMessage::Insert(_) => MessageType::Insert,
+            BitmexMessage::Update(_) => MessageType::Update,
+            BitmexMessage::Delete(_) => MessageType::Delete,
+            BitmexMessage::TradeSnapshot(_) => MessageType::TradePartial,
+            BitmexMessage::Trade(_) => MessageType::Trade,
+            _ => MessageType::Other,
+        }
+    }
+
+    pub fn from_ref(message: &BitmexMessageRef) -> Self {
+        match message {
+            BitmexMessageRef::Insert(_) => MessageType::Insert,
+            BitmexMessageRef::Update(_) => MessageType::Update,
+            BitmexMessageRef::Delete(_) => MessageType::Delete,
+            BitmexMessageRef::Trade(_) => MessageType::Trade,
+            BitmexMessageRef::Message(message) => MessageType::from_message(message),
+        }
+    }
+
+    pub fn name(&self) -> &'static str {
+        match self {
+            MessageType::Partial => "partial",
+            MessageType::Insert => "insert",
+            MessageType::Update => "update",
+            MessageType::Delete => "delete",
+            MessageType::TradePartial => "trade_partial",
+            MessageType::Trade => "trade",
+            MessageType::Other => "other",
+        }
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+enum ClockSource {
+    MonotonicRaw,
+    // time stamp counter with the time of a reference tick and the nanos per tick
+    Tsc {
+        base_tick: u64,
+        base_nanos: u64,
+        nanos_per_tick: f64,
+    },
+}
+
+// Monotonic nanos for the stage timestamps, either CLOCK_MONOTONIC_RAW or the time stamp counter
+// calibrated against it. The clock is not related to the wall clock.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct Clock {
+    source: ClockSource,
+}
+
+impl Default for Clock {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl Clock {
+    // CLOCK_MONOTONIC_RAW
+    pub fn new() -> Self {
+        Clock {
+            source: ClockSource::MonotonicRaw,
+        }
+    }
+
+    // time stamp counter, calibrated over the given duration (e.g. 10ms). None if the cpu has no
+    // invariant tsc, its rate could then change with the frequency of the core.
+    pub fn tsc(calibration: Duration) -> Option<Self> {
+        if !has_invariant_tsc() {
+            return None;
+        }
+        let (start_tick, start_nanos) = (read_tsc(), monotonic_raw_nanos());
+        std::thread::sleep(calibration);
+        let (end_tick, end_nanos) = (read_tsc(), monotonic_raw_nanos());
+        if end_tick <= start_tick || end_nanos <= start_nanos {
+            return None;
+        }
+        Some(Clock {
+            source: ClockSource::Tsc {
+                base_tick: end_tick,
+                base_nanos: end_nanos,
+                nanos_per_tick: (end_nanos - start_nanos) as f64 / (end_tick - start_tick) as f64,
+            },
+        })
+    }
+
+    pub fn is_tsc(&self) -> bool {
+        matches!(self.source, ClockSource::Tsc { .. })
+    }
+
+    pub fn now(&self) -> u64 {
+        match self.source {
+            ClockSource::MonotonicRaw => monotonic_raw_nanos(),
+            ClockSource::Tsc {
+                base_tick,
+                base_nanos,
+                nanos_per_tick,
+            } => {
+                let ticks = read_tsc().wrapping_sub(base_tick) as i64;
+                (base_nanos as i64 + (ticks as f64 * nanos_per_tick) as i64) as u64
+            }
+        }
+    }
+}
+
+fn monotonic_raw_nanos() -> u64 {
+    let mut ts = libc::timespec {
+        tv_sec: 0,
+        tv_nsec: 0,
+    };
+    unsafe {
+        libc::clock_gettime(libc::CLOCK_MONOTONIC_RAW, &mut ts);
+    }
+    ts.tv_sec as u64 * 1_000_000_000 + ts.tv_nsec as u64
+}
+
+#[cfg(target_arch = "x86_64")]
+fn read_tsc() -> u64 {
+    unsafe { std::arch::x86_64::_rdtsc() }
+}
+
+#[cfg(not(target_arch = "x86_64"))]
+fn read_tsc() -> u64 {
+    0
+}
+
+// __cpuid is only unsafe on older toolchains
+#[cfg(target_arch = "x86_64")]
+#[allow(unused_unsafe)]
+fn has_invariant_tsc() -> bool {
+    use std::arch::x86_64::__cpuid;
+    // invariant tsc flag of the advanced power management leaf
+    let max_leaf = unsafe { __cpuid(0x8000_0000) }.eax;
+    max_leaf >= 0x8000_0007 && unsafe { __cpuid(0x8000_0007) }.edx & (1 << 8) != 0
+}
+
+#[cfg(not(target_arch = "x86_64"))]
+fn has_invariant_tsc() -> bool {
+    false
+}
+
+// Timestamps of the end of the stages of one message. The duration of a stage is the time since
+// the previous stage ended, or since the start for the first one. Stages that are not ended are
+// not recorded (e.g. no socket read for the second message of a read).
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct StageTimes {
+    last: u64,
+    durations: [Option<u64>; STAGE_COUNT],
+}
+
+impl StageTimes {
+    pub fn new(start: u64) -> Self {
+        StageTimes {
+            last: start,
+            durations: [None; STAGE_COUNT],
+        }
+    }
+
+    pub fn end(&mut self, stage: Stage, now: u64) {
+        self.durations[stage as usize] = Some(now.saturating_sub(self.last));
+        self.last = now;
+    }
+
+    pub fn duration(&self, stage: Stage) -> Option<u64> {
+        self.durations[stage as usize]
+    }
+
+    // time from the start to the end of the last stage ended
+    pub fn total(&self) -> u64 {
+        self.durations.iter().flatten().sum()
+    }
+}
+
+// Percentiles of a histogram, in nanos
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct LatencySummary {
+    pub count: u64,
+    pub min: u64,
+    pub mean: f64,
+    pub p50: u64,
+    pub p90: u64,
+    pub p99: u64,
+    pub p999: u64,
+    pub max: u64,
+}
+
+impl LatencySummary {
+    pub fn from_histogram(histogram: &Histogram<u64>) -> Self {
+        LatencySummary {
+            count: histogram.len(),
+            min: histogram.min(),
+            mean: histogram.mean(),
+            p50: histogram.value_at_quantile(0.5),
+            p90: histogram.value_at_quantile(0.9),
+            p99: histogram.value_at_quantile(0.99),
+            p999: histogram.value_at_quantile(0.999),
+            max: histogram.max(),
+        }
+    }
+}
+
+// Latency histograms in nanos, per stage and message type, and of the trades from the exchange
+// timestamp to the receive time
+pub struct LatencyStats {
+    stages: Vec<Histogram<u64>>,
+    exchange: Histogram<u64>,
+    // trades received before their exchange timestamp, from clock skew between the hosts
+    negative_exchange: u64,
+}
+
+impl Default for LatencyStats {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl LatencyStats {
+    pub fn new() -> Self {
+        let histogram = || {
+            Histogram::new_with_bounds(1, MAX_LATENCY_NANOS, SIGNIFICANT_DIGITS)
+                .expect("valid histogram bounds")
+        };
+        LatencyStats {
+            stages: (0..STAGE_COUNT * MESSAGE_TYPE_COUNT)
+                .map(|_| histogram())
+                .collect(),
+            exchange: histogram(),
+            negative_exchange: 0,
+        }
+    }
+
+    // record the durations of the stages ended for a message
+    pub fn record(&mut self, message_type: MessageType, times: &StageTimes) {
+        for stage in Stage::ALL.iter() {
+            if let Some(nanos) = times.duration(*stage) {
+                self.record_stage(*stage, message_type, nanos);
+            }
+        }
+    }
+
+    pub fn record_stage(&mut self, stage: Stage, message_type: MessageType, nanos: u64) {
+        let index = stage as usize * MESSAGE_TYPE_COUNT + message_type as usize;
+        self.stages[index].saturating_record(nanos);
+    }
+
+    // exchange to local latency of a trade, both times in nanos since unix epoch
+    pub fn record_exchange_latency(&mut self, exchange_time: i64, receive_time: i64) {
+        let nanos = receive_time.saturating_sub(exchange_time);
+        if nanos < 0 {
+            self.negative_exchange += 1;
+        } else {
+            self.exchange.saturating_record(nanos as u64);
+        }
+    }
+
+    // exchange to local latency of the trades of a message, trades with an invalid timestamp are
+    // skipped
+    pub fn record_trades(&mut self, trades: &[TradeEntry], receive_time: i64) {
+        for trade in trades.iter() {
+            if let Some(exchange_time) = trade.timestamp_nanos() {
+                self.record_exchange_latency(exchange_time, receive_time);
+            }
+        }
+    }
+
+    pub fn stage(&self, stage: Stage, message_type: MessageType) -> &Histogram<u64> {
+        &self.stages[stage as usize * MESSAGE_TYPE_COUNT + message_type as usize]
+    }
+
+    pub fn stage_summary(&self, stage: Stage, message_type: MessageType) -> LatencySummary {
+        LatencySummary::from_histogram(self.stage(stage, message_type))
+    }
+
+    pub fn exchange_latency(&self) -> &Histogram<u64> {
+        &self.exchange
+    }
+
+    pub fn exchange_summary(&self) -> LatencySummary {
+        LatencySummary::from_histogram(&self.exchange)
+    }
+
+    pub fn negative_exchange_latency_count(&self) -> u64 {
+        self.negative_exchange
+    }
+
+    pub fn reset(&mut self) {
+        for histogram in self.stages.iter_mut() {
+            histogram.reset();
+        }
+        self.exchange.reset();
+        self.negative_exchange = 0;
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::bitmex_md_handler::BitmexMdHandler;
+    use crate::bitmex_message::{parse, parse_timestamp, BitmexMessage};
+    use crate::fixtures::{TRADE, UPDATE};
+    use crate::instrument::SymbolRegistry;
+    use crate::latency::{Clock, LatencyStats, MessageType, Stage, StageTimes};
+    use crate::normalized::MarketDataSource;
+    use crate::parser::Parser;
+    use std::time::Duration;
+
+    #[test]
+    fn clocks() {
+        let clock = Clock::new();
+        let start = clock.now();
+        std::thread::sleep(Duration::from_millis(2));
+        assert!(clock.now() - start >= 2_000_000);
+
+        // the tsc follows the monotonic clock
+        if let Some(tsc) = Clock::tsc(Duration::from_millis(10)) {
+            assert!(tsc.is_tsc());
+            let (tsc_start, start) = (tsc.now(), clock.now());
+            std::thread::sleep(Duration::from_millis(200));
+            let (tsc_elapsed, elapsed) = (tsc.now() - tsc_start, clock.now() - start);
+            // loose bound, the calibration is short and the thread can be preempted between the
+            // reads of the two clocks
+            assert!((tsc_elapsed as f64 - elapsed as f64).abs() < 0.25 * elapsed as f64);
+        }
+    }
+
+    #[test]
+    fn stage_histograms() {
+        let mut stats = LatencyStats::new();
+        let mut parser = Parser::new();
+        let mut symbols = SymbolRegistry::new();
+        let mut times = StageTimes::new(1_000);
+        times.end(Stage::SocketRead, 3_000);
+        times.end(Stage::FrameAssembly, 3_100);
+        let message_type = MessageType::from_ref(&parser.parse(UPDATE, &mut symbols).unwrap());
+        assert_eq!(message_type, MessageType::Update);
+        times.end(Stage::Parse, 3_600);
+        times.end(Stage::BookApply, 3_800);
+        times.end(Stage::Dispatch, 3_850);
+        assert_eq!(times.total(), 2_850);
+        stats.record(message_type, &times);
+
+        // second message of the same read, without a socket read
+        let mut times = StageTimes::new(3_850);
+        times.end(Stage::FrameAssembly, 3_900);
+        times.end(Stage::Parse, 4_900);
+        stats.record(message_type, &times);
+
+        assert_eq!(stats.stage(Stage::SocketRead, MessageType::Update).len(), 1);
+        assert_eq!(stats.stage(Stage::Dispatch, MessageType::Update).len(), 1);
+        assert_eq!(stats.stage(Stage::Parse, MessageType::Trade).len(), 0);
+        let parse = stats.stage_summary(Stage::Parse, MessageType::Update);
+        assert_eq!(parse.count, 2);
+        assert_eq!(parse.min, 500);
+        // values are kept with 2 significant digits
+        assert!(parse.max >= 1_000 && parse.max < 1_010);
+
+        stats.record_stage(Stage::Parse, MessageType::Other, u64::MAX);
+        assert!(stats.stage(Stage::Parse, MessageType::Other).max() >= 60_000_000_000);
+
+        stats.reset();
+        assert_eq!(
+            stats.stage_summary(Stage::Parse, MessageType::Update).count,
+            0
+        );
+    }
+
+    #[test]
+    fn exchange_latency() {
+        let mut stats = LatencyStats::new();
+        let message = parse(TRADE).ok().unwrap();
+        assert_eq!(MessageType::from_message(&message), MessageType::Trade);
+        let trade = match message {
+            BitmexMessage::Trade(trade) => trade,
+            _ => panic!("wrong message type"),
+        };
+        let exchange_time = trade.data[0].timestamp_nanos().unwrap();
+        stats.record_trades(&trade.data, exchange_time + 2_500_000);
+        stats.record_trades(&trade.data, exchange_time - 1_000_000);
+        let summary = stats.exchange_summary();
+        assert_eq!(summary.count, 1);
+        assert!(summary.p50 >= 2_490_000 && summary.p50 <= 2_510_000);
+        assert_eq!(stats.negative_exchange_latency_count(), 1);
+        stats.reset();
+        assert_eq!(stats.exchange_summary().count, 0);
+    }
+
+    #[test]
+    fn handler_latency() {
+        let clock = Clock::new();
+        let mut handler = BitmexMdHandler::new();
+        handler.set_latency_clock(clock);
+        handler.add_book("XBTUSD");
+        // the socket read and frame assembly are timed by the caller
+        let mut times = StageTimes::new(clock.now());
+        times.end(Stage::SocketRead, clock.now());
+        times.end(Stage::FrameAssembly, clock.now());
+        handler.set_stage_times(times);
+        handler.on_payload(0, UPDATE, &mut |_| {}).unwrap();
+        let exchange_time = parse_timestamp("2020-07-19T19:43:21.401Z").unwrap();
+        handler
+            .on_payload(exchange_time + 1_000_000, TRADE, &mut |_| {})
+            .unwrap();
+        assert!(handler.on_payload(0, b"{", &mut |_| {}).is_err());
+
+        let stats = handler.latency_stats().unwrap();
+        for message_type in [MessageType::Update, MessageType::Trade].iter() {
+            assert_eq!(stats.stage(Stage::Parse, *message_type).len(), 1);
+            assert_eq!(stats.stage(Stage::BookApply, *message_type).len(), 1);
+            assert_eq!(stats.stage(Stage::Dispatch, *message_type).len(), 1);
+        }
+        assert_eq!(stats.stage(Stage::SocketRead, MessageType::Update).len(), 1);
+        assert_eq!(
+            stats.stage(Stage::FrameAssembly, MessageType::Update).len(),
+            1
+        );
+        assert_eq!(stats.stage(Stage::SocketRead, MessageType::Trade).len(), 0);
+        assert_eq!(stats.stage(Stage::Parse, MessageType::Other).len(), 1);
+        assert_eq!(stats.stage(Stage::Dispatch, MessageType::Other).len(), 0);
+        assert_eq!(stats.exchange_summary().count, 1);
+    }
+}
@@ -1,2 +1,27 @@
 pub mod bitmex_md_handler;
 pub mod bitmex_message;
+pub mod book_view;
+#[cfg(feature = "columnar")]
+pub mod columnar;
+pub mod event_codec;
+pub mod export;
+pub mod fanout_server;
+#[cfg(test)]
+mod fixtures;
+pub mod instrument;
+mod json_scanner;
+pub mod l2_decoder;
+#[cfg(feature = "latency")]
+pub mod latency;
+pub mod metrics;
+pub mod mock_server;
+pub mod multicast;
+pub mod normalized;
+pub mod order_book;
+pub mod parser;
+pub mod pcap;
+pub mod recorder;
+pub mod replayer;
+pub mod shm_ring;
+pub mod table_store;
+pub mod ws_server;
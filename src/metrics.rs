@@ -0,0 +1,569 @@
+use crate::bitmex_message::{BitmexMessage, ParseError};
+use crate::order_book::BookEvent;
+use crate::parser::BitmexMessageRef;
+use std::collections::BTreeMap;
+use std::fmt::Write as _;
+use std::io::{self, Read, Write};
+use std::net::{SocketAddr, TcpListener, TcpStream, ToSocketAddrs};
+use std::sync::atomic::{AtomicBool, AtomicI64, AtomicU64, Ordering};
+use std::sync::{Arc, Mutex};
+use std::thread::{self, JoinHandle};
+use std::time::Duration;
+
+// counted message kinds as (table, action), info and subscribe messages have no table
+const MESSAGE_LABELS: [(&str, &str); 8] = [
+    ("orderBookL2", "partial"),
+    ("orderBookL2", "insert"),
+    ("orderBookL2", "update"),
+    ("orderBookL2", "delete"),
+    ("trade", "partial"),
+    ("trade", "insert"),
+    ("", "info"),
+    ("", "subscribe"),
+];
+const PARSE_ERROR_LABELS: [&str; 3] = ["invalid", "invalid_action", "invalid_table"];
+
+// the rate limit is unknown until the first info message
+const UNKNOWN_LIMIT: i64 = i64::MIN;
+
+// max length of the request line and headers of a metrics request
+const MAX_HTTP_REQUEST_LEN: usize = 8192;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum SubscriptionState {
+    // subscribe request sent, not acknowledged yet
+    Requested,
+    Subscribed,
+}
+
+impl SubscriptionState {
+    const ALL: [SubscriptionState; 2] =
+        [SubscriptionState::Requested, SubscriptionState::Subscribed];
+
+    fn name(&self) -> &'static str {
+        match self {
+            SubscriptionState::Requested => "requested",
+            SubscriptionState::Subscribed => "subscribed",
+        }
+    }
+}
+
+// Counters and gauges of the feed handler. The metrics are updated through a shared reference so
+// that they can be fed from the receive loop and rendered from another thread (see
+// MetricsServer), e.g. with the BitmexMdHandler given an Arc<FeedMetrics> with set_metrics.
+pub struct FeedMetrics {
+    messages: [AtomicU64; MESSAGE_LABELS.len()],
+    parse_errors: [AtomicU64; PARSE_ERROR_LABELS.len()],
+    connections: AtomicU64,
+    reconnects: AtomicU64,
+    book_resets: AtomicU64,
+    book_violations: AtomicU64,
+    limit_remaining: AtomicI64,
+    subscriptions: Mutex<BTreeMap<String, SubscriptionState>>,
+}
+
+impl Default for FeedMetrics {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl FeedMetrics {
+    pub fn new() -> Self {
+        FeedMetrics {
+            messages: Default::default(),
+            parse_errors: Default::default(),
+            connections: AtomicU64::new(0),
+            reconnects: AtomicU64::new(0),
+            book_resets: AtomicU64::new(0),
+            book_violations: AtomicU64::new(0),
+            limit_remaining: AtomicI64::new(UNKNOWN_LIMIT),
+            subscriptions: Mutex::new(BTreeMap::new()),
+        }
+    }
+
+    // count a parsed message, the rate limit and subscription acks are taken from the message
+    pub fn on_message(&self, message: &BitmexMessage) {
+        let index = match message {
+            BitmexMessage::Snapshot(_) => 0,
+            BitmexMessage::Insert(_) => 1,
+            BitmexMessage::Update(_) => 2,
+            BitmexMessage::Delete(_) => 3,
+            BitmexMessage::TradeSnapshot(_) => 4,
+            BitmexMessage::Trade(_) => 5,
+            BitmexMessage::Info(info) => {
+                self.limit_remaining
+                    .store(info.limit_remaining() as i64, Ordering::Relaxed);
+                6
+            }
+            BitmexMessage::Subscribe(subscribe) => {
+                if subscribe.success() {
+                    self.set_subscription_state(subscribe.topic(), SubscriptionState::Subscribed);
+                }
+                7
+            }
+        };
+        self.messages[index].fetch_add(1, Ordering::Relaxed);
+    }
+
+    // count a message given by the Parser
+    pub fn on_message_ref(&self, message: &BitmexMessageRef) {
+        let index = match message {
+            BitmexMessageRef::Insert(_) => 1,
+            BitmexMessageRef::Update(_) => 2,
+            BitmexMessageRef::Delete(_) => 3,
+            BitmexMessageRef::Trade(_) => 5,
+            BitmexMessageRef::Message(message) => return self.on_message(message),
+        };
+        self.messages[index].fetch_add(1, Ordering::Relaxed);
+    }
+
+    pub fn on_parse_error(&self, error: &ParseError) {
+        let index = match error {
+            ParseError::Invalid => 0,
+            ParseError::InvalidAction => 1,
+            ParseError::InvalidTable => 2,
+        };
+        self.parse_errors[index].fetch_add(1, Ordering::Relaxed);
+    }
+
+    // count a connection to the exchange, every connection after the first is a reconnect
+    pub fn on_connect(&self) {
+        if self.connections.fetch_add(1, Ordering::Relaxed) > 0 {
+            self.reconnects.fetch_add(1, Ordering::Relaxed);
+        }
+    }
+
+    // count the book resets (partials applied) and integrity violations of an order book
+    pub fn on_book_event(&self, event: &BookEvent) {
+        match event {
+            BookEvent::Snapshot => {
+                self.book_resets.fetch_add(1, Ordering::Relaxed);
+            }
+            BookEvent::Violation(_) => {
+                self.book_violations.fetch_add(1, Ordering::Relaxed);
+            }
+            _ => {}
+        }
+    }
+
+    pub fn set_subscription_state(&self, topic: &str, state: SubscriptionState) {
+        let mut subscriptions = self.subscriptions.lock().unwrap();
+        match subscriptions.get_mut(topic) {
+            Some(current) => *current = state,
+            None => {
+                subscriptions.insert(String::from(topic), state);
+            }
+        }
+    }
+
+    // forget a topic that was unsubscribed from
+    pub fn remove_subscription(&self, topic: &str) {
+        self.subscriptions.lock().unwrap().remove(topic);
+    }
+
+    pub fn subscription_state(&self, topic: &str) -> Option<SubscriptionState> {
+        self.subscriptions.lock().unwrap().get(topic).copied()
+    }
+
+    // messages counted for a table and action, e.g. orderBookL2 and update
+    pub fn messages(&self, table: &str, action: &str) -> u64 {
+        MESSAGE_LABELS
+            .iter()
+            .position(|labels| *labels == (table, action))
+            .map_or(0, |index| self.messages[index].load(Ordering::Relaxed))
+    }
+
+    pub fn parse_errors(&self) -> u64 {
+        self.parse_errors
+            .iter()
+            .map(|count| count.load(Ordering::Relaxed))
+            .sum()
+    }
+
+    pub fn reconnects(&self) -> u64 {
+        self.reconnects.load(Ordering::Relaxed)
+    }
+
+    pub fn book_resets(&self) -> u64 {
+        self.book_resets.load(Ordering::Relaxed)
+    }
+
+    pub fn book_violations(&self) -> u64 {
+        self.book_violations.load(Ordering::Relaxed)
+    }
+
+    // requests left in the rate limit window, from the last info message
+    pub fn limit_remaining(&self) -> Option<i64> {
+        match self.limit_remaining.load(Ordering::Relaxed) {
+            UNKNOWN_LIMIT => None,
+            remaining => Some(remaining),
+        }
+    }
+
+    // metrics in the Prometheus text exposition format
+    pub fn render(&self) -> String {
+        let mut out = String::new();
+        header(
+            &mut out,
+            "bitmex_messages_total",
+            "counter",
+            "Messages received by table and action",
+        );
+        for (i, (table, action)) in MESSAGE_LABELS.iter().enumerate() {
+            let _ = writeln!(
+                out,
+                "bitmex_messages_total{{table=\"{}\",action=\"{}\"}} {}",
+                table,
+                action,
+                self.messages[i].load(Ordering::Relaxed)
+            );
+        }
+        header(
+            &mut out,
+            "bitmex_parse_errors_total",
+            "counter",
+            "Messages that could not be parsed by error kind",
+        );
+        for (i, kind) in PARSE_ERROR_LABELS.iter().enumerate() {
+            let _ = writeln!(
+                out,
+                "bitmex_parse_errors_total{{kind=\"{}\"}} {}",
+                kind,
+                self.parse_errors[i].load(Ordering::Relaxed)
+            );
+        }
+        counter(
+            &mut out,
+            "bitmex_connections_total",
+            "Connections to the exchange",
+            &self.connections,
+        );
+        counter(
+            &mut out,
+            "bitmex_reconnects_total",
+            "Connections after the first one",
+            &self.reconnects,
+        );
+        counter(
+            &mut out,
+            "bitmex_book_resets_total",
+            "Order books reset from a partial",
+            &self.book_resets,
+        );
+        counter(
+            &mut out,
+            "bitmex_book_violations_total",
+            "Order book integrity violations",
+            &self.book_violations,
+        );
+        if let Some(remaining) = self.limit_remaining() {
+            header(
+                &mut out,
+                "bitmex_rate_limit_remaining",
+                "gauge",
+                "Requests left in the rate limit window",
+            );
+            let _ = writeln!(out, "bitmex_rate_limit_remaining {}", remaining);
+        }
+        header(
+            &mut out,
+            "bitmex_subscription_state",
+            "gauge",
+            "State of the subscriptions by topic, 1 for the current state",
+        );
+        for (topic, current) in self.subscriptions.lock().unwrap().iter() {
+            for state in SubscriptionState::ALL.iter() {
+                let _ = writeln!(
+                    out,
+                    "bitmex_subscription_state{{topic=\"{}\",state=\"{}\"}} {}",
+                    escape_label(topic),
+                    state.name(),
+                    (state == current) as u8
+                );
+            }
+        }
+        out
+    }
+}
+
+fn header(out: &mut String, name: &str, metric_type: &str, help: &str) {
+    let _ = writeln!(out, "# HELP {} {}", name, help);
+    let _ = writeln!(out, "# TYPE {} {}", name, metric_type);
+}
+
+fn counter(out: &mut String, name: &str, help: &str, value: &AtomicU64) {
+    header(out, name, "counter", help);
+    let _ = writeln!(out, "{} {}", name, value.load(Ordering::Relaxed));
+}
+
+// label values escape backslash, double quote and line feed
+fn escape_label(value: &str) -> String {
+    value
+        .replace('\\', "\\\\")
+        .replace('"', "\\\"")
+        .replace('\n', "\\n")
+}
+
+// Small HTTP server answering GET /metrics with the rendered metrics, one request per connection.
+// The server is stopped when dropped.
+pub struct MetricsServer {
+    address: SocketAddr,
+    stopped: Arc<AtomicBool>,
+    acceptor: Option<JoinHandle<()>>,
+}
+
+impl MetricsServer {
+    pub fn start<A: ToSocketAddrs>(address: A, metrics: Arc<FeedMetrics>) -> io::Result<Self> {
+        let listener = TcpListener::bind(address)?;
+        let address = listener.local_addr()?;
+        let stopped = Arc::new(AtomicBool::new(false));
+
+        let acceptor = {
+            let stopped = stopped.clone();
+            thread::spawn(move || {
+                for stream in listener.incoming() {
+                    if stopped.load(Ordering::SeqCst) {
+                        break;
+                    }
+                    if let Ok(stream) = stream {
+                        let _ = serve(stream, &metrics);
+                    }
+                }
+            })
+        };
+        Ok(MetricsServer {
+            address,
+            stopped,
+            acceptor: Some(acceptor),
+        })
+    }
+
+    pub fn address(&self) -> SocketAddr {
+        self.address
+    }
+}
+
+impl Drop for MetricsServer {
+    fn drop(&mut self) {
+        self.stopped.store(true, Ordering::SeqCst);
+        // wake up the acceptor blocked in accept
+        let _ = TcpStream::connect(self.address);
+        if let Some(acceptor) = self.acceptor.take() {
+            let _ = acceptor.join();
+        }
+    }
+}
+
+fn serve(mut stream: TcpStream, metrics: &FeedMetrics) -> io::Result<()> {
+    // a client that does not send its request does not hold the server
+    stream.set_read_timeout(Some(Duration::from_secs(5)))?;
+    let mut buf = vec![];
+    let mut chunk = [0u8; 1024];
+    while !buf.windows(4).any(|w| w == b"\r\n\r\n") {
+        if buf.len() > MAX_HTTP_REQUEST_LEN {
+            return respond(&mut stream, "431 Request Header Fields Too Large", "");
+        }
+        match stream.read(&mut chunk)? {
+            0 => return Ok(()),
+            n => buf.extend_from_slice(&chunk[..n]),
+        }
+    }
+    let request = String::from_utf8_lossy(&buf);
+    let mut request_line = request.lines().next().unwrap_or("").split(' ');
+    match (request_line.next(), request_line.next()) {
+        (Some("GET"), Some("/metrics")) => respond(&mut stream, "200 OK", &metrics.render()),
+        (Some("GET"), _) => respond(&mut stream, "404 Not Found", ""),
+        _ => respond(&mut stream, "405 Method Not Allowed", ""),
+    }
+}
+
+fn respond(stream: &mut TcpStream, status: &str, body: &str) -> io::Result<()> {
+    let response = format!(
+        "HTTP/1.1 {}\r\nContent-Type: text/plain; version=0.0.4\r\nContent-Length: {}\r\nConnection: close\r\n\r\n{}",
+        status,
+        body.len(),
+        body
+    );
+    stream.write_all(response.as_bytes())?;
+    stream.flush()
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::bitmex_md_handler::BitmexMdHandler;
+    use crate::bitmex_message::{parse, parse_multiplexed, ParseError};
+    use crate::fixtures::{INFO, SUBSCRIBE, TOP_SNAPSHOT, TRADE, UPDATE};
+    use crate::instrument::SymbolRegistry;
+    use crate::metrics::{FeedMetrics, MetricsServer, SubscriptionState};
+    use crate::normalized::MarketDataSource;
+    use crate::order_book::{BookEvent, IntegrityViolation};
+    use crate::parser::Parser;
+    use std::io::{Read, Write};
+    use std::net::TcpStream;
+    use std::sync::Arc;
+
+    fn feed(metrics: &FeedMetrics) {
+        metrics.on_connect();
+        metrics.set_subscription_state("orderBookL2:XBTUSD", SubscriptionState::Requested);
+        for message in [INFO, SUBSCRIBE, UPDATE].iter() {
+            metrics.on_message(&parse(message).ok().unwrap());
+        }
+        let mut parser = Parser::new();
+        let mut symbols = SymbolRegistry::new();
+        metrics.on_message_ref(&parser.parse(UPDATE, &mut symbols).unwrap());
+        metrics.on_message_ref(&parser.parse(INFO, &mut symbols).unwrap());
+        metrics.on_parse_error(&ParseError::InvalidTable);
+        metrics.on_book_event(&BookEvent::Snapshot);
+        metrics.on_book_event(&BookEvent::Violation(IntegrityViolation::UnknownUpdate {
+            id: 1,
+        }));
+        metrics.on_connect();
+    }
+
+    #[test]
+    fn counters_and_gauges() {
+        let metrics = FeedMetrics::new();
+        assert_eq!(metrics.limit_remaining(), None);
+        feed(&metrics);
+        assert_eq!(metrics.messages("orderBookL2", "update"), 2);
+        assert_eq!(metrics.messages("", "info"), 2);
+        assert_eq!(metrics.messages("trade", "insert"), 0);
+        assert_eq!(metrics.parse_errors(), 1);
+        assert_eq!(metrics.reconnects(), 1);
+        assert_eq!(metrics.book_resets(), 1);
+        assert_eq!(metrics.book_violations(), 1);
+        assert_eq!(metrics.limit_remaining(), Some(39));
+        assert_eq!(
+            metrics.subscription_state("orderBookL2:XBTUSD"),
+            Some(SubscriptionState::Subscribed)
+        );
+
+        let text = metrics.render();
+        for line in [
+            "# TYPE bitmex_messages_total counter",
+            "bitmex_messages_total{table=\"orderBookL2\",action=\"update\"} 2",
+            "bitmex_messages_total{table=\"\",action=\"subscribe\"} 1",
+            "bitmex_parse_errors_total{kind=\"invalid_table\"} 1",
+            "bitmex_parse_errors_total{kind=\"invalid\"} 0",
+            "bitmex_connections_total 2",
+            "bitmex_reconnects_total 1",
+            "bitmex_book_resets_total 1",
+            "bitmex_book_violations_total 1",
+            "# TYPE bitmex_rate_limit_remaining gauge",
+            "bitmex_rate_limit_remaining 39",
+            "bitmex_subscription_state{topic=\"orderBookL2:XBTUSD\",state=\"subscribed\"} 1",
+            "bitmex_subscription_state{topic=\"orderBookL2:XBTUSD\",state=\"requested\"} 0",
+        ]
+        .iter()
+        {
+            assert!(text.lines().any(|l| l == *line), "missing {}", line);
+        }
+    }
+
+    #[test]
+    fn handler_metrics() {
+        let metrics = Arc::new(FeedMetrics::new());
+        let mut handler = BitmexMdHandler::new();
+        handler.set_metrics(metrics.clone());
+        handler.add_symbol("XBTUSD");
+        let request = handler.get_subscription_request();
+        assert_eq!(metrics.subscription_state("trade:XBTUSD"), None);
+        handler.on_request_sent(&request);
+        assert_eq!(
+            metrics.subscription_state("trade:XBTUSD"),
+            Some(SubscriptionState::Requested)
+        );
+
+        let subscribe = b"{\"success\":true,\"subscribe\":\"trade:XBTUSD\",\"request\":{\"op\":\"subscribe\",\"args\":[\"trade:XBTUSD\"]}}";
+        let invalid = b"{\"table\":\"quote\",\"action\":\"insert\",\"data\":[]}";
+        for payload in [INFO, &subscribe[..], TRADE, &invalid[..]].iter() {
+            let _ = handler.on_payload(0, payload, &mut |_| {});
+        }
+        assert_eq!(metrics.limit_remaining(), Some(39));
+        assert_eq!(
+            metrics.subscription_state("trade:XBTUSD"),
+            Some(SubscriptionState::Subscribed)
+        );
+        assert_eq!(metrics.messages("trade", "insert"), 1);
+        assert_eq!(metrics.parse_errors(), 1);
+
+        let requests = handler.get_resubscribe_requests("trade:XBTUSD");
+        handler.on_request_sent(&requests[0]);
+        assert_eq!(metrics.subscription_state("trade:XBTUSD"), None);
+        handler.on_request_sent(&requests[1]);
+        assert_eq!(
+            metrics.subscription_state("trade:XBTUSD"),
+            Some(SubscriptionState::Requested)
+        );
+
+        // the topics of a stream are forgotten once it is closed
+        handler.add_stream("stream-1", "md");
+        handler.add_stream_symbol("stream-1", "ETHUSD");
+        handler.on_request_sent(&handler.get_stream_subscription_request("stream-1").unwrap());
+        assert_eq!(
+            metrics.subscription_state("trade:ETHUSD"),
+            Some(SubscriptionState::Requested)
+        );
+        let subscribed = b"[0,\"stream-1\",\"md\",{\"success\":true,\"subscribe\":\"trade:ETHUSD\",\"request\":{\"op\":\"subscribe\",\"args\":[\"trade:ETHUSD\"]}}]";
+        handler.on_stream_message(&parse_multiplexed(subscribed).unwrap());
+        assert_eq!(
+            metrics.subscription_state("trade:ETHUSD"),
+            Some(SubscriptionState::Subscribed)
+        );
+        handler.on_request_sent(&handler.get_close_stream_request("stream-1").unwrap());
+        assert_eq!(metrics.subscription_state("trade:ETHUSD"), None);
+        assert!(metrics.subscription_state("trade:XBTUSD").is_some());
+    }
+
+    #[test]
+    fn handler_book_events() {
+        let metrics = Arc::new(FeedMetrics::new());
+        let mut handler = BitmexMdHandler::new();
+        handler.set_metrics(metrics.clone());
+        handler.add_book("XBTUSD");
+        assert!(handler
+            .get_subscription_request()
+            .contains("\"orderBookL2:XBTUSD\""));
+
+        // the update of an unknown level is an integrity violation of the book
+        for payload in [TOP_SNAPSHOT, UPDATE].iter() {
+            let _ = handler.on_payload(0, payload, &mut |_| {});
+        }
+        assert_eq!(metrics.book_resets(), 1);
+        assert_eq!(metrics.book_violations(), 1);
+        assert_eq!(
+            handler.book("XBTUSD").unwrap().best_bid().unwrap().size,
+            1023444
+        );
+    }
+
+    fn get(server: &MetricsServer, path: &str) -> String {
+        let mut stream = TcpStream::connect(server.address()).unwrap();
+        write!(stream, "GET {} HTTP/1.1\r\nHost: localhost\r\n\r\n", path).unwrap();
+        let mut response = String::new();
+        stream.read_to_string(&mut response).unwrap();
+        response
+    }
+
+    #[test]
+    fn metrics_endpoint() {
+        let metrics = Arc::new(FeedMetrics::new());
+        let server = MetricsServer::start("127.0.0.1:0", metrics.clone()).unwrap();
+        feed(&metrics);
+
+        let response = get(&server, "/metrics");
+        assert!(response.starts_with("HTTP/1.1 200 OK\r\n"));
+        assert!(response.contains("Content-Type: text/plain; version=0.0.4\r\n"));
+        let (_, body) = response.split_once("\r\n\r\n").unwrap();
+        assert_eq!(body, metrics.render());
+
+        assert!(get(&server, "/").starts_with("HTTP/1.1 404 Not Found\r\n"));
+        let mut stream = TcpStream::connect(server.address()).unwrap();
+        stream.write_all(b"POST /metrics HTTP/1.1\r\n\r\n").unwrap();
+        let mut response = String::new();
+        stream.read_to_string(&mut response).unwrap();
+        assert!(response.starts_with("HTTP/1.1 405 Method Not Allowed\r\n"));
+        drop(server);
+    }
+}
@@ -0,0 +1,418 @@
+use crate::bitmex_message::MarketDataSubscriptionRequest;
+use crate::ws_server::{
+    read_client_frame, upgrade_response, welcome_message, write_frame, CLOSE_OP_CODE, PING_OP_CODE,
+    PONG_OP_CODE, TEXT_OP_CODE,
+};
+use std::io::{self, Read, Write};
+use std::net::{Shutdown, SocketAddr, TcpListener, TcpStream};
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::{Arc, Mutex};
+use std::thread::{self, JoinHandle};
+use std::time::Duration;
+
+#[derive(Debug, Clone, PartialEq)]
+pub enum MockStep {
+    // text frame
+    Send(String),
+    // bytes written as is on the connection
+    SendRaw(Vec<u8>),
+    Delay(Duration),
+    // close the tcp connection without a close frame
+    Drop,
+}
+
+// Steps played on a connection once the first subscribe request is acknowledged
+#[derive(Debug, Clone, Default)]
+pub struct MockScript {
+    steps: Vec<MockStep>,
+}
+
+impl MockScript {
+    pub fn new() -> Self {
+        MockScript { steps: vec![] }
+    }
+
+    pub fn steps(&self) -> &[MockStep] {
+        &self.steps
+    }
+
+    // send a message, e.g. a partial, insert, update, delete or trade table message
+    pub fn add_message(&mut self, message: &str) {
+        self.steps.push(MockStep::Send(String::from(message)));
+    }
+
+    pub fn add_raw(&mut self, bytes: &[u8]) {
+        self.steps.push(MockStep::SendRaw(bytes.to_vec()));
+    }
+
+    pub fn add_delay(&mut self, delay: Duration) {
+        self.steps.push(MockStep::Delay(delay));
+    }
+
+    pub fn add_drop(&mut self) {
+        self.steps.push(MockStep::Drop);
+    }
+
+    // frame with the reserved bits set and a reserved op code
+    pub fn add_malformed_frame(&mut self) {
+        self.add_raw(&[0xf3, 0x03, b'b', b'a', b'd']);
+    }
+
+    // text frame with the first half of a message only
+    pub fn add_truncated_message(&mut self, message: &str) {
+        let mut frame = vec![];
+        write_frame(
+            &mut frame,
+            TEXT_OP_CODE,
+            &message.as_bytes()[..message.len() / 2],
+        );
+        self.add_raw(&frame);
+    }
+}
+
+#[derive(Default)]
+struct MockState {
+    connections: usize,
+    requests: Vec<String>,
+}
+
+// Local stand-in for the BitMEX realtime endpoint, for integration tests. Every connection does
+// the websocket handshake, gets the info welcome message and a success ack for each subscribe
+// and unsubscribe topic. The script of the connection is played after the first subscribe ack:
+// the n-th connection plays the n-th script, the last script is used for the connections beyond.
+pub struct MockServer {
+    address: SocketAddr,
+    state: Arc<Mutex<MockState>>,
+    stopped: Arc<AtomicBool>,
+    acceptor: Option<JoinHandle<()>>,
+}
+
+impl MockServer {
+    pub fn start(scripts: Vec<MockScript>) -> io::Result<Self> {
+        let listener = TcpListener::bind("127.0.0.1:0")?;
+        let address = listener.local_addr()?;
+        let state = Arc::new(Mutex::new(MockState::default()));
+        let stopped = Arc::new(AtomicBool::new(false));
+
+        let acceptor = {
+            let state = state.clone();
+            let stopped = stopped.clone();
+            thread::spawn(move || {
+                for stream in listener.incoming() {
+                    if stopped.load(Ordering::SeqCst) {
+                        break;
+                    }
+                    let stream = match stream {
+                        Ok(stream) => stream,
+                        Err(_) => continue,
+                    };
+                    let connection = {
+                        let mut state = state.lock().unwrap();
+                        state.connections += 1;
+                        state.connections
+                    };
+                    let script = scripts
+                        .get(connection - 1)
+                        .or_else(|| scripts.last())
+                        .cloned()
+                        .unwrap_or_default();
+                    let state = state.clone();
+                    thread::spawn(move || {
+                        let _ = serve(stream, &script, &state);
+                    });
+                }
+            })
+        };
+
+        Ok(MockServer {
+            address,
+            state,
+            stopped,
+            acceptor: Some(acceptor),
+        })
+    }
+
+    pub fn address(&self) -> SocketAddr {
+        self.address
+    }
+
+    // host to give to the handshake, e.g. 127.0.0.1:9000
+    pub fn host(&self) -> String {
+        self.address.to_string()
+    }
+
+    // number of connections accepted so far
+    pub fn connections(&self) -> usize {
+        self.state.lock().unwrap().connections
+    }
+
+    // text messages received from the clients, in the order they were received
+    pub fn requests(&self) -> Vec<String> {
+        self.state.lock().unwrap().requests.clone()
+    }
+}
+
+impl Drop for MockServer {
+    fn drop(&mut self) {
+        self.stopped.store(true, Ordering::SeqCst);
+        // wake up the acceptor blocked in accept
+        let _ = TcpStream::connect(self.address);
+        if let Some(acceptor) = self.acceptor.take() {
+            let _ = acceptor.join();
+        }
+    }
+}
+
+// serve a connection until the client closes it or the script drops it
+fn serve(mut stream: TcpStream, script: &MockScript, state: &Mutex<MockState>) -> io::Result<()> {
+    let mut in_buf = vec![];
+    let mut buf = [0u8; 4096];
+    let (response, len) = loop {
+        if let Some(upgrade) = upgrade_response(&in_buf)? {
+            break upgrade;
+        }
+        let n = stream.read(&mut buf)?;
+        if n == 0 {
+            return Ok(());
+        }
+        in_buf.extend_from_slice(&buf[..n]);
+    };
+    in_buf.drain(..len);
+    stream.write_all(response.as_bytes())?;
+    send_text(&mut stream, welcome_message().as_bytes())?;
+
+    let mut script_played = false;
+    loop {
+        while let Some((frame, len)) = read_client_frame(&in_buf)? {
+            in_buf.drain(..len);
+            match frame.op_code {
+                TEXT_OP_CODE => {
+                    let text = String::from_utf8_lossy(&frame.payload).into_owned();
+                    state.lock().unwrap().requests.push(text.clone());
+                    if text == "ping" {
+                        send_text(&mut stream, b"pong")?;
+                        continue;
+                    }
+                    let subscribed = acknowledge(&mut stream, &text)?;
+                    if subscribed && !script_played {
+                        script_played = true;
+                        if !play(&mut stream, script)? {
+                            return Ok(());
+                        }
+                    }
+                }
+                PING_OP_CODE => {
+                    let mut pong = vec![];
+                    write_frame(&mut pong, PONG_OP_CODE, &frame.payload);
+                    stream.write_all(&pong)?;
+                }
+                CLOSE_OP_CODE => {
+                    let mut close = vec![];
+                    write_frame(&mut close, CLOSE_OP_CODE, &frame.payload);
+                    stream.write_all(&close)?;
+                    return Ok(());
+                }
+                _ => {}
+            }
+        }
+        let n = stream.read(&mut buf)?;
+        if n == 0 {
+            return Ok(());
+        }
+        in_buf.extend_from_slice(&buf[..n]);
+    }
+}
+
+fn send_text(stream: &mut TcpStream, text: &[u8]) -> io::Result<()> {
+    let mut frame = vec![];
+    write_frame(&mut frame, TEXT_OP_CODE, text);
+    stream.write_all(&frame)
+}
+
+// acknowledge a subscribe or unsubscribe request, returns true for a subscribe
+fn acknowledge(stream: &mut TcpStream, text: &str) -> io::Result<bool> {
+    let request: MarketDataSubscriptionRequest = match serde_json::from_str(text) {
+        Ok(request) => request,
+        Err(_) => {
+            send_text(
+                stream,
+                b"{\"status\":400,\"error\":\"Unable to parse request.\"}",
+            )?;
+            return Ok(false);
+        }
+    };
+    let request_json = serde_json::to_string(&request).unwrap_or_default();
+    for topic in request.args.iter() {
+        let ack = format!(
+            "{{\"success\":true,\"{}\":{},\"request\":{}}}",
+            request.op,
+            serde_json::Value::from(topic.as_str()),
+            request_json
+        );
+        send_text(stream, ack.as_bytes())?;
+    }
+    Ok(request.op == "subscribe")
+}
+
+// play the script, returns false if the connection was dropped
+fn play(stream: &mut TcpStream, script: &MockScript) -> io::Result<bool> {
+    for step in script.steps() {
+        match step {
+            MockStep::Send(message) => send_text(stream, message.as_bytes())?,
+            MockStep::SendRaw(bytes) => stream.write_all(bytes)?,
+            MockStep::Delay(delay) => thread::sleep(*delay),
+            MockStep::Drop => {
+                let _ = stream.shutdown(Shutdown::Both);
+                return Ok(false);
+            }
+        }
+    }
+    Ok(true)
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::bitmex_md_handler::BitmexMdHandler;
+    use crate::bitmex_message::{parse, BitmexMessage};
+    use crate::fixtures::{TOP_SNAPSHOT, TOP_UPDATE};
+    use crate::mock_server::{MockScript, MockServer};
+    use crate::order_book::OrderBook;
+    use std::io::{Read, Write};
+    use std::net::TcpStream;
+    use std::time::{Duration, Instant};
+
+    // test client: does the handshake and reads the server frames as (op code, payload)
+    struct Client {
+        stream: TcpStream,
+        buf: Vec<u8>,
+    }
+
+    impl Client {
+        fn connect(server: &MockServer) -> Client {
+            let mut stream = TcpStream::connect(server.address()).unwrap();
+            stream
+                .set_read_timeout(Some(Duration::from_secs(5)))
+                .unwrap();
+            write!(stream, "GET /realtime HTTP/1.1\r\nHost: {}\r\nUpgrade: websocket\r\nConnection: Upgrade\r\nSec-WebSocket-Key: dGhlIHNhbXBsZSBub25jZQ==\r\nSec-WebSocket-Version: 13\r\n\r\n", server.host()).unwrap();
+            let mut client = Client {
+                stream,
+                buf: vec![],
+            };
+            while !client.buf.windows(4).any(|w| w == b"\r\n\r\n") {
+                assert!(client.fill());
+            }
+            let end = client
+                .buf
+                .windows(4)
+                .position(|w| w == b"\r\n\r\n")
+                .unwrap()
+                + 4;
+            assert!(client.buf.starts_with(b"HTTP/1.1 101"));
+            client.buf.drain(..end);
+            client
+        }
+
+        // read more bytes, false at the end of the stream
+        fn fill(&mut self) -> bool {
+            let mut chunk = [0u8; 4096];
+            match self.stream.read(&mut chunk) {
+                Ok(0) | Err(_) => false,
+                Ok(n) => {
+                    self.buf.extend_from_slice(&chunk[..n]);
+                    true
+                }
+            }
+        }
+
+        fn send(&mut self, text: &str) {
+            let mask = [7u8, 1, 3, 5];
+            let mut frame = vec![0x81, 0x80 | text.len() as u8];
+            frame.extend_from_slice(&mask);
+            frame.extend(text.bytes().enumerate().map(|(i, b)| b ^ mask[i % 4]));
+            self.stream.write_all(&frame).unwrap();
+        }
+
+        // next frame, none at the end of the stream
+        fn read(&mut self) -> Option<(u8, Vec<u8>)> {
+            loop {
+                if self.buf.len() >= 2 {
+                    let (len, offset) = match self.buf[1] & 0x7f {
+                        126 if self.buf.len() >= 4 => {
+                            (u16::from_be_bytes([self.buf[2], self.buf[3]]) as usize, 4)
+                        }
+                        126 => (usize::MAX, 0),
+                        len => (len as usize, 2),
+                    };
+                    if len != usize::MAX && self.buf.len() >= offset + len {
+                        let op_code = self.buf[0];
+                        let payload = self.buf[offset..offset + len].to_vec();
+                        self.buf.drain(..offset + len);
+                        return Some((op_code, payload));
+                    }
+                }
+                if !self.fill() {
+                    return None;
+                }
+            }
+        }
+
+        fn read_message(&mut self) -> BitmexMessage {
+            let (_, payload) = self.read().unwrap();
+            match parse(&payload) {
+                Ok(message) => message,
+                Err(e) => panic!("{:?}: {}", e, String::from_utf8_lossy(&payload)),
+            }
+        }
+    }
+
+    #[test]
+    fn scripted_session_with_faults() {
+        let snapshot = std::str::from_utf8(TOP_SNAPSHOT).unwrap();
+        let update = std::str::from_utf8(TOP_UPDATE).unwrap();
+        let mut first = MockScript::new();
+        first.add_message(snapshot);
+        first.add_delay(Duration::from_millis(50));
+        first.add_message(update);
+        first.add_malformed_frame();
+        first.add_truncated_message(update);
+        first.add_drop();
+        let mut second = MockScript::new();
+        second.add_message(snapshot);
+        let server = MockServer::start(vec![first, second]).unwrap();
+
+        let mut handler = BitmexMdHandler::new();
+        handler.add_symbol("XBTUSD");
+        let mut client = Client::connect(&server);
+        assert!(matches!(client.read_message(), BitmexMessage::Info(_)));
+        client.send(&handler.get_subscription_request());
+        assert!(matches!(client.read_message(), BitmexMessage::Subscribe(_)));
+
+        let mut book = OrderBook::new(handler.instruments_mut(), "XBTUSD");
+        let start = Instant::now();
+        book.apply(&client.read_message(), |_| {});
+        book.apply(&client.read_message(), |_| {});
+        assert!(start.elapsed() >= Duration::from_millis(50));
+        assert_eq!(book.best_ask().unwrap().size, 900);
+
+        let (op_code, _) = client.read().unwrap();
+        assert_eq!(op_code, 0xf3);
+        let (_, truncated) = client.read().unwrap();
+        assert_eq!(truncated, &TOP_UPDATE[..TOP_UPDATE.len() / 2]);
+        assert!(client.read().is_none());
+
+        // reconnect, the second connection plays the second script
+        let mut client = Client::connect(&server);
+        client.read_message();
+        client.send(&handler.get_subscription_request());
+        client.read_message();
+        assert!(matches!(client.read_message(), BitmexMessage::Snapshot(_)));
+        client.send("ping");
+        assert_eq!(client.read().unwrap().1, b"pong");
+
+        assert_eq!(server.connections(), 2);
+        assert_eq!(
+            server.requests()[0],
+            "{\"op\":\"subscribe\",\"args\":[\"trade:XBTUSD\"]}"
+        );
+    }
+}
@@ -0,0 +1,633 @@
+use crate::bitmex_message::BitmexMessage;
+use crate::event_codec::{decode, DecodeError, Encoder, L2DeltaRecord};
+use crate::instrument::{InstrumentId, SymbolRegistry};
+use crate::normalized::DeltaAction;
+use crate::order_book::OrderBook;
+use std::collections::{HashMap, VecDeque};
+use std::io::{self, Read, Write};
+use std::net::{Ipv4Addr, SocketAddr, TcpListener, TcpStream, ToSocketAddrs, UdpSocket};
+use std::sync::{Arc, Mutex};
+use std::time::Duration;
+
+// UDP packet layout, all integers are little endian:
+//   version u8, reserved u8, record count u16, sequence number u64, event_codec records
+// Sequence numbers start at 1 and increase by one per packet.
+pub const PACKET_VERSION: u8 = 1;
+pub const PACKET_HEADER_LEN: usize = 12;
+// max packet length, below the usual ethernet mtu
+pub const MAX_PACKET_LEN: usize = 1400;
+pub const DEFAULT_RETAINED_PACKETS: usize = 1 << 16;
+
+// recovery service requests
+const RETRANSMIT_REQUEST: u8 = b'R';
+const SNAPSHOT_REQUEST: u8 = b'S';
+
+// snapshot reply status
+const SNAPSHOT_AVAILABLE: u8 = 0;
+const SNAPSHOT_UNAVAILABLE: u8 = 1;
+
+// time given to a client to send its request
+const DEFAULT_REQUEST_TIMEOUT: Duration = Duration::from_secs(5);
+
+// Packets kept for retransmission and the books of the published symbols, shared between the
+// publisher and the recovery server
+pub struct RecoveryState {
+    seq: u64,
+    packets: VecDeque<(u64, Vec<u8>)>,
+    max_packets: usize,
+    // ids of the books only, the packets and snapshots carry the symbols
+    symbols: SymbolRegistry,
+    books: HashMap<InstrumentId, OrderBook>,
+    timestamp: i64,
+}
+
+impl RecoveryState {
+    // packets in the given range still retained
+    fn packets(&self, from: u64, to: u64) -> Vec<&[u8]> {
+        self.packets
+            .iter()
+            .filter(|(seq, _)| *seq >= from && *seq <= to)
+            .map(|(_, packet)| &packet[..])
+            .collect()
+    }
+
+    // the book of a symbol as partial l2 delta records, none while the book is invalid (e.g.
+    // before its partial or after an integrity violation). A symbol never published has an
+    // empty book.
+    fn snapshot(&self, symbol: &str) -> Option<Vec<u8>> {
+        let mut encoder = Encoder::new();
+        let book = self.symbols.id(symbol).and_then(|id| self.books.get(&id));
+        if let Some(book) = book {
+            if !book.is_valid() {
+                return None;
+            }
+            for level in book.bids().chain(book.asks()) {
+                // the symbol fitted when the deltas were encoded
+                let _ = encoder.encode_l2_delta(&L2DeltaRecord {
+                    timestamp: self.timestamp,
+                    symbol: String::from(symbol),
+                    action: DeltaAction::Partial,
+                    side: level.side,
+                    id: level.id,
+                    size: Some(level.size),
+                    price: Some(level.price),
+                });
+            }
+        }
+        Some(encoder.as_bytes().to_vec())
+    }
+}
+
+// symbols of the orderBookL2 entries of a message
+fn book_symbols(message: &BitmexMessage) -> Vec<&str> {
+    let mut symbols: Vec<&str> = match message {
+        BitmexMessage::Snapshot(m) => m.data.iter().map(|e| &e.symbol[..]).collect(),
+        BitmexMessage::Insert(m) => m.data.iter().map(|e| &e.symbol[..]).collect(),
+        BitmexMessage::Update(m) => m.data.iter().map(|e| &e.symbol[..]).collect(),
+        BitmexMessage::Delete(m) => m.data.iter().map(|e| &e.symbol[..]).collect(),
+        _ => vec![],
+    };
+    symbols.sort_unstable();
+    symbols.dedup();
+    symbols
+}
+
+// Publishes the trades and orderBookL2 entries of BitMEX messages as sequenced UDP packets of
+// event_codec records, to a multicast group or a unicast address
+pub struct MulticastPublisher {
+    socket: UdpSocket,
+    destination: SocketAddr,
+    encoder: Encoder,
+    state: Arc<Mutex<RecoveryState>>,
+}
+
+impl MulticastPublisher {
+    pub fn new(destination: SocketAddr) -> io::Result<Self> {
+        let socket = UdpSocket::bind("0.0.0.0:0")?;
+        if destination.ip().is_multicast() {
+            socket.set_multicast_ttl_v4(1)?;
+            socket.set_multicast_loop_v4(true)?;
+        }
+        Ok(MulticastPublisher {
+            socket,
+            destination,
+            encoder: Encoder::new(),
+            state: Arc::new(Mutex::new(RecoveryState {
+                seq: 0,
+                packets: VecDeque::new(),
+                max_packets: DEFAULT_RETAINED_PACKETS,
+                symbols: SymbolRegistry::new(),
+                books: HashMap::new(),
+                timestamp: 0,
+            })),
+        })
+    }
+
+    // multicast ttl, 1 keeps the packets on the local network
+    pub fn set_ttl(&mut self, ttl: u32) -> io::Result<()> {
+        self.socket.set_multicast_ttl_v4(ttl)
+    }
+
+    // number of packets kept for retransmission
+    pub fn set_max_retained_packets(&mut self, max_packets: usize) {
+        self.state.lock().unwrap().max_packets = max_packets;
+    }
+
+    // state to give to the RecoveryServer
+    pub fn recovery_state(&self) -> Arc<Mutex<RecoveryState>> {
+        self.state.clone()
+    }
+
+    // sequence number of the last packet sent
+    pub fn seq(&self) -> u64 {
+        self.state.lock().unwrap().seq
+    }
+
+    // publish a message, returns the number of packets sent
+    pub fn publish_message(
+        &mut self,
+        receive_time: i64,
+        message: &BitmexMessage,
+    ) -> io::Result<usize> {
+        self.encoder.clear();
+        self.encoder
+            .encode_message(receive_time, message)
+            .map_err(|e| io::Error::new(io::ErrorKind::InvalidData, format!("{:?}", e)))?;
+
+        // the books and the packet sequence are updated together so that a snapshot is always
+        // consistent with the sequence number it is sent with
+        let mut guard = self.state.lock().unwrap();
+        let state = &mut *guard;
+        for symbol in book_symbols(message) {
+            let id = state.symbols.intern(symbol);
+            let symbols = &mut state.symbols;
+            state
+                .books
+                .entry(id)
+                .or_insert_with(|| OrderBook::new(symbols, symbol))
+                .apply(message, |_| {});
+        }
+        state.timestamp = receive_time;
+
+        let mut count = 0;
+        let mut records = self.encoder.as_bytes();
+        while !records.is_empty() {
+            let mut len = 0;
+            let mut record_count: u16 = 0;
+            while let Ok((_, record_len)) = decode(&records[len..]) {
+                if PACKET_HEADER_LEN + len + record_len > MAX_PACKET_LEN {
+                    break;
+                }
+                len += record_len;
+                record_count += 1;
+            }
+            state.seq += 1;
+            let mut packet = Vec::with_capacity(PACKET_HEADER_LEN + len);
+            packet.push(PACKET_VERSION);
+            packet.push(0);
+            packet.extend_from_slice(&record_count.to_le_bytes());
+            packet.extend_from_slice(&state.seq.to_le_bytes());
+            packet.extend_from_slice(&records[..len]);
+            records = &records[len..];
+
+            // retained before sending so that a packet failing to send can be retransmitted
+            let seq = state.seq;
+            state.packets.push_back((seq, packet));
+            while state.packets.len() > state.max_packets {
+                state.packets.pop_front();
+            }
+            if let Some((_, packet)) = state.packets.back() {
+                self.socket.send_to(packet, self.destination)?;
+            }
+            count += 1;
+        }
+        Ok(count)
+    }
+}
+
+// parse a packet, returns its sequence number and records
+pub fn parse_packet(packet: &[u8]) -> Result<(u64, &[u8]), DecodeError> {
+    if packet.len() < PACKET_HEADER_LEN {
+        return Err(DecodeError::Truncated);
+    }
+    if packet[0] != PACKET_VERSION {
+        return Err(DecodeError::UnsupportedVersion);
+    }
+    let mut seq = [0u8; 8];
+    seq.copy_from_slice(&packet[4..12]);
+    Ok((u64::from_le_bytes(seq), &packet[PACKET_HEADER_LEN..]))
+}
+
+#[derive(Debug, PartialEq)]
+pub struct ReceivedPacket<'a> {
+    pub seq: u64,
+    // sequence numbers missed before this packet, first and last included
+    pub gap: Option<(u64, u64)>,
+    pub records: &'a [u8],
+}
+
+// Tracks the packet sequence numbers of the feed
+pub struct PacketSequencer {
+    next: Option<u64>,
+}
+
+impl PacketSequencer {
+    pub fn new() -> Self {
+        PacketSequencer { next: None }
+    }
+
+    // sequence number of the next packet expected, none before the first packet
+    pub fn next_seq(&self) -> Option<u64> {
+        self.next
+    }
+
+    // returns none for a packet already received (e.g. a retransmission already filled in)
+    pub fn on_packet<'a>(
+        &mut self,
+        packet: &'a [u8],
+    ) -> Result<Option<ReceivedPacket<'a>>, DecodeError> {
+        let (seq, records) = parse_packet(packet)?;
+        let gap = match self.next {
+            Some(next) if seq < next => return Ok(None),
+            Some(next) if seq > next => Some((next, seq - 1)),
+            _ => None,
+        };
+        self.next = Some(seq + 1);
+        Ok(Some(ReceivedPacket { seq, gap, records }))
+    }
+}
+
+impl Default for PacketSequencer {
+    fn default() -> Self {
+        PacketSequencer::new()
+    }
+}
+
+pub struct MulticastSubscriber {
+    socket: UdpSocket,
+    sequencer: PacketSequencer,
+    buf: Vec<u8>,
+}
+
+impl MulticastSubscriber {
+    // join a multicast group on the given interface
+    pub fn join(group: SocketAddr, interface: Ipv4Addr) -> io::Result<Self> {
+        let socket = UdpSocket::bind(SocketAddr::new(Ipv4Addr::UNSPECIFIED.into(), group.port()))?;
+        match group {
+            SocketAddr::V4(group) => socket.join_multicast_v4(group.ip(), &interface)?,
+            SocketAddr::V6(_) => {
+                return Err(io::Error::new(
+                    io::ErrorKind::InvalidInput,
+                    "only ipv4 multicast groups are supported",
+                ))
+            }
+        }
+        Ok(MulticastSubscriber::from_socket(socket))
+    }
+
+    // receive on a unicast address
+    pub fn bind<A: ToSocketAddrs>(address: A) -> io::Result<Self> {
+        Ok(MulticastSubscriber::from_socket(UdpSocket::bind(address)?))
+    }
+
+    fn from_socket(socket: UdpSocket) -> Self {
+        MulticastSubscriber {
+            socket,
+            sequencer: PacketSequencer::new(),
+            buf: vec![0u8; MAX_PACKET_LEN],
+        }
+    }
+
+    pub fn local_addr(&self) -> io::Result<SocketAddr> {
+        self.socket.local_addr()
+    }
+
+    pub fn socket(&self) -> &UdpSocket {
+        &self.socket
+    }
+
+    // receive the next packet, packets received twice and invalid packets are skipped
+    pub fn recv(&mut self) -> io::Result<ReceivedPacket<'_>> {
+        let len = loop {
+            let len = self.socket.recv(&mut self.buf)?;
+            let next = self.sequencer.next_seq();
+            match parse_packet(&self.buf[..len]) {
+                Ok((seq, _)) if next.map(|next| seq >= next).unwrap_or(true) => break len,
+                _ => {}
+            }
+        };
+        match self.sequencer.on_packet(&self.buf[..len]) {
+            Ok(Some(packet)) => Ok(packet),
+            // checked before leaving the receive loop
+            _ => Err(io::Error::new(io::ErrorKind::InvalidData, "invalid packet")),
+        }
+    }
+}
+
+// TCP service answering retransmit and snapshot requests, one request per connection:
+//   retransmit: 'R', first sequence number u64, last sequence number u64
+//     reply: packet count u32, packets (u32 length + packet). Packets no longer retained are
+//     left out.
+//   snapshot: 'S', symbol (u16 length + utf8)
+//     reply: status u8, 1 if the book is not available (e.g. waiting for its partial) and
+//     nothing follows, 0 followed by the sequence number of the last packet included in the
+//     snapshot u64, records length u32, partial l2 delta records of the book
+// Connections are served one at a time, a client has the request timeout to send its request.
+pub struct RecoveryServer {
+    listener: TcpListener,
+    state: Arc<Mutex<RecoveryState>>,
+    request_timeout: Duration,
+}
+
+impl RecoveryServer {
+    pub fn bind<A: ToSocketAddrs>(
+        address: A,
+        state: Arc<Mutex<RecoveryState>>,
+    ) -> io::Result<Self> {
+        Ok(RecoveryServer {
+            listener: TcpListener::bind(address)?,
+            state,
+            request_timeout: DEFAULT_REQUEST_TIMEOUT,
+        })
+    }
+
+    // time a connection is kept waiting for its request
+    pub fn set_request_timeout(&mut self, timeout: Duration) {
+        self.request_timeout = timeout;
+    }
+
+    pub fn local_addr(&self) -> io::Result<SocketAddr> {
+        self.listener.local_addr()
+    }
+
+    // serve requests until accept fails, a failed request only closes its connection
+    pub fn run(&self) -> io::Result<()> {
+        loop {
+            let (stream, _) = self.listener.accept()?;
+            let _ = self.handle(stream);
+        }
+    }
+
+    // serve the request of the next connection
+    pub fn handle_next(&self) -> io::Result<()> {
+        let (stream, _) = self.listener.accept()?;
+        self.handle(stream)
+    }
+
+    fn handle(&self, mut stream: TcpStream) -> io::Result<()> {
+        // a client that does not send its request does not hold the server
+        stream.set_read_timeout(Some(self.request_timeout))?;
+        stream.set_write_timeout(Some(self.request_timeout))?;
+        let mut request = [0u8; 1];
+        stream.read_exact(&mut request)?;
+        let mut reply = vec![];
+        match request[0] {
+            RETRANSMIT_REQUEST => {
+                let from = read_u64(&mut stream)?;
+                let to = read_u64(&mut stream)?;
+                let state = self.state.lock().unwrap();
+                let packets = state.packets(from, to);
+                reply.extend_from_slice(&(packets.len() as u32).to_le_bytes());
+                for packet in packets {
+                    reply.extend_from_slice(&(packet.len() as u32).to_le_bytes());
+                    reply.extend_from_slice(packet);
+                }
+            }
+            SNAPSHOT_REQUEST => {
+                let mut len = [0u8; 2];
+                stream.read_exact(&mut len)?;
+                let mut symbol = vec![0u8; u16::from_le_bytes(len) as usize];
+                stream.read_exact(&mut symbol)?;
+                let symbol = String::from_utf8(symbol)
+                    .map_err(|_| io::Error::new(io::ErrorKind::InvalidData, "invalid symbol"))?;
+                let state = self.state.lock().unwrap();
+                match state.snapshot(&symbol) {
+                    Some(records) => {
+                        reply.push(SNAPSHOT_AVAILABLE);
+                        reply.extend_from_slice(&state.seq.to_le_bytes());
+                        reply.extend_from_slice(&(records.len() as u32).to_le_bytes());
+                        reply.extend_from_slice(&records);
+                    }
+                    None => reply.push(SNAPSHOT_UNAVAILABLE),
+                }
+            }
+            _ => {
+                return Err(io::Error::new(
+                    io::ErrorKind::InvalidData,
+                    "unknown request",
+                ))
+            }
+        }
+        stream.write_all(&reply)
+    }
+}
+
+// request the retransmission of packets, first and last sequence numbers included
+pub fn request_retransmit<A: ToSocketAddrs>(
+    address: A,
+    from: u64,
+    to: u64,
+) -> io::Result<Vec<Vec<u8>>> {
+    let mut stream = TcpStream::connect(address)?;
+    let mut request = vec![RETRANSMIT_REQUEST];
+    request.extend_from_slice(&from.to_le_bytes());
+    request.extend_from_slice(&to.to_le_bytes());
+    stream.write_all(&request)?;
+
+    let count = read_u32(&mut stream)?;
+    let mut packets = vec![];
+    for _ in 0..count {
+        let len = read_u32(&mut stream)? as usize;
+        if len > MAX_PACKET_LEN {
+            return Err(io::Error::new(
+                io::ErrorKind::InvalidData,
+                "packet too long",
+            ));
+        }
+        let mut packet = vec![0u8; len];
+        stream.read_exact(&mut packet)?;
+        packets.push(packet);
+    }
+    Ok(packets)
+}
+
+// request the book of a symbol, returns the sequence number of the last packet included in the
+// snapshot and the partial l2 delta records of the book. Packets after this sequence number are
+// to be applied on top of the snapshot. None if the book is not available yet, the request is
+// to be retried later.
+pub fn request_snapshot<A: ToSocketAddrs>(
+    address: A,
+    symbol: &str,
+) -> io::Result<Option<(u64, Vec<u8>)>> {
+    let mut stream = TcpStream::connect(address)?;
+    let mut request = vec![SNAPSHOT_REQUEST];
+    request.extend_from_slice(&(symbol.len() as u16).to_le_bytes());
+    request.extend_from_slice(symbol.as_bytes());
+    stream.write_all(&request)?;
+
+    let mut status = [0u8; 1];
+    stream.read_exact(&mut status)?;
+    match status[0] {
+        SNAPSHOT_AVAILABLE => {}
+        SNAPSHOT_UNAVAILABLE => return Ok(None),
+        _ => {
+            return Err(io::Error::new(
+                io::ErrorKind::InvalidData,
+                "unknown snapshot status",
+            ))
+        }
+    }
+    let seq = read_u64(&mut stream)?;
+    let len = read_u32(&mut stream)? as usize;
+    let mut records = vec![0u8; len];
+    stream.read_exact(&mut records)?;
+    Ok(Some((seq, records)))
+}
+
+fn read_u32<R: Read>(reader: &mut R) -> io::Result<u32> {
+    let mut buf = [0u8; 4];
+    reader.read_exact(&mut buf)?;
+    Ok(u32::from_le_bytes(buf))
+}
+
+fn read_u64<R: Read>(reader: &mut R) -> io::Result<u64> {
+    let mut buf = [0u8; 8];
+    reader.read_exact(&mut buf)?;
+    Ok(u64::from_le_bytes(buf))
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::bitmex_message::parse;
+    use crate::event_codec::{Decoder, Record};
+    use crate::fixtures::{ASK_INSERT, ASK_UPDATE, TOP_SNAPSHOT};
+    use crate::multicast::{
+        parse_packet, request_retransmit, request_snapshot, MulticastPublisher,
+        MulticastSubscriber, PacketSequencer, RecoveryServer,
+    };
+    use crate::normalized::DeltaAction;
+    use std::net::{TcpStream, UdpSocket};
+    use std::thread;
+    use std::time::Duration;
+
+    #[test]
+    fn publish_and_subscribe_over_loopback() {
+        let mut subscriber = MulticastSubscriber::bind("127.0.0.1:0").unwrap();
+        subscriber
+            .socket()
+            .set_read_timeout(Some(Duration::from_secs(5)))
+            .unwrap();
+        let mut publisher = MulticastPublisher::new(subscriber.local_addr().unwrap()).unwrap();
+        assert_eq!(
+            publisher
+                .publish_message(1, &parse(TOP_SNAPSHOT).ok().unwrap())
+                .unwrap(),
+            1
+        );
+        publisher
+            .publish_message(2, &parse(ASK_INSERT).ok().unwrap())
+            .unwrap();
+
+        let packet = subscriber.recv().unwrap();
+        assert_eq!(packet.seq, 1);
+        assert_eq!(packet.gap, None);
+        assert_eq!(Decoder::new(packet.records).count(), 2);
+        let packet = subscriber.recv().unwrap();
+        assert_eq!(packet.seq, 2);
+        match Decoder::new(packet.records).next() {
+            Some(Ok(Record::L2Delta(view))) => {
+                assert_eq!(view.action(), DeltaAction::Insert);
+                assert_eq!(view.id(), 8799070900);
+            }
+            _ => panic!("expected an l2 delta"),
+        }
+    }
+
+    #[test]
+    fn gap_detection_and_recovery() {
+        let receiver = UdpSocket::bind("127.0.0.1:0").unwrap();
+        receiver
+            .set_read_timeout(Some(Duration::from_secs(5)))
+            .unwrap();
+        let mut publisher = MulticastPublisher::new(receiver.local_addr().unwrap()).unwrap();
+        let server = RecoveryServer::bind("127.0.0.1:0", publisher.recovery_state()).unwrap();
+        let address = server.local_addr().unwrap();
+        thread::spawn(move || server.run());
+
+        for message in [TOP_SNAPSHOT, ASK_INSERT, ASK_UPDATE].iter() {
+            publisher
+                .publish_message(0, &parse(message).ok().unwrap())
+                .unwrap();
+        }
+        let mut packets = vec![];
+        let mut buf = [0u8; 2048];
+        for _ in 0..3 {
+            let len = receiver.recv(&mut buf).unwrap();
+            packets.push(buf[..len].to_vec());
+        }
+
+        // packet 2 is lost
+        let mut sequencer = PacketSequencer::new();
+        assert_eq!(sequencer.on_packet(&packets[0]).unwrap().unwrap().gap, None);
+        let packet = sequencer.on_packet(&packets[2]).unwrap().unwrap();
+        assert_eq!(packet.seq, 3);
+        assert_eq!(packet.gap, Some((2, 2)));
+        assert!(sequencer.on_packet(&packets[1]).unwrap().is_none());
+
+        let retransmitted = request_retransmit(address, 2, 2).unwrap();
+        assert_eq!(retransmitted, vec![packets[1].clone()]);
+        assert_eq!(parse_packet(&retransmitted[0]).unwrap().0, 2);
+
+        // the snapshot holds the state after the last packet
+        let (seq, records) = request_snapshot(address, "XBTUSD").unwrap().unwrap();
+        assert_eq!(seq, 3);
+        let levels: Vec<(i64, Option<i64>)> = Decoder::new(&records)
+            .map(|record| match record.unwrap() {
+                Record::L2Delta(view) => {
+                    assert_eq!(view.action(), DeltaAction::Partial);
+                    (view.id(), view.size())
+                }
+                _ => panic!("expected an l2 delta"),
+            })
+            .collect();
+        assert_eq!(
+            levels,
+            vec![
+                (8799070950, Some(1023444)),
+                (8799070900, Some(20)),
+                (8799070850, Some(832))
+            ]
+        );
+
+        let (_, records) = request_snapshot(address, "ETHUSD").unwrap().unwrap();
+        assert!(records.is_empty());
+    }
+
+    #[test]
+    fn recovery_of_invalid_books_and_idle_clients() {
+        let receiver = UdpSocket::bind("127.0.0.1:0").unwrap();
+        let mut publisher = MulticastPublisher::new(receiver.local_addr().unwrap()).unwrap();
+        let mut server = RecoveryServer::bind("127.0.0.1:0", publisher.recovery_state()).unwrap();
+        server.set_request_timeout(Duration::from_millis(100));
+        let address = server.local_addr().unwrap();
+        thread::spawn(move || server.run());
+
+        // a client connected without sending its request only holds the server until the
+        // request timeout
+        let idle = TcpStream::connect(address).unwrap();
+
+        // an update before the partial leaves the book invalid
+        publisher
+            .publish_message(0, &parse(ASK_UPDATE).ok().unwrap())
+            .unwrap();
+        assert_eq!(request_snapshot(address, "XBTUSD").unwrap(), None);
+
+        publisher
+            .publish_message(0, &parse(TOP_SNAPSHOT).ok().unwrap())
+            .unwrap();
+        let (seq, records) = request_snapshot(address, "XBTUSD").unwrap().unwrap();
+        assert_eq!(seq, 2);
+        assert_eq!(Decoder::new(&records).count(), 2);
+        drop(idle);
+    }
+}
@@ -0,0 +1,377 @@
+use crate::bitmex_message::{
+    BitmexMessage, DeleteEntry, Filter, InsertEntry, TradeEntry, UpdateEntry,
+};
+use crate::instrument::{InstrumentId, SymbolRegistry};
+
+// aggressor side of a trade or side of a book level
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub enum Side {
+    Buy,
+    Sell,
+}
+
+impl Side {
+    pub fn from_bitmex(side: &str) -> Option<Side> {
+        match side {
+            "Buy" => Some(Side::Buy),
+            "Sell" => Some(Side::Sell),
+            _ => None,
+        }
+    }
+}
+
+// change of a book level, a partial replaces the levels of the instrument
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum DeltaAction {
+    Partial,
+    Insert,
+    Update,
+    Delete,
+}
+
+// Exchange neutral market data. Timestamps are nanos since unix epoch, trades carry the exchange
+// timestamp and book events the receive time given by the caller when the venue has no timestamp
+// for book updates. Prices and quantities are in the units of the venue. Instruments are
+// identified by the ids of the registry of the source (see MarketDataSource::instruments).
+#[derive(Debug, Clone, PartialEq)]
+pub struct Trade {
+    pub ts: i64,
+    pub instrument: InstrumentId,
+    // aggressor side
+    pub side: Side,
+    pub px: f64,
+    pub qty: f64,
+    pub trade_id: String,
+}
+
+impl Trade {
+    pub fn from_bitmex(entry: &TradeEntry, symbols: &mut SymbolRegistry) -> Option<Trade> {
+        Some(Trade {
+            ts: entry.timestamp_nanos()?,
+            instrument: symbols.intern(&entry.symbol),
+            side: Side::from_bitmex(&entry.side)?,
+            px: entry.price,
+            qty: entry.size,
+            trade_id: entry.trd_match_id.clone(),
+        })
+    }
+}
+
+// Change of a price level identified by a venue level id. The price is not known for updates and
+// deletes on venues which only send it with the insert, the qty is not known for deletes.
+#[derive(Debug, Clone, PartialEq)]
+pub struct BookDelta {
+    pub ts: i64,
+    pub instrument: InstrumentId,
+    pub action: DeltaAction,
+    pub side: Side,
+    pub level_id: i64,
+    pub px: Option<f64>,
+    pub qty: Option<f64>,
+}
+
+impl BookDelta {
+    pub fn from_bitmex_insert(
+        ts: i64,
+        entry: &InsertEntry,
+        symbols: &mut SymbolRegistry,
+    ) -> Option<BookDelta> {
+        Some(BookDelta {
+            ts,
+            instrument: symbols.intern(&entry.symbol),
+            action: DeltaAction::Insert,
+            side: Side::from_bitmex(&entry.side)?,
+            level_id: entry.id,
+            px: Some(entry.price),
+            qty: Some(entry.size as f64),
+        })
+    }
+
+    pub fn from_bitmex_update(
+        ts: i64,
+        entry: &UpdateEntry,
+        symbols: &mut SymbolRegistry,
+    ) -> Option<BookDelta> {
+        Some(BookDelta {
+            ts,
+            instrument: symbols.intern(&entry.symbol),
+            action: DeltaAction::Update,
+            side: Side::from_bitmex(&entry.side)?,
+            level_id: entry.id,
+            px: None,
+            qty: Some(entry.size as f64),
+        })
+    }
+
+    pub fn from_bitmex_delete(
+        ts: i64,
+        entry: &DeleteEntry,
+        symbols: &mut SymbolRegistry,
+    ) -> Option<BookDelta> {
+        Some(BookDelta {
+            ts,
+            instrument: symbols.intern(&entry.symbol),
+            action: DeltaAction::Delete,
+            side: Side::from_bitmex(&entry.side)?,
+            level_id: entry.id,
+            px: None,
+            qty: None,
+        })
+    }
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub struct BookLevel {
+    pub side: Side,
+    pub level_id: i64,
+    pub px: f64,
+    pub qty: f64,
+}
+
+// Full book of a symbol, replaces any previous state of the book
+#[derive(Debug, Clone, PartialEq)]
+pub struct BookSnapshot {
+    pub ts: i64,
+    pub instrument: InstrumentId,
+    pub levels: Vec<BookLevel>,
+}
+
+impl BookSnapshot {
+    // a BitMEX partial may hold the book of several symbols, one snapshot is made per symbol in
+    // the order the symbols first appear. The snapshot of the filter symbol comes first and is
+    // made even without rows, a partial with no rows is an empty book.
+    pub fn from_bitmex(
+        ts: i64,
+        filter: &Filter,
+        entries: &[InsertEntry],
+        symbols: &mut SymbolRegistry,
+    ) -> Option<Vec<BookSnapshot>> {
+        let mut snapshots = vec![BookSnapshot {
+            ts,
+            instrument: symbols.add_filter(filter),
+            levels: vec![],
+        }];
+        for entry in entries.iter() {
+            let instrument = symbols.intern(&entry.symbol);
+            let level = BookLevel {
+                side: Side::from_bitmex(&entry.side)?,
+                level_id: entry.id,
+                px: entry.price,
+                qty: entry.size as f64,
+            };
+            match snapshots.iter_mut().find(|s| s.instrument == instrument) {
+                Some(snapshot) => snapshot.levels.push(level),
+                None => snapshots.push(BookSnapshot {
+                    ts,
+                    instrument,
+                    levels: vec![level],
+                }),
+            }
+        }
+        Some(snapshots)
+    }
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub enum MarketDataEvent {
+    Trade(Trade),
+    BookSnapshot(BookSnapshot),
+    BookDelta(BookDelta),
+}
+
+#[derive(Debug, PartialEq)]
+pub enum SourceError {
+    // payload could not be parsed
+    InvalidMessage,
+    // message parsed but a field could not be normalized (e.g. unknown side)
+    InvalidField,
+    // payload could not be written to the recording
+    RecordingFailed,
+}
+
+// Venue adapter producing normalized events from the payloads received on its connection
+pub trait MarketDataSource {
+    // venue name, e.g. bitmex
+    fn venue(&self) -> &str;
+
+    fn add_symbol(&mut self, symbol: &str);
+
+    // registry of the instrument ids of the events
+    fn instruments(&self) -> &SymbolRegistry;
+
+    // requests to send once connected to subscribe to the symbols added
+    fn subscription_requests(&self) -> Vec<String>;
+
+    // normalize a payload received on the connection. The receive time is used as the timestamp
+    // of the events the venue does not timestamp. Payloads without market data (e.g. subscription
+    // acks) produce no events.
+    fn on_payload(
+        &mut self,
+        receive_time: i64,
+        payload: &[u8],
+        on_event: &mut dyn FnMut(MarketDataEvent),
+    ) -> Result<(), SourceError>;
+}
+
+// normalize a BitMEX message, nothing is given to the callback if an entry can not be normalized.
+// The symbols are interned in the registry, including the filter of partials without rows.
+pub fn normalize_bitmex(
+    receive_time: i64,
+    message: &BitmexMessage,
+    symbols: &mut SymbolRegistry,
+    on_event: &mut dyn FnMut(MarketDataEvent),
+) -> Result<(), SourceError> {
+    let events = match message {
+        BitmexMessage::Trade(trade) => trade_events(&trade.data, symbols),
+        BitmexMessage::TradeSnapshot(trade) => {
+            symbols.add_filter(&trade.filter);
+            trade_events(&trade.data, symbols)
+        }
+        BitmexMessage::Snapshot(snapshot) => {
+            BookSnapshot::from_bitmex(receive_time, &snapshot.filter, &snapshot.data, symbols).map(
+                |snapshots| {
+                    snapshots
+                        .into_iter()
+                        .map(MarketDataEvent::BookSnapshot)
+                        .collect()
+                },
+            )
+        }
+        BitmexMessage::Insert(insert) => insert
+            .data
+            .iter()
+            .map(|e| {
+                BookDelta::from_bitmex_insert(receive_time, e, symbols)
+                    .map(MarketDataEvent::BookDelta)
+            })
+            .collect(),
+        BitmexMessage::Update(update) => update
+            .data
+            .iter()
+            .map(|e| {
+                BookDelta::from_bitmex_update(receive_time, e, symbols)
+                    .map(MarketDataEvent::BookDelta)
+            })
+            .collect(),
+        BitmexMessage::Delete(delete) => delete
+            .data
+            .iter()
+            .map(|e| {
+                BookDelta::from_bitmex_delete(receive_time, e, symbols)
+                    .map(MarketDataEvent::BookDelta)
+            })
+            .collect(),
+        _ => Some(vec![]),
+    };
+    for event in events.ok_or(SourceError::InvalidField)? {
+        on_event(event);
+    }
+    Ok(())
+}
+
+fn trade_events(
+    entries: &[TradeEntry],
+    symbols: &mut SymbolRegistry,
+) -> Option<Vec<MarketDataEvent>> {
+    entries
+        .iter()
+        .map(|e| Trade::from_bitmex(e, symbols).map(MarketDataEvent::Trade))
+        .collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::bitmex_md_handler::BitmexMdHandler;
+    use crate::fixtures::{DELETE, TOP_SNAPSHOT, TRADE};
+    use crate::normalized::{DeltaAction, MarketDataEvent, MarketDataSource, Side, SourceError};
+
+    fn events(source: &mut dyn MarketDataSource, payload: &[u8]) -> Vec<MarketDataEvent> {
+        let mut events = vec![];
+        source
+            .on_payload(7, payload, &mut |event| events.push(event))
+            .unwrap();
+        events
+    }
+
+    #[test]
+    fn bitmex_source() {
+        let mut handler = BitmexMdHandler::new();
+        let source: &mut dyn MarketDataSource = &mut handler;
+        assert_eq!(source.venue(), "bitmex");
+        source.add_symbol("XBTUSD");
+        let xbtusd = source.instruments().id("XBTUSD").unwrap();
+        assert_eq!(
+            source.subscription_requests(),
+            vec!["{\"op\":\"subscribe\",\"args\":[\"trade:XBTUSD\"]}"]
+        );
+
+        match &events(source, TRADE)[..] {
+            [MarketDataEvent::Trade(trade)] => {
+                assert_eq!(trade.ts, 1595187801401000000);
+                assert_eq!(trade.instrument, xbtusd);
+                assert_eq!(trade.side, Side::Sell);
+                assert_eq!(trade.px, 9155.5);
+                assert_eq!(trade.qty, 16000.0);
+                assert_eq!(trade.trade_id, "ec06df7b-0dc0-8181-f693-c9f39fb57e56");
+            }
+            _ => panic!("expected a trade"),
+        }
+
+        match &events(source, TOP_SNAPSHOT)[..] {
+            [MarketDataEvent::BookSnapshot(snapshot)] => {
+                assert_eq!(snapshot.ts, 7);
+                assert_eq!(snapshot.instrument, xbtusd);
+                assert_eq!(snapshot.levels.len(), 2);
+                assert_eq!(snapshot.levels[1].side, Side::Buy);
+                assert_eq!(snapshot.levels[1].px, 9290.5);
+            }
+            _ => panic!("expected a snapshot"),
+        }
+
+        // a partial without rows is an empty book
+        let empty = b"{\"table\":\"orderBookL2\",\"action\":\"partial\",\"keys\":[\"symbol\",\"id\",\"side\"],\"types\":{\"symbol\":\"symbol\",\"id\":\"long\",\"side\":\"symbol\",\"size\":\"long\",\"price\":\"float\"},\"foreignKeys\":{\"symbol\":\"instrument\",\"side\":\"side\"},\"attributes\":{\"symbol\":\"parted\",\"id\":\"sorted\"},\"filter\":{\"symbol\":\"XBTUSD\"},\"data\":[]}";
+        match &events(source, empty)[..] {
+            [MarketDataEvent::BookSnapshot(snapshot)] => {
+                assert_eq!(snapshot.instrument, xbtusd);
+                assert!(snapshot.levels.is_empty());
+            }
+            _ => panic!("expected an empty snapshot"),
+        }
+
+        match &events(source, DELETE)[..] {
+            [MarketDataEvent::BookDelta(delta)] => {
+                assert_eq!(delta.instrument, xbtusd);
+                assert_eq!(delta.action, DeltaAction::Delete);
+                assert_eq!(delta.level_id, 8799594200);
+                assert_eq!(delta.qty, None);
+            }
+            _ => panic!("expected a delta"),
+        }
+
+        // symbols not subscribed to get the next id
+        let ethusd = b"{\"table\":\"orderBookL2\",\"action\":\"delete\",\"data\":[{\"symbol\":\"ETHUSD\",\"id\":1,\"side\":\"Buy\"}]}";
+        match &events(source, ethusd)[..] {
+            [MarketDataEvent::BookDelta(delta)] => {
+                assert_ne!(delta.instrument, xbtusd);
+                assert_eq!(
+                    source.instruments().symbol(delta.instrument),
+                    Some("ETHUSD")
+                );
+            }
+            _ => panic!("expected a delta"),
+        }
+    }
+
+    #[test]
+    fn invalid_side() {
+        let mut handler = BitmexMdHandler::new();
+        let mut count = 0;
+        let result = handler.on_payload(
+            0,
+            b"{\"table\":\"orderBookL2\",\"action\":\"delete\",\"data\":[{\"symbol\":\"XBTUSD\",\"id\":1,\"side\":\"Buy\"},{\"symbol\":\"XBTUSD\",\"id\":2,\"side\":\"Both\"}]}",
+            &mut |_| count += 1,
+        );
+        assert_eq!(result, Err(SourceError::InvalidField));
+        assert_eq!(count, 0);
+    }
+}
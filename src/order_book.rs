@@ -0,0 +1,897 @@
+use crate::bitmex_message::{BitmexMessage, DeleteEntry, InsertEntry, UpdateEntry};
+use crate::instrument::{InstrumentId, PriceIdMap, SymbolRegistry};
+use crate::l2_decoder::L2Entry;
+use crate::normalized::Side;
+use crate::parser::BitmexMessageRef;
+use std::cmp::Ordering;
+use std::collections::{BTreeMap, HashMap};
+use std::ops::Bound;
+
+// L2 price level of the book, identified by the BitMEX level id
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct Level {
+    pub id: i64,
+    pub side: Side,
+    pub price: f64,
+    pub size: i64,
+}
+
+// Integrity violations found while applying deltas, any violation means the book no longer
+// mirrors the exchange and a fresh partial is needed
+#[derive(Debug, Clone, PartialEq)]
+pub enum IntegrityViolation {
+    // update for an id that was never inserted
+    UnknownUpdate { id: i64 },
+    // delete for an id that was never inserted
+    UnknownDelete { id: i64 },
+    // insert for an id that is already in the book
+    DuplicateInsert { id: i64 },
+    // insert at the price of another level of the same side
+    DuplicatePrice { id: i64 },
+    // delta with a side that does not match the side of the level
+    SideMismatch { id: i64 },
+    // insert or update with a size of zero
+    ZeroSize { id: i64 },
+    // invalid side value in a delta
+    InvalidSide { id: i64 },
+    // best bid at or above best ask after applying a message
+    CrossedBook { best_bid: f64, best_ask: f64 },
+}
+
+// Best bid and ask of the book
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct TopOfBook {
+    pub bid: Option<Level>,
+    pub ask: Option<Level>,
+}
+
+// Events derived from the messages applied to the book. The seq of an event is the number of
+// messages that changed the book so far, including the message that triggered it. Messages which
+// leave the book unchanged (other symbols or tables, deltas while the book is invalid, deltas
+// rejected at their first entry) do not advance the seq, so consecutive seqs have no gaps.
+#[derive(Debug, Clone, PartialEq)]
+pub enum BookEvent {
+    // book was reset from a partial
+    Snapshot,
+    Violation(IntegrityViolation),
+    // book is corrupted and auto resubscribe is enabled, the topic should be resubscribed to get
+    // a fresh partial (see BitmexMdHandler::get_resubscribe_requests)
+    ResubscribeRequired {
+        topic: String,
+    },
+    // best bid or ask price or size changed
+    TopOfBookChanged {
+        old: TopOfBook,
+        new: TopOfBook,
+        seq: u64,
+    },
+    LevelAdded {
+        new: Level,
+        seq: u64,
+    },
+    LevelRemoved {
+        old: Level,
+        seq: u64,
+    },
+    LevelResized {
+        old: Level,
+        new: Level,
+        seq: u64,
+    },
+}
+
+// f64 price used as a key of the price ordered side maps
+#[derive(Debug, Clone, Copy)]
+struct PriceKey(f64);
+
+impl PartialEq for PriceKey {
+    fn eq(&self, other: &Self) -> bool {
+        self.cmp(other) == Ordering::Equal
+    }
+}
+
+impl Eq for PriceKey {}
+
+impl PartialOrd for PriceKey {
+    fn partial_cmp(&self, other: &Self) -> Option<Ordering> {
+        Some(self.cmp(other))
+    }
+}
+
+impl Ord for PriceKey {
+    fn cmp(&self, other: &Self) -> Ordering {
+        self.0.total_cmp(&other.0)
+    }
+}
+
+// L2 order book for one symbol maintained from the orderBookL2 partial, insert, update and delete
+// messages. Each delta is checked against the state of the book, deltas are ignored after a
+// violation until the next partial. The rows of the messages given by the Parser are matched on
+// the instrument id, the symbol is only compared for messages parsed with parse.
+pub struct OrderBook {
+    instrument: InstrumentId,
+    symbol: String,
+    levels: HashMap<i64, Level>,
+    // price to level id for each side
+    bids: BTreeMap<PriceKey, i64>,
+    asks: BTreeMap<PriceKey, i64>,
+    is_valid: bool,
+    auto_resubscribe: bool,
+    price_id_map: Option<PriceIdMap>,
+    // level events are only emitted for levels within this many levels of the top of the book
+    event_depth: usize,
+    // number of messages that changed the book
+    seq: u64,
+    // a level was added, resized or removed by the message being applied
+    changed: bool,
+}
+
+impl OrderBook {
+    // book of a symbol, interned in the registry used to parse the messages given to the book
+    pub fn new(symbols: &mut SymbolRegistry, symbol: &str) -> Self {
+        OrderBook {
+            instrument: symbols.intern(symbol),
+            symbol: String::from(symbol),
+            levels: HashMap::new(),
+            bids: BTreeMap::new(),
+            asks: BTreeMap::new(),
+            is_valid: false,
+            auto_resubscribe: false,
+            price_id_map: None,
+            event_depth: usize::MAX,
+            seq: 0,
+            changed: false,
+        }
+    }
+
+    // only emit level events for the first event_depth levels of each side
+    pub fn set_event_depth(&mut self, event_depth: usize) {
+        self.event_depth = event_depth;
+    }
+
+    // number of messages that changed the book, the seq of the events of the last change
+    pub fn seq(&self) -> u64 {
+        self.seq
+    }
+
+    pub fn top_of_book(&self) -> TopOfBook {
+        TopOfBook {
+            bid: self.best_bid().copied(),
+            ask: self.best_ask().copied(),
+        }
+    }
+
+    // id to price mapping of the instrument, used to resolve the price of ids not in the book
+    pub fn set_price_id_map(&mut self, price_id_map: PriceIdMap) {
+        self.price_id_map = Some(price_id_map);
+    }
+
+    // emit a ResubscribeRequired event when the book becomes invalid
+    pub fn set_auto_resubscribe(&mut self, auto_resubscribe: bool) {
+        self.auto_resubscribe = auto_resubscribe;
+    }
+
+    pub fn instrument(&self) -> InstrumentId {
+        self.instrument
+    }
+
+    pub fn symbol(&self) -> &str {
+        &self.symbol
+    }
+
+    // true once a partial was applied and no violation was found since
+    pub fn is_valid(&self) -> bool {
+        self.is_valid
+    }
+
+    pub fn level(&self, id: i64) -> Option<&Level> {
+        self.levels.get(&id)
+    }
+
+    // price of a level id (e.g. of an update or delete entry), taken from the level in the book or
+    // derived from the id if the instrument has an id to price mapping
+    pub fn price(&self, id: i64) -> Option<f64> {
+        match self.levels.get(&id) {
+            Some(level) => Some(level.price),
+            None => self.price_id_map.map(|map| map.price(id)),
+        }
+    }
+
+    pub fn best_bid(&self) -> Option<&Level> {
+        self.bids.values().next_back().map(|id| &self.levels[id])
+    }
+
+    pub fn best_ask(&self) -> Option<&Level> {
+        self.asks.values().next().map(|id| &self.levels[id])
+    }
+
+    // bid levels from best (highest) to worst price
+    pub fn bids(&self) -> impl Iterator<Item = &Level> {
+        self.bids.values().rev().map(move |id| &self.levels[id])
+    }
+
+    // ask levels from best (lowest) to worst price
+    pub fn asks(&self) -> impl Iterator<Item = &Level> {
+        self.asks.values().map(move |id| &self.levels[id])
+    }
+
+    pub fn clear(&mut self) {
+        self.levels.clear();
+        self.bids.clear();
+        self.asks.clear();
+        self.is_valid = false;
+    }
+
+    // apply a parsed message to the book, messages for other tables or symbols are ignored
+    pub fn apply<F>(&mut self, message: &BitmexMessage, mut on_event: F)
+    where
+        F: FnMut(BookEvent),
+    {
+        let old_top = self.top_of_book();
+        self.changed = false;
+        match message {
+            BitmexMessage::Snapshot(snapshot) => {
+                if snapshot.filter.symbol != self.symbol {
+                    return;
+                }
+                self.clear();
+                self.is_valid = true;
+                self.changed = true;
+                on_event(BookEvent::Snapshot);
+                // no level events for the levels of a partial
+                self.apply_inserts(&snapshot.data, &mut |event| match event {
+                    BookEvent::LevelAdded { .. } => {}
+                    _ => on_event(event),
+                });
+            }
+            BitmexMessage::Insert(insert) if self.is_valid => {
+                self.apply_inserts(&insert.data, &mut on_event);
+            }
+            BitmexMessage::Update(update) if self.is_valid => {
+                self.apply_updates(&update.data, &mut on_event);
+            }
+            BitmexMessage::Delete(delete) if self.is_valid => {
+                self.apply_deletes(&delete.data, &mut on_event);
+            }
+            _ => return,
+        }
+        self.end_message(old_top, &mut on_event);
+    }
+
+    // apply a message given by the Parser, the rows of other instruments are ignored
+    pub fn apply_ref<F>(&mut self, message: &BitmexMessageRef, mut on_event: F)
+    where
+        F: FnMut(BookEvent),
+    {
+        let entries = match message {
+            BitmexMessageRef::Message(message) => return self.apply(message, on_event),
+            BitmexMessageRef::Insert(entries)
+            | BitmexMessageRef::Update(entries)
+            | BitmexMessageRef::Delete(entries) => *entries,
+            BitmexMessageRef::Trade(_) => return,
+        };
+        if !self.is_valid {
+            return;
+        }
+        let old_top = self.top_of_book();
+        self.changed = false;
+        for entry in entries.iter() {
+            if entry.instrument != self.instrument {
+                continue;
+            }
+            let result = match message {
+                BitmexMessageRef::Insert(_) => self.apply_insert(entry, &mut on_event),
+                BitmexMessageRef::Update(_) => self.apply_update(entry, &mut on_event),
+                _ => self.delete_level(entry.id, Some(entry.side), &mut on_event),
+            };
+            if let Err(violation) = result {
+                self.violation(violation, &mut on_event);
+                break;
+            }
+        }
+        self.end_message(old_top, &mut on_event);
+    }
+
+    fn end_message<F: FnMut(BookEvent)>(&mut self, old_top: TopOfBook, on_event: &mut F) {
+        if !self.changed {
+            return;
+        }
+        self.seq += 1;
+        self.check_crossed(on_event);
+
+        let new_top = self.top_of_book();
+        if self.is_valid && new_top != old_top {
+            on_event(BookEvent::TopOfBookChanged {
+                old: old_top,
+                new: new_top,
+                seq: self.seq,
+            });
+        }
+    }
+
+    fn apply_inserts<F: FnMut(BookEvent)>(&mut self, entries: &[InsertEntry], on_event: &mut F) {
+        for entry in entries.iter() {
+            if entry.symbol != self.symbol {
+                continue;
+            }
+            let side = Side::from_bitmex(&entry.side);
+            if let Err(violation) =
+                self.insert_level(entry.id, side, entry.size, entry.price, on_event)
+            {
+                return self.violation(violation, on_event);
+            }
+        }
+    }
+
+    fn apply_updates<F: FnMut(BookEvent)>(&mut self, entries: &[UpdateEntry], on_event: &mut F) {
+        for entry in entries.iter() {
+            if entry.symbol != self.symbol {
+                continue;
+            }
+            let side = Side::from_bitmex(&entry.side);
+            if let Err(violation) = self.update_level(entry.id, side, entry.size, on_event) {
+                return self.violation(violation, on_event);
+            }
+        }
+    }
+
+    fn apply_deletes<F: FnMut(BookEvent)>(&mut self, entries: &[DeleteEntry], on_event: &mut F) {
+        for entry in entries.iter() {
+            if entry.symbol != self.symbol {
+                continue;
+            }
+            let side = Side::from_bitmex(&entry.side);
+            if let Err(violation) = self.delete_level(entry.id, side, on_event) {
+                return self.violation(violation, on_event);
+            }
+        }
+    }
+
+    // the L2Decoder only gives inserts with a size and a price and updates with a size
+    fn apply_insert<F: FnMut(BookEvent)>(
+        &mut self,
+        entry: &L2Entry,
+        on_event: &mut F,
+    ) -> Result<(), IntegrityViolation> {
+        let size = entry.size.unwrap_or_default();
+        let price = entry.price.unwrap_or_default();
+        self.insert_level(entry.id, Some(entry.side), size, price, on_event)
+    }
+
+    fn apply_update<F: FnMut(BookEvent)>(
+        &mut self,
+        entry: &L2Entry,
+        on_event: &mut F,
+    ) -> Result<(), IntegrityViolation> {
+        let size = entry.size.unwrap_or_default();
+        self.update_level(entry.id, Some(entry.side), size, on_event)
+    }
+
+    fn insert_level<F: FnMut(BookEvent)>(
+        &mut self,
+        id: i64,
+        side: Option<Side>,
+        size: i64,
+        price: f64,
+        on_event: &mut F,
+    ) -> Result<(), IntegrityViolation> {
+        let side = side.ok_or(IntegrityViolation::InvalidSide { id })?;
+        if self.levels.contains_key(&id) {
+            return Err(IntegrityViolation::DuplicateInsert { id });
+        }
+        if size == 0 {
+            return Err(IntegrityViolation::ZeroSize { id });
+        }
+        if self.side(side).contains_key(&PriceKey(price)) {
+            return Err(IntegrityViolation::DuplicatePrice { id });
+        }
+        let level = Level {
+            id,
+            side,
+            price,
+            size,
+        };
+        self.side_mut(side).insert(PriceKey(price), id);
+        self.levels.insert(id, level);
+        // the seq is advanced once the whole message is applied (see end_message)
+        self.changed = true;
+        if self.within_event_depth(&level) {
+            on_event(BookEvent::LevelAdded {
+                new: level,
+                seq: self.seq + 1,
+            });
+        }
+        Ok(())
+    }
+
+    fn update_level<F: FnMut(BookEvent)>(
+        &mut self,
+        id: i64,
+        side: Option<Side>,
+        size: i64,
+        on_event: &mut F,
+    ) -> Result<(), IntegrityViolation> {
+        let old = match self.levels.get(&id) {
+            Some(level) => *level,
+            None => return Err(IntegrityViolation::UnknownUpdate { id }),
+        };
+        if side != Some(old.side) {
+            return Err(IntegrityViolation::SideMismatch { id });
+        }
+        if size == 0 {
+            return Err(IntegrityViolation::ZeroSize { id });
+        }
+        let new = Level { size, ..old };
+        self.levels.insert(id, new);
+        self.changed = true;
+        if self.within_event_depth(&new) {
+            on_event(BookEvent::LevelResized {
+                old,
+                new,
+                seq: self.seq + 1,
+            });
+        }
+        Ok(())
+    }
+
+    fn delete_level<F: FnMut(BookEvent)>(
+        &mut self,
+        id: i64,
+        side: Option<Side>,
+        on_event: &mut F,
+    ) -> Result<(), IntegrityViolation> {
+        let level = match self.levels.get(&id) {
+            Some(level) => *level,
+            None => return Err(IntegrityViolation::UnknownDelete { id }),
+        };
+        if side != Some(level.side) {
+            return Err(IntegrityViolation::SideMismatch { id });
+        }
+        let notify = self.within_event_depth(&level);
+        self.levels.remove(&id);
+        self.side_mut(level.side).remove(&PriceKey(level.price));
+        self.changed = true;
+        if notify {
+            on_event(BookEvent::LevelRemoved {
+                old: level,
+                seq: self.seq + 1,
+            });
+        }
+        Ok(())
+    }
+
+    fn check_crossed<F: FnMut(BookEvent)>(&mut self, on_event: &mut F) {
+        if !self.is_valid {
+            return;
+        }
+        if let (Some(bid), Some(ask)) = (self.best_bid(), self.best_ask()) {
+            if bid.price >= ask.price {
+                let violation = IntegrityViolation::CrossedBook {
+                    best_bid: bid.price,
+                    best_ask: ask.price,
+                };
+                self.violation(violation, on_event);
+            }
+        }
+    }
+
+    fn violation<F: FnMut(BookEvent)>(&mut self, violation: IntegrityViolation, on_event: &mut F) {
+        self.is_valid = false;
+        on_event(BookEvent::Violation(violation));
+        if self.auto_resubscribe {
+            on_event(BookEvent::ResubscribeRequired {
+                topic: String::from("orderBookL2:") + &self.symbol,
+            });
+        }
+    }
+
+    // true if the level is within the first event_depth levels of its side
+    fn within_event_depth(&self, level: &Level) -> bool {
+        if self.event_depth == usize::MAX {
+            return true;
+        }
+        let key = PriceKey(level.price);
+        let better_levels = match level.side {
+            Side::Buy => self
+                .bids
+                .range((Bound::Excluded(key), Bound::Unbounded))
+                .count(),
+            Side::Sell => self.asks.range(..key).count(),
+        };
+        better_levels < self.event_depth
+    }
+
+    fn side(&self, side: Side) -> &BTreeMap<PriceKey, i64> {
+        match side {
+            Side::Buy => &self.bids,
+            Side::Sell => &self.asks,
+        }
+    }
+
+    fn side_mut(&mut self, side: Side) -> &mut BTreeMap<PriceKey, i64> {
+        match side {
+            Side::Buy => &mut self.bids,
+            Side::Sell => &mut self.asks,
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::bitmex_message::parse;
+    use crate::fixtures::{DELETE, SNAPSHOT, TOP_UPDATE, TRADE};
+    use crate::instrument::{PriceIdMap, SymbolRegistry};
+    use crate::normalized::Side;
+    use crate::order_book::{BookEvent, IntegrityViolation, Level, OrderBook, TopOfBook};
+    use crate::parser::Parser;
+
+    fn apply(book: &mut OrderBook, text: &[u8]) -> Vec<BookEvent> {
+        let mut events = vec![];
+        book.apply(&parse(text).ok().unwrap(), |event| events.push(event));
+        events
+    }
+
+    #[test]
+    fn apply_snapshot_and_deltas() {
+        let mut book = OrderBook::new(&mut SymbolRegistry::new(), "XBTUSD");
+        let best_bid = Level {
+            id: 8799070950,
+            side: Side::Buy,
+            price: 9290.5,
+            size: 1023444,
+        };
+        let best_ask = Level {
+            id: 8799070900,
+            side: Side::Sell,
+            price: 9291.0,
+            size: 1186665,
+        };
+        let events = apply(&mut book, SNAPSHOT);
+        assert_eq!(
+            events,
+            vec![
+                BookEvent::Snapshot,
+                BookEvent::TopOfBookChanged {
+                    old: TopOfBook {
+                        bid: None,
+                        ask: None
+                    },
+                    new: TopOfBook {
+                        bid: Some(best_bid),
+                        ask: Some(best_ask)
+                    },
+                    seq: 1,
+                }
+            ]
+        );
+        assert!(book.is_valid());
+        assert_eq!(book.bids().count(), 5);
+        assert_eq!(book.asks().count(), 9);
+
+        let events = apply(&mut book, b"{\"table\":\"orderBookL2\",\"action\":\"update\",\"data\":[{\"symbol\":\"XBTUSD\",\"id\":8799070950,\"side\":\"Buy\",\"size\":5}]}");
+        let resized_bid = Level {
+            size: 5,
+            ..best_bid
+        };
+        assert_eq!(
+            events,
+            vec![
+                BookEvent::LevelResized {
+                    old: best_bid,
+                    new: resized_bid,
+                    seq: 2
+                },
+                BookEvent::TopOfBookChanged {
+                    old: TopOfBook {
+                        bid: Some(best_bid),
+                        ask: Some(best_ask)
+                    },
+                    new: TopOfBook {
+                        bid: Some(resized_bid),
+                        ask: Some(best_ask)
+                    },
+                    seq: 2,
+                }
+            ]
+        );
+
+        let events = apply(&mut book, b"{\"table\":\"orderBookL2\",\"action\":\"delete\",\"data\":[{\"symbol\":\"XBTUSD\",\"id\":8799070900,\"side\":\"Sell\"}]}");
+        let next_ask = Level {
+            id: 8799070850,
+            side: Side::Sell,
+            price: 9291.5,
+            size: 832,
+        };
+        assert_eq!(
+            events,
+            vec![
+                BookEvent::LevelRemoved {
+                    old: best_ask,
+                    seq: 3
+                },
+                BookEvent::TopOfBookChanged {
+                    old: TopOfBook {
+                        bid: Some(resized_bid),
+                        ask: Some(best_ask)
+                    },
+                    new: TopOfBook {
+                        bid: Some(resized_bid),
+                        ask: Some(next_ask)
+                    },
+                    seq: 3,
+                }
+            ]
+        );
+
+        let events = apply(&mut book, b"{\"table\":\"orderBookL2\",\"action\":\"insert\",\"data\":[{\"symbol\":\"XBTUSD\",\"id\":8799070900,\"side\":\"Buy\",\"size\":7,\"price\":9291}]}");
+        let new_bid = Level {
+            id: 8799070900,
+            side: Side::Buy,
+            price: 9291.0,
+            size: 7,
+        };
+        assert_eq!(
+            events,
+            vec![
+                BookEvent::LevelAdded {
+                    new: new_bid,
+                    seq: 4
+                },
+                BookEvent::TopOfBookChanged {
+                    old: TopOfBook {
+                        bid: Some(resized_bid),
+                        ask: Some(next_ask)
+                    },
+                    new: TopOfBook {
+                        bid: Some(new_bid),
+                        ask: Some(next_ask)
+                    },
+                    seq: 4,
+                }
+            ]
+        );
+        assert!(book.is_valid());
+    }
+
+    #[test]
+    fn seq_of_changes() {
+        let mut symbols = SymbolRegistry::new();
+        let mut book = OrderBook::new(&mut symbols, "XBTUSD");
+        apply(&mut book, SNAPSHOT);
+        assert_eq!(book.seq(), 1);
+
+        // messages which leave the book unchanged do not advance the seq
+        assert!(apply(&mut book, TRADE).is_empty());
+        assert!(apply(&mut book, b"{\"table\":\"orderBookL2\",\"action\":\"update\",\"data\":[{\"symbol\":\"ETHUSD\",\"id\":1,\"side\":\"Buy\",\"size\":1}]}").is_empty());
+        let mut parser = Parser::new();
+        book.apply_ref(&parser.parse(TRADE, &mut symbols).unwrap(), |_| {
+            panic!("no event")
+        });
+        assert_eq!(book.seq(), 1);
+
+        let events = apply(&mut book, b"{\"table\":\"orderBookL2\",\"action\":\"update\",\"data\":[{\"symbol\":\"XBTUSD\",\"id\":8799071150,\"side\":\"Buy\",\"size\":5}]}");
+        assert_eq!(
+            events,
+            vec![BookEvent::LevelResized {
+                old: Level {
+                    id: 8799071150,
+                    side: Side::Buy,
+                    price: 9288.5,
+                    size: 2113
+                },
+                new: Level {
+                    id: 8799071150,
+                    side: Side::Buy,
+                    price: 9288.5,
+                    size: 5
+                },
+                seq: 2
+            }]
+        );
+
+        // a delta rejected at its first entry is no change, the book is invalid until the next
+        // partial
+        apply(&mut book, DELETE);
+        assert!(apply(&mut book, TOP_UPDATE).is_empty());
+        assert_eq!(book.seq(), 2);
+        apply(&mut book, SNAPSHOT);
+        assert_eq!(book.seq(), 3);
+    }
+
+    #[test]
+    fn unknown_update_and_delete() {
+        let mut book = OrderBook::new(&mut SymbolRegistry::new(), "XBTUSD");
+        apply(&mut book, SNAPSHOT);
+        let events = apply(&mut book, b"{\"table\":\"orderBookL2\",\"action\":\"update\",\"data\":[{\"symbol\":\"XBTUSD\",\"id\":8799065200,\"side\":\"Sell\",\"size\":182112}]}");
+        assert_eq!(
+            events,
+            vec![BookEvent::Violation(IntegrityViolation::UnknownUpdate {
+                id: 8799065200
+            })]
+        );
+        assert!(!book.is_valid());
+
+        // deltas are ignored until the next partial
+        let events = apply(&mut book, DELETE);
+        assert!(events.is_empty());
+
+        apply(&mut book, SNAPSHOT);
+        assert!(book.is_valid());
+        let events = apply(&mut book, DELETE);
+        assert_eq!(
+            events,
+            vec![BookEvent::Violation(IntegrityViolation::UnknownDelete {
+                id: 8799594200
+            })]
+        );
+    }
+
+    #[test]
+    fn crossed_book_and_zero_size() {
+        let mut book = OrderBook::new(&mut SymbolRegistry::new(), "XBTUSD");
+        book.set_auto_resubscribe(true);
+        apply(&mut book, SNAPSHOT);
+        let events = apply(&mut book, b"{\"table\":\"orderBookL2\",\"action\":\"insert\",\"data\":[{\"symbol\":\"XBTUSD\",\"id\":8799070850,\"side\":\"Buy\",\"size\":10,\"price\":9292}]}");
+        assert_eq!(
+            events,
+            vec![
+                BookEvent::Violation(IntegrityViolation::DuplicateInsert { id: 8799070850 }),
+                BookEvent::ResubscribeRequired {
+                    topic: String::from("orderBookL2:XBTUSD")
+                }
+            ]
+        );
+
+        apply(&mut book, SNAPSHOT);
+        let events = apply(&mut book, b"{\"table\":\"orderBookL2\",\"action\":\"insert\",\"data\":[{\"symbol\":\"XBTUSD\",\"id\":8799070000,\"side\":\"Buy\",\"size\":10,\"price\":9300}]}");
+        assert_eq!(
+            events[1],
+            BookEvent::Violation(IntegrityViolation::CrossedBook {
+                best_bid: 9300.0,
+                best_ask: 9291.0
+            })
+        );
+
+        apply(&mut book, SNAPSHOT);
+        let events = apply(&mut book, b"{\"table\":\"orderBookL2\",\"action\":\"update\",\"data\":[{\"symbol\":\"XBTUSD\",\"id\":8799070950,\"side\":\"Buy\",\"size\":0}]}");
+        assert_eq!(
+            events[0],
+            BookEvent::Violation(IntegrityViolation::ZeroSize { id: 8799070950 })
+        );
+    }
+
+    #[test]
+    fn duplicate_price() {
+        let mut book = OrderBook::new(&mut SymbolRegistry::new(), "XBTUSD");
+        apply(&mut book, SNAPSHOT);
+        let events = apply(&mut book, b"{\"table\":\"orderBookL2\",\"action\":\"insert\",\"data\":[{\"symbol\":\"XBTUSD\",\"id\":8799070000,\"side\":\"Sell\",\"size\":10,\"price\":9292}]}");
+        assert_eq!(
+            events,
+            vec![BookEvent::Violation(IntegrityViolation::DuplicatePrice {
+                id: 8799070000
+            })]
+        );
+        // the level at that price is untouched
+        assert_eq!(book.asks().nth(2).unwrap().id, 8799070800);
+        assert!(book.level(8799070000).is_none());
+    }
+
+    #[test]
+    fn price_of_id() {
+        let mut book = OrderBook::new(&mut SymbolRegistry::new(), "XBTUSD");
+        apply(&mut book, SNAPSHOT);
+        assert_eq!(book.price(8799070500), Some(9295.0));
+        assert_eq!(book.price(8799065200), None);
+
+        book.set_price_id_map(PriceIdMap::new(88, 0.01));
+        assert_eq!(book.price(8799065200), Some(9348.0));
+    }
+
+    #[test]
+    fn top_of_book_and_level_events() {
+        let mut book = OrderBook::new(&mut SymbolRegistry::new(), "XBTUSD");
+        let events = apply(&mut book, SNAPSHOT);
+        let best_bid = Level {
+            id: 8799070950,
+            side: Side::Buy,
+            price: 9290.5,
+            size: 1023444,
+        };
+        let best_ask = Level {
+            id: 8799070900,
+            side: Side::Sell,
+            price: 9291.0,
+            size: 1186665,
+        };
+        assert_eq!(
+            events[1],
+            BookEvent::TopOfBookChanged {
+                old: TopOfBook {
+                    bid: None,
+                    ask: None
+                },
+                new: TopOfBook {
+                    bid: Some(best_bid),
+                    ask: Some(best_ask)
+                },
+                seq: 1,
+            }
+        );
+
+        let events = apply(&mut book, b"{\"table\":\"orderBookL2\",\"action\":\"update\",\"data\":[{\"symbol\":\"XBTUSD\",\"id\":8799070950,\"side\":\"Buy\",\"size\":5}]}");
+        let new_bid = Level {
+            size: 5,
+            ..best_bid
+        };
+        assert_eq!(
+            events,
+            vec![
+                BookEvent::LevelResized {
+                    old: best_bid,
+                    new: new_bid,
+                    seq: 2
+                },
+                BookEvent::TopOfBookChanged {
+                    old: TopOfBook {
+                        bid: Some(best_bid),
+                        ask: Some(best_ask)
+                    },
+                    new: TopOfBook {
+                        bid: Some(new_bid),
+                        ask: Some(best_ask)
+                    },
+                    seq: 2,
+                }
+            ]
+        );
+
+        // level events only for the first 2 levels of each side
+        book.set_event_depth(2);
+        let events = apply(&mut book, b"{\"table\":\"orderBookL2\",\"action\":\"delete\",\"data\":[{\"symbol\":\"XBTUSD\",\"id\":8799071150,\"side\":\"Buy\"},{\"symbol\":\"XBTUSD\",\"id\":8799071000,\"side\":\"Buy\"}]}");
+        assert_eq!(
+            events,
+            vec![BookEvent::LevelRemoved {
+                old: Level {
+                    id: 8799071000,
+                    side: Side::Buy,
+                    price: 9290.0,
+                    size: 23490
+                },
+                seq: 3
+            }]
+        );
+
+        let events = apply(&mut book, b"{\"table\":\"orderBookL2\",\"action\":\"insert\",\"data\":[{\"symbol\":\"XBTUSD\",\"id\":8799070450,\"side\":\"Sell\",\"size\":1,\"price\":9295.5}]}");
+        assert!(events.is_empty());
+        assert_eq!(book.seq(), 4);
+    }
+
+    #[test]
+    fn apply_parser_views() {
+        let mut parser = Parser::new();
+        let mut symbols = SymbolRegistry::new();
+        let mut book = OrderBook::new(&mut symbols, "XBTUSD");
+        let mut expected_book = OrderBook::new(&mut symbols, "XBTUSD");
+        let messages: [&[u8]; 7] = [
+            SNAPSHOT,
+            b"{\"table\":\"orderBookL2\",\"action\":\"update\",\"data\":[{\"symbol\":\"XBTUSD\",\"id\":8799070950,\"side\":\"Buy\",\"size\":5},{\"symbol\":\"ETHUSD\",\"id\":1,\"side\":\"Buy\",\"size\":1}]}",
+            b"{\"table\":\"orderBookL2\",\"action\":\"delete\",\"data\":[{\"symbol\":\"XBTUSD\",\"id\":8799070900,\"side\":\"Sell\"}]}",
+            b"{\"table\":\"orderBookL2\",\"action\":\"insert\",\"data\":[{\"symbol\":\"XBTUSD\",\"id\":8799070900,\"side\":\"Buy\",\"size\":7,\"price\":9291}]}",
+            TRADE,
+            b"{\"table\":\"orderBookL2\",\"action\":\"insert\",\"data\":[{\"symbol\":\"XBTUSD\",\"id\":8799070850,\"side\":\"Sell\",\"size\":1,\"price\":9291.5}]}",
+            b"{\"table\":\"orderBookL2\",\"action\":\"update\",\"data\":[{\"symbol\":\"XBTUSD\",\"id\":8799070950,\"side\":\"Buy\",\"size\":6}]}",
+        ];
+        for message in messages.iter() {
+            let mut events = vec![];
+            book.apply_ref(&parser.parse(message, &mut symbols).unwrap(), |event| {
+                events.push(event)
+            });
+            assert_eq!(events, apply(&mut expected_book, message));
+            assert_eq!(book.seq(), expected_book.seq());
+        }
+        assert_eq!(book.top_of_book(), expected_book.top_of_book());
+        // the insert of a level already in the book invalidated both books
+        assert!(!book.is_valid());
+        assert_eq!(symbols.symbol(book.instrument()), Some("XBTUSD"));
+    }
+}
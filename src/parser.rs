@@ -0,0 +1,386 @@
+use crate::bitmex_message::{parse, parse_timestamp, parse_uuid, BitmexMessage, ParseError};
+use crate::instrument::{InstrumentId, SymbolRegistry};
+use crate::json_scanner::{FieldSet, ScanError, Scanner};
+use crate::l2_decoder::{L2Decoder, L2Entry};
+use crate::normalized::{DeltaAction, Side};
+
+const TRADE_PREFIX: &[u8] = b"{\"table\":\"trade\",\"action\":\"insert\",\"data\":";
+
+// fields of a trade row
+const TIMESTAMP_FIELD: u32 = 1;
+const SYMBOL_FIELD: u32 = 2;
+const SIDE_FIELD: u32 = 4;
+const SIZE_FIELD: u32 = 8;
+const PRICE_FIELD: u32 = 16;
+const TICK_DIRECTION_FIELD: u32 = 32;
+const TRADE_ID_FIELD: u32 = 64;
+const GROSS_VALUE_FIELD: u32 = 128;
+const HOME_NOTIONAL_FIELD: u32 = 256;
+const FOREIGN_NOTIONAL_FIELD: u32 = 512;
+const TRADE_FIELDS: u32 = 1023;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum TickDirection {
+    PlusTick,
+    ZeroPlusTick,
+    MinusTick,
+    ZeroMinusTick,
+}
+
+impl TickDirection {
+    pub fn from_bitmex(tick_direction: &str) -> Option<TickDirection> {
+        match tick_direction {
+            "PlusTick" => Some(TickDirection::PlusTick),
+            "ZeroPlusTick" => Some(TickDirection::ZeroPlusTick),
+            "MinusTick" => Some(TickDirection::MinusTick),
+            "ZeroMinusTick" => Some(TickDirection::ZeroMinusTick),
+            _ => None,
+        }
+    }
+}
+
+// Row of a trade insert with the instrument id of its symbol, the timestamp in nanos since unix
+// epoch and the trade id as bytes
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct TradeRow {
+    pub instrument: InstrumentId,
+    pub timestamp: i64,
+    pub side: Side,
+    pub size: f64,
+    pub price: f64,
+    pub tick_direction: TickDirection,
+    pub trade_id: [u8; 16],
+    pub gross_value: f64,
+    pub home_notional: f64,
+    pub foreign_notional: f64,
+}
+
+// Message parsed by the Parser, the rows point into the buffers of the parser and are valid until
+// the next message
+#[derive(Debug)]
+pub enum BitmexMessageRef<'a> {
+    Insert(&'a [L2Entry]),
+    Update(&'a [L2Entry]),
+    Delete(&'a [L2Entry]),
+    Trade(&'a [TradeRow]),
+    // messages without a view (partials, info, subscribe) and the deltas or trades the scanners do
+    // not handle, parsed with bitmex_message::parse
+    Message(&'a BitmexMessage),
+}
+
+// Parser keeping its buffers from one message to the next. The orderBookL2 deltas and the trades
+// are scanned into reusable buffers of rows, so once the buffers have grown to the largest batch
+// the steady state stream is parsed without heap allocation. Other messages go through parse.
+// The symbols of the rows and of the filter of partials are interned in the registry given to
+// parse, e.g. the one of the handler (see SymbolRegistry).
+pub struct Parser {
+    l2: L2Decoder,
+    trades: Vec<TradeRow>,
+    message: Option<BitmexMessage>,
+}
+
+impl Default for Parser {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl Parser {
+    pub fn new() -> Self {
+        Parser {
+            l2: L2Decoder::new(),
+            trades: vec![],
+            message: None,
+        }
+    }
+
+    pub fn parse(
+        &mut self,
+        payload: &[u8],
+        symbols: &mut SymbolRegistry,
+    ) -> Result<BitmexMessageRef<'_>, ParseError> {
+        if payload.starts_with(TRADE_PREFIX) {
+            self.trades.clear();
+            if decode_trades(payload, &mut self.trades, symbols).is_ok() {
+                return Ok(BitmexMessageRef::Trade(&self.trades));
+            }
+            self.trades.clear();
+        } else if let Ok(action) = self.l2.decode(payload, symbols) {
+            let entries = self.l2.entries();
+            return Ok(match action {
+                DeltaAction::Insert => BitmexMessageRef::Insert(entries),
+                DeltaAction::Delete => BitmexMessageRef::Delete(entries),
+                _ => BitmexMessageRef::Update(entries),
+            });
+        }
+        let message = self.message.insert(parse(payload)?);
+        match message {
+            BitmexMessage::Snapshot(snapshot) => {
+                symbols.add_filter(&snapshot.filter);
+            }
+            BitmexMessage::TradeSnapshot(snapshot) => {
+                symbols.add_filter(&snapshot.filter);
+            }
+            _ => {}
+        }
+        Ok(BitmexMessageRef::Message(message))
+    }
+}
+
+fn decode_trades(
+    message: &[u8],
+    trades: &mut Vec<TradeRow>,
+    symbols: &mut SymbolRegistry,
+) -> Result<(), ScanError> {
+    let mut scanner = Scanner::new(message, TRADE_PREFIX.len());
+    scanner.array(|scanner| {
+        trades.push(trade_row(scanner, symbols)?);
+        Ok(())
+    })?;
+    scanner.end_object()
+}
+
+fn trade_row(scanner: &mut Scanner, symbols: &mut SymbolRegistry) -> Result<TradeRow, ScanError> {
+    // the instrument is only known once the symbol field is read
+    let mut instrument: Option<InstrumentId> = None;
+    let mut timestamp = 0;
+    let mut side = Side::Buy;
+    let mut size = 0.0;
+    let mut price = 0.0;
+    let mut tick_direction = TickDirection::PlusTick;
+    let mut trade_id = [0; 16];
+    let mut gross_value = 0.0;
+    let mut home_notional = 0.0;
+    let mut foreign_notional = 0.0;
+    let mut fields = FieldSet::default();
+    scanner.object(|scanner, key| {
+        match key {
+            b"timestamp" => {
+                fields.add(TIMESTAMP_FIELD)?;
+                timestamp = parse_timestamp(scanner.str()?).ok_or(ScanError::Unsupported)?;
+            }
+            b"symbol" => {
+                fields.add(SYMBOL_FIELD)?;
+                instrument = Some(symbols.intern(scanner.str()?));
+            }
+            b"side" => {
+                fields.add(SIDE_FIELD)?;
+                side = Side::from_bitmex(scanner.str()?).ok_or(ScanError::Unsupported)?;
+            }
+            b"size" => {
+                fields.add(SIZE_FIELD)?;
+                size = scanner.float()?;
+            }
+            b"price" => {
+                fields.add(PRICE_FIELD)?;
+                price = scanner.float()?;
+            }
+            b"tickDirection" => {
+                fields.add(TICK_DIRECTION_FIELD)?;
+                tick_direction =
+                    TickDirection::from_bitmex(scanner.str()?).ok_or(ScanError::Unsupported)?;
+            }
+            b"trdMatchID" => {
+                fields.add(TRADE_ID_FIELD)?;
+                trade_id = parse_uuid(scanner.str()?).ok_or(ScanError::Unsupported)?;
+            }
+            b"grossValue" => {
+                fields.add(GROSS_VALUE_FIELD)?;
+                gross_value = scanner.float()?;
+            }
+            b"homeNotional" => {
+                fields.add(HOME_NOTIONAL_FIELD)?;
+                home_notional = scanner.float()?;
+            }
+            b"foreignNotional" => {
+                fields.add(FOREIGN_NOTIONAL_FIELD)?;
+                foreign_notional = scanner.float()?;
+            }
+            _ => scanner.skip_value()?,
+        }
+        Ok(())
+    })?;
+    fields.require(TRADE_FIELDS)?;
+    Ok(TradeRow {
+        instrument: instrument.ok_or(ScanError::Invalid)?,
+        timestamp,
+        side,
+        size,
+        price,
+        tick_direction,
+        trade_id,
+        gross_value,
+        home_notional,
+        foreign_notional,
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::bitmex_message::{parse, parse_uuid, BitmexMessage};
+    use crate::fixtures::{DELETE, INFO, INSERT, TRADE, UPDATE};
+    use crate::instrument::SymbolRegistry;
+    use crate::normalized::Side;
+    use crate::parser::{BitmexMessageRef, Parser, TickDirection, TradeRow};
+    use proptest::prelude::*;
+
+    // whenever the trade scanner accepts a message, parse must give the same rows
+    fn check_trades_against_parse(
+        parser: &mut Parser,
+        symbols: &mut SymbolRegistry,
+        message: &[u8],
+    ) -> bool {
+        let trades: Vec<TradeRow> = match parser.parse(message, symbols) {
+            Ok(BitmexMessageRef::Trade(trades)) => trades.to_vec(),
+            _ => return false,
+        };
+        let entries = match parse(message) {
+            Ok(BitmexMessage::Trade(trade)) => trade.data,
+            _ => panic!("parse failed"),
+        };
+        assert_eq!(trades.len(), entries.len());
+        for (trade, entry) in trades.iter().zip(entries.iter()) {
+            assert_eq!(symbols.symbol(trade.instrument), Some(&entry.symbol[..]));
+            assert_eq!(Some(trade.timestamp), entry.timestamp_nanos());
+            assert_eq!(Some(trade.side), Side::from_bitmex(&entry.side));
+            assert_eq!(trade.size, entry.size);
+            assert_eq!(trade.price, entry.price);
+            let tick_direction = TickDirection::from_bitmex(&entry.tick_direction);
+            assert_eq!(Some(trade.tick_direction), tick_direction);
+            assert_eq!(Some(trade.trade_id), parse_uuid(&entry.trd_match_id));
+            assert_eq!(trade.gross_value, entry.gross_value);
+            assert_eq!(trade.home_notional, entry.home_notional);
+            assert_eq!(trade.foreign_notional, entry.foreign_notional);
+        }
+        true
+    }
+
+    #[test]
+    fn parse_views() {
+        let mut parser = Parser::new();
+        let mut symbols = SymbolRegistry::new();
+        let xbtusd = symbols.intern("XBTUSD");
+        match parser.parse(TRADE, &mut symbols).unwrap() {
+            BitmexMessageRef::Trade(trades) => {
+                assert_eq!(trades.len(), 1);
+                let trade = &trades[0];
+                let expected = match parse(TRADE).unwrap() {
+                    BitmexMessage::Trade(message) => message.data.into_iter().next().unwrap(),
+                    _ => panic!("wrong message type"),
+                };
+                assert_eq!(expected.symbol, "XBTUSD");
+                assert_eq!(trade.instrument, xbtusd);
+                assert_eq!(Some(trade.timestamp), expected.timestamp_nanos());
+                assert_eq!(trade.side, Side::Sell);
+                assert_eq!(trade.size, expected.size);
+                assert_eq!(trade.price, expected.price);
+                assert_eq!(trade.tick_direction, TickDirection::ZeroMinusTick);
+                assert_eq!(Some(trade.trade_id), parse_uuid(&expected.trd_match_id));
+                assert_eq!(trade.gross_value, expected.gross_value);
+                assert_eq!(trade.home_notional, expected.home_notional);
+                assert_eq!(trade.foreign_notional, expected.foreign_notional);
+            }
+            _ => panic!("wrong message type"),
+        }
+        match parser.parse(UPDATE, &mut symbols).unwrap() {
+            BitmexMessageRef::Update(entries) => {
+                assert_eq!(entries.len(), 2);
+                assert_eq!(entries[0].instrument, xbtusd);
+                assert_eq!(entries[0].id, 8799065200);
+                assert_eq!(entries[1].size, Some(19575));
+            }
+            _ => panic!("wrong message type"),
+        }
+        assert!(matches!(
+            parser.parse(INSERT, &mut symbols).unwrap(),
+            BitmexMessageRef::Insert(_)
+        ));
+        assert!(matches!(
+            parser.parse(DELETE, &mut symbols).unwrap(),
+            BitmexMessageRef::Delete(_)
+        ));
+
+        // messages without a view and rows the scanners do not handle are parsed with parse
+        let info = INFO;
+        assert!(matches!(
+            parser.parse(info, &mut symbols).unwrap(),
+            BitmexMessageRef::Message(BitmexMessage::Info(_))
+        ));
+        let escaped = b"{\"table\":\"orderBookL2\",\"action\":\"delete\",\"data\":[{\"symbol\":\"XBT\\u0055SD\",\"id\":1,\"side\":\"Buy\"}]}";
+        match parser.parse(escaped, &mut symbols).unwrap() {
+            BitmexMessageRef::Message(BitmexMessage::Delete(delete)) => {
+                assert_eq!(delete.data[0].symbol, "XBTUSD")
+            }
+            _ => panic!("wrong message type"),
+        }
+        assert!(parser
+            .parse(&TRADE[..TRADE.len() - 1], &mut symbols)
+            .is_err());
+        assert!(parser.parse(b"", &mut symbols).is_err());
+        assert!(check_trades_against_parse(&mut parser, &mut symbols, TRADE));
+        assert_eq!(symbols.len(), 1);
+    }
+
+    #[test]
+    fn symbols_are_interned() {
+        let mut parser = Parser::new();
+        let mut symbols = SymbolRegistry::new();
+        // the symbol of a partial is registered even if the partial has no rows
+        let snapshot = b"{\"table\":\"orderBookL2\",\"action\":\"partial\",\"keys\":[\"symbol\",\"id\",\"side\"],\"types\":{\"symbol\":\"symbol\",\"id\":\"long\",\"side\":\"symbol\",\"size\":\"long\",\"price\":\"float\"},\"foreignKeys\":{\"symbol\":\"instrument\",\"side\":\"side\"},\"attributes\":{\"symbol\":\"parted\",\"id\":\"sorted\"},\"filter\":{\"symbol\":\"ETHUSD\"},\"data\":[]}";
+        parser.parse(snapshot, &mut symbols).unwrap();
+        let ethusd = symbols.id("ETHUSD").unwrap();
+
+        // symbols first seen in a delta get the next id
+        let xbtusd = match parser.parse(DELETE, &mut symbols).unwrap() {
+            BitmexMessageRef::Delete(entries) => entries[0].instrument,
+            _ => panic!("wrong message type"),
+        };
+        assert_ne!(xbtusd, ethusd);
+        match parser.parse(TRADE, &mut symbols).unwrap() {
+            BitmexMessageRef::Trade(trades) => assert_eq!(trades[0].instrument, xbtusd),
+            _ => panic!("wrong message type"),
+        }
+        assert_eq!(symbols.symbol(xbtusd), Some("XBTUSD"));
+        assert_eq!(symbols.len(), 2);
+    }
+
+    proptest! {
+        #[test]
+        fn trades_match_parse_on_corrupted_messages(
+            position in any::<prop::sample::Index>(),
+            byte in any::<u8>(),
+        ) {
+            let mut message = TRADE.to_vec();
+            let i = position.index(message.len());
+            message[i] = byte;
+            check_trades_against_parse(&mut Parser::new(), &mut SymbolRegistry::new(), &message);
+        }
+
+        // the scanners and parse give the same prices, to the last bit
+        #[test]
+        fn prices_match_parse(
+            price in "(0|[1-9][0-9]{0,8})(\\.[0-9]{1,20})?([eE][-+]?[0-9]{1,2})?",
+        ) {
+            let trade = String::from_utf8(TRADE.to_vec())
+                .unwrap()
+                .replace("9155.5", &price);
+            let mut parser = Parser::new();
+            let mut symbols = SymbolRegistry::new();
+            prop_assert!(check_trades_against_parse(&mut parser, &mut symbols, trade.as_bytes()));
+
+            let insert = String::from_utf8(INSERT.to_vec())
+                .unwrap()
+                .replace("18581.5", &price);
+            let expected = match parse(insert.as_bytes()) {
+                Ok(BitmexMessage::Insert(insert)) => insert.data[0].price,
+                _ => panic!("parse failed"),
+            };
+            match parser.parse(insert.as_bytes(), &mut symbols) {
+                Ok(BitmexMessageRef::Insert(entries)) => {
+                    prop_assert_eq!(entries[0].price.map(f64::to_bits), Some(expected.to_bits()))
+                }
+                _ => panic!("insert not scanned"),
+            }
+        }
+    }
+}
@@ -0,0 +1,662 @@
+use crate::bitmex_message::{parse, BitmexMessage, ParseError};
+use crate::ws_server::TEXT_OP_CODE;
+use llws::FrameAssembler;
+use std::collections::{HashMap, VecDeque};
+use std::fs::File;
+use std::io::{self, BufReader, Read};
+use std::net::{IpAddr, Ipv4Addr, Ipv6Addr, SocketAddr};
+use std::path::Path;
+
+// pcap file magic numbers as read in native (little endian) order
+const MAGIC_MICROS: u32 = 0xa1b2_c3d4;
+const MAGIC_NANOS: u32 = 0xa1b2_3c4d;
+
+// link layer types
+const LINKTYPE_NULL: u32 = 0;
+const LINKTYPE_ETHERNET: u32 = 1;
+const LINKTYPE_RAW: u32 = 101;
+const LINKTYPE_LINUX_SLL: u32 = 113;
+
+const ETHERTYPE_IPV4: u16 = 0x0800;
+const ETHERTYPE_IPV6: u16 = 0x86dd;
+const ETHERTYPE_VLAN: u16 = 0x8100;
+
+const IP_PROTOCOL_TCP: u8 = 6;
+
+const TCP_FLAG_FIN: u8 = 0x01;
+const TCP_FLAG_SYN: u8 = 0x02;
+const TCP_FLAG_RST: u8 = 0x04;
+
+// largest packet record accepted, whatever the snapshot length of the file says
+const MAX_SNAPLEN: u32 = 262_144;
+
+// out of order data buffered per flow before giving up on the missing segment and the flow
+const MAX_PENDING_BYTES: usize = 4 << 20;
+
+// Packet captured in a pcap file
+pub struct PcapPacket {
+    // capture time in nanos since unix epoch
+    pub timestamp: u64,
+    pub data: Vec<u8>,
+}
+
+// Reader of the classic pcap file format, micro and nano second resolution files in either
+// byte order are supported
+pub struct PcapReader<R: Read> {
+    reader: R,
+    big_endian: bool,
+    nanos: bool,
+    snaplen: u32,
+    link_type: u32,
+}
+
+impl PcapReader<BufReader<File>> {
+    pub fn open(path: &Path) -> io::Result<Self> {
+        PcapReader::new(BufReader::new(File::open(path)?))
+    }
+}
+
+impl<R: Read> PcapReader<R> {
+    pub fn new(mut reader: R) -> io::Result<Self> {
+        let mut header = [0u8; 24];
+        reader.read_exact(&mut header)?;
+        let magic = u32::from_le_bytes([header[0], header[1], header[2], header[3]]);
+        let (big_endian, nanos) = if magic == MAGIC_MICROS {
+            (false, false)
+        } else if magic == MAGIC_NANOS {
+            (false, true)
+        } else if magic.swap_bytes() == MAGIC_MICROS {
+            (true, false)
+        } else if magic.swap_bytes() == MAGIC_NANOS {
+            (true, true)
+        } else {
+            return Err(invalid_data("not a pcap file"));
+        };
+        let mut pcap = PcapReader {
+            reader,
+            big_endian,
+            nanos,
+            snaplen: 0,
+            link_type: 0,
+        };
+        // some writers leave the snapshot length unset
+        pcap.snaplen = match pcap.u32_at(&header, 16) {
+            0 => MAX_SNAPLEN,
+            snaplen => snaplen.min(MAX_SNAPLEN),
+        };
+        pcap.link_type = pcap.u32_at(&header, 20);
+        Ok(pcap)
+    }
+
+    pub fn link_type(&self) -> u32 {
+        self.link_type
+    }
+
+    // read the next packet into the given packet, the data buffer is reused. Returns false at the
+    // end of the file, a record larger than the snapshot length is an InvalidData error.
+    pub fn read_packet(&mut self, packet: &mut PcapPacket) -> io::Result<bool> {
+        let mut header = [0u8; 16];
+        match self.reader.read_exact(&mut header) {
+            Ok(()) => {}
+            Err(ref e) if e.kind() == io::ErrorKind::UnexpectedEof => return Ok(false),
+            Err(e) => return Err(e),
+        }
+        let seconds = self.u32_at(&header, 0) as u64;
+        let fraction = self.u32_at(&header, 4) as u64;
+        let captured_len = self.u32_at(&header, 8);
+        if captured_len > self.snaplen {
+            return Err(invalid_data("packet larger than the snapshot length"));
+        }
+        packet.timestamp = seconds * 1_000_000_000
+            + if self.nanos {
+                fraction
+            } else {
+                fraction * 1_000
+            };
+        packet.data.resize(captured_len as usize, 0);
+        self.reader.read_exact(&mut packet.data)?;
+        Ok(true)
+    }
+
+    fn u32_at(&self, buf: &[u8], offset: usize) -> u32 {
+        let bytes = [
+            buf[offset],
+            buf[offset + 1],
+            buf[offset + 2],
+            buf[offset + 3],
+        ];
+        if self.big_endian {
+            u32::from_be_bytes(bytes)
+        } else {
+            u32::from_le_bytes(bytes)
+        }
+    }
+}
+
+// TCP segment decoded from a captured packet
+struct TcpSegment<'a> {
+    source: SocketAddr,
+    destination: SocketAddr,
+    seq: u32,
+    flags: u8,
+    payload: &'a [u8],
+}
+
+fn decode_segment(link_type: u32, data: &[u8]) -> Option<TcpSegment<'_>> {
+    let ip = match link_type {
+        LINKTYPE_ETHERNET => {
+            let mut offset = 12;
+            let mut ether_type = u16::from_be_bytes([*data.get(offset)?, *data.get(offset + 1)?]);
+            if ether_type == ETHERTYPE_VLAN {
+                offset += 4;
+                ether_type = u16::from_be_bytes([*data.get(offset)?, *data.get(offset + 1)?]);
+            }
+            match ether_type {
+                ETHERTYPE_IPV4 | ETHERTYPE_IPV6 => data.get(offset + 2..)?,
+                _ => return None,
+            }
+        }
+        LINKTYPE_LINUX_SLL => data.get(16..)?,
+        LINKTYPE_NULL => data.get(4..)?,
+        LINKTYPE_RAW => data,
+        _ => return None,
+    };
+
+    let (source_ip, destination_ip, tcp): (IpAddr, IpAddr, &[u8]) = match ip.first()? >> 4 {
+        4 => {
+            let header_len = ((ip[0] & 0x0f) as usize) * 4;
+            if ip.len() < 20 || header_len < 20 || ip[9] != IP_PROTOCOL_TCP {
+                return None;
+            }
+            let total_len = u16::from_be_bytes([ip[2], ip[3]]) as usize;
+            let source = Ipv4Addr::new(ip[12], ip[13], ip[14], ip[15]);
+            let destination = Ipv4Addr::new(ip[16], ip[17], ip[18], ip[19]);
+            // ethernet frames can be padded beyond the ip packet
+            let end = total_len.min(ip.len());
+            (
+                IpAddr::V4(source),
+                IpAddr::V4(destination),
+                ip.get(header_len..end)?,
+            )
+        }
+        6 => {
+            // extension headers are not supported
+            if ip.len() < 40 || ip[6] != IP_PROTOCOL_TCP {
+                return None;
+            }
+            let payload_len = u16::from_be_bytes([ip[4], ip[5]]) as usize;
+            let mut source = [0u8; 16];
+            source.copy_from_slice(&ip[8..24]);
+            let mut destination = [0u8; 16];
+            destination.copy_from_slice(&ip[24..40]);
+            let end = (40 + payload_len).min(ip.len());
+            (
+                IpAddr::V6(Ipv6Addr::from(source)),
+                IpAddr::V6(Ipv6Addr::from(destination)),
+                ip.get(40..end)?,
+            )
+        }
+        _ => return None,
+    };
+
+    if tcp.len() < 20 {
+        return None;
+    }
+    let source_port = u16::from_be_bytes([tcp[0], tcp[1]]);
+    let destination_port = u16::from_be_bytes([tcp[2], tcp[3]]);
+    let seq = u32::from_be_bytes([tcp[4], tcp[5], tcp[6], tcp[7]]);
+    let data_offset = ((tcp[12] >> 4) as usize) * 4;
+    let flags = tcp[13];
+    Some(TcpSegment {
+        source: SocketAddr::new(source_ip, source_port),
+        destination: SocketAddr::new(destination_ip, destination_port),
+        seq,
+        flags,
+        payload: tcp.get(data_offset..)?,
+    })
+}
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+enum Handshake {
+    // no data seen on the stream yet
+    Unknown,
+    // skipping the http upgrade request or response headers
+    Headers,
+    // websocket frames follow
+    Done,
+}
+
+// One direction of a TCP connection carrying websocket frames
+struct Flow {
+    next_seq: Option<u32>,
+    // out of order segments waiting for the missing data
+    pending: Vec<(u32, Vec<u8>)>,
+    pending_bytes: usize,
+    // sequence number after the last byte of the stream, once the fin is seen
+    fin_seq: Option<u32>,
+    // a segment was not captured, the frame boundaries of the rest of the stream are unknown
+    lost: bool,
+    handshake: Handshake,
+    headers: Vec<u8>,
+    from_server: bool,
+    assembler: FrameAssembler,
+}
+
+impl Flow {
+    fn new() -> Self {
+        Flow {
+            next_seq: None,
+            pending: vec![],
+            pending_bytes: 0,
+            fin_seq: None,
+            lost: false,
+            handshake: Handshake::Unknown,
+            headers: vec![],
+            from_server: false,
+            assembler: FrameAssembler::new(),
+        }
+    }
+}
+
+// Websocket payload rebuilt from a capture, with the capture time of the packet that completed
+// the frame
+#[derive(Debug, Clone, PartialEq)]
+pub struct TimestampedPayload {
+    pub timestamp: u64,
+    pub source: SocketAddr,
+    pub destination: SocketAddr,
+    // true if the frame was sent by the server (i.e. market data)
+    pub from_server: bool,
+    pub op_code: u8,
+    pub payload: Vec<u8>,
+}
+
+// Imports websocket traffic from a pcap capture of BitMEX connections. The capture must hold the
+// plaintext of the connections: the TLS traffic of a wss connection on port 443 has to be
+// decrypted first (e.g. exported from wireshark with the session keys), encrypted records are not
+// detected and give garbage frames.
+// TCP streams are rebuilt from the captured segments, the http upgrade is skipped and the
+// websocket frames are reassembled with the FrameAssembler. A stream is forgotten once it is
+// closed (fin or rst), and a stream that misses a segment is not reported anymore as its frame
+// boundaries cannot be found again.
+pub struct PcapImporter<R: Read> {
+    reader: PcapReader<R>,
+    flows: HashMap<(SocketAddr, SocketAddr), Flow>,
+    ready: VecDeque<TimestampedPayload>,
+    packet: PcapPacket,
+}
+
+impl PcapImporter<BufReader<File>> {
+    pub fn open(path: &Path) -> io::Result<Self> {
+        Ok(PcapImporter::new(PcapReader::open(path)?))
+    }
+}
+
+impl<R: Read> PcapImporter<R> {
+    pub fn new(reader: PcapReader<R>) -> Self {
+        PcapImporter {
+            reader,
+            flows: HashMap::new(),
+            ready: VecDeque::new(),
+            packet: PcapPacket {
+                timestamp: 0,
+                data: vec![],
+            },
+        }
+    }
+
+    // next websocket payload of the capture, None at the end of the file
+    pub fn next_payload(&mut self) -> io::Result<Option<TimestampedPayload>> {
+        loop {
+            if let Some(payload) = self.ready.pop_front() {
+                return Ok(Some(payload));
+            }
+            if !self.reader.read_packet(&mut self.packet)? {
+                return Ok(None);
+            }
+            self.on_packet();
+        }
+    }
+
+    // feed the text frames sent by the server through the parser, the callback gets the capture
+    // time of the frame and the parse result. Returns the number of frames parsed.
+    pub fn run<F>(&mut self, mut on_message: F) -> io::Result<u64>
+    where
+        F: FnMut(u64, Result<BitmexMessage, ParseError>),
+    {
+        let mut count = 0;
+        while let Some(payload) = self.next_payload()? {
+            if payload.from_server && payload.op_code == TEXT_OP_CODE {
+                on_message(payload.timestamp, parse(&payload.payload));
+                count += 1;
+            }
+        }
+        Ok(count)
+    }
+
+    fn on_packet(&mut self) {
+        let timestamp = self.packet.timestamp;
+        let segment = match decode_segment(self.reader.link_type, &self.packet.data) {
+            Some(segment) => segment,
+            None => return,
+        };
+        let key = (segment.source, segment.destination);
+        if segment.flags & TCP_FLAG_RST != 0 {
+            self.flows.remove(&key);
+            return;
+        }
+        let flow = self.flows.entry(key).or_insert_with(Flow::new);
+        if segment.flags & TCP_FLAG_SYN != 0 {
+            // new connection, syn consumes one sequence number
+            *flow = Flow::new();
+            flow.next_seq = Some(segment.seq.wrapping_add(1));
+            return;
+        }
+        if flow.lost {
+            if segment.flags & TCP_FLAG_FIN != 0 {
+                self.flows.remove(&key);
+            }
+            return;
+        }
+        if segment.flags & TCP_FLAG_FIN != 0 {
+            // the fin follows the data of its segment
+            flow.fin_seq = Some(segment.seq.wrapping_add(segment.payload.len() as u32));
+        }
+        if segment.payload.is_empty() && flow.fin_seq.is_none() {
+            return;
+        }
+
+        let next_seq = *flow.next_seq.get_or_insert(segment.seq);
+        if !segment.payload.is_empty() {
+            flow.pending.push((segment.seq, segment.payload.to_vec()));
+            flow.pending_bytes += segment.payload.len();
+        }
+        if flow.pending_bytes > MAX_PENDING_BYTES {
+            // the missing segment was not captured, the data after it would be read from the
+            // middle of a frame
+            flow.lost = true;
+            flow.pending.clear();
+            flow.pending_bytes = 0;
+            return;
+        }
+        // deliver the buffered segments that continue the stream, drop retransmitted data
+        let mut next_seq = next_seq;
+        let source = segment.source;
+        let destination = segment.destination;
+        loop {
+            let position = flow
+                .pending
+                .iter()
+                .position(|(seq, _)| seq_diff(*seq, next_seq) <= 0);
+            let (seq, data) = match position {
+                Some(i) => flow.pending.swap_remove(i),
+                None => break,
+            };
+            flow.pending_bytes -= data.len();
+            let overlap = seq_diff(next_seq, seq) as usize;
+            if overlap >= data.len() {
+                continue;
+            }
+            deliver(
+                flow,
+                &data[overlap..],
+                timestamp,
+                source,
+                destination,
+                &mut self.ready,
+            );
+            next_seq = next_seq.wrapping_add((data.len() - overlap) as u32);
+        }
+        flow.next_seq = Some(next_seq);
+        if let Some(fin_seq) = flow.fin_seq {
+            if seq_diff(next_seq, fin_seq) >= 0 {
+                self.flows.remove(&key);
+            }
+        }
+    }
+}
+
+impl<R: Read> Iterator for PcapImporter<R> {
+    type Item = io::Result<TimestampedPayload>;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        self.next_payload().transpose()
+    }
+}
+
+// distance from b to a in sequence space
+fn seq_diff(a: u32, b: u32) -> i32 {
+    a.wrapping_sub(b) as i32
+}
+
+// give in order stream data to the flow, skipping the http upgrade headers
+fn deliver(
+    flow: &mut Flow,
+    data: &[u8],
+    timestamp: u64,
+    source: SocketAddr,
+    destination: SocketAddr,
+    ready: &mut VecDeque<TimestampedPayload>,
+) {
+    let mut data = data;
+    let mut remainder = vec![];
+    if flow.handshake == Handshake::Unknown {
+        if data.starts_with(b"HTTP/") {
+            flow.from_server = true;
+            flow.handshake = Handshake::Headers;
+        } else if data.starts_with(b"GET ") {
+            flow.from_server = false;
+            flow.handshake = Handshake::Headers;
+        } else {
+            // capture started after the upgrade, assume the server is on a well known port (443
+            // once decrypted)
+            flow.from_server = source.port() == 443 || source.port() == 80;
+            flow.handshake = Handshake::Done;
+        }
+    }
+    if flow.handshake == Handshake::Headers {
+        flow.headers.extend_from_slice(data);
+        match flow.headers.windows(4).position(|w| w == b"\r\n\r\n") {
+            Some(end) => {
+                remainder = flow.headers.split_off(end + 4);
+                flow.headers.clear();
+                flow.handshake = Handshake::Done;
+                data = &remainder;
+            }
+            None => return,
+        }
+    }
+
+    let from_server = flow.from_server;
+    flow.assembler.read(data, |op_code, payload| {
+        ready.push_back(TimestampedPayload {
+            timestamp,
+            source,
+            destination,
+            from_server,
+            op_code,
+            payload: payload.to_vec(),
+        })
+    });
+}
+
+fn invalid_data(message: &str) -> io::Error {
+    io::Error::new(io::ErrorKind::InvalidData, message)
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::bitmex_message::BitmexMessage;
+    use crate::fixtures::{DELETE, TRADE};
+    use crate::pcap::{PcapImporter, PcapPacket, PcapReader, MAX_PENDING_BYTES};
+    use std::io;
+
+    // unmasked websocket text frame
+    fn frame(payload: &[u8]) -> Vec<u8> {
+        let mut frame = vec![0x81];
+        if payload.len() < 126 {
+            frame.push(payload.len() as u8);
+        } else {
+            frame.push(126);
+            frame.extend_from_slice(&(payload.len() as u16).to_be_bytes());
+        }
+        frame.extend_from_slice(payload);
+        frame
+    }
+
+    // ethernet + ipv4 + tcp packet record from the server (10.0.0.1:80) to the client
+    fn packet(timestamp_micros: u32, seq: u32, flags: u8, payload: &[u8]) -> Vec<u8> {
+        let mut data = vec![0u8; 12];
+        data.extend_from_slice(&[0x08, 0x00]);
+        let total_len = (20 + 20 + payload.len()) as u16;
+        data.extend_from_slice(&[0x45, 0, 0, 0, 0, 0, 0, 0, 64, 6, 0, 0]);
+        data[16..18].copy_from_slice(&total_len.to_be_bytes());
+        data.extend_from_slice(&[10, 0, 0, 1, 10, 0, 0, 2]);
+        data.extend_from_slice(&80u16.to_be_bytes());
+        data.extend_from_slice(&50000u16.to_be_bytes());
+        data.extend_from_slice(&seq.to_be_bytes());
+        data.extend_from_slice(&[0, 0, 0, 0, 0x50, flags, 0xff, 0xff, 0, 0, 0, 0]);
+        data.extend_from_slice(payload);
+
+        let mut record = vec![];
+        record.extend_from_slice(&1_595_187_801u32.to_le_bytes());
+        record.extend_from_slice(&timestamp_micros.to_le_bytes());
+        record.extend_from_slice(&(data.len() as u32).to_le_bytes());
+        record.extend_from_slice(&(data.len() as u32).to_le_bytes());
+        record.extend_from_slice(&data);
+        record
+    }
+
+    // pcap file header of an ethernet capture
+    fn file_header() -> Vec<u8> {
+        let mut pcap = vec![];
+        pcap.extend_from_slice(&0xa1b2_c3d4u32.to_le_bytes());
+        pcap.extend_from_slice(&[2, 0, 4, 0, 0, 0, 0, 0, 0, 0, 0, 0]);
+        pcap.extend_from_slice(&65535u32.to_le_bytes());
+        pcap.extend_from_slice(&1u32.to_le_bytes());
+        pcap
+    }
+
+    fn capture() -> Vec<u8> {
+        let mut pcap = file_header();
+        let response = b"HTTP/1.1 101 Switching Protocols\r\nUpgrade: websocket\r\n\r\n";
+        let trade = frame(TRADE);
+        let delete = frame(DELETE);
+        let mut seq = 1000;
+        pcap.extend_from_slice(&packet(0, seq, 0x02, b""));
+        seq += 1;
+        pcap.extend_from_slice(&packet(1, seq, 0x18, response));
+        seq += response.len() as u32;
+        // trade frame split in two segments delivered out of order, then the second one again
+        let (first, second) = trade.split_at(100);
+        let second_seq = seq + first.len() as u32;
+        pcap.extend_from_slice(&packet(2, second_seq, 0x18, second));
+        pcap.extend_from_slice(&packet(3, seq, 0x18, first));
+        pcap.extend_from_slice(&packet(4, second_seq, 0x18, second));
+        seq += trade.len() as u32;
+        pcap.extend_from_slice(&packet(5, seq, 0x18, &delete));
+        pcap
+    }
+
+    #[test]
+    fn import_websocket_payloads() {
+        let capture = capture();
+        let reader = PcapReader::new(&capture[..]).unwrap();
+        assert_eq!(reader.link_type(), 1);
+        let payloads: Vec<_> = PcapImporter::new(reader).map(|p| p.unwrap()).collect();
+        assert_eq!(payloads.len(), 2);
+        assert!(payloads[0].from_server);
+        assert_eq!(payloads[0].payload, TRADE);
+        // trade frame completed by the out of order segment
+        assert_eq!(payloads[0].timestamp, 1_595_187_801_000_003_000);
+        assert_eq!(payloads[1].payload, DELETE);
+        assert_eq!(payloads[1].source.port(), 80);
+    }
+
+    #[test]
+    fn import_into_parser() {
+        let capture = capture();
+        let mut importer = PcapImporter::new(PcapReader::new(&capture[..]).unwrap());
+        let mut messages = vec![];
+        let count = importer
+            .run(|timestamp, message| match message {
+                Ok(BitmexMessage::Trade(_)) => messages.push((timestamp, "trade")),
+                Ok(BitmexMessage::Delete(_)) => messages.push((timestamp, "delete")),
+                _ => panic!("wrong message type"),
+            })
+            .unwrap();
+        assert_eq!(count, 2);
+        assert_eq!(
+            messages,
+            vec![
+                (1_595_187_801_000_003_000, "trade"),
+                (1_595_187_801_000_005_000, "delete")
+            ]
+        );
+    }
+
+    #[test]
+    fn reject_records_larger_than_snaplen() {
+        let mut capture = file_header();
+        capture.extend_from_slice(&[0u8; 8]);
+        capture.extend_from_slice(&u32::MAX.to_le_bytes());
+        capture.extend_from_slice(&u32::MAX.to_le_bytes());
+        let mut reader = PcapReader::new(&capture[..]).unwrap();
+        let mut packet = PcapPacket {
+            timestamp: 0,
+            data: vec![],
+        };
+        let error = reader.read_packet(&mut packet).unwrap_err();
+        assert_eq!(error.kind(), io::ErrorKind::InvalidData);
+        assert!(packet.data.is_empty());
+    }
+
+    #[test]
+    fn remove_flow_on_fin() {
+        let mut capture = file_header();
+        let response = b"HTTP/1.1 101 Switching Protocols\r\nUpgrade: websocket\r\n\r\n";
+        let trade = frame(TRADE);
+        let mut seq = 1000;
+        capture.extend_from_slice(&packet(0, seq, 0x02, b""));
+        seq += 1;
+        capture.extend_from_slice(&packet(1, seq, 0x18, response));
+        seq += response.len() as u32;
+        // the last frame and the fin arrive before the frame preceding them
+        capture.extend_from_slice(&packet(2, seq + trade.len() as u32, 0x19, &frame(DELETE)));
+        capture.extend_from_slice(&packet(3, seq, 0x18, &trade));
+
+        let mut importer = PcapImporter::new(PcapReader::new(&capture[..]).unwrap());
+        assert_eq!(importer.next_payload().unwrap().unwrap().payload, TRADE);
+        assert_eq!(importer.flows.len(), 0);
+        assert_eq!(importer.next_payload().unwrap().unwrap().payload, DELETE);
+        assert!(importer.next_payload().unwrap().is_none());
+    }
+
+    #[test]
+    fn stop_flow_on_missing_segment() {
+        let mut capture = file_header();
+        let mut seq = 1000;
+        capture.extend_from_slice(&packet(0, seq, 0x02, b""));
+        seq += 1;
+        // the segment at seq is never captured, the following ones are buffered until the last
+        // one goes over the limit and the rest of the flow is dropped, even the missing segment
+        let segment = vec![0u8; 60_000];
+        let mut pending_seq = seq + 100;
+        for _ in 0..MAX_PENDING_BYTES / segment.len() + 1 {
+            capture.extend_from_slice(&packet(1, pending_seq, 0x18, &segment));
+            pending_seq += segment.len() as u32;
+        }
+        capture.extend_from_slice(&packet(2, pending_seq, 0x18, &frame(TRADE)));
+        capture.extend_from_slice(&packet(2, seq, 0x18, &[0u8; 100]));
+        // until a new connection is made
+        capture.extend_from_slice(&packet(3, 5000, 0x02, b""));
+        capture.extend_from_slice(&packet(4, 5001, 0x18, &frame(DELETE)));
+
+        let payloads: Vec<_> = PcapImporter::new(PcapReader::new(&capture[..]).unwrap())
+            .map(|p| p.unwrap())
+            .collect();
+        assert_eq!(payloads.len(), 1);
+        assert!(payloads[0].from_server);
+        assert_eq!(payloads[0].payload, DELETE);
+    }
+}
@@ -0,0 +1,439 @@
+use std::fs::{self, File};
+use std::io::{self, BufReader, BufWriter, Read, Write};
+use std::path::{Path, PathBuf};
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+
+// Recording file layout, all integers are little endian:
+//   header: magic "BMXR", version u16, start time u64 (nanos since unix epoch),
+//           endpoint (u32 length + utf8), subscription count u32, subscriptions (u32 length + utf8)
+//   frames: payload length u32, receive time u64 (nanos since unix epoch), op code u8, payload
+pub const RECORDING_MAGIC: &[u8; 4] = b"BMXR";
+pub const RECORDING_VERSION: u16 = 1;
+
+const FRAME_HEADER_LEN: u64 = 4 + 8 + 1;
+// largest payload recorded or read back, a larger length read from a file is corrupted data
+pub const MAX_FRAME_LEN: u32 = 64 << 20;
+const WRITE_BUFFER_SIZE: usize = 1 << 16;
+
+pub const DEFAULT_MAX_FILE_SIZE: u64 = 1 << 30;
+pub const DEFAULT_MAX_FILE_DURATION: Duration = Duration::from_secs(60 * 60);
+
+// nanos since unix epoch of the current time
+pub fn now_nanos() -> u64 {
+    system_time_nanos(SystemTime::now())
+}
+
+fn system_time_nanos(time: SystemTime) -> u64 {
+    time.duration_since(UNIX_EPOCH)
+        .map(|d| d.as_nanos() as u64)
+        .unwrap_or(0)
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub struct RecordingHeader {
+    pub start_time: u64,
+    pub endpoint: String,
+    pub subscriptions: Vec<String>,
+}
+
+// Payload received on the websocket with its local receive time
+#[derive(Debug, Clone, PartialEq)]
+pub struct RecordedFrame {
+    pub receive_time: u64,
+    pub op_code: u8,
+    pub payload: Vec<u8>,
+}
+
+pub struct RecorderConfig {
+    // directory the recording files are written to
+    pub directory: PathBuf,
+    // file name prefix, files are named <prefix>-<start time nanos>.rec
+    pub prefix: String,
+    pub endpoint: String,
+    pub subscriptions: Vec<String>,
+    // a new file is started when the current one would grow beyond this size
+    pub max_file_size: u64,
+    // a new file is started when the current one is older than this
+    pub max_file_duration: Duration,
+}
+
+impl RecorderConfig {
+    pub fn new(directory: &Path, endpoint: &str) -> Self {
+        RecorderConfig {
+            directory: directory.to_path_buf(),
+            prefix: String::from("bitmex"),
+            endpoint: String::from(endpoint),
+            subscriptions: vec![],
+            max_file_size: DEFAULT_MAX_FILE_SIZE,
+            max_file_duration: DEFAULT_MAX_FILE_DURATION,
+        }
+    }
+}
+
+// Append only recorder of the websocket payloads delivered by the FrameAssembler. Writes are
+// buffered, the buffer is flushed on rotation, close and drop.
+pub struct Recorder {
+    config: RecorderConfig,
+    writer: Option<BufWriter<File>>,
+    file_start: SystemTime,
+    file_size: u64,
+    files: Vec<PathBuf>,
+}
+
+impl Recorder {
+    pub fn new(config: RecorderConfig) -> io::Result<Self> {
+        fs::create_dir_all(&config.directory)?;
+        let mut recorder = Recorder {
+            config,
+            writer: None,
+            file_start: SystemTime::now(),
+            file_size: 0,
+            files: vec![],
+        };
+        recorder.open_file()?;
+        Ok(recorder)
+    }
+
+    // files written so far, the last one is the current file
+    pub fn files(&self) -> &[PathBuf] {
+        &self.files
+    }
+
+    // record a payload with the current time as receive time
+    pub fn record(&mut self, op_code: u8, payload: &[u8]) -> io::Result<()> {
+        self.record_at(now_nanos(), op_code, payload)
+    }
+
+    pub fn record_at(&mut self, receive_time: u64, op_code: u8, payload: &[u8]) -> io::Result<()> {
+        if payload.len() > MAX_FRAME_LEN as usize {
+            return Err(io::Error::new(
+                io::ErrorKind::InvalidInput,
+                "payload larger than the max frame length",
+            ));
+        }
+        let frame_len = FRAME_HEADER_LEN + payload.len() as u64;
+        if self.should_rotate(frame_len) {
+            self.open_file()?;
+        }
+        let writer = self.writer.as_mut().unwrap();
+        writer.write_all(&(payload.len() as u32).to_le_bytes())?;
+        writer.write_all(&receive_time.to_le_bytes())?;
+        writer.write_all(&[op_code])?;
+        writer.write_all(payload)?;
+        self.file_size += frame_len;
+        Ok(())
+    }
+
+    pub fn flush(&mut self) -> io::Result<()> {
+        match self.writer.as_mut() {
+            Some(writer) => writer.flush(),
+            None => Ok(()),
+        }
+    }
+
+    // flush and close the current file
+    pub fn close(mut self) -> io::Result<()> {
+        let result = self.flush();
+        self.writer = None;
+        result
+    }
+
+    fn should_rotate(&self, frame_len: u64) -> bool {
+        let has_frames = self.file_size > self.header_len();
+        let too_large = self.file_size + frame_len > self.config.max_file_size;
+        let too_old = self
+            .file_start
+            .elapsed()
+            .map(|age| age >= self.config.max_file_duration)
+            .unwrap_or(false);
+        has_frames && (too_large || too_old)
+    }
+
+    fn header_len(&self) -> u64 {
+        let subscriptions: usize = self.config.subscriptions.iter().map(|s| 4 + s.len()).sum();
+        (4 + 2 + 8 + 4 + self.config.endpoint.len() + 4 + subscriptions) as u64
+    }
+
+    fn open_file(&mut self) -> io::Result<()> {
+        self.flush()?;
+        self.file_start = SystemTime::now();
+        let start_time = system_time_nanos(self.file_start);
+        let mut path = self
+            .config
+            .directory
+            .join(format!("{}-{}.rec", self.config.prefix, start_time));
+        // keep names unique when files are rotated within the clock resolution
+        let mut suffix = 1;
+        while path.exists() {
+            path = self.config.directory.join(format!(
+                "{}-{}-{}.rec",
+                self.config.prefix, start_time, suffix
+            ));
+            suffix += 1;
+        }
+
+        let mut writer = BufWriter::with_capacity(WRITE_BUFFER_SIZE, File::create(&path)?);
+        writer.write_all(RECORDING_MAGIC)?;
+        writer.write_all(&RECORDING_VERSION.to_le_bytes())?;
+        writer.write_all(&start_time.to_le_bytes())?;
+        write_string(&mut writer, &self.config.endpoint)?;
+        writer.write_all(&(self.config.subscriptions.len() as u32).to_le_bytes())?;
+        for subscription in self.config.subscriptions.iter() {
+            write_string(&mut writer, subscription)?;
+        }
+
+        self.writer = Some(writer);
+        self.file_size = self.header_len();
+        self.files.push(path);
+        Ok(())
+    }
+}
+
+impl Drop for Recorder {
+    fn drop(&mut self) {
+        let _ = self.flush();
+    }
+}
+
+fn write_string<W: Write>(writer: &mut W, value: &str) -> io::Result<()> {
+    writer.write_all(&(value.len() as u32).to_le_bytes())?;
+    writer.write_all(value.as_bytes())
+}
+
+// Reader of a recording file written by the Recorder
+pub struct RecordingReader<R: Read> {
+    reader: R,
+    header: RecordingHeader,
+    // the iterator ends after the first error, the position in the file is lost
+    failed: bool,
+}
+
+impl RecordingReader<BufReader<File>> {
+    pub fn open(path: &Path) -> io::Result<Self> {
+        RecordingReader::new(BufReader::new(File::open(path)?))
+    }
+}
+
+impl<R: Read> RecordingReader<R> {
+    pub fn new(mut reader: R) -> io::Result<Self> {
+        let mut magic = [0u8; 4];
+        reader.read_exact(&mut magic)?;
+        if &magic != RECORDING_MAGIC {
+            return Err(invalid_data("not a recording file"));
+        }
+        let version = read_u16(&mut reader)?;
+        if version != RECORDING_VERSION {
+            return Err(invalid_data("unsupported recording version"));
+        }
+        let start_time = read_u64(&mut reader)?;
+        let endpoint = read_string(&mut reader)?;
+        let count = read_u32(&mut reader)?;
+        let mut subscriptions = vec![];
+        for _ in 0..count {
+            subscriptions.push(read_string(&mut reader)?);
+        }
+        Ok(RecordingReader {
+            reader,
+            header: RecordingHeader {
+                start_time,
+                endpoint,
+                subscriptions,
+            },
+            failed: false,
+        })
+    }
+
+    pub fn header(&self) -> &RecordingHeader {
+        &self.header
+    }
+
+    // read the next frame into the given frame, the payload buffer is reused. Returns false at
+    // the end of the file, a frame cut short (e.g. by a crash of the recording process) is an
+    // UnexpectedEof error and a length above MAX_FRAME_LEN an InvalidData error.
+    pub fn read_frame(&mut self, frame: &mut RecordedFrame) -> io::Result<bool> {
+        let mut len = [0u8; 4];
+        let mut filled = 0;
+        while filled < len.len() {
+            match self.reader.read(&mut len[filled..]) {
+                Ok(0) if filled == 0 => return Ok(false),
+                Ok(0) => {
+                    return Err(io::Error::new(
+                        io::ErrorKind::UnexpectedEof,
+                        "truncated frame length",
+                    ))
+                }
+                Ok(n) => filled += n,
+                Err(ref e) if e.kind() == io::ErrorKind::Interrupted => {}
+                Err(e) => return Err(e),
+            }
+        }
+        let len = u32::from_le_bytes(len);
+        if len > MAX_FRAME_LEN {
+            return Err(invalid_data("frame larger than the max frame length"));
+        }
+        frame.receive_time = read_u64(&mut self.reader)?;
+        let mut op_code = [0u8; 1];
+        self.reader.read_exact(&mut op_code)?;
+        frame.op_code = op_code[0];
+        frame.payload.resize(len as usize, 0);
+        self.reader.read_exact(&mut frame.payload)?;
+        Ok(true)
+    }
+}
+
+impl<R: Read> Iterator for RecordingReader<R> {
+    type Item = io::Result<RecordedFrame>;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        if self.failed {
+            return None;
+        }
+        let mut frame = RecordedFrame {
+            receive_time: 0,
+            op_code: 0,
+            payload: vec![],
+        };
+        match self.read_frame(&mut frame) {
+            Ok(true) => Some(Ok(frame)),
+            Ok(false) => None,
+            Err(e) => {
+                self.failed = true;
+                Some(Err(e))
+            }
+        }
+    }
+}
+
+fn read_u16<R: Read>(reader: &mut R) -> io::Result<u16> {
+    let mut buf = [0u8; 2];
+    reader.read_exact(&mut buf)?;
+    Ok(u16::from_le_bytes(buf))
+}
+
+fn read_u32<R: Read>(reader: &mut R) -> io::Result<u32> {
+    let mut buf = [0u8; 4];
+    reader.read_exact(&mut buf)?;
+    Ok(u32::from_le_bytes(buf))
+}
+
+fn read_u64<R: Read>(reader: &mut R) -> io::Result<u64> {
+    let mut buf = [0u8; 8];
+    reader.read_exact(&mut buf)?;
+    Ok(u64::from_le_bytes(buf))
+}
+
+fn read_string<R: Read>(reader: &mut R) -> io::Result<String> {
+    let len = read_u32(reader)? as usize;
+    let mut buf = vec![0u8; len];
+    reader.read_exact(&mut buf)?;
+    String::from_utf8(buf).map_err(|_| invalid_data("invalid utf8 string"))
+}
+
+fn invalid_data(message: &str) -> io::Error {
+    io::Error::new(io::ErrorKind::InvalidData, message)
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::recorder::{Recorder, RecorderConfig, RecordingReader, MAX_FRAME_LEN};
+    use std::fs;
+    use std::io;
+
+    #[test]
+    fn record_and_read_back() {
+        let directory = std::env::temp_dir().join("bitmex-md-recorder-test");
+        let _ = fs::remove_dir_all(&directory);
+
+        let mut config = RecorderConfig::new(&directory, "wss://www.bitmex.com/realtime");
+        config.subscriptions.push(String::from("trade:XBTUSD"));
+        // small enough to rotate after two frames
+        config.max_file_size = 120;
+        let mut recorder = Recorder::new(config).unwrap();
+        recorder.record_at(1, 1, b"{\"info\":\"a\"}").unwrap();
+        recorder.record_at(2, 1, b"{\"info\":\"b\"}").unwrap();
+        recorder.record_at(3, 1, b"{\"info\":\"c\"}").unwrap();
+        let files = recorder.files().to_vec();
+        recorder.close().unwrap();
+        assert_eq!(files.len(), 2);
+
+        let reader = RecordingReader::open(&files[0]).unwrap();
+        assert_eq!(reader.header().endpoint, "wss://www.bitmex.com/realtime");
+        assert_eq!(reader.header().subscriptions, vec!["trade:XBTUSD"]);
+        let frames: Vec<_> = reader.map(|f| f.unwrap()).collect();
+        assert_eq!(frames.len(), 2);
+        assert_eq!(frames[0].receive_time, 1);
+        assert_eq!(frames[0].op_code, 1);
+        assert_eq!(frames[1].payload, b"{\"info\":\"b\"}");
+
+        let frames: Vec<_> = RecordingReader::open(&files[1])
+            .unwrap()
+            .map(|f| f.unwrap())
+            .collect();
+        assert_eq!(frames.len(), 1);
+        assert_eq!(frames[0].receive_time, 3);
+
+        let _ = fs::remove_dir_all(&directory);
+    }
+
+    #[test]
+    fn truncated_recording() {
+        let directory = std::env::temp_dir().join("bitmex-md-recorder-truncated-test");
+        let _ = fs::remove_dir_all(&directory);
+        let mut recorder = Recorder::new(RecorderConfig::new(
+            &directory,
+            "wss://www.bitmex.com/realtime",
+        ))
+        .unwrap();
+        recorder.record_at(1, 1, b"{\"info\":\"a\"}").unwrap();
+        recorder.record_at(2, 1, b"{\"info\":\"b\"}").unwrap();
+        let path = recorder.files()[0].clone();
+        recorder.close().unwrap();
+        let recording = fs::read(&path).unwrap();
+
+        // cut in the length of the last frame and in its payload
+        for cut in [23, 2].iter() {
+            fs::write(&path, &recording[..recording.len() - cut]).unwrap();
+            let frames: Vec<_> = RecordingReader::open(&path).unwrap().collect();
+            assert_eq!(frames.len(), 2);
+            assert_eq!(frames[0].as_ref().unwrap().receive_time, 1);
+            assert_eq!(
+                frames[1].as_ref().unwrap_err().kind(),
+                io::ErrorKind::UnexpectedEof
+            );
+        }
+
+        let _ = fs::remove_dir_all(&directory);
+    }
+
+    #[test]
+    fn reject_frames_above_max_len() {
+        let directory = std::env::temp_dir().join("bitmex-md-recorder-max-len-test");
+        let _ = fs::remove_dir_all(&directory);
+        let mut recorder = Recorder::new(RecorderConfig::new(
+            &directory,
+            "wss://www.bitmex.com/realtime",
+        ))
+        .unwrap();
+        recorder.record_at(1, 1, b"{\"info\":\"a\"}").unwrap();
+        let path = recorder.files()[0].clone();
+        recorder.close().unwrap();
+
+        // corrupted length followed by a valid frame, the reader stops at the corrupted one
+        let mut recording = fs::read(&path).unwrap();
+        recording.extend_from_slice(&(MAX_FRAME_LEN + 1).to_le_bytes());
+        recording.extend_from_slice(&[0u8; 9]);
+        recording.extend_from_slice(&1u32.to_le_bytes());
+        recording.extend_from_slice(&[0u8; 9]);
+        recording.push(b'a');
+        fs::write(&path, &recording).unwrap();
+        let frames: Vec<_> = RecordingReader::open(&path).unwrap().collect();
+        assert_eq!(frames.len(), 2);
+        assert_eq!(frames[0].as_ref().unwrap().receive_time, 1);
+        assert_eq!(
+            frames[1].as_ref().unwrap_err().kind(),
+            io::ErrorKind::InvalidData
+        );
+
+        let _ = fs::remove_dir_all(&directory);
+    }
+}
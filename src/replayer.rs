@@ -0,0 +1,231 @@
+use crate::bitmex_message::{parse, BitmexMessage, ParseError};
+use crate::recorder::{RecordedFrame, RecordingReader};
+use crate::ws_server::TEXT_OP_CODE;
+use std::io;
+use std::path::{Path, PathBuf};
+use std::thread;
+use std::time::{Duration, Instant};
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum ReplaySpeed {
+    // no delay between frames
+    AsFastAsPossible,
+    // frames are delivered with the same spacing as they were received
+    RealTime,
+    // frames are delivered this many times faster than they were received
+    Scaled(f64),
+}
+
+// scale of a ReplaySpeed::Scaled that is not a positive number
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct InvalidSpeed(pub f64);
+
+impl ReplaySpeed {
+    // frames delivered this many times faster than they were received, the scale must be
+    // positive and finite
+    pub fn scaled(scale: f64) -> Result<ReplaySpeed, InvalidSpeed> {
+        if scale > 0.0 && scale.is_finite() {
+            Ok(ReplaySpeed::Scaled(scale))
+        } else {
+            Err(InvalidSpeed(scale))
+        }
+    }
+}
+
+// Replays recording files written by the Recorder. Frames are delivered in file order from a
+// single thread, so replaying the same files always produces the same sequence of callbacks
+// whatever the speed.
+pub struct Replayer {
+    files: Vec<PathBuf>,
+    speed: ReplaySpeed,
+    start_time: Option<u64>,
+    stop_time: Option<u64>,
+}
+
+impl Replayer {
+    // files are replayed in the given order, e.g. the order of Recorder::files
+    pub fn new(files: &[PathBuf]) -> Self {
+        Replayer {
+            files: files.to_vec(),
+            speed: ReplaySpeed::AsFastAsPossible,
+            start_time: None,
+            stop_time: None,
+        }
+    }
+
+    pub fn from_file(path: &Path) -> Self {
+        Replayer::new(&[path.to_path_buf()])
+    }
+
+    pub fn set_speed(&mut self, speed: ReplaySpeed) -> Result<(), InvalidSpeed> {
+        if let ReplaySpeed::Scaled(scale) = speed {
+            ReplaySpeed::scaled(scale)?;
+        }
+        self.speed = speed;
+        Ok(())
+    }
+
+    // skip frames received before this time (nanos since unix epoch)
+    pub fn set_start_time(&mut self, start_time: u64) {
+        self.start_time = Some(start_time);
+    }
+
+    // stop at the first frame received after this time (nanos since unix epoch)
+    pub fn set_stop_time(&mut self, stop_time: u64) {
+        self.stop_time = Some(stop_time);
+    }
+
+    // replay the recorded frames, returns the number of frames delivered
+    pub fn run_frames<F>(&self, mut on_frame: F) -> io::Result<u64>
+    where
+        F: FnMut(&RecordedFrame),
+    {
+        let mut frame = RecordedFrame {
+            receive_time: 0,
+            op_code: 0,
+            payload: vec![],
+        };
+        let mut count = 0;
+        // receive time of the first delivered frame and the wall clock time it was delivered at
+        let mut origin: Option<(u64, Instant)> = None;
+        for path in self.files.iter() {
+            let mut reader = RecordingReader::open(path)?;
+            while reader.read_frame(&mut frame)? {
+                if let Some(start_time) = self.start_time {
+                    if frame.receive_time < start_time {
+                        continue;
+                    }
+                }
+                if let Some(stop_time) = self.stop_time {
+                    if frame.receive_time > stop_time {
+                        return Ok(count);
+                    }
+                }
+                match origin {
+                    Some((receive_time, instant)) => {
+                        self.wait(frame.receive_time.saturating_sub(receive_time), instant)
+                    }
+                    None => origin = Some((frame.receive_time, Instant::now())),
+                }
+                on_frame(&frame);
+                count += 1;
+            }
+        }
+        Ok(count)
+    }
+
+    // replay the recorded text frames through the parser, the callback gets the receive time of
+    // the frame and the parse result
+    pub fn run<F>(&self, mut on_message: F) -> io::Result<u64>
+    where
+        F: FnMut(u64, Result<BitmexMessage, ParseError>),
+    {
+        self.run_frames(|frame| {
+            if frame.op_code == TEXT_OP_CODE {
+                on_message(frame.receive_time, parse(&frame.payload));
+            }
+        })
+    }
+
+    // wait until the given time since the first frame has elapsed at the replay speed
+    fn wait(&self, since_origin: u64, origin: Instant) {
+        let target = match self.speed {
+            ReplaySpeed::AsFastAsPossible => return,
+            ReplaySpeed::RealTime => Duration::from_nanos(since_origin),
+            ReplaySpeed::Scaled(scale) => {
+                Duration::from_nanos((since_origin as f64 / scale) as u64)
+            }
+        };
+        let elapsed = origin.elapsed();
+        if target > elapsed {
+            thread::sleep(target - elapsed);
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::fixtures::{ASK_INSERT, ASK_UPDATE, TOP_SNAPSHOT};
+    use crate::instrument::SymbolRegistry;
+    use crate::order_book::OrderBook;
+    use crate::recorder::{Recorder, RecorderConfig};
+    use crate::replayer::{InvalidSpeed, ReplaySpeed, Replayer};
+    use std::fs;
+    use std::path::PathBuf;
+    use std::time::Instant;
+
+    fn record(name: &str) -> Vec<PathBuf> {
+        let directory = std::env::temp_dir().join(name);
+        let _ = fs::remove_dir_all(&directory);
+        let mut recorder = Recorder::new(RecorderConfig::new(
+            &directory,
+            "wss://www.bitmex.com/realtime",
+        ))
+        .unwrap();
+        recorder.record_at(1_000_000, 1, TOP_SNAPSHOT).unwrap();
+        recorder.record_at(2_000_000, 1, ASK_INSERT).unwrap();
+        recorder.record_at(3_000_000, 1, ASK_UPDATE).unwrap();
+        let files = recorder.files().to_vec();
+        recorder.close().unwrap();
+        files
+    }
+
+    fn replay(replayer: &Replayer) -> OrderBook {
+        let mut symbols = SymbolRegistry::new();
+        let mut book = OrderBook::new(&mut symbols, "XBTUSD");
+        replayer
+            .run(|_, message| book.apply(&message.ok().unwrap(), |_| {}))
+            .unwrap();
+        book
+    }
+
+    #[test]
+    fn replay_is_deterministic() {
+        let files = record("bitmex-md-replayer-test");
+        let mut replayer = Replayer::new(&files);
+        let first = replay(&replayer);
+        replayer
+            .set_speed(ReplaySpeed::scaled(10.0).unwrap())
+            .unwrap();
+        let start = Instant::now();
+        let second = replay(&replayer);
+        // 2 millis of recording at 10x speed
+        assert!(start.elapsed().as_micros() >= 200);
+
+        assert_eq!(first.top_of_book(), second.top_of_book());
+        assert_eq!(first.best_ask().unwrap().size, 20);
+        assert_eq!(first.asks().count(), second.asks().count());
+        let _ = fs::remove_dir_all(files[0].parent().unwrap());
+    }
+
+    #[test]
+    fn replay_window() {
+        let files = record("bitmex-md-replayer-window-test");
+        let mut replayer = Replayer::new(&files);
+        replayer.set_start_time(2_000_000);
+        replayer.set_stop_time(2_500_000);
+        let mut times = vec![];
+        let count = replayer
+            .run_frames(|frame| times.push(frame.receive_time))
+            .unwrap();
+        assert_eq!(count, 1);
+        assert_eq!(times, vec![2_000_000]);
+        let _ = fs::remove_dir_all(files[0].parent().unwrap());
+    }
+
+    #[test]
+    fn invalid_speed() {
+        assert_eq!(ReplaySpeed::scaled(0.5), Ok(ReplaySpeed::Scaled(0.5)));
+        assert_eq!(ReplaySpeed::scaled(0.0), Err(InvalidSpeed(0.0)));
+        assert_eq!(ReplaySpeed::scaled(-2.0), Err(InvalidSpeed(-2.0)));
+        assert!(ReplaySpeed::scaled(f64::NAN).is_err());
+        assert!(ReplaySpeed::scaled(f64::INFINITY).is_err());
+
+        let mut replayer = Replayer::new(&[]);
+        assert_eq!(
+            replayer.set_speed(ReplaySpeed::Scaled(-1.0)),
+            Err(InvalidSpeed(-1.0))
+        );
+        assert_eq!(replayer.set_speed(ReplaySpeed::RealTime), Ok(()));
+    }
+}
@@ -0,0 +1,444 @@
+use crate::bitmex_message::BitmexMessage;
+use crate::event_codec::{decode, EncodeError, Encoder, TRADE_RECORD_LEN};
+use std::fs::{File, OpenOptions};
+use std::io;
+use std::os::unix::io::AsRawFd;
+use std::path::Path;
+use std::ptr;
+use std::sync::atomic::{fence, AtomicU64, Ordering};
+
+// Single producer, multi consumer ring buffer in a shared memory file (e.g. in /dev/shm). Every
+// consumer reads every record, the producer never waits for the consumers: a consumer falling
+// more than the ring size behind is overrun and told how many records it lost.
+//
+// Layout, all integers are little endian:
+//   header (128 bytes): magic "BMXQ", version u32, slot count u64, slot size u64,
+//                       at offset 64 the sequence number of the last published record u64
+//   slots: sequence number u64, payload length u32, padding u32, payload (slot size bytes)
+// Sequence numbers start at 1, record n is written to slot n % slot count. The slot sequence
+// number is set to WRITING while the slot is written so that readers can detect torn reads.
+pub const RING_MAGIC: &[u8; 4] = b"BMXQ";
+pub const RING_VERSION: u32 = 1;
+
+pub const DEFAULT_SLOT_SIZE: usize = 128;
+
+const HEADER_LEN: usize = 128;
+const WRITE_SEQ_OFFSET: usize = 64;
+const SLOT_HEADER_LEN: usize = 16;
+const WRITING: u64 = u64::MAX;
+
+// Shared mapping of a file, unmapped on drop
+struct SharedMemory {
+    ptr: *mut u8,
+    len: usize,
+}
+
+impl SharedMemory {
+    fn map(file: &File, len: usize, writable: bool) -> io::Result<Self> {
+        let protection = if writable {
+            libc::PROT_READ | libc::PROT_WRITE
+        } else {
+            libc::PROT_READ
+        };
+        let ptr = unsafe {
+            libc::mmap(
+                ptr::null_mut(),
+                len,
+                protection,
+                libc::MAP_SHARED,
+                file.as_raw_fd(),
+                0,
+            )
+        };
+        if ptr == libc::MAP_FAILED {
+            return Err(io::Error::last_os_error());
+        }
+        Ok(SharedMemory {
+            ptr: ptr as *mut u8,
+            len,
+        })
+    }
+
+    // offsets given to atomic are multiples of 8 and the mapping is page aligned
+    fn atomic(&self, offset: usize) -> &AtomicU64 {
+        assert!(offset + 8 <= self.len);
+        unsafe { &*(self.ptr.add(offset) as *const AtomicU64) }
+    }
+}
+
+impl Drop for SharedMemory {
+    fn drop(&mut self) {
+        unsafe {
+            libc::munmap(self.ptr as *mut libc::c_void, self.len);
+        }
+    }
+}
+
+// the mapping is only accessed through atomics and the seqlock protocol of the slots
+unsafe impl Send for SharedMemory {}
+
+fn slot_stride(slot_size: usize) -> usize {
+    SLOT_HEADER_LEN + slot_size
+}
+
+fn invalid_data(message: &str) -> io::Error {
+    io::Error::new(io::ErrorKind::InvalidData, message)
+}
+
+pub struct RingPublisher {
+    memory: SharedMemory,
+    slot_count: u64,
+    slot_size: usize,
+    seq: u64,
+    encoder: Encoder,
+}
+
+impl RingPublisher {
+    // create the ring file, an existing file is overwritten. The slot count is rounded up to a
+    // power of two and the slot size to a multiple of 8, slots hold at least any event_codec
+    // record.
+    pub fn create(path: &Path, slot_count: usize, slot_size: usize) -> io::Result<Self> {
+        let slot_count = slot_count.max(1).next_power_of_two();
+        let slot_size = (slot_size.max(TRADE_RECORD_LEN) + 7) & !7;
+        let len = HEADER_LEN + slot_count * slot_stride(slot_size);
+        let file = OpenOptions::new()
+            .read(true)
+            .write(true)
+            .create(true)
+            .truncate(true)
+            .open(path)?;
+        file.set_len(len as u64)?;
+        let memory = SharedMemory::map(&file, len, true)?;
+
+        let mut header = [0u8; 24];
+        header[0..4].copy_from_slice(RING_MAGIC);
+        header[4..8].copy_from_slice(&RING_VERSION.to_le_bytes());
+        header[8..16].copy_from_slice(&(slot_count as u64).to_le_bytes());
+        header[16..24].copy_from_slice(&(slot_size as u64).to_le_bytes());
+        unsafe { ptr::copy_nonoverlapping(header.as_ptr(), memory.ptr, header.len()) };
+
+        Ok(RingPublisher {
+            memory,
+            slot_count: slot_count as u64,
+            slot_size,
+            seq: 0,
+            encoder: Encoder::with_capacity(slot_size),
+        })
+    }
+
+    // max payload length of a record
+    pub fn slot_size(&self) -> usize {
+        self.slot_size
+    }
+
+    // sequence number of the last published record
+    pub fn seq(&self) -> u64 {
+        self.seq
+    }
+
+    // publish a record, returns its sequence number
+    pub fn publish(&mut self, payload: &[u8]) -> io::Result<u64> {
+        if payload.len() > self.slot_size {
+            return Err(io::Error::new(
+                io::ErrorKind::InvalidInput,
+                "payload larger than the slot size",
+            ));
+        }
+        Ok(self.write_slot(payload))
+    }
+
+    // publish the trades and orderBookL2 entries of a message, one event_codec record per
+    // entry. Returns the number of records published.
+    pub fn publish_message(
+        &mut self,
+        receive_time: i64,
+        message: &BitmexMessage,
+    ) -> Result<usize, EncodeError> {
+        let mut encoder = std::mem::take(&mut self.encoder);
+        encoder.clear();
+        let result = encoder.encode_message(receive_time, message);
+        let mut records = encoder.as_bytes();
+        // records were just encoded so they always decode
+        while let Ok((_, len)) = decode(records) {
+            let (record, rest) = records.split_at(len);
+            self.write_slot(record);
+            records = rest;
+        }
+        self.encoder = encoder;
+        result
+    }
+
+    fn write_slot(&mut self, payload: &[u8]) -> u64 {
+        let seq = self.seq + 1;
+        let offset =
+            HEADER_LEN + ((seq & (self.slot_count - 1)) as usize) * slot_stride(self.slot_size);
+        let slot_seq = self.memory.atomic(offset);
+        slot_seq.store(WRITING, Ordering::Relaxed);
+        fence(Ordering::Release);
+        unsafe {
+            let slot = self.memory.ptr.add(offset);
+            let len = (payload.len() as u32).to_le_bytes();
+            ptr::copy_nonoverlapping(len.as_ptr(), slot.add(8), len.len());
+            ptr::copy_nonoverlapping(payload.as_ptr(), slot.add(SLOT_HEADER_LEN), payload.len());
+        }
+        slot_seq.store(seq, Ordering::Release);
+        self.memory
+            .atomic(WRITE_SEQ_OFFSET)
+            .store(seq, Ordering::Release);
+        self.seq = seq;
+        seq
+    }
+}
+
+#[derive(Debug, PartialEq)]
+pub enum ReadResult<'a> {
+    // no record published since the last read
+    Empty,
+    Record { seq: u64, payload: &'a [u8] },
+    // the producer overwrote records not read yet, the next read returns the oldest record
+    // still in the ring
+    Overrun { lost: u64 },
+}
+
+pub struct RingSubscriber {
+    memory: SharedMemory,
+    slot_count: u64,
+    slot_size: usize,
+    next: u64,
+    buf: Vec<u8>,
+}
+
+impl RingSubscriber {
+    // open a ring created by a publisher, reading starts after the last published record
+    pub fn open(path: &Path) -> io::Result<Self> {
+        let file = File::open(path)?;
+        let file_len = file.metadata()?.len() as usize;
+        if file_len < HEADER_LEN {
+            return Err(invalid_data("not a ring file"));
+        }
+        let memory = SharedMemory::map(&file, file_len, false)?;
+        let mut header = [0u8; 24];
+        unsafe { ptr::copy_nonoverlapping(memory.ptr, header.as_mut_ptr(), header.len()) };
+        if &header[0..4] != RING_MAGIC {
+            return Err(invalid_data("not a ring file"));
+        }
+        let mut u32_bytes = [0u8; 4];
+        u32_bytes.copy_from_slice(&header[4..8]);
+        if u32::from_le_bytes(u32_bytes) != RING_VERSION {
+            return Err(invalid_data("unsupported ring version"));
+        }
+        let mut u64_bytes = [0u8; 8];
+        u64_bytes.copy_from_slice(&header[8..16]);
+        let slot_count = u64::from_le_bytes(u64_bytes);
+        u64_bytes.copy_from_slice(&header[16..24]);
+        let slot_size = u64::from_le_bytes(u64_bytes) as usize;
+        if !slot_count.is_power_of_two()
+            || HEADER_LEN + slot_count as usize * slot_stride(slot_size) != file_len
+        {
+            return Err(invalid_data("invalid ring size"));
+        }
+
+        let mut subscriber = RingSubscriber {
+            memory,
+            slot_count,
+            slot_size,
+            next: 0,
+            buf: vec![0u8; slot_size],
+        };
+        subscriber.next = subscriber.published() + 1;
+        Ok(subscriber)
+    }
+
+    // sequence number of the next record to read
+    pub fn next_seq(&self) -> u64 {
+        self.next
+    }
+
+    // move to the oldest record still in the ring
+    pub fn seek_oldest(&mut self) {
+        self.next = self.oldest();
+    }
+
+    pub fn read(&mut self) -> ReadResult<'_> {
+        loop {
+            let published = self.published();
+            if self.next > published {
+                return ReadResult::Empty;
+            }
+            let oldest = self.oldest();
+            if self.next < oldest {
+                let lost = oldest - self.next;
+                self.next = oldest;
+                return ReadResult::Overrun { lost };
+            }
+
+            let offset = HEADER_LEN
+                + ((self.next & (self.slot_count - 1)) as usize) * slot_stride(self.slot_size);
+            let slot_seq = self.memory.atomic(offset);
+            if slot_seq.load(Ordering::Acquire) != self.next {
+                // overwritten since published was read
+                continue;
+            }
+            let mut len = [0u8; 4];
+            unsafe {
+                let slot = self.memory.ptr.add(offset);
+                ptr::copy_nonoverlapping(slot.add(8), len.as_mut_ptr(), 4);
+            }
+            let len = (u32::from_le_bytes(len) as usize).min(self.slot_size);
+            unsafe {
+                let slot = self.memory.ptr.add(offset);
+                ptr::copy_nonoverlapping(slot.add(SLOT_HEADER_LEN), self.buf.as_mut_ptr(), len);
+            }
+            fence(Ordering::Acquire);
+            if slot_seq.load(Ordering::Relaxed) != self.next {
+                // overwritten while copied
+                continue;
+            }
+            let seq = self.next;
+            self.next += 1;
+            return ReadResult::Record {
+                seq,
+                payload: &self.buf[..len],
+            };
+        }
+    }
+
+    fn published(&self) -> u64 {
+        self.memory.atomic(WRITE_SEQ_OFFSET).load(Ordering::Acquire)
+    }
+
+    fn oldest(&self) -> u64 {
+        let published = self.published();
+        if published >= self.slot_count {
+            published - self.slot_count + 1
+        } else {
+            1
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::bitmex_message::parse;
+    use crate::event_codec::{decode, Record};
+    use crate::shm_ring::{ReadResult, RingPublisher, RingSubscriber, DEFAULT_SLOT_SIZE};
+    use std::fs;
+    use std::path::PathBuf;
+    use std::thread;
+
+    fn ring_path(name: &str) -> PathBuf {
+        let path = std::env::temp_dir().join(name);
+        let _ = fs::remove_file(&path);
+        path
+    }
+
+    #[test]
+    fn publish_and_read() {
+        let path = ring_path("bitmex-md-ring-test");
+        let mut publisher = RingPublisher::create(&path, 8, DEFAULT_SLOT_SIZE).unwrap();
+        publisher.publish(b"before").unwrap();
+        let mut subscriber = RingSubscriber::open(&path).unwrap();
+        assert_eq!(subscriber.next_seq(), 2);
+        assert_eq!(subscriber.read(), ReadResult::Empty);
+
+        assert_eq!(publisher.publish(b"a").unwrap(), 2);
+        assert_eq!(publisher.publish(b"bc").unwrap(), 3);
+        assert_eq!(
+            subscriber.read(),
+            ReadResult::Record {
+                seq: 2,
+                payload: b"a"
+            }
+        );
+        assert_eq!(
+            subscriber.read(),
+            ReadResult::Record {
+                seq: 3,
+                payload: b"bc"
+            }
+        );
+        assert_eq!(subscriber.read(), ReadResult::Empty);
+
+        subscriber.seek_oldest();
+        assert_eq!(
+            subscriber.read(),
+            ReadResult::Record {
+                seq: 1,
+                payload: b"before"
+            }
+        );
+        assert!(publisher.publish(&[0u8; 200]).is_err());
+        let _ = fs::remove_file(&path);
+    }
+
+    #[test]
+    fn overrun() {
+        let path = ring_path("bitmex-md-ring-overrun-test");
+        let mut publisher = RingPublisher::create(&path, 4, DEFAULT_SLOT_SIZE).unwrap();
+        let mut subscriber = RingSubscriber::open(&path).unwrap();
+        for i in 0..10u8 {
+            publisher.publish(&[i]).unwrap();
+        }
+        assert_eq!(subscriber.read(), ReadResult::Overrun { lost: 6 });
+        assert_eq!(
+            subscriber.read(),
+            ReadResult::Record {
+                seq: 7,
+                payload: &[6]
+            }
+        );
+        let _ = fs::remove_file(&path);
+    }
+
+    #[test]
+    fn publish_messages() {
+        let path = ring_path("bitmex-md-ring-message-test");
+        let mut publisher = RingPublisher::create(&path, 8, DEFAULT_SLOT_SIZE).unwrap();
+        let mut subscriber = RingSubscriber::open(&path).unwrap();
+        let update = parse(b"{\"table\":\"orderBookL2\",\"action\":\"update\",\"data\":[{\"symbol\":\"XBTUSD\",\"id\":8799065200,\"side\":\"Sell\",\"size\":182112},{\"symbol\":\"XBTUSD\",\"id\":8799065250,\"side\":\"Buy\",\"size\":19575}]}").ok().unwrap();
+        assert_eq!(publisher.publish_message(5, &update), Ok(2));
+        assert_eq!(publisher.seq(), 2);
+
+        let mut ids = vec![];
+        while let ReadResult::Record { payload, .. } = subscriber.read() {
+            match decode(payload).unwrap().0 {
+                Record::L2Delta(view) => ids.push(view.id()),
+                _ => panic!("wrong record type"),
+            }
+        }
+        assert_eq!(ids, vec![8799065200, 8799065250]);
+        let _ = fs::remove_file(&path);
+    }
+
+    #[test]
+    fn concurrent_reader_sees_no_torn_records() {
+        let path = ring_path("bitmex-md-ring-concurrent-test");
+        let mut publisher = RingPublisher::create(&path, 16, DEFAULT_SLOT_SIZE).unwrap();
+        let mut subscriber = RingSubscriber::open(&path).unwrap();
+        const COUNT: u64 = 100_000;
+
+        let producer = thread::spawn(move || {
+            for seq in 1..=COUNT {
+                // every byte of the payload holds the low byte of the sequence number
+                publisher.publish(&[seq as u8; 64]).unwrap();
+            }
+        });
+
+        let mut last = 0;
+        let mut lost = 0;
+        while last < COUNT {
+            match subscriber.read() {
+                ReadResult::Record { seq, payload } => {
+                    assert!(seq > last);
+                    assert!(payload.iter().all(|b| *b == seq as u8));
+                    last = seq;
+                }
+                ReadResult::Overrun { lost: n } => lost += n,
+                ReadResult::Empty => thread::yield_now(),
+            }
+        }
+        producer.join().unwrap();
+        assert!(lost < COUNT);
+        let _ = fs::remove_file(&path);
+    }
+}
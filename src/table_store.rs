@@ -0,0 +1,369 @@
+use crate::bitmex_message::TableMessage;
+use serde_json::{Map, Value};
+use std::collections::{HashMap, HashSet};
+
+pub type Row = Map<String, Value>;
+
+// max number of rows kept for append only tables (e.g. trade, quote)
+pub const DEFAULT_MAX_TABLE_LEN: usize = 200;
+
+#[derive(Debug, PartialEq)]
+pub enum TableError {
+    // insert, update or delete received for a table before its partial
+    UnknownTable,
+    // row is missing one of the key fields of the table
+    MissingKey,
+    // update or delete for a key that is not in the table
+    RowNotFound,
+    // update or delete for a table without keys
+    NotKeyed,
+    // action other than partial, insert, update or delete
+    UnknownAction,
+}
+
+// Table of rows identified by the keys given in the partial. Tables without keys are append only
+// and are capped at a max number of rows, the oldest rows are dropped first.
+#[derive(Debug)]
+pub struct Table {
+    keys: Vec<String>,
+    rows: Vec<Row>,
+    // map of row key to index in rows, only used for keyed tables
+    index: HashMap<String, usize>,
+    max_len: usize,
+}
+
+impl Table {
+    pub fn new(keys: Vec<String>, max_len: usize) -> Self {
+        Table {
+            keys,
+            rows: vec![],
+            index: HashMap::new(),
+            max_len,
+        }
+    }
+
+    pub fn keys(&self) -> &[String] {
+        &self.keys
+    }
+
+    // rows of the table. Appended rows are kept in arrival order, the order of keyed rows is not
+    // preserved after a delete.
+    pub fn rows(&self) -> &[Row] {
+        &self.rows
+    }
+
+    pub fn len(&self) -> usize {
+        self.rows.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.rows.is_empty()
+    }
+
+    pub fn is_keyed(&self) -> bool {
+        !self.keys.is_empty()
+    }
+
+    // find the row with the same key fields as the given row
+    pub fn find(&self, row: &Row) -> Option<&Row> {
+        let key = self.row_key(row)?;
+        self.index.get(&key).map(|&i| &self.rows[i])
+    }
+
+    pub fn insert(&mut self, row: Row) -> Result<(), TableError> {
+        if !self.is_keyed() {
+            self.rows.push(row);
+            if self.rows.len() > self.max_len {
+                let excess = self.rows.len() - self.max_len;
+                self.rows.drain(..excess);
+            }
+            return Ok(());
+        }
+        let key = self.row_key(&row).ok_or(TableError::MissingKey)?;
+        match self.index.get(&key) {
+            // insert for an existing key replaces the row
+            Some(&i) => self.rows[i] = row,
+            None => {
+                self.index.insert(key, self.rows.len());
+                self.rows.push(row);
+            }
+        }
+        Ok(())
+    }
+
+    // merge the fields of the given row into the row with the same key
+    pub fn update(&mut self, row: Row) -> Result<(), TableError> {
+        if !self.is_keyed() {
+            return Err(TableError::NotKeyed);
+        }
+        let key = self.row_key(&row).ok_or(TableError::MissingKey)?;
+        let i = *self.index.get(&key).ok_or(TableError::RowNotFound)?;
+        let existing = &mut self.rows[i];
+        for (field, value) in row {
+            existing.insert(field, value);
+        }
+        Ok(())
+    }
+
+    pub fn delete(&mut self, row: &Row) -> Result<Row, TableError> {
+        if !self.is_keyed() {
+            return Err(TableError::NotKeyed);
+        }
+        let key = self.row_key(row).ok_or(TableError::MissingKey)?;
+        let i = self.index.remove(&key).ok_or(TableError::RowNotFound)?;
+        let removed = self.rows.swap_remove(i);
+        // fix up the index of the row moved into the removed slot
+        if i < self.rows.len() {
+            let moved_key = self.row_key(&self.rows[i]).unwrap();
+            self.index.insert(moved_key, i);
+        }
+        Ok(removed)
+    }
+
+    // check that a batch of rows can be applied with the action, so that a message is either
+    // applied entirely or not at all
+    fn validate(&self, action: &str, rows: &[Row]) -> Result<(), TableError> {
+        if action != "insert" && !self.is_keyed() {
+            return Err(TableError::NotKeyed);
+        }
+        if !self.is_keyed() {
+            return Ok(());
+        }
+        let mut deleted = HashSet::new();
+        for row in rows.iter() {
+            let key = self.row_key(row).ok_or(TableError::MissingKey)?;
+            if action == "insert" {
+                continue;
+            }
+            // a row deleted earlier in the batch is not there anymore
+            if !self.index.contains_key(&key) || deleted.contains(&key) {
+                return Err(TableError::RowNotFound);
+            }
+            if action == "delete" {
+                deleted.insert(key);
+            }
+        }
+        Ok(())
+    }
+
+    // remove the rows with the given field values, e.g. the rows of a symbol
+    fn remove_matching(&mut self, filter: &Row) {
+        self.rows.retain(|row| {
+            filter
+                .iter()
+                .any(|(field, value)| row.get(field) != Some(value))
+        });
+        self.index.clear();
+        for i in 0..self.rows.len() {
+            if let Some(key) = self.row_key(&self.rows[i]) {
+                self.index.insert(key, i);
+            }
+        }
+    }
+
+    // key of a row is its key field values joined, None if a key field is missing
+    fn row_key(&self, row: &Row) -> Option<String> {
+        let mut key = String::new();
+        for field in self.keys.iter() {
+            let value = row.get(field)?;
+            key.push_str(&value.to_string());
+            key.push('\u{1f}');
+        }
+        Some(key)
+    }
+}
+
+// Client side store of BitMEX tables, mirrors the keyed table data model of the realtime api.
+// A table is created by its partial, then kept up to date by insert, update and delete messages.
+// BitMEX sends a partial per subscribed topic, a partial with a filter (e.g. the symbol of
+// orderBookL2:XBTUSD) only replaces the rows matching the filter, a partial without one resets
+// the table. A message failing with an error leaves the table unchanged.
+pub struct TableStore {
+    tables: HashMap<String, Table>,
+    max_table_len: usize,
+}
+
+impl TableStore {
+    pub fn new() -> Self {
+        TableStore::with_max_table_len(DEFAULT_MAX_TABLE_LEN)
+    }
+
+    // max number of rows to keep for append only tables
+    pub fn with_max_table_len(max_table_len: usize) -> Self {
+        TableStore {
+            tables: HashMap::new(),
+            max_table_len,
+        }
+    }
+
+    pub fn get(&self, table: &str) -> Option<&Table> {
+        self.tables.get(table)
+    }
+
+    pub fn apply(&mut self, message: &TableMessage) -> Result<(), TableError> {
+        if message.action == "partial" {
+            let table = match self.tables.get_mut(&message.table) {
+                Some(table) if !message.filter.is_empty() => table,
+                _ => {
+                    let mut table = Table::new(message.keys.clone(), self.max_table_len);
+                    table.validate("insert", &message.data)?;
+                    for row in message.data.iter() {
+                        table.insert(row.clone())?;
+                    }
+                    self.tables.insert(message.table.clone(), table);
+                    return Ok(());
+                }
+            };
+            table.validate("insert", &message.data)?;
+            table.remove_matching(&message.filter);
+            for row in message.data.iter() {
+                table.insert(row.clone())?;
+            }
+            return Ok(());
+        }
+
+        let table = self
+            .tables
+            .get_mut(&message.table)
+            .ok_or(TableError::UnknownTable)?;
+        match message.action.as_str() {
+            "insert" | "update" | "delete" => table.validate(&message.action, &message.data)?,
+            _ => return Err(TableError::UnknownAction),
+        }
+        match message.action.as_str() {
+            "insert" => {
+                for row in message.data.iter() {
+                    table.insert(row.clone())?;
+                }
+            }
+            "update" => {
+                for row in message.data.iter() {
+                    table.update(row.clone())?;
+                }
+            }
+            _ => {
+                for row in message.data.iter() {
+                    table.delete(row)?;
+                }
+            }
+        }
+        Ok(())
+    }
+}
+
+impl Default for TableStore {
+    fn default() -> Self {
+        TableStore::new()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::bitmex_message::{parse_table, TableMessage};
+    use crate::table_store::{TableError, TableStore};
+
+    fn apply(store: &mut TableStore, text: &[u8]) -> Result<(), TableError> {
+        store.apply(&parse_table(text).unwrap())
+    }
+
+    #[test]
+    fn keyed_table_insert_update_delete() {
+        let mut store = TableStore::new();
+        apply(&mut store, b"{\"table\":\"orderBookL2\",\"action\":\"partial\",\"keys\":[\"symbol\",\"id\",\"side\"],\"data\":[{\"symbol\":\"XBTUSD\",\"id\":8799070500,\"side\":\"Sell\",\"size\":384243,\"price\":9295},{\"symbol\":\"XBTUSD\",\"id\":8799070950,\"side\":\"Buy\",\"size\":1023444,\"price\":9290.5}]}").unwrap();
+        apply(&mut store, b"{\"table\":\"orderBookL2\",\"action\":\"insert\",\"data\":[{\"symbol\":\"XBTUSD\",\"id\":8799071000,\"side\":\"Buy\",\"size\":23490,\"price\":9290}]}").unwrap();
+        apply(&mut store, b"{\"table\":\"orderBookL2\",\"action\":\"update\",\"data\":[{\"symbol\":\"XBTUSD\",\"id\":8799070500,\"side\":\"Sell\",\"size\":100}]}").unwrap();
+        apply(&mut store, b"{\"table\":\"orderBookL2\",\"action\":\"delete\",\"data\":[{\"symbol\":\"XBTUSD\",\"id\":8799070950,\"side\":\"Buy\"}]}").unwrap();
+
+        let table = store.get("orderBookL2").unwrap();
+        assert_eq!(table.keys(), ["symbol", "id", "side"]);
+        assert_eq!(table.len(), 2);
+
+        let updated = table.find(&table.rows()[0]).unwrap();
+        assert_eq!(updated["id"], 8799070500_i64);
+        assert_eq!(updated["size"], 100);
+        // update only merges the given fields
+        assert_eq!(updated["price"], 9295);
+
+        let inserted = table.find(&table.rows()[1]).unwrap();
+        assert_eq!(inserted["id"], 8799071000_i64);
+
+        assert_eq!(
+            apply(&mut store, b"{\"table\":\"orderBookL2\",\"action\":\"update\",\"data\":[{\"symbol\":\"XBTUSD\",\"id\":8799070950,\"side\":\"Buy\",\"size\":1}]}"),
+            Err(TableError::RowNotFound)
+        );
+    }
+
+    #[test]
+    fn append_only_table_is_capped() {
+        let mut store = TableStore::with_max_table_len(2);
+        apply(&mut store, b"{\"table\":\"trade\",\"action\":\"partial\",\"keys\":[],\"data\":[{\"symbol\":\"XBTUSD\",\"size\":1}]}").unwrap();
+        apply(&mut store, b"{\"table\":\"trade\",\"action\":\"insert\",\"data\":[{\"symbol\":\"XBTUSD\",\"size\":2},{\"symbol\":\"XBTUSD\",\"size\":3}]}").unwrap();
+
+        let table = store.get("trade").unwrap();
+        assert!(!table.is_keyed());
+        assert_eq!(table.len(), 2);
+        assert_eq!(table.rows()[0]["size"], 2);
+        assert_eq!(table.rows()[1]["size"], 3);
+    }
+
+    #[test]
+    fn partials_per_symbol() {
+        let mut store = TableStore::new();
+        apply(&mut store, b"{\"table\":\"orderBookL2\",\"action\":\"partial\",\"keys\":[\"symbol\",\"id\",\"side\"],\"filter\":{\"symbol\":\"XBTUSD\"},\"data\":[{\"symbol\":\"XBTUSD\",\"id\":8799070500,\"side\":\"Sell\",\"size\":384243,\"price\":9295}]}").unwrap();
+        apply(&mut store, b"{\"table\":\"orderBookL2\",\"action\":\"partial\",\"keys\":[\"symbol\",\"id\",\"side\"],\"filter\":{\"symbol\":\"ETHUSD\"},\"data\":[{\"symbol\":\"ETHUSD\",\"id\":29699998100,\"side\":\"Sell\",\"size\":10,\"price\":240}]}").unwrap();
+        assert_eq!(store.get("orderBookL2").unwrap().len(), 2);
+
+        // a new partial of a symbol only replaces the rows of the symbol
+        apply(&mut store, b"{\"table\":\"orderBookL2\",\"action\":\"partial\",\"keys\":[\"symbol\",\"id\",\"side\"],\"filter\":{\"symbol\":\"XBTUSD\"},\"data\":[{\"symbol\":\"XBTUSD\",\"id\":8799070950,\"side\":\"Buy\",\"size\":1023444,\"price\":9290.5}]}").unwrap();
+        let table = store.get("orderBookL2").unwrap();
+        let mut ids: Vec<i64> = table
+            .rows()
+            .iter()
+            .map(|row| row["id"].as_i64().unwrap())
+            .collect();
+        ids.sort_unstable();
+        assert_eq!(ids, [8799070950, 29699998100]);
+        apply(&mut store, b"{\"table\":\"orderBookL2\",\"action\":\"delete\",\"data\":[{\"symbol\":\"ETHUSD\",\"id\":29699998100,\"side\":\"Sell\"}]}").unwrap();
+        assert_eq!(store.get("orderBookL2").unwrap().len(), 1);
+
+        // a partial without filter resets the table
+        apply(&mut store, b"{\"table\":\"orderBookL2\",\"action\":\"partial\",\"keys\":[\"symbol\",\"id\",\"side\"],\"filter\":{},\"data\":[]}").unwrap();
+        assert!(store.get("orderBookL2").unwrap().is_empty());
+    }
+
+    #[test]
+    fn failed_messages_leave_the_table_unchanged() {
+        let mut store = TableStore::new();
+        apply(&mut store, b"{\"table\":\"orderBookL2\",\"action\":\"partial\",\"keys\":[\"symbol\",\"id\",\"side\"],\"data\":[{\"symbol\":\"XBTUSD\",\"id\":8799070500,\"side\":\"Sell\",\"size\":384243,\"price\":9295},{\"symbol\":\"XBTUSD\",\"id\":8799070950,\"side\":\"Buy\",\"size\":1023444,\"price\":9290.5}]}").unwrap();
+        assert_eq!(
+            apply(&mut store, b"{\"table\":\"orderBookL2\",\"action\":\"update\",\"data\":[{\"symbol\":\"XBTUSD\",\"id\":8799070500,\"side\":\"Sell\",\"size\":1},{\"symbol\":\"XBTUSD\",\"id\":8799071000,\"side\":\"Buy\",\"size\":1}]}"),
+            Err(TableError::RowNotFound)
+        );
+        assert_eq!(
+            apply(&mut store, b"{\"table\":\"orderBookL2\",\"action\":\"delete\",\"data\":[{\"symbol\":\"XBTUSD\",\"id\":8799070500,\"side\":\"Sell\"},{\"symbol\":\"XBTUSD\",\"id\":8799070500,\"side\":\"Sell\"}]}"),
+            Err(TableError::RowNotFound)
+        );
+        assert_eq!(
+            apply(&mut store, b"{\"table\":\"orderBookL2\",\"action\":\"insert\",\"data\":[{\"symbol\":\"XBTUSD\",\"id\":8799071000,\"side\":\"Buy\",\"size\":1},{\"symbol\":\"XBTUSD\",\"side\":\"Buy\",\"size\":1}]}"),
+            Err(TableError::MissingKey)
+        );
+        // parse_table rejects other actions, the message is built as it could be by hand
+        let message: TableMessage =
+            serde_json::from_str("{\"table\":\"orderBookL2\",\"action\":\"replace\",\"data\":[]}")
+                .unwrap();
+        assert_eq!(store.apply(&message), Err(TableError::UnknownAction));
+
+        let table = store.get("orderBookL2").unwrap();
+        assert_eq!(table.len(), 2);
+        assert_eq!(table.rows()[0]["size"], 384243);
+    }
+
+    #[test]
+    fn insert_before_partial() {
+        let mut store = TableStore::new();
+        assert_eq!(
+            apply(&mut store, b"{\"table\":\"trade\",\"action\":\"insert\",\"data\":[{\"symbol\":\"XBTUSD\",\"size\":2}]}"),
+            Err(TableError::UnknownTable)
+        );
+    }
+}
@@ -0,0 +1,171 @@
+use crate::bitmex_message::format_timestamp;
+use crate::recorder::now_nanos;
+use base64::Engine;
+use std::io;
+
+// Server side of the websocket protocol shared by the servers of the crate
+
+const WEBSOCKET_GUID: &str = "258EAFA5-E914-47DA-95CA-C5AB0DC85B11";
+
+// websocket op codes
+pub(crate) const CONTINUATION_OP_CODE: u8 = 0;
+pub(crate) const TEXT_OP_CODE: u8 = 1;
+pub(crate) const CLOSE_OP_CODE: u8 = 8;
+pub(crate) const PING_OP_CODE: u8 = 9;
+pub(crate) const PONG_OP_CODE: u8 = 10;
+
+pub(crate) const MAX_REQUEST_LEN: usize = 8192;
+pub(crate) const MAX_MESSAGE_LEN: usize = 1 << 16;
+
+pub(crate) fn invalid_data(message: &str) -> io::Error {
+    io::Error::new(io::ErrorKind::InvalidData, message)
+}
+
+// Sec-WebSocket-Accept value of a Sec-WebSocket-Key
+pub(crate) fn accept_key(key: &str) -> String {
+    let digest = sha1_smol::Sha1::from(format!("{}{}", key, WEBSOCKET_GUID)).digest();
+    base64::engine::general_purpose::STANDARD.encode(digest.bytes())
+}
+
+// response to the http upgrade request at the start of the buffer and the length of the
+// request, none until the whole request is buffered
+pub(crate) fn upgrade_response(buf: &[u8]) -> io::Result<Option<(String, usize)>> {
+    let end = match buf.windows(4).position(|w| w == b"\r\n\r\n") {
+        Some(end) => end + 4,
+        None if buf.len() > MAX_REQUEST_LEN => {
+            return Err(invalid_data("upgrade request too long"))
+        }
+        None => return Ok(None),
+    };
+    let request = String::from_utf8_lossy(&buf[..end]);
+    if !request.starts_with("GET ") {
+        return Err(invalid_data("not an upgrade request"));
+    }
+    let key = request
+        .lines()
+        .filter_map(|line| line.split_once(':'))
+        .find(|(name, _)| name.trim().eq_ignore_ascii_case("sec-websocket-key"))
+        .map(|(_, value)| value.trim())
+        .ok_or_else(|| invalid_data("missing websocket key"))?;
+    let response = format!(
+        "HTTP/1.1 101 Switching Protocols\r\nUpgrade: websocket\r\nConnection: Upgrade\r\nSec-WebSocket-Accept: {}\r\n\r\n",
+        accept_key(key)
+    );
+    Ok(Some((response, end)))
+}
+
+// info message sent by BitMEX when a connection is established
+pub(crate) fn welcome_message() -> String {
+    format!(
+        "{{\"info\":\"Welcome to the BitMEX Realtime API.\",\"version\":\"{}\",\"timestamp\":\"{}\",\"docs\":\"https://www.bitmex.com/app/wsAPI\",\"limit\":{{\"remaining\":{}}}}}",
+        env!("CARGO_PKG_VERSION"),
+        format_timestamp(now_nanos() as i64),
+        i32::MAX
+    )
+}
+
+// append an unmasked frame, as sent by a server
+pub(crate) fn write_frame(out: &mut Vec<u8>, op_code: u8, payload: &[u8]) {
+    out.push(0x80 | op_code);
+    let len = payload.len();
+    if len < 126 {
+        out.push(len as u8);
+    } else if len <= u16::MAX as usize {
+        out.push(126);
+        out.extend_from_slice(&(len as u16).to_be_bytes());
+    } else {
+        out.push(127);
+        out.extend_from_slice(&(len as u64).to_be_bytes());
+    }
+    out.extend_from_slice(payload);
+}
+
+// A frame sent by a client, the payload is unmasked
+pub(crate) struct ClientFrame {
+    pub(crate) is_final: bool,
+    pub(crate) op_code: u8,
+    pub(crate) payload: Vec<u8>,
+}
+
+// decode the frame at the start of the buffer, returns none until the whole frame is buffered
+pub(crate) fn read_client_frame(buf: &[u8]) -> io::Result<Option<(ClientFrame, usize)>> {
+    if buf.len() < 2 {
+        return Ok(None);
+    }
+    let is_final = buf[0] & 0x80 != 0;
+    let op_code = buf[0] & 0x0f;
+    if buf[1] & 0x80 == 0 {
+        return Err(invalid_data("client frames must be masked"));
+    }
+    let (len, mut offset) = match buf[1] & 0x7f {
+        126 if buf.len() >= 4 => (u16::from_be_bytes([buf[2], buf[3]]) as usize, 4),
+        127 if buf.len() >= 10 => {
+            let mut len = [0u8; 8];
+            len.copy_from_slice(&buf[2..10]);
+            (u64::from_be_bytes(len) as usize, 10)
+        }
+        126 | 127 => return Ok(None),
+        len => (len as usize, 2),
+    };
+    if len > MAX_MESSAGE_LEN {
+        return Err(invalid_data("client frame too long"));
+    }
+    if buf.len() < offset + 4 + len {
+        return Ok(None);
+    }
+    let mask = [
+        buf[offset],
+        buf[offset + 1],
+        buf[offset + 2],
+        buf[offset + 3],
+    ];
+    offset += 4;
+    let payload = buf[offset..offset + len]
+        .iter()
+        .enumerate()
+        .map(|(i, b)| b ^ mask[i % 4])
+        .collect();
+    Ok(Some((
+        ClientFrame {
+            is_final,
+            op_code,
+            payload,
+        },
+        offset + len,
+    )))
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::ws_server::{accept_key, read_client_frame, upgrade_response};
+
+    #[test]
+    fn websocket_accept_key() {
+        assert_eq!(
+            accept_key("dGhlIHNhbXBsZSBub25jZQ=="),
+            "s3pPLMBiTxaQ9kYGzzhZRbK+xOo="
+        );
+    }
+
+    #[test]
+    fn upgrade_and_client_frames() {
+        let request =
+            b"GET /realtime HTTP/1.1\r\nSec-WebSocket-Key: dGhlIHNhbXBsZSBub25jZQ==\r\n\r\n";
+        assert!(upgrade_response(&request[..20]).unwrap().is_none());
+        let (response, len) = upgrade_response(request).unwrap().unwrap();
+        assert_eq!(len, request.len());
+        assert!(response.contains("s3pPLMBiTxaQ9kYGzzhZRbK+xOo="));
+
+        // masked "Hello" from RFC 6455
+        let frame = [
+            0x81, 0x85, 0x37, 0xfa, 0x21, 0x3d, 0x7f, 0x9f, 0x4d, 0x51, 0x58,
+        ];
+        assert!(read_client_frame(&frame[..6]).unwrap().is_none());
+        let (frame, len) = read_client_frame(&frame).unwrap().unwrap();
+        assert_eq!(len, 11);
+        assert!(frame.is_final);
+        assert_eq!(frame.payload, b"Hello");
+        // unmasked frames are rejected
+        assert!(read_client_frame(&[0x81, 0x01, b'a']).is_err());
+    }
+}
@@ -0,0 +1,93 @@
+// The allocation counting allocator replaces the global allocator of the whole test binary, the
+// test has a binary of its own so that the unit tests run on the system allocator.
+use bitmex_md::bitmex_message::parse;
+use bitmex_md::instrument::SymbolRegistry;
+use bitmex_md::parser::{BitmexMessageRef, Parser};
+use std::alloc::{GlobalAlloc, Layout, System};
+use std::cell::Cell;
+
+#[allow(dead_code)]
+#[path = "../src/fixtures.rs"]
+mod fixtures;
+
+use fixtures::{DELETE, INSERT, TRADE, UPDATE};
+
+// counts the allocations of the current thread so that tests running in parallel do not
+// interfere
+struct CountingAllocator;
+
+thread_local! {
+    static ALLOCATIONS: Cell<usize> = const { Cell::new(0) };
+}
+
+fn count_allocation() {
+    let _ = ALLOCATIONS.try_with(|count| count.set(count.get() + 1));
+}
+
+fn allocations() -> usize {
+    ALLOCATIONS.with(|count| count.get())
+}
+
+unsafe impl GlobalAlloc for CountingAllocator {
+    unsafe fn alloc(&self, layout: Layout) -> *mut u8 {
+        count_allocation();
+        System.alloc(layout)
+    }
+
+    unsafe fn dealloc(&self, ptr: *mut u8, layout: Layout) {
+        System.dealloc(ptr, layout)
+    }
+
+    unsafe fn realloc(&self, ptr: *mut u8, layout: Layout, new_size: usize) -> *mut u8 {
+        count_allocation();
+        System.realloc(ptr, layout, new_size)
+    }
+}
+
+#[global_allocator]
+static ALLOCATOR: CountingAllocator = CountingAllocator;
+
+#[test]
+fn steady_state_without_allocation() {
+    let rows: Vec<String> = (0..1000)
+        .map(|i| {
+            format!(
+                "{{\"symbol\":\"XBTUSD\",\"id\":{},\"side\":\"Buy\",\"size\":{}}}",
+                8799065200i64 + i,
+                i + 1
+            )
+        })
+        .collect();
+    let batch = format!(
+        "{{\"table\":\"orderBookL2\",\"action\":\"update\",\"data\":[{}]}}",
+        rows.join(",")
+    );
+    let messages = [INSERT, UPDATE, DELETE, TRADE, batch.as_bytes()];
+
+    let mut parser = Parser::new();
+    let mut symbols = SymbolRegistry::new();
+    // the buffers grow to the largest batch
+    for message in messages.iter() {
+        parser.parse(message, &mut symbols).unwrap();
+    }
+    let before = allocations();
+    let mut rows = 0;
+    for _ in 0..100 {
+        for message in messages.iter() {
+            rows += match parser.parse(message, &mut symbols).unwrap() {
+                BitmexMessageRef::Insert(entries)
+                | BitmexMessageRef::Update(entries)
+                | BitmexMessageRef::Delete(entries) => entries.len(),
+                BitmexMessageRef::Trade(trades) => trades.len(),
+                BitmexMessageRef::Message(_) => panic!("message not scanned"),
+            };
+        }
+    }
+    assert_eq!(allocations(), before);
+    assert_eq!(rows, 100 * 1005);
+
+    // parse allocates the rows and strings of every message
+    let before = allocations();
+    parse(UPDATE).unwrap();
+    assert!(allocations() > before);
+}